@@ -0,0 +1,32 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::io;
+use tree::limits::Limits;
+use tree::vm::Vm;
+use tree::Lox;
+
+// Little fuel and a shallow call stack keep each run short, so time goes
+// on trying inputs rather than running loops.
+const LIMITS: Limits = Limits {
+    fuel: Some(10_000),
+    timeout: None,
+};
+const MAX_CALL_DEPTH: usize = 64;
+
+// Feeds arbitrary bytes through every phase of both backends. Any error
+// is fine; a panic is a bug.
+fuzz_target!(|data: &[u8]| {
+    let source = String::from_utf8_lossy(data);
+    let mut lox = Lox::new();
+    lox.set_limits(LIMITS);
+    lox.set_max_call_depth(MAX_CALL_DEPTH);
+    lox.set_output(io::sink());
+    let _ = lox.check_types(&source);
+    let _ = lox.run(&source);
+    let mut vm = Vm::new();
+    vm.set_limits(LIMITS);
+    vm.set_max_call_depth(MAX_CALL_DEPTH);
+    vm.set_output(io::sink());
+    let _ = vm.interpret(&source);
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tree::scanner::Scanner;
+
+// The scanner decodes its source a character at a time itself, so the
+// bytes go in as they are, invalid UTF-8 included.
+fuzz_target!(|data: &[u8]| {
+    let mut scanner = Scanner::new();
+    scanner.source = data.to_vec();
+    scanner.by_ref().for_each(drop);
+});
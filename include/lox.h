@@ -0,0 +1,57 @@
+/* The C interface to the Lox interpreter. See src/ffi.rs for how to build
+ * the library and who owns what. */
+#ifndef LOX_H
+#define LOX_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct Lox Lox;
+typedef struct LoxValue LoxValue;
+
+enum {
+  LOX_NIL = 0,
+  LOX_BOOL = 1,
+  LOX_NUMBER = 2,
+  LOX_STRING = 3,
+  LOX_OTHER = 4,
+};
+
+/* Returns a new value for the interpreter to take, one from lox_value_error
+ * to fail the call, or NULL for nil. The arguments are freed after. */
+typedef LoxValue *(*LoxNative)(void *data, size_t argc, const LoxValue *const *argv);
+
+Lox *lox_new(void);
+void lox_free(Lox *lox);
+/* 0 on success, 65 for compile errors, 70 for runtime errors or a panic. */
+int lox_run(Lox *lox, const char *source, LoxValue **result);
+const char *lox_last_error(const Lox *lox);
+/* Frees the sources of earlier runs, kept until now for error reports. */
+void lox_release_sources(Lox *lox);
+void lox_set_max_call_depth(Lox *lox, size_t depth);
+void lox_define_native(Lox *lox, const char *name, size_t arity, LoxNative function,
+                       void *data);
+LoxValue *lox_get_global(const Lox *lox, const char *name);
+void lox_set_global(Lox *lox, const char *name, const LoxValue *value);
+
+LoxValue *lox_value_nil(void);
+LoxValue *lox_value_bool(int b);
+LoxValue *lox_value_number(double n);
+LoxValue *lox_value_string(const char *s);
+LoxValue *lox_value_error(const char *message);
+void lox_value_free(LoxValue *value);
+int lox_value_type(const LoxValue *value);
+int lox_value_as_bool(const LoxValue *value);
+double lox_value_as_number(const LoxValue *value);
+const char *lox_value_as_string(const LoxValue *value);
+char *lox_value_to_string(const LoxValue *value);
+void lox_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
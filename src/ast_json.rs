@@ -0,0 +1,611 @@
+use crate::json::Json;
+use crate::parser::{Expr, FunctionDecl, LiteralValue, Stmt};
+use crate::scanner::{Token, TokenType};
+use crate::span::Span;
+
+// Serializes a program as a JSON array of statement objects. Every node has
+// a "type" naming its variant and a "span" of byte offsets; names and
+// operators appear as plain strings.
+pub fn to_json(statements: &[Stmt]) -> String {
+    array(statements.iter().map(stmt))
+}
+
+// Builds one JSON object, keeping fields in insertion order.
+struct Object {
+    fields: Vec<(&'static str, String)>,
+}
+impl Object {
+    fn new(kind: &str, span: Span) -> Object {
+        Object {
+            fields: vec![
+                ("type", string(kind)),
+                (
+                    "span",
+                    format!("{{\"start\":{},\"end\":{}}}", span.start, span.end),
+                ),
+            ],
+        }
+    }
+    fn field(mut self, key: &'static str, value: String) -> Object {
+        self.fields.push((key, value));
+        self
+    }
+    fn finish(self) -> String {
+        let fields: Vec<String> = self
+            .fields
+            .into_iter()
+            .map(|(key, value)| format!("{}:{}", string(key), value))
+            .collect();
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+fn string(s: &str) -> String {
+    Json::string(s).to_string()
+}
+
+fn array(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<_>>().join(","))
+}
+
+fn optional(value: Option<String>) -> String {
+    value.unwrap_or_else(|| "null".to_string())
+}
+
+fn name(token: &Token) -> String {
+    string(&token.lexeme)
+}
+
+fn stmt(stmt_: &Stmt) -> String {
+    let span = stmt_span(stmt_);
+    match stmt_ {
+        Stmt::Block(statements) => {
+            Object::new("Block", span).field("statements", array(statements.iter().map(stmt)))
+        }
+        Stmt::DoWhile { body, condition } => Object::new("DoWhile", span)
+            .field("body", stmt(body))
+            .field("condition", expr(condition)),
+        Stmt::Destructure {
+            names, initializer, ..
+        } => Object::new("Destructure", span)
+            .field("names", array(names.iter().map(name)))
+            .field("initializer", expr(initializer)),
+        Stmt::Class {
+            name: class_name,
+            superclass,
+            traits,
+            methods,
+            class_methods,
+        } => Object::new("Class", span)
+            .field("name", name(class_name))
+            .field("superclass", optional(superclass.as_ref().map(expr)))
+            .field("traits", array(traits.iter().map(expr)))
+            .field(
+                "methods",
+                array(
+                    methods
+                        .iter()
+                        .map(|method| function(method_kind(method), method)),
+                ),
+            )
+            .field(
+                "classMethods",
+                array(
+                    class_methods
+                        .iter()
+                        .map(|method| function(method_kind(method), method)),
+                ),
+            ),
+        Stmt::Expression(expression) => {
+            Object::new("Expression", span).field("expression", expr(expression))
+        }
+        Stmt::For {
+            initializer,
+            condition,
+            increment,
+            body,
+        } => Object::new("For", span)
+            .field("initializer", optional(initializer.as_deref().map(stmt)))
+            .field("condition", expr(condition))
+            .field("increment", optional(increment.as_ref().map(expr)))
+            .field("body", stmt(body)),
+        Stmt::ForEach {
+            name: variable,
+            iterable,
+            body,
+            ..
+        } => Object::new("ForEach", span)
+            .field("name", name(variable))
+            .field("iterable", expr(iterable))
+            .field("body", stmt(body)),
+        Stmt::Function(declaration) => return function("Function", declaration),
+        Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => Object::new("If", span)
+            .field("condition", expr(condition))
+            .field("then", stmt(then_branch))
+            .field("else", optional(else_branch.as_deref().map(stmt))),
+        Stmt::Import { path, .. } => Object::new("Import", span).field("path", string(path)),
+        Stmt::Print(expression) => Object::new("Print", span).field("expression", expr(expression)),
+        Stmt::Return { value, .. } => {
+            Object::new("Return", span).field("value", optional(value.as_ref().map(expr)))
+        }
+        Stmt::Switch {
+            subject,
+            cases,
+            default,
+        } => Object::new("Switch", span)
+            .field("subject", expr(subject))
+            .field(
+                "cases",
+                array(cases.iter().map(|(value, body)| {
+                    Object::new("Case", cover(case_spans(value, body)))
+                        .field("value", expr(value))
+                        .field("body", array(body.iter().map(stmt)))
+                        .finish()
+                })),
+            )
+            .field(
+                "default",
+                optional(
+                    default
+                        .as_ref()
+                        .map(|default| array(default.iter().map(stmt))),
+                ),
+            ),
+        Stmt::Trait {
+            name: trait_name,
+            methods,
+        } => Object::new("Trait", span)
+            .field("name", name(trait_name))
+            .field(
+                "methods",
+                array(
+                    methods
+                        .iter()
+                        .map(|method| function(method_kind(method), method)),
+                ),
+            ),
+        Stmt::Throw { value, .. } => Object::new("Throw", span).field("value", expr(value)),
+        Stmt::Try {
+            body,
+            catch,
+            finally,
+        } => Object::new("Try", span)
+            .field("body", array(body.iter().map(stmt)))
+            .field(
+                "catch",
+                optional(catch.as_ref().map(|(variable, handler)| {
+                    Object::new("Catch", cover(catch_spans(variable, handler)))
+                        .field("name", name(variable))
+                        .field("body", array(handler.iter().map(stmt)))
+                        .finish()
+                })),
+            )
+            .field(
+                "finally",
+                optional(
+                    finally
+                        .as_ref()
+                        .map(|finally| array(finally.iter().map(stmt))),
+                ),
+            ),
+        Stmt::Var {
+            name: var_name,
+            annotation,
+            initializer,
+        } => Object::new("Var", span)
+            .field("name", name(var_name))
+            .field("annotation", optional(annotation.as_ref().map(name)))
+            .field("initializer", optional(initializer.as_ref().map(expr))),
+        Stmt::While { condition, body } => Object::new("While", span)
+            .field("condition", expr(condition))
+            .field("body", stmt(body)),
+        Stmt::Yield { value, .. } => {
+            Object::new("Yield", span).field("value", optional(value.as_ref().map(expr)))
+        }
+    }
+    .finish()
+}
+
+fn expr(expr_: &Expr) -> String {
+    let span = expr_span(expr_);
+    match expr_ {
+        Expr::Assign {
+            name: target,
+            value,
+            ..
+        } => Object::new("Assign", span)
+            .field("name", name(target))
+            .field("value", expr(value)),
+        Expr::Compound {
+            target,
+            operator,
+            value,
+        } => Object::new("Compound", span)
+            .field("target", expr(target))
+            .field("operator", name(operator))
+            .field("value", expr(value)),
+        Expr::Destructure { targets, value, .. } => Object::new("Destructure", span)
+            .field("targets", array(targets.iter().map(expr)))
+            .field("value", expr(value)),
+        Expr::Binary {
+            left,
+            operator,
+            right,
+        } => Object::new("Binary", span)
+            .field("operator", name(operator))
+            .field("left", expr(left))
+            .field("right", expr(right)),
+        Expr::Call {
+            callee, arguments, ..
+        } => Object::new("Call", span)
+            .field("callee", expr(callee))
+            .field("arguments", array(arguments.iter().map(expr))),
+        Expr::Conditional {
+            condition,
+            then_branch,
+            else_branch,
+        } => Object::new("Conditional", span)
+            .field("condition", expr(condition))
+            .field("then", expr(then_branch))
+            .field("else", expr(else_branch)),
+        Expr::Get {
+            object,
+            name: property,
+            safe,
+        } => Object::new("Get", span)
+            .field("object", expr(object))
+            .field("name", name(property))
+            .field("safe", safe.to_string()),
+        Expr::Grouping(expression) => {
+            Object::new("Grouping", span).field("expression", expr(expression))
+        }
+        Expr::Index { object, index, .. } => Object::new("Index", span)
+            .field("object", expr(object))
+            .field("index", expr(index)),
+        Expr::IndexSet {
+            object,
+            index,
+            value,
+            ..
+        } => Object::new("IndexSet", span)
+            .field("object", expr(object))
+            .field("index", expr(index))
+            .field("value", expr(value)),
+        Expr::Lambda(declaration) => return function("Lambda", declaration),
+        Expr::List(elements) => {
+            Object::new("List", span).field("elements", array(elements.iter().map(expr)))
+        }
+        Expr::Literal { value, .. } => Object::new("Literal", span).field(
+            "value",
+            match value {
+                LiteralValue::Nil => "null".to_string(),
+                LiteralValue::Bool(b) => b.to_string(),
+                // JSON has no representation for NaN or the infinities.
+                LiteralValue::Number(n) if n.is_finite() => n.to_string(),
+                LiteralValue::Number(n) => string(&n.to_string()),
+                LiteralValue::Str(s) => string(s),
+            },
+        ),
+        Expr::Logical {
+            left,
+            operator,
+            right,
+        } => Object::new("Logical", span)
+            .field("operator", name(operator))
+            .field("left", expr(left))
+            .field("right", expr(right)),
+        Expr::Map { entries, .. } => Object::new("Map", span).field(
+            "entries",
+            array(
+                entries
+                    .iter()
+                    .map(|(key, value)| format!("[{},{}]", expr(key), expr(value))),
+            ),
+        ),
+        Expr::Set {
+            object,
+            name: property,
+            value,
+        } => Object::new("Set", span)
+            .field("object", expr(object))
+            .field("name", name(property))
+            .field("value", expr(value)),
+        Expr::Super { method, .. } => Object::new("Super", span).field("method", name(method)),
+        Expr::This { .. } => Object::new("This", span),
+        // Interpolated `${..}` parts convert to strings through a unary
+        // operator on the Interpolation token; name it by what it does.
+        Expr::Unary { operator, right } => Object::new("Unary", span)
+            .field(
+                "operator",
+                match operator.tty {
+                    TokenType::Interpolation => string("str"),
+                    _ => name(operator),
+                },
+            )
+            .field("right", expr(right)),
+        Expr::Update {
+            operator,
+            prefix,
+            target,
+            ..
+        } => Object::new("Update", span)
+            .field("operator", name(operator))
+            .field("prefix", prefix.to_string())
+            .field("target", expr(target)),
+        Expr::Variable { name: variable, .. } => {
+            Object::new("Variable", span).field("name", name(variable))
+        }
+    }
+    .finish()
+}
+
+fn method_kind(declaration: &FunctionDecl) -> &'static str {
+    if declaration.is_getter {
+        "Getter"
+    } else {
+        "Function"
+    }
+}
+
+fn function(kind: &str, declaration: &FunctionDecl) -> String {
+    Object::new(kind, function_span(declaration))
+        .field("name", name(&declaration.name))
+        .field("params", array(declaration.params.iter().map(name)))
+        .field(
+            "paramTypes",
+            array(
+                declaration
+                    .param_types
+                    .iter()
+                    .map(|annotation| optional(annotation.as_ref().map(name))),
+            ),
+        )
+        .field(
+            "defaults",
+            array(
+                declaration
+                    .defaults
+                    .iter()
+                    .map(|default| optional(default.as_ref().map(expr))),
+            ),
+        )
+        .field(
+            "returnType",
+            optional(declaration.return_type.as_ref().map(name)),
+        )
+        .field("body", array(declaration.body.iter().map(stmt)))
+        .finish()
+}
+
+// A node's span runs from the first to the last token the AST keeps for it
+// or its children. Punctuation the parser discards, such as a closing `;`
+// or `}`, is not included.
+pub(crate) fn stmt_span(stmt: &Stmt) -> Span {
+    let spans: Vec<Span> = match stmt {
+        Stmt::Block(statements) => statements.iter().map(stmt_span).collect(),
+        Stmt::DoWhile { body, condition } => vec![stmt_span(body), expr_span(condition)],
+        Stmt::Destructure {
+            names,
+            equals,
+            initializer,
+        } => names
+            .iter()
+            .map(|name| name.span)
+            .chain([equals.span, expr_span(initializer)])
+            .collect(),
+        Stmt::Class {
+            name,
+            superclass,
+            traits,
+            methods,
+            class_methods,
+        } => std::iter::once(name.span)
+            .chain(superclass.iter().map(expr_span))
+            .chain(traits.iter().map(expr_span))
+            .chain(methods.iter().map(|method| function_span(method)))
+            .chain(class_methods.iter().map(|method| function_span(method)))
+            .collect(),
+        Stmt::Expression(expr) | Stmt::Print(expr) => vec![expr_span(expr)],
+        Stmt::For {
+            initializer,
+            condition,
+            increment,
+            body,
+        } => initializer
+            .iter()
+            .map(|initializer| stmt_span(initializer))
+            .chain(std::iter::once(expr_span(condition)))
+            .chain(increment.iter().map(expr_span))
+            .chain(std::iter::once(stmt_span(body)))
+            .collect(),
+        Stmt::ForEach {
+            name,
+            iterable,
+            body,
+            ..
+        } => vec![name.span, expr_span(iterable), stmt_span(body)],
+        Stmt::Function(declaration) => vec![function_span(declaration)],
+        Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => std::iter::once(expr_span(condition))
+            .chain(std::iter::once(stmt_span(then_branch)))
+            .chain(else_branch.as_deref().map(stmt_span))
+            .collect(),
+        Stmt::Import { keyword, span, .. } => vec![keyword.span, *span],
+        Stmt::Return { keyword, value } => std::iter::once(keyword.span)
+            .chain(value.iter().map(expr_span))
+            .collect(),
+        Stmt::Switch {
+            subject,
+            cases,
+            default,
+        } => std::iter::once(expr_span(subject))
+            .chain(
+                cases
+                    .iter()
+                    .flat_map(|(value, body)| case_spans(value, body)),
+            )
+            .chain(default.iter().flatten().map(stmt_span))
+            .collect(),
+        Stmt::Trait { name, methods } => std::iter::once(name.span)
+            .chain(methods.iter().map(|method| function_span(method)))
+            .collect(),
+        Stmt::Throw { keyword, value } => vec![keyword.span, expr_span(value)],
+        Stmt::Try {
+            body,
+            catch,
+            finally,
+        } => body
+            .iter()
+            .map(stmt_span)
+            .chain(
+                catch
+                    .iter()
+                    .flat_map(|(name, handler)| catch_spans(name, handler)),
+            )
+            .chain(finally.iter().flatten().map(stmt_span))
+            .collect(),
+        Stmt::Var {
+            name,
+            annotation,
+            initializer,
+        } => std::iter::once(name.span)
+            .chain(annotation.iter().map(|annotation| annotation.span))
+            .chain(initializer.iter().map(expr_span))
+            .collect(),
+        Stmt::While { condition, body } => vec![expr_span(condition), stmt_span(body)],
+        Stmt::Yield { keyword, value } => std::iter::once(keyword.span)
+            .chain(value.iter().map(expr_span))
+            .collect(),
+    };
+    cover(spans)
+}
+
+fn case_spans(value: &Expr, body: &[Stmt]) -> Vec<Span> {
+    std::iter::once(expr_span(value))
+        .chain(body.iter().map(stmt_span))
+        .collect()
+}
+
+fn catch_spans(name: &Token, handler: &[Stmt]) -> Vec<Span> {
+    std::iter::once(name.span)
+        .chain(handler.iter().map(stmt_span))
+        .collect()
+}
+
+pub(crate) fn expr_span(expr: &Expr) -> Span {
+    let spans: Vec<Span> = match expr {
+        Expr::Assign { name, value, .. } => vec![name.span, expr_span(value)],
+        Expr::Compound {
+            target,
+            operator,
+            value,
+        } => vec![expr_span(target), operator.span, expr_span(value)],
+        Expr::Binary {
+            left,
+            operator,
+            right,
+        }
+        | Expr::Logical {
+            left,
+            operator,
+            right,
+        } => vec![expr_span(left), operator.span, expr_span(right)],
+        Expr::Call {
+            callee,
+            paren,
+            arguments,
+        } => std::iter::once(expr_span(callee))
+            .chain(std::iter::once(paren.span))
+            .chain(arguments.iter().map(expr_span))
+            .collect(),
+        Expr::Conditional {
+            condition,
+            then_branch,
+            else_branch,
+        } => vec![
+            expr_span(condition),
+            expr_span(then_branch),
+            expr_span(else_branch),
+        ],
+        Expr::Destructure {
+            targets,
+            equals,
+            value,
+        } => targets
+            .iter()
+            .map(expr_span)
+            .chain([equals.span, expr_span(value)])
+            .collect(),
+        Expr::Get { object, name, .. } => vec![expr_span(object), name.span],
+        Expr::Grouping(expr) => vec![expr_span(expr)],
+        Expr::Index {
+            object,
+            bracket,
+            index,
+        } => vec![expr_span(object), bracket.span, expr_span(index)],
+        Expr::IndexSet {
+            object,
+            bracket,
+            index,
+            value,
+        } => vec![
+            expr_span(object),
+            bracket.span,
+            expr_span(index),
+            expr_span(value),
+        ],
+        Expr::Lambda(declaration) => vec![function_span(declaration)],
+        Expr::List(elements) => elements.iter().map(expr_span).collect(),
+        Expr::Literal { span, .. } => vec![*span],
+        Expr::Map { brace, entries } => std::iter::once(brace.span)
+            .chain(
+                entries
+                    .iter()
+                    .flat_map(|(key, value)| [expr_span(key), expr_span(value)]),
+            )
+            .collect(),
+        Expr::Set {
+            object,
+            name,
+            value,
+        } => vec![expr_span(object), name.span, expr_span(value)],
+        Expr::Super {
+            keyword, method, ..
+        } => vec![keyword.span, method.span],
+        Expr::This { keyword, .. } => vec![keyword.span],
+        Expr::Unary { operator, right } => vec![operator.span, expr_span(right)],
+        Expr::Update {
+            operator, target, ..
+        } => vec![operator.span, expr_span(target)],
+        Expr::Variable { name, .. } => vec![name.span],
+    };
+    cover(spans)
+}
+
+fn function_span(declaration: &FunctionDecl) -> Span {
+    cover(
+        std::iter::once(declaration.name.span)
+            .chain(declaration.params.iter().map(|param| param.span))
+            .chain(declaration.param_types.iter().flatten().map(|t| t.span))
+            .chain(declaration.defaults.iter().flatten().map(expr_span))
+            .chain(declaration.return_type.iter().map(|t| t.span))
+            .chain(declaration.body.iter().map(stmt_span))
+            .collect(),
+    )
+}
+
+// Empty spans, like those of empty blocks, have no place to contribute.
+fn cover(spans: Vec<Span>) -> Span {
+    spans
+        .into_iter()
+        .filter(|span| span.start < span.end)
+        .reduce(Span::to)
+        .unwrap_or_default()
+}
@@ -0,0 +1,380 @@
+use crate::parser::{Expr, FunctionDecl, LiteralValue, Stmt};
+use crate::scanner::{Token, TokenType};
+
+// Both renderings are produced from this generic shape: a label plus the
+// child nodes beneath it. Leaves are names and literals; anything else is
+// parenthesized even when it has no children, as in `(return)`.
+struct Node {
+    label: String,
+    children: Vec<Node>,
+    leaf: bool,
+}
+impl Node {
+    fn leaf(label: impl Into<String>) -> Node {
+        Node {
+            label: label.into(),
+            children: Vec::new(),
+            leaf: true,
+        }
+    }
+    fn new(label: impl Into<String>, children: Vec<Node>) -> Node {
+        Node {
+            label: label.into(),
+            children,
+            leaf: false,
+        }
+    }
+}
+
+// Lisp-like form, one statement per line: `print 1 + 2 * 3;` becomes
+// `(print (+ 1 (* 2 3)))`.
+pub fn to_sexpr(statements: &[Stmt]) -> String {
+    statements
+        .iter()
+        .map(|stmt| sexpr(&stmt_node(stmt)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn expr_to_sexpr(expr: &Expr) -> String {
+    sexpr(&expr_node(expr))
+}
+
+// One node per line, children indented beneath their parent.
+pub fn to_tree(statements: &[Stmt]) -> String {
+    let mut out = String::new();
+    for stmt in statements {
+        tree(&stmt_node(stmt), 0, &mut out);
+    }
+    out.pop();
+    out
+}
+
+fn sexpr(node: &Node) -> String {
+    if node.leaf {
+        return node.label.clone();
+    }
+    let mut out = format!("({}", node.label);
+    for child in &node.children {
+        out.push(' ');
+        out.push_str(&sexpr(child));
+    }
+    out.push(')');
+    out
+}
+
+fn tree(node: &Node, depth: usize, out: &mut String) {
+    out.push_str(&"  ".repeat(depth));
+    out.push_str(&node.label);
+    out.push('\n');
+    for child in &node.children {
+        tree(child, depth + 1, out);
+    }
+}
+
+fn stmt_node(stmt: &Stmt) -> Node {
+    match stmt {
+        Stmt::Block(statements) => Node::new("block", statements.iter().map(stmt_node).collect()),
+        Stmt::DoWhile { body, condition } => {
+            Node::new("do", vec![stmt_node(body), expr_node(condition)])
+        }
+        Stmt::Destructure {
+            names, initializer, ..
+        } => {
+            let names = names.iter().map(|name| Node::leaf(lexeme(name))).collect();
+            Node::new("var", vec![Node::new("()", names), expr_node(initializer)])
+        }
+        Stmt::Class {
+            name,
+            superclass,
+            traits,
+            methods,
+            class_methods,
+        } => {
+            let mut children = vec![Node::leaf(lexeme(name))];
+            if let Some(superclass) = superclass {
+                children.push(Node::new("<", vec![expr_node(superclass)]));
+            }
+            if !traits.is_empty() {
+                children.push(Node::new("with", traits.iter().map(expr_node).collect()));
+            }
+            children.extend(methods.iter().map(|method| method_node("", method)));
+            children.extend(
+                class_methods
+                    .iter()
+                    .map(|method| method_node("class ", method)),
+            );
+            Node::new("class", children)
+        }
+        Stmt::Expression(expr) => Node::new(";", vec![expr_node(expr)]),
+        // Missing clauses are shown as `;`.
+        Stmt::For {
+            initializer,
+            condition,
+            increment,
+            body,
+        } => Node::new(
+            "for",
+            vec![
+                initializer
+                    .as_deref()
+                    .map_or_else(|| Node::leaf(";".to_string()), stmt_node),
+                expr_node(condition),
+                increment
+                    .as_ref()
+                    .map_or_else(|| Node::leaf(";".to_string()), expr_node),
+                stmt_node(body),
+            ],
+        ),
+        Stmt::ForEach {
+            name,
+            iterable,
+            body,
+            ..
+        } => Node::new(
+            "for-in",
+            vec![
+                Node::leaf(lexeme(name)),
+                expr_node(iterable),
+                stmt_node(body),
+            ],
+        ),
+        Stmt::Function(declaration) => function_node("fun", declaration),
+        Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => {
+            let mut children = vec![expr_node(condition), stmt_node(then_branch)];
+            if let Some(else_branch) = else_branch {
+                children.push(stmt_node(else_branch));
+            }
+            Node::new("if", children)
+        }
+        Stmt::Import { path, .. } => Node::new("import", vec![Node::leaf(format!("{:?}", path))]),
+        Stmt::Print(expr) => Node::new("print", vec![expr_node(expr)]),
+        Stmt::Return { value, .. } => Node::new("return", value.iter().map(expr_node).collect()),
+        Stmt::Switch {
+            subject,
+            cases,
+            default,
+        } => {
+            let mut children = vec![expr_node(subject)];
+            for (value, body) in cases {
+                let mut case = vec![expr_node(value)];
+                case.extend(body.iter().map(stmt_node));
+                children.push(Node::new("case", case));
+            }
+            if let Some(default) = default {
+                children.push(Node::new(
+                    "default",
+                    default.iter().map(stmt_node).collect(),
+                ));
+            }
+            Node::new("switch", children)
+        }
+        Stmt::Trait { name, methods } => {
+            let mut children = vec![Node::leaf(lexeme(name))];
+            children.extend(methods.iter().map(|method| method_node("", method)));
+            Node::new("trait", children)
+        }
+        Stmt::Throw { value, .. } => Node::new("throw", vec![expr_node(value)]),
+        Stmt::Try {
+            body,
+            catch,
+            finally,
+        } => {
+            let mut children = vec![Node::new("block", body.iter().map(stmt_node).collect())];
+            if let Some((name, handler)) = catch {
+                let mut handler: Vec<Node> = handler.iter().map(stmt_node).collect();
+                handler.insert(0, Node::leaf(lexeme(name)));
+                children.push(Node::new("catch", handler));
+            }
+            if let Some(finally) = finally {
+                children.push(Node::new(
+                    "finally",
+                    finally.iter().map(stmt_node).collect(),
+                ));
+            }
+            Node::new("try", children)
+        }
+        Stmt::Var {
+            name,
+            annotation,
+            initializer,
+        } => {
+            let mut children = vec![Node::leaf(annotated(name, annotation))];
+            children.extend(initializer.iter().map(expr_node));
+            Node::new("var", children)
+        }
+        Stmt::While { condition, body } => {
+            Node::new("while", vec![expr_node(condition), stmt_node(body)])
+        }
+        Stmt::Yield { value, .. } => Node::new("yield", value.iter().map(expr_node).collect()),
+    }
+}
+
+fn expr_node(expr: &Expr) -> Node {
+    match expr {
+        Expr::Assign { name, value, .. } => {
+            Node::new("=", vec![Node::leaf(lexeme(name)), expr_node(value)])
+        }
+        Expr::Compound {
+            target,
+            operator,
+            value,
+        } => Node::new(
+            format!("{}=", lexeme(operator)),
+            vec![expr_node(target), expr_node(value)],
+        ),
+        Expr::Binary {
+            left,
+            operator,
+            right,
+        }
+        | Expr::Logical {
+            left,
+            operator,
+            right,
+        } => Node::new(lexeme(operator), vec![expr_node(left), expr_node(right)]),
+        Expr::Call {
+            callee, arguments, ..
+        } => {
+            let mut children = vec![expr_node(callee)];
+            children.extend(arguments.iter().map(expr_node));
+            Node::new("call", children)
+        }
+        Expr::Conditional {
+            condition,
+            then_branch,
+            else_branch,
+        } => Node::new(
+            "?:",
+            vec![
+                expr_node(condition),
+                expr_node(then_branch),
+                expr_node(else_branch),
+            ],
+        ),
+        Expr::Destructure { targets, value, .. } => Node::new(
+            "=",
+            vec![
+                Node::new("()", targets.iter().map(expr_node).collect()),
+                expr_node(value),
+            ],
+        ),
+        Expr::Get { object, name, safe } => Node::new(
+            if *safe { "?." } else { "." },
+            vec![expr_node(object), Node::leaf(lexeme(name))],
+        ),
+        Expr::Grouping(expr) => Node::new("group", vec![expr_node(expr)]),
+        Expr::Index { object, index, .. } => {
+            Node::new("[]", vec![expr_node(object), expr_node(index)])
+        }
+        Expr::IndexSet {
+            object,
+            index,
+            value,
+            ..
+        } => Node::new(
+            "[]=",
+            vec![expr_node(object), expr_node(index), expr_node(value)],
+        ),
+        Expr::Lambda(declaration) => function_node("lambda", declaration),
+        Expr::List(elements) => Node::new("list", elements.iter().map(expr_node).collect()),
+        Expr::Literal { value, .. } => Node::leaf(match value {
+            LiteralValue::Nil => "nil".to_string(),
+            LiteralValue::Bool(b) => b.to_string(),
+            LiteralValue::Number(n) => n.to_string(),
+            LiteralValue::Str(s) => format!("{:?}", s),
+        }),
+        Expr::Map { entries, .. } => Node::new(
+            "map",
+            entries
+                .iter()
+                .map(|(key, value)| Node::new(":", vec![expr_node(key), expr_node(value)]))
+                .collect(),
+        ),
+        Expr::Set {
+            object,
+            name,
+            value,
+        } => Node::new(
+            ".=",
+            vec![
+                expr_node(object),
+                Node::leaf(lexeme(name)),
+                expr_node(value),
+            ],
+        ),
+        Expr::Super { method, .. } => Node::new("super", vec![Node::leaf(lexeme(method))]),
+        Expr::This { .. } => Node::leaf("this"),
+        // String interpolation desugars to a unary operator on the
+        // Interpolation token, whose lexeme is a chunk of the string.
+        Expr::Unary { operator, right } if operator.tty == TokenType::Interpolation => {
+            Node::new("str", vec![expr_node(right)])
+        }
+        Expr::Unary { operator, right } => Node::new(lexeme(operator), vec![expr_node(right)]),
+        Expr::Update {
+            operator,
+            prefix,
+            target,
+            ..
+        } => {
+            let label = if *prefix {
+                lexeme(operator)
+            } else {
+                format!("post{}", lexeme(operator))
+            };
+            Node::new(label, vec![expr_node(target)])
+        }
+        Expr::Variable { name, .. } => Node::leaf(lexeme(name)),
+    }
+}
+
+// `(fun name (params a b:Number) (-> Number) body...)`; lambdas have no
+// name.
+fn method_node(prefix: &str, declaration: &FunctionDecl) -> Node {
+    let kind = if declaration.is_getter { "get" } else { "fun" };
+    function_node(&format!("{}{}", prefix, kind), declaration)
+}
+
+fn function_node(kind: &str, declaration: &FunctionDecl) -> Node {
+    let mut children = Vec::new();
+    if kind != "lambda" {
+        children.push(Node::leaf(lexeme(&declaration.name)));
+    }
+    children.push(Node::new(
+        "params",
+        declaration
+            .params
+            .iter()
+            .zip(&declaration.param_types)
+            .zip(&declaration.defaults)
+            .map(|((param, annotation), default)| {
+                let param = Node::leaf(annotated(param, annotation));
+                match default {
+                    Some(default) => Node::new("=", vec![param, expr_node(default)]),
+                    None => param,
+                }
+            })
+            .collect(),
+    ));
+    if let Some(return_type) = &declaration.return_type {
+        children.push(Node::new("->", vec![Node::leaf(lexeme(return_type))]));
+    }
+    children.extend(declaration.body.iter().map(stmt_node));
+    Node::new(kind, children)
+}
+
+// A name followed by its type, as `x:Number`, if it has one.
+fn annotated(name: &Token, annotation: &Option<Token>) -> String {
+    match annotation {
+        Some(annotation) => format!("{}:{}", name.lexeme, annotation.lexeme),
+        None => lexeme(name),
+    }
+}
+
+fn lexeme(token: &Token) -> String {
+    token.lexeme.to_string()
+}
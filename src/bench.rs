@@ -0,0 +1,122 @@
+use std::env;
+use std::io;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+// The wall times of one script's runs on one backend.
+struct Timings {
+    min: Duration,
+    median: Duration,
+    stddev: Duration,
+}
+
+impl Timings {
+    fn new(mut runs: Vec<Duration>) -> Timings {
+        runs.sort();
+        let n = runs.len();
+        let median = if n.is_multiple_of(2) {
+            (runs[n / 2 - 1] + runs[n / 2]) / 2
+        } else {
+            runs[n / 2]
+        };
+        let secs: Vec<f64> = runs.iter().map(Duration::as_secs_f64).collect();
+        let mean = secs.iter().sum::<f64>() / n as f64;
+        let variance = secs.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / n as f64;
+        Timings {
+            min: runs[0],
+            median,
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+        }
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.2}", duration.as_secs_f64() * 1000.0)
+}
+
+// Runs `script` on `backend` in a child process with its output thrown
+// away, so a run is timed from start to exit as the book's benchmarks are.
+// A failed run yields its error report.
+fn time_run(script: &str, backend: &str) -> io::Result<Result<Duration, String>> {
+    let start = Instant::now();
+    let run = Command::new(env::current_exe()?)
+        .args(["run", script, "--backend", backend])
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()?;
+    let elapsed = start.elapsed();
+    if run.status.success() {
+        Ok(Ok(elapsed))
+    } else {
+        Ok(Err(String::from_utf8_lossy(&run.stderr).into_owned()))
+    }
+}
+
+// Runs each script `iterations` times on each backend and prints the
+// minimum, median and standard deviation of the wall times. With more than
+// one backend, each after the first is compared with the first by median.
+// Returns whether every run succeeded.
+pub fn run_benchmarks(
+    scripts: &[String],
+    backends: &[&str],
+    iterations: usize,
+) -> io::Result<bool> {
+    let width = scripts
+        .iter()
+        .map(|script| script.len())
+        .chain([6])
+        .max()
+        .unwrap_or(0);
+    println!(
+        "{:<width$}  {:<7}  {:>4}  {:>10}  {:>10}  {:>10}",
+        "Script",
+        "Backend",
+        "Runs",
+        "Min ms",
+        "Median ms",
+        "Stddev ms",
+        width = width
+    );
+    let mut succeeded = true;
+    for script in scripts {
+        let mut baseline: Option<Duration> = None;
+        'backends: for backend in backends {
+            let mut runs = Vec::with_capacity(iterations);
+            for _ in 0..iterations {
+                match time_run(script, backend)? {
+                    Ok(elapsed) => runs.push(elapsed),
+                    Err(report) => {
+                        eprintln!("{} failed on the {} backend:", script, backend);
+                        eprint!("{}", report);
+                        succeeded = false;
+                        continue 'backends;
+                    }
+                }
+            }
+            let timings = Timings::new(runs);
+            let comparison = match baseline {
+                Some(baseline) => format!(
+                    "  {:.2}x the speed of {}",
+                    baseline.as_secs_f64() / timings.median.as_secs_f64(),
+                    backends[0]
+                ),
+                None => String::new(),
+            };
+            if *backend == backends[0] {
+                baseline = Some(timings.median);
+            }
+            println!(
+                "{:<width$}  {:<7}  {:>4}  {:>10}  {:>10}  {:>10}{}",
+                script,
+                backend,
+                iterations,
+                millis(timings.min),
+                millis(timings.median),
+                millis(timings.stddev),
+                comparison,
+                width = width
+            );
+        }
+    }
+    Ok(succeeded)
+}
@@ -0,0 +1,278 @@
+use crate::ast_json::stmt_span;
+use crate::environment::Environment;
+use crate::error::LoxError;
+use crate::interpreter::DebugHook;
+use crate::parser::{Expr, FunctionDecl, Stmt};
+use crate::span::{SourceMap, Span};
+use crate::Lox;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::rc::Rc;
+
+// Counts how often each statement of the entry script runs, installed as
+// the interpreter's debug hook. Clones share their counts, so one can be
+// handed to the interpreter and another read once the run is over.
+// Imported modules aren't covered.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    // Keyed by where each statement starts.
+    hits: Rc<RefCell<HashMap<usize, u64>>>,
+}
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+    // Each line of `source` that starts a statement, with how many times
+    // the statements starting there ran.
+    pub fn lines(&self, source: &str) -> Result<BTreeMap<usize, u64>, LoxError> {
+        let mut starts = Vec::new();
+        statements(&Lox::parse(source)?, &mut starts);
+        let map = SourceMap::new(source);
+        let hits = self.hits.borrow();
+        let mut lines = BTreeMap::new();
+        for start in starts {
+            *lines.entry(map.line(start)).or_insert(0) += hits.get(&start).copied().unwrap_or(0);
+        }
+        Ok(lines)
+    }
+    // An lcov tracefile for the script at `path`.
+    pub fn lcov(&self, path: &str, source: &str) -> Result<String, LoxError> {
+        let lines = self.lines(source)?;
+        let mut out = format!("TN:\nSF:{}\n", path);
+        for (line, hits) in &lines {
+            let _ = writeln!(out, "DA:{},{}", line, hits);
+        }
+        let hit = lines.values().filter(|hits| **hits > 0).count();
+        let _ = write!(out, "LF:{}\nLH:{}\nend_of_record\n", lines.len(), hit);
+        Ok(out)
+    }
+    // `source` with each line prefixed as gcov does: the times it ran,
+    // `#####` if it never did, or `-` if it holds no statement.
+    pub fn annotate(&self, source: &str) -> Result<String, LoxError> {
+        let lines = self.lines(source)?;
+        let mut out = String::new();
+        for (i, text) in source.lines().enumerate() {
+            let count = match lines.get(&(i + 1)) {
+                Some(0) => "#####".to_string(),
+                Some(hits) => hits.to_string(),
+                None => "-".to_string(),
+            };
+            let _ = writeln!(out, "{:>9}:{:>5}:{}", count, i + 1, text);
+        }
+        Ok(out)
+    }
+}
+impl DebugHook for Coverage {
+    fn statement(
+        &mut self,
+        span: Span,
+        _depth: usize,
+        _scope: &Rc<RefCell<Environment>>,
+    ) -> Result<(), String> {
+        *self.hits.borrow_mut().entry(span.start).or_insert(0) += 1;
+        Ok(())
+    }
+}
+
+// Where each statement the debug hook would report starts: all but blocks,
+// including those in function and lambda bodies.
+fn statements(statements: &[Stmt], starts: &mut Vec<usize>) {
+    for statement in statements {
+        stmt(statement, starts);
+    }
+}
+
+fn stmt(statement: &Stmt, starts: &mut Vec<usize>) {
+    if !matches!(statement, Stmt::Block(_)) {
+        starts.push(stmt_span(statement).start);
+    }
+    match statement {
+        Stmt::Block(body) => statements(body, starts),
+        Stmt::DoWhile { body, condition } => {
+            stmt(body, starts);
+            expr(condition, starts);
+        }
+        Stmt::Class {
+            superclass,
+            methods,
+            class_methods,
+            ..
+        } => {
+            if let Some(superclass) = superclass {
+                expr(superclass, starts);
+            }
+            for method in methods.iter().chain(class_methods) {
+                function(method, starts);
+            }
+        }
+        Stmt::Destructure {
+            initializer: value, ..
+        }
+        | Stmt::Expression(value)
+        | Stmt::Print(value)
+        | Stmt::Throw { value, .. } => expr(value, starts),
+        Stmt::For {
+            initializer,
+            condition,
+            increment,
+            body,
+        } => {
+            if let Some(initializer) = initializer {
+                stmt(initializer, starts);
+            }
+            expr(condition, starts);
+            if let Some(increment) = increment {
+                expr(increment, starts);
+            }
+            stmt(body, starts);
+        }
+        Stmt::ForEach { iterable, body, .. } => {
+            expr(iterable, starts);
+            stmt(body, starts);
+        }
+        Stmt::Function(declaration) => function(declaration, starts),
+        Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+        } => {
+            expr(condition, starts);
+            stmt(then_branch, starts);
+            if let Some(else_branch) = else_branch {
+                stmt(else_branch, starts);
+            }
+        }
+        Stmt::Import { .. } => {}
+        Stmt::Return { value, .. } => {
+            if let Some(value) = value {
+                expr(value, starts);
+            }
+        }
+        Stmt::Switch {
+            subject,
+            cases,
+            default,
+        } => {
+            expr(subject, starts);
+            for (value, body) in cases {
+                expr(value, starts);
+                statements(body, starts);
+            }
+            if let Some(body) = default {
+                statements(body, starts);
+            }
+        }
+        Stmt::Trait { methods, .. } => {
+            for method in methods {
+                function(method, starts);
+            }
+        }
+        Stmt::Try {
+            body,
+            catch,
+            finally,
+        } => {
+            statements(body, starts);
+            if let Some((_, handler)) = catch {
+                statements(handler, starts);
+            }
+            if let Some(finally) = finally {
+                statements(finally, starts);
+            }
+        }
+        Stmt::Var { initializer, .. } => {
+            if let Some(initializer) = initializer {
+                expr(initializer, starts);
+            }
+        }
+        Stmt::While { condition, body } => {
+            expr(condition, starts);
+            stmt(body, starts);
+        }
+        Stmt::Yield { value, .. } => {
+            if let Some(value) = value {
+                expr(value, starts);
+            }
+        }
+    }
+}
+
+fn function(declaration: &FunctionDecl, starts: &mut Vec<usize>) {
+    for default in declaration.defaults.iter().flatten() {
+        expr(default, starts);
+    }
+    statements(&declaration.body, starts);
+}
+
+// Only lambdas hold statements, but they may be nested anywhere.
+fn expr(expression: &Expr, starts: &mut Vec<usize>) {
+    match expression {
+        Expr::Lambda(declaration) => function(declaration, starts),
+        Expr::Assign { value, .. }
+        | Expr::Unary { right: value, .. }
+        | Expr::Update { target: value, .. } => expr(value, starts),
+        Expr::Binary { left, right, .. } | Expr::Logical { left, right, .. } => {
+            expr(left, starts);
+            expr(right, starts);
+        }
+        Expr::Compound { target, value, .. } => {
+            expr(target, starts);
+            expr(value, starts);
+        }
+        Expr::Call {
+            callee, arguments, ..
+        } => {
+            expr(callee, starts);
+            for argument in arguments {
+                expr(argument, starts);
+            }
+        }
+        Expr::Conditional {
+            condition,
+            then_branch,
+            else_branch,
+        } => {
+            expr(condition, starts);
+            expr(then_branch, starts);
+            expr(else_branch, starts);
+        }
+        Expr::Destructure { targets, value, .. } => {
+            expr(value, starts);
+            for target in targets {
+                expr(target, starts);
+            }
+        }
+        Expr::Get { object, .. } | Expr::Grouping(object) => expr(object, starts),
+        Expr::Index { object, index, .. } => {
+            expr(object, starts);
+            expr(index, starts);
+        }
+        Expr::IndexSet {
+            object,
+            index,
+            value,
+            ..
+        } => {
+            expr(object, starts);
+            expr(index, starts);
+            expr(value, starts);
+        }
+        Expr::List(elements) => {
+            for element in elements {
+                expr(element, starts);
+            }
+        }
+        Expr::Map { entries, .. } => {
+            for (key, value) in entries {
+                expr(key, starts);
+                expr(value, starts);
+            }
+        }
+        Expr::Set { object, value, .. } => {
+            expr(object, starts);
+            expr(value, starts);
+        }
+        Expr::Literal { .. } | Expr::Super { .. } | Expr::This { .. } | Expr::Variable { .. } => {}
+    }
+}
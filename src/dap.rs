@@ -0,0 +1,519 @@
+use crate::debugger::{self, Resume, Stepper};
+use crate::environment::Environment;
+use crate::interpreter::{DebugHook, Value};
+use crate::json::{self, Json};
+use crate::span::Span;
+use crate::Lox;
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+// A Lox program has just the one thread.
+const THREAD_ID: usize = 1;
+// References to the variables of the two scopes a pause shows.
+const LOCALS: usize = 1;
+const GLOBALS: usize = 2;
+
+// Serves one Debug Adapter Protocol session, so that an editor such as VS
+// Code can launch a script, set breakpoints, step, and show variables and
+// the call stack. The program runs on this thread, so requests are only
+// answered before it starts and while it is paused. Its output goes to
+// stdout as usual, not to the editor.
+pub fn serve(reader: impl BufRead + 'static, writer: impl Write + 'static) -> io::Result<()> {
+    let connection = Rc::new(RefCell::new(Connection {
+        reader: Box::new(reader),
+        writer: Box::new(writer),
+        seq: 0,
+        closed: false,
+    }));
+    let mut program: Option<(String, bool)> = None;
+    let mut configured = false;
+    let mut breakpoints = Breakpoints::default();
+    loop {
+        let Some(request) = connection.borrow_mut().read()? else {
+            return Ok(());
+        };
+        let arguments = request.get("arguments").unwrap_or(&Json::Null);
+        let mut session = connection.borrow_mut();
+        match command(&request) {
+            "initialize" => {
+                let capabilities =
+                    Json::object(vec![("supportsConfigurationDoneRequest", true.into())]);
+                session.respond(&request, capabilities)?;
+                session.event("initialized", Json::Null)?;
+            }
+            "launch" => match arguments.get("program").and_then(Json::as_str) {
+                Some(path) => {
+                    let stop_on_entry = arguments
+                        .get("stopOnEntry")
+                        .and_then(Json::as_bool)
+                        .unwrap_or(false);
+                    program = Some((path.to_string(), stop_on_entry));
+                    session.respond(&request, Json::Null)?;
+                }
+                None => session.fail(&request, "Expect a 'program' to launch.")?,
+            },
+            "setBreakpoints" => {
+                let lines = breakpoints.set(arguments);
+                session.respond(&request, verified(&lines))?;
+            }
+            "configurationDone" => {
+                configured = true;
+                session.respond(&request, Json::Null)?;
+            }
+            "threads" => session.respond(&request, threads())?,
+            "disconnect" | "terminate" => return session.respond(&request, Json::Null),
+            _ => session.fail(&request, "The program is not paused.")?,
+        }
+        drop(session);
+        // The program starts once the editor has launched it and sent its
+        // breakpoints, in whichever order those come.
+        if !configured {
+            continue;
+        }
+        if let Some((path, stop_on_entry)) = program.take() {
+            run(
+                &connection,
+                &path,
+                stop_on_entry,
+                breakpoints.for_file(&path),
+            )?;
+            if connection.borrow().closed {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn run(
+    connection: &Rc<RefCell<Connection>>,
+    path: &str,
+    stop_on_entry: bool,
+    breakpoints: Vec<usize>,
+) -> io::Result<()> {
+    let exit_code = match fs::read_to_string(path) {
+        Ok(source) => {
+            let mut stepper = Stepper::new(stop_on_entry);
+            for line in breakpoints {
+                stepper.add_breakpoint(line);
+            }
+            let mut lox = Lox::new();
+            lox.allow_imports();
+            lox.set_entry_script(path);
+            lox.set_debug_hook(DapHook {
+                connection: Rc::clone(connection),
+                path: path.to_string(),
+                line_starts: std::iter::once(0)
+                    .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+                    .collect(),
+                stepper,
+                entered: false,
+                calls: Vec::new(),
+            });
+            match lox.run(&source) {
+                Ok(_) => 0,
+                Err(e) => {
+                    let rendered = e.render_with(&lox.source_map());
+                    output(connection, &format!("{}\n", rendered))?;
+                    e.exit_code()
+                }
+            }
+        }
+        Err(e) => {
+            output(connection, &format!("Could not read {}: {}\n", path, e))?;
+            66
+        }
+    };
+    let mut session = connection.borrow_mut();
+    if session.closed {
+        return Ok(());
+    }
+    session.event(
+        "exited",
+        Json::object(vec![("exitCode", (exit_code as f64).into())]),
+    )?;
+    session.event("terminated", Json::Null)
+}
+
+fn output(connection: &Rc<RefCell<Connection>>, text: &str) -> io::Result<()> {
+    let mut session = connection.borrow_mut();
+    if session.closed {
+        return Ok(());
+    }
+    let body = Json::object(vec![
+        ("category", Json::string("stderr")),
+        ("output", Json::string(text)),
+    ]);
+    session.event("output", body)
+}
+
+// Reports the program's progress to the editor, and answers its requests
+// while paused.
+struct DapHook {
+    connection: Rc<RefCell<Connection>>,
+    path: String,
+    line_starts: Vec<usize>,
+    stepper: Stepper,
+    // Whether the program has paused yet, for the first pause's reason.
+    entered: bool,
+    // The function and call site of each call in progress.
+    calls: Vec<(String, Span)>,
+}
+impl DapHook {
+    fn line(&self, offset: usize) -> usize {
+        self.line_starts.partition_point(|&start| start <= offset)
+    }
+    fn pause(
+        &mut self,
+        line: usize,
+        depth: usize,
+        scope: &Rc<RefCell<Environment>>,
+    ) -> io::Result<()> {
+        let reason = if self
+            .stepper
+            .breakpoints()
+            .any(|breakpoint| breakpoint == line)
+        {
+            "breakpoint"
+        } else if !self.entered {
+            "entry"
+        } else {
+            "step"
+        };
+        self.entered = true;
+        let stopped = Json::object(vec![
+            ("reason", Json::string(reason)),
+            ("threadId", THREAD_ID.into()),
+            ("allThreadsStopped", true.into()),
+        ]);
+        let connection = Rc::clone(&self.connection);
+        let mut session = connection.borrow_mut();
+        session.event("stopped", stopped)?;
+        loop {
+            let Some(request) = session.read()? else {
+                session.closed = true;
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed"));
+            };
+            let arguments = request.get("arguments").unwrap_or(&Json::Null);
+            let resume = match command(&request) {
+                "continue" => Resume::Continue,
+                "next" => Resume::Next,
+                "stepIn" => Resume::Step,
+                "stepOut" => Resume::Out,
+                "threads" => {
+                    session.respond(&request, threads())?;
+                    continue;
+                }
+                "stackTrace" => {
+                    session.respond(&request, self.stack_trace(line))?;
+                    continue;
+                }
+                "scopes" => {
+                    let frame = arguments.get("frameId").and_then(Json::as_f64);
+                    session.respond(&request, scopes(frame == Some(0.0)))?;
+                    continue;
+                }
+                "variables" => {
+                    let reference = arguments.get("variablesReference").and_then(Json::as_f64);
+                    let variables = match reference {
+                        Some(r) if r == LOCALS as f64 => debugger::locals(scope),
+                        Some(r) if r == GLOBALS as f64 => debugger::globals(scope),
+                        _ => Vec::new(),
+                    };
+                    session.respond(&request, self::variables(variables))?;
+                    continue;
+                }
+                "evaluate" => {
+                    let name = arguments
+                        .get("expression")
+                        .and_then(Json::as_str)
+                        .unwrap_or("")
+                        .trim();
+                    let value = scope.borrow().get(name);
+                    match value {
+                        Some(value) => {
+                            let body = Json::object(vec![
+                                ("result", Json::String(show(&value))),
+                                ("variablesReference", 0.0.into()),
+                            ]);
+                            session.respond(&request, body)?;
+                        }
+                        None => {
+                            let message = format!("Undefined variable '{}'.", name);
+                            session.fail(&request, &message)?;
+                        }
+                    }
+                    continue;
+                }
+                "setBreakpoints" => {
+                    let (file, lines) = breakpoint_request(arguments);
+                    if same_file(&file, &self.path) {
+                        self.stepper.clear_breakpoints();
+                        for line in &lines {
+                            self.stepper.add_breakpoint(*line);
+                        }
+                    }
+                    session.respond(&request, verified(&lines))?;
+                    continue;
+                }
+                "disconnect" | "terminate" => {
+                    session.respond(&request, Json::Null)?;
+                    session.closed = true;
+                    return Err(io::Error::new(io::ErrorKind::Interrupted, "closed"));
+                }
+                _ => {
+                    session.fail(&request, "Unsupported request.")?;
+                    continue;
+                }
+            };
+            let body = Json::object(vec![("allThreadsContinued", true.into())]);
+            session.respond(&request, body)?;
+            self.stepper.resume(resume, depth);
+            return Ok(());
+        }
+    }
+    // The innermost frame is paused at `line`; each outer one at the call
+    // that led to the frame inside it.
+    fn stack_trace(&self, line: usize) -> Json {
+        let source = Json::object(vec![
+            ("name", Json::string(&file_name(&self.path))),
+            ("path", Json::string(&self.path)),
+        ]);
+        let frame = |id: usize, name: &str, line: usize| {
+            Json::object(vec![
+                ("id", id.into()),
+                ("name", Json::string(name)),
+                ("source", source.clone()),
+                ("line", line.into()),
+                ("column", 1.0.into()),
+            ])
+        };
+        let name = |i: usize| match i {
+            0 => "script".to_string(),
+            i => self.calls[i - 1].0.clone(),
+        };
+        let mut frames = vec![frame(0, &name(self.calls.len()), line)];
+        for (i, (_, call_site)) in self.calls.iter().enumerate().rev() {
+            frames.push(frame(frames.len(), &name(i), self.line(call_site.start)));
+        }
+        Json::object(vec![
+            ("totalFrames", frames.len().into()),
+            ("stackFrames", Json::Array(frames)),
+        ])
+    }
+}
+impl DebugHook for DapHook {
+    fn statement(
+        &mut self,
+        span: Span,
+        depth: usize,
+        scope: &Rc<RefCell<Environment>>,
+    ) -> Result<(), String> {
+        let line = self.line(span.start);
+        if self.stepper.should_pause(line, depth) {
+            self.pause(line, depth, scope)
+                .map_err(|_| "Stopped by the debugger.".to_string())?;
+        }
+        Ok(())
+    }
+    fn call(&mut self, function: &str, call_site: Span) {
+        self.calls.push((function.to_string(), call_site));
+    }
+    fn ret(&mut self) {
+        self.calls.pop();
+    }
+}
+
+// The breakpoint lines the editor has set, by file.
+#[derive(Default)]
+struct Breakpoints {
+    files: Vec<(String, Vec<usize>)>,
+}
+impl Breakpoints {
+    // Replaces the lines of the file a `setBreakpoints` request names, and
+    // returns them.
+    fn set(&mut self, arguments: &Json) -> Vec<usize> {
+        let (path, lines) = breakpoint_request(arguments);
+        self.files.retain(|(file, _)| *file != path);
+        self.files.push((path, lines.clone()));
+        lines
+    }
+    fn for_file(&self, path: &str) -> Vec<usize> {
+        self.files
+            .iter()
+            .filter(|(file, _)| same_file(file, path))
+            .flat_map(|(_, lines)| lines.iter().copied())
+            .collect()
+    }
+}
+
+// The file a `setBreakpoints` request is for, and its breakpoint lines.
+fn breakpoint_request(arguments: &Json) -> (String, Vec<usize>) {
+    let path = arguments
+        .get("source")
+        .and_then(|source| source.get("path"))
+        .and_then(Json::as_str)
+        .unwrap_or("");
+    let lines = arguments
+        .get("breakpoints")
+        .and_then(Json::as_array)
+        .unwrap_or(&[])
+        .iter()
+        .filter_map(|breakpoint| breakpoint.get("line")?.as_f64())
+        .map(|line| line as usize)
+        .collect();
+    (path.to_string(), lines)
+}
+
+fn same_file(a: &str, b: &str) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path).file_name().map_or_else(
+        || path.to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    )
+}
+
+fn command(request: &Json) -> &str {
+    request.get("command").and_then(Json::as_str).unwrap_or("")
+}
+
+fn threads() -> Json {
+    let thread = Json::object(vec![
+        ("id", THREAD_ID.into()),
+        ("name", Json::string("main")),
+    ]);
+    Json::object(vec![("threads", Json::Array(vec![thread]))])
+}
+
+// Only the innermost frame's locals can be shown; the environments of the
+// frames outside it aren't kept anywhere the debugger can reach.
+fn scopes(innermost: bool) -> Json {
+    let scope = |name: &str, reference: usize| {
+        Json::object(vec![
+            ("name", Json::string(name)),
+            ("variablesReference", reference.into()),
+            ("expensive", false.into()),
+        ])
+    };
+    let mut scopes = vec![scope("Globals", GLOBALS)];
+    if innermost {
+        scopes.insert(0, scope("Locals", LOCALS));
+    }
+    Json::object(vec![("scopes", Json::Array(scopes))])
+}
+
+fn variables(variables: Vec<(String, Value)>) -> Json {
+    let variables = variables
+        .into_iter()
+        .map(|(name, value)| {
+            Json::object(vec![
+                ("name", Json::String(name)),
+                ("value", Json::String(show(&value))),
+                ("variablesReference", 0.0.into()),
+            ])
+        })
+        .collect();
+    Json::object(vec![("variables", Json::Array(variables))])
+}
+
+fn verified(lines: &[usize]) -> Json {
+    let breakpoints = lines
+        .iter()
+        .map(|line| Json::object(vec![("verified", true.into()), ("line", (*line).into())]))
+        .collect();
+    Json::object(vec![("breakpoints", Json::Array(breakpoints))])
+}
+
+// Strings are quoted so they can be told apart from other values.
+fn show(value: &Value) -> String {
+    match value {
+        Value::Str(s) => format!("{:?}", s),
+        value => value.to_string(),
+    }
+}
+
+// Messages framed with a `Content-Length` header, as the protocol sends
+// them.
+struct Connection {
+    reader: Box<dyn BufRead>,
+    writer: Box<dyn Write>,
+    seq: usize,
+    // Set once the editor disconnects, after which nothing more is sent.
+    closed: bool,
+}
+impl Connection {
+    // The next message, or None at the end of input.
+    fn read(&mut self) -> io::Result<Option<Json>> {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if self.reader.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+            let header = header.trim_end();
+            if header.is_empty() && length.is_some() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length:") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+        let mut body = vec![0; length.unwrap_or(0)];
+        self.reader.read_exact(&mut body)?;
+        json::parse(&String::from_utf8_lossy(&body))
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+    fn send(&mut self, fields: Vec<(&str, Json)>) -> io::Result<()> {
+        self.seq += 1;
+        let mut message = vec![("seq", self.seq.into())];
+        message.extend(fields.into_iter().filter(|(_, value)| *value != Json::Null));
+        let text = Json::object(message).to_string();
+        write!(
+            self.writer,
+            "Content-Length: {}\r\n\r\n{}",
+            text.len(),
+            text
+        )?;
+        self.writer.flush()
+    }
+    fn respond(&mut self, request: &Json, body: Json) -> io::Result<()> {
+        self.send(vec![
+            ("type", Json::string("response")),
+            (
+                "request_seq",
+                request.get("seq").cloned().unwrap_or(Json::Null),
+            ),
+            ("success", true.into()),
+            ("command", Json::string(command(request))),
+            ("body", body),
+        ])
+    }
+    fn fail(&mut self, request: &Json, message: &str) -> io::Result<()> {
+        self.send(vec![
+            ("type", Json::string("response")),
+            (
+                "request_seq",
+                request.get("seq").cloned().unwrap_or(Json::Null),
+            ),
+            ("success", false.into()),
+            ("command", Json::string(command(request))),
+            ("message", Json::string(message)),
+        ])
+    }
+    fn event(&mut self, event: &str, body: Json) -> io::Result<()> {
+        self.send(vec![
+            ("type", Json::string("event")),
+            ("event", Json::string(event)),
+            ("body", body),
+        ])
+    }
+}
@@ -0,0 +1,116 @@
+use std::cell::RefCell;
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::rc::Rc;
+use tree::debugger::{self, Resume, Stepper};
+use tree::environment::Environment;
+use tree::interpreter::DebugHook;
+use tree::span::{SourceMap, Span};
+
+const HELP: &str = "Commands:
+  step, s          run to the next line, stepping into calls
+  next, n          run to the next line, stepping over calls
+  finish, f        run until the current call returns
+  continue, c      run to the next breakpoint
+  break, b <line>  pause before the line runs
+  delete, d <line> remove the breakpoint on the line
+  locals, l        show the local variables
+  print, p <name>  show a variable
+  quit, q          stop the program
+An empty line repeats the last command.";
+
+// Pauses the program on the terminal, reading commands from stdin. The
+// debugger's own output goes to stderr, leaving stdout to the program.
+pub struct DebugPrompt {
+    map: SourceMap<'static>,
+    stepper: Stepper,
+    last_command: String,
+}
+impl DebugPrompt {
+    pub fn new(source: &'static str, breakpoints: &[usize]) -> DebugPrompt {
+        let mut stepper = Stepper::new(true);
+        for line in breakpoints {
+            stepper.add_breakpoint(*line);
+        }
+        DebugPrompt {
+            map: SourceMap::new(source),
+            stepper,
+            last_command: String::new(),
+        }
+    }
+    fn show_line(&self, line: usize) {
+        eprintln!("[line {}] {}", line, self.map.line_text(line).trim());
+    }
+    // Reads commands until one resumes the program.
+    fn pause(&mut self, depth: usize, scope: &Rc<RefCell<Environment>>) {
+        let stdin = io::stdin();
+        loop {
+            eprint!("(debug) ");
+            let _ = io::stderr().flush();
+            let mut line = String::new();
+            // At the end of input, run the rest of the program undisturbed.
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                eprintln!();
+                self.stepper.clear_breakpoints();
+                self.stepper.resume(Resume::Continue, depth);
+                return;
+            }
+            let mut command = line.trim().to_string();
+            if command.is_empty() {
+                command = self.last_command.clone();
+            }
+            self.last_command = command.clone();
+            let mut words = command.split_whitespace();
+            let verb = words.next().unwrap_or("");
+            let argument = words.next();
+            let line = argument.and_then(|argument| argument.parse::<usize>().ok());
+            match (verb, line) {
+                ("step" | "s", _) => return self.stepper.resume(Resume::Step, depth),
+                ("next" | "n", _) => return self.stepper.resume(Resume::Next, depth),
+                ("finish" | "f", _) => return self.stepper.resume(Resume::Out, depth),
+                ("continue" | "c", _) => return self.stepper.resume(Resume::Continue, depth),
+                ("break" | "b", Some(line)) => {
+                    self.stepper.add_breakpoint(line);
+                    eprintln!("Breakpoint at line {}.", line);
+                }
+                ("delete" | "d", Some(line)) => {
+                    if self.stepper.remove_breakpoint(line) {
+                        eprintln!("Deleted the breakpoint at line {}.", line);
+                    } else {
+                        eprintln!("No breakpoint at line {}.", line);
+                    }
+                }
+                ("break" | "b" | "delete" | "d", None) => eprintln!("Expect a line number."),
+                ("locals" | "l", _) => {
+                    for (name, value) in debugger::locals(scope) {
+                        eprintln!("{} = {}", name, value);
+                    }
+                }
+                ("print" | "p", _) => match argument {
+                    Some(name) => match scope.borrow().get(name) {
+                        Some(value) => eprintln!("{} = {}", name, value),
+                        None => eprintln!("Undefined variable '{}'.", name),
+                    },
+                    None => eprintln!("Expect a variable name."),
+                },
+                ("quit" | "q", _) => process::exit(0),
+                _ => eprintln!("{}", HELP),
+            }
+        }
+    }
+}
+impl DebugHook for DebugPrompt {
+    fn statement(
+        &mut self,
+        span: Span,
+        depth: usize,
+        scope: &Rc<RefCell<Environment>>,
+    ) -> Result<(), String> {
+        let line = self.map.line(span.start);
+        if self.stepper.should_pause(line, depth) {
+            self.show_line(line);
+            self.pause(depth, scope);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use crate::environment::Environment;
+use crate::interpreter::Value;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::rc::Rc;
+
+// How to carry on from a pause.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    // Pause at the next line, even inside a call.
+    Step,
+    // Pause at the next line of this call or a caller.
+    Next,
+    // Pause at the next line of a caller.
+    Out,
+    // Run until a breakpoint.
+    Continue,
+}
+
+// Decides where a debugger pauses, from the statements the interpreter
+// reports through a `DebugHook`. Pauses are by line: a line holding several
+// statements, or a loop written on one line, pauses once on entering it.
+// Frontends supply the commands and show each pause.
+#[derive(Debug)]
+pub struct Stepper {
+    breakpoints: BTreeSet<usize>,
+    resume: Resume,
+    // The call depth `Next` was given at.
+    depth: usize,
+    // The line and call depth of the last statement reported.
+    last: Option<(usize, usize)>,
+}
+impl Stepper {
+    // With `stop_on_entry`, pauses before the first statement.
+    pub fn new(stop_on_entry: bool) -> Stepper {
+        Stepper {
+            breakpoints: BTreeSet::new(),
+            resume: if stop_on_entry {
+                Resume::Step
+            } else {
+                Resume::Continue
+            },
+            depth: 0,
+            last: None,
+        }
+    }
+    // Returns false if there already was one on `line`.
+    pub fn add_breakpoint(&mut self, line: usize) -> bool {
+        self.breakpoints.insert(line)
+    }
+    // Returns false if there was none on `line`.
+    pub fn remove_breakpoint(&mut self, line: usize) -> bool {
+        self.breakpoints.remove(&line)
+    }
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+    // Whether to pause before a statement on `line` runs with `depth` calls
+    // in progress.
+    pub fn should_pause(&mut self, line: usize, depth: usize) -> bool {
+        let entered = self.last != Some((line, depth));
+        self.last = Some((line, depth));
+        entered
+            && match self.resume {
+                Resume::Step => true,
+                Resume::Next => depth <= self.depth || self.breakpoints.contains(&line),
+                Resume::Out => depth < self.depth || self.breakpoints.contains(&line),
+                Resume::Continue => self.breakpoints.contains(&line),
+            }
+    }
+    // Carries on from a pause at call depth `depth`.
+    pub fn resume(&mut self, resume: Resume, depth: usize) {
+        self.resume = resume;
+        self.depth = depth;
+    }
+}
+
+// The global variables `scope` sees, other than natives.
+pub fn globals(scope: &Rc<RefCell<Environment>>) -> Vec<(String, Value)> {
+    let mut scope = Rc::clone(scope);
+    loop {
+        let Some(enclosing) = scope.borrow().enclosing() else {
+            break;
+        };
+        scope = enclosing;
+    }
+    let mut globals = scope.borrow().bindings();
+    globals.retain(|(_, value)| !matches!(value, Value::Native(_)));
+    globals
+}
+
+// The variables visible in `scope` other than globals, innermost first,
+// leaving out those shadowed by an inner one of the same name.
+pub fn locals(scope: &Rc<RefCell<Environment>>) -> Vec<(String, Value)> {
+    let mut locals: Vec<(String, Value)> = Vec::new();
+    let mut scope = Rc::clone(scope);
+    loop {
+        let Some(enclosing) = scope.borrow().enclosing() else {
+            // The outermost scope holds the globals.
+            return locals;
+        };
+        for (name, value) in scope.borrow().bindings() {
+            if !locals.iter().any(|(local, _)| *local == name) {
+                locals.push((name, value));
+            }
+        }
+        scope = enclosing;
+    }
+}
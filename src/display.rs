@@ -0,0 +1,25 @@
+// How values read when printed or converted to strings. Both backends
+// format numbers here, so their output matches the reference jlox's.
+
+// Formats `n` as Java's `Double.toString` does, less the `.0` jlox strips
+// from integers: plain decimals from 0.001 up to ten million, scientific
+// notation like `1.0E21` beyond, and `NaN`, `Infinity` and `-Infinity`.
+pub fn number(n: f64) -> String {
+    if n.is_nan() {
+        return "NaN".to_string();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "Infinity" } else { "-Infinity" }.to_string();
+    }
+    let magnitude = n.abs();
+    if magnitude == 0.0 || (1e-3..1e7).contains(&magnitude) {
+        return n.to_string();
+    }
+    let text = format!("{:e}", n);
+    let (mantissa, exponent) = text.split_once('e').unwrap_or((&text, "0"));
+    if mantissa.contains('.') {
+        format!("{}E{}", mantissa, exponent)
+    } else {
+        format!("{}.0E{}", mantissa, exponent)
+    }
+}
@@ -0,0 +1,259 @@
+use crate::json::Json;
+use crate::scanner::{Token, TokenType};
+use crate::span::{SourceMap, Span};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    // Code that runs but is probably a mistake. Warnings never stop a
+    // program unless they are denied.
+    Warning,
+}
+
+// A single problem in a program, located by the span of source it covers.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+    pub severity: Severity,
+    // Context appended to "Error", such as " at 'foo'" or " at end".
+    pub location: String,
+    // Lox-level call frames a runtime error unwound through, innermost
+    // first.
+    pub trace: Vec<Frame>,
+}
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub function: String,
+    pub call_site: Span,
+    // The frame is a module being imported, and `function` its path.
+    pub import: bool,
+}
+impl Diagnostic {
+    pub fn new(message: &str, span: Span) -> Diagnostic {
+        Diagnostic {
+            message: message.to_string(),
+            span,
+            severity: Severity::Error,
+            location: String::new(),
+            trace: Vec::new(),
+        }
+    }
+    pub fn at(token: &Token, message: &str) -> Diagnostic {
+        Diagnostic::new(message, token.span)
+    }
+    // Like `at`, but also names the token in the message, as syntax errors do.
+    pub fn at_token(token: &Token, message: &str) -> Diagnostic {
+        let mut diagnostic = Diagnostic::at(token, message);
+        diagnostic.location = if token.tty == TokenType::Eof {
+            " at end".to_string()
+        } else {
+            format!(" at '{}'", token.lexeme)
+        };
+        diagnostic
+    }
+    // Renders the diagnostic followed by the source lines it covers, with
+    // the span underlined.
+    pub fn render(&self, map: &SourceMap) -> String {
+        const MAX_LINES: usize = 5;
+        let file = map.file(self.span.start);
+        let (line, col) = file.location(self.span.start);
+        let (mut end_line, mut end_col) = file.location(self.span.end);
+        // A span ending in a newline finishes on the line before.
+        if end_line > line && end_col == 0 {
+            end_line -= 1;
+            end_col = file.line_text(end_line).chars().count();
+        }
+        let mut out = format!("[{}] {}", map.place(self.span.start), self);
+        let gutter = end_line.to_string().len();
+        out.push_str(&format!("\n{:>gutter$} |", ""));
+        for current in line..=end_line.min(line + MAX_LINES - 1) {
+            let text = file.line_text(current);
+            let from = if current == line { col } else { 0 };
+            let to = if current == end_line {
+                end_col
+            } else {
+                text.chars().count()
+            };
+            out.push_str(&format!(
+                "\n{:>gutter$} | {}\n{:>gutter$} | {}{}",
+                current,
+                text,
+                "",
+                " ".repeat(from),
+                "^".repeat(to.saturating_sub(from).max(1)),
+            ));
+        }
+        if end_line >= line + MAX_LINES {
+            out.push_str(&format!("\n{:>gutter$} | ...", ""));
+        }
+        // Deep recursion repeats the same frame many times over, so runs of
+        // identical frames are folded into one line.
+        let mut frames = self
+            .trace
+            .iter()
+            .map(|frame| {
+                let place = map.place(frame.call_site.start);
+                if frame.import {
+                    format!("in module '{}' imported from {}", frame.function, place)
+                } else {
+                    format!("in {}() called from {}", frame.function, place)
+                }
+            })
+            .peekable();
+        while let Some(frame) = frames.next() {
+            let mut repeats = 0;
+            while frames.next_if_eq(&frame).is_some() {
+                repeats += 1;
+            }
+            out.push_str(&format!("\n  {}", frame));
+            if repeats > 0 {
+                out.push_str(&format!(" (repeated {} more times)", repeats));
+            }
+        }
+        out
+    }
+    // The diagnostic as one line of JSON, for tools reading `jlox
+    // --error-format=json`. Lines are 1-based and columns 1-based
+    // characters; `phase` names what found the problem. Locations in
+    // unnamed sources are in `file`.
+    pub fn to_json(&self, map: &SourceMap, file: &str, phase: &str) -> String {
+        let file_of = |offset| map.file(offset).name.unwrap_or(file);
+        let (line, column) = map.location(self.span.start);
+        let (end_line, end_column) = map.location(self.span.end);
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let trace = self
+            .trace
+            .iter()
+            .map(|frame| {
+                Json::object(vec![
+                    ("function", Json::string(&frame.function)),
+                    ("import", frame.import.into()),
+                    ("file", Json::string(file_of(frame.call_site.start))),
+                    ("line", map.line(frame.call_site.start).into()),
+                ])
+            })
+            .collect();
+        Json::object(vec![
+            ("severity", Json::string(severity)),
+            ("phase", Json::string(phase)),
+            ("message", Json::string(&self.message)),
+            ("file", Json::string(file_of(self.span.start))),
+            (
+                "span",
+                Json::object(vec![
+                    (
+                        "start",
+                        map.file(self.span.start).offset(self.span.start).into(),
+                    ),
+                    (
+                        "end",
+                        map.file(self.span.start).offset(self.span.end).into(),
+                    ),
+                    ("line", line.into()),
+                    ("column", (column + 1).into()),
+                    ("endLine", end_line.into()),
+                    ("endColumn", (end_column + 1).into()),
+                ]),
+            ),
+            ("trace", Json::Array(trace)),
+        ])
+        .to_string()
+    }
+}
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        };
+        write!(f, "{}{}: {}", severity, self.location, self.message)
+    }
+}
+
+// Every way running a Lox program can fail, grouped by the phase that
+// detected the problem.
+#[derive(Debug)]
+pub enum LoxError {
+    Scan(Vec<Diagnostic>),
+    Parse(Vec<Diagnostic>),
+    Resolve(Vec<Diagnostic>),
+    // Only from `Lox::check_types`; running a program never checks types.
+    Type(Vec<Diagnostic>),
+    // Warnings that were denied, which are errors.
+    Lint(Vec<Diagnostic>),
+    Runtime(Diagnostic),
+    // The program was stopped by a fuel or time limit.
+    Timeout(Diagnostic),
+}
+impl LoxError {
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        match self {
+            LoxError::Scan(diagnostics)
+            | LoxError::Parse(diagnostics)
+            | LoxError::Resolve(diagnostics)
+            | LoxError::Type(diagnostics)
+            | LoxError::Lint(diagnostics) => diagnostics,
+            LoxError::Runtime(diagnostic) | LoxError::Timeout(diagnostic) => {
+                std::slice::from_ref(diagnostic)
+            }
+        }
+    }
+    // Follows sysexits.h like the book: 65 (EX_DATAERR) when the program
+    // never started, 70 (EX_SOFTWARE) when it failed while running.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            LoxError::Runtime(_) | LoxError::Timeout(_) => 70,
+            _ => 65,
+        }
+    }
+    pub fn phase(&self) -> &'static str {
+        match self {
+            LoxError::Scan(_) => "scan",
+            LoxError::Parse(_) => "parse",
+            LoxError::Resolve(_) => "resolve",
+            LoxError::Type(_) => "type",
+            LoxError::Lint(_) => "lint",
+            LoxError::Runtime(_) => "runtime",
+            LoxError::Timeout(_) => "timeout",
+        }
+    }
+    pub fn render(&self, source: &str) -> String {
+        self.render_with(&SourceMap::new(source))
+    }
+    // Like `render`, for errors that can point into any source `map`
+    // covers, as those from a `Lox` that has imported modules can.
+    pub fn render_with(&self, map: &SourceMap) -> String {
+        self.diagnostics()
+            .iter()
+            .map(|diagnostic| diagnostic.render(map))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+    // Each diagnostic as a line of JSON; see `Diagnostic::to_json`.
+    pub fn to_json(&self, source: &str, file: &str) -> String {
+        self.to_json_with(&SourceMap::new(source), file)
+    }
+    pub fn to_json_with(&self, map: &SourceMap, file: &str) -> String {
+        self.diagnostics()
+            .iter()
+            .map(|diagnostic| diagnostic.to_json(map, file, self.phase()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, diagnostic) in self.diagnostics().iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
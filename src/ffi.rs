@@ -0,0 +1,370 @@
+// A C interface for embedding the tree-walk interpreter in programs not
+// written in Rust, declared in include/lox.h. Build it as a shared library
+// with
+//
+//     cargo rustc --lib --release --features ffi --crate-type cdylib
+//
+// Interpreters and values are handed out as opaque pointers the host frees
+// with `lox_free` and `lox_value_free`. Strings cross as NUL-terminated
+// UTF-8; those returned stay owned by the interpreter or value they came
+// from, except where a function says the host must free them. It isn't
+// built for WebAssembly, whose bindings in src/wasm.rs use the same names.
+//
+// A panic must not unwind into C, so each function catches any and
+// returns as it would for a failure: 70 from `lox_run`, otherwise null or
+// nothing.
+use crate::interpreter::Value;
+use crate::Lox;
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+pub struct LoxHandle {
+    lox: Lox,
+    // The report of the last failed run, for `lox_last_error`.
+    error: Option<CString>,
+}
+
+// A Lox value held by the host, or the error a native function returns.
+pub struct LoxValue {
+    value: Value,
+    error: Option<String>,
+    // A string value as C sees it, made when the value is.
+    text: Option<CString>,
+}
+
+// What `lox_value_type` returns. Values of other types can only be passed
+// back to Lox or printed.
+pub const LOX_NIL: c_int = 0;
+pub const LOX_BOOL: c_int = 1;
+pub const LOX_NUMBER: c_int = 2;
+pub const LOX_STRING: c_int = 3;
+pub const LOX_OTHER: c_int = 4;
+
+// A native function: given the `data` it was defined with and its
+// arguments, it returns a new value for the interpreter to take, null for
+// nil, or a value from `lox_value_error` to fail the call.
+pub type LoxNative =
+    extern "C" fn(data: *mut c_void, argc: usize, argv: *const *const LoxValue) -> *mut LoxValue;
+
+fn boxed(value: Value) -> *mut LoxValue {
+    let text = match &value {
+        Value::Str(s) => CString::new(s.as_str()).ok(),
+        _ => None,
+    };
+    Box::into_raw(Box::new(LoxValue {
+        value,
+        error: None,
+        text,
+    }))
+}
+
+// Runs `f`, or gives `failed` if it panics.
+fn guard<T>(failed: T, f: impl FnOnce() -> T) -> T {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(failed)
+}
+
+// Borrows a string from C, or None if it is null or not UTF-8.
+unsafe fn borrow_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+// Makes an interpreter with the standard natives and no I/O.
+#[no_mangle]
+pub extern "C" fn lox_new() -> *mut LoxHandle {
+    guard(ptr::null_mut(), || {
+        Box::into_raw(Box::new(LoxHandle {
+            lox: Lox::new(),
+            error: None,
+        }))
+    })
+}
+
+/// # Safety
+///
+/// `lox` must be from `lox_new` and not yet freed, or null.
+#[no_mangle]
+pub unsafe extern "C" fn lox_free(lox: *mut LoxHandle) {
+    if !lox.is_null() {
+        guard((), || drop(Box::from_raw(lox)));
+    }
+}
+
+// Runs `source` as a program, with the globals of earlier runs. Returns 0
+// if it ran to the end, or the exit code `jlox` would: 65 for a program
+// with compile errors, 70 for one that failed while running. If `result`
+// isn't null, it is set to the value of a final expression statement, or
+// to null if the run failed.
+/// # Safety
+///
+/// `lox` must be from `lox_new` and `source` a NUL-terminated string.
+/// `result`, if not null, must point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn lox_run(
+    lox: *mut LoxHandle,
+    source: *const c_char,
+    result: *mut *mut LoxValue,
+) -> c_int {
+    let handle = &mut *lox;
+    let Some(source) = borrow_str(source) else {
+        handle.error = CString::new("Source is not valid UTF-8.").ok();
+        return 65;
+    };
+    let run = panic::catch_unwind(AssertUnwindSafe(|| handle.lox.run(source)));
+    let (value, code) = match run {
+        Ok(Ok(value)) => {
+            handle.error = None;
+            (boxed(value), 0)
+        }
+        Ok(Err(e)) => {
+            let rendered = e.render_with(&handle.lox.source_map());
+            handle.error = CString::new(rendered.replace('\0', "")).ok();
+            (ptr::null_mut(), e.exit_code())
+        }
+        Err(_) => {
+            handle.error = CString::new("The interpreter panicked.").ok();
+            (ptr::null_mut(), 70)
+        }
+    };
+    if result.is_null() {
+        lox_value_free(value);
+    } else {
+        *result = value;
+    }
+    code
+}
+
+// The report of why the last run failed, or null if it didn't.
+/// # Safety
+///
+/// `lox` must be from `lox_new`. The string lasts until the next run.
+#[no_mangle]
+pub unsafe extern "C" fn lox_last_error(lox: *const LoxHandle) -> *const c_char {
+    guard(ptr::null(), || {
+        (*lox)
+            .error
+            .as_ref()
+            .map_or(ptr::null(), |error| error.as_ptr())
+    })
+}
+
+// Frees the sources of every run so far, which are otherwise kept for
+// error reports for as long as the interpreter lives. See
+// `Lox::release_sources`.
+/// # Safety
+///
+/// `lox` must be from `lox_new`.
+#[no_mangle]
+pub unsafe extern "C" fn lox_release_sources(lox: *mut LoxHandle) {
+    guard((), || (*lox).lox.release_sources());
+}
+
+/// # Safety
+///
+/// `lox` must be from `lox_new`.
+#[no_mangle]
+pub unsafe extern "C" fn lox_set_max_call_depth(lox: *mut LoxHandle, depth: usize) {
+    guard((), || (*lox).lox.set_max_call_depth(depth));
+}
+
+// Makes `function` callable from Lox as the global `name`, passing it
+// `data` on each call. Calls with other than `arity` arguments fail before
+// it runs.
+/// # Safety
+///
+/// `lox` must be from `lox_new` and `name` a NUL-terminated string.
+/// `data` must stay valid for as long as the interpreter does.
+#[no_mangle]
+pub unsafe extern "C" fn lox_define_native(
+    lox: *mut LoxHandle,
+    name: *const c_char,
+    arity: usize,
+    function: LoxNative,
+    data: *mut c_void,
+) {
+    let Some(name) = borrow_str(name) else {
+        return;
+    };
+    let define = || {
+        (*lox).lox.define_native(name, arity, move |args| {
+            let args: Vec<*mut LoxValue> = args.into_iter().map(boxed).collect();
+            let argv: Vec<*const LoxValue> = args.iter().map(|arg| *arg as *const _).collect();
+            let returned = function(data, argv.len(), argv.as_ptr());
+            for arg in args {
+                drop(Box::from_raw(arg));
+            }
+            if returned.is_null() {
+                return Ok(Value::Nil);
+            }
+            let returned = Box::from_raw(returned);
+            match returned.error {
+                Some(message) => Err(message),
+                None => Ok(returned.value),
+            }
+        })
+    };
+    guard((), define);
+}
+
+// The global variable `name`, or null if there is none.
+/// # Safety
+///
+/// `lox` must be from `lox_new` and `name` a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn lox_get_global(
+    lox: *const LoxHandle,
+    name: *const c_char,
+) -> *mut LoxValue {
+    guard(ptr::null_mut(), || {
+        borrow_str(name)
+            .and_then(|name| (*lox).lox.get_global(name))
+            .map_or(ptr::null_mut(), boxed)
+    })
+}
+
+// Defines the global `name` as a copy of `value`, which the host still
+// owns.
+/// # Safety
+///
+/// `lox` must be from `lox_new`, `name` a NUL-terminated string and
+/// `value` a live value.
+#[no_mangle]
+pub unsafe extern "C" fn lox_set_global(
+    lox: *mut LoxHandle,
+    name: *const c_char,
+    value: *const LoxValue,
+) {
+    if let Some(name) = borrow_str(name) {
+        guard((), || (*lox).lox.set_global(name, (*value).value.clone()));
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn lox_value_nil() -> *mut LoxValue {
+    guard(ptr::null_mut(), || boxed(Value::Nil))
+}
+
+#[no_mangle]
+pub extern "C" fn lox_value_bool(b: c_int) -> *mut LoxValue {
+    guard(ptr::null_mut(), || boxed(Value::Bool(b != 0)))
+}
+
+#[no_mangle]
+pub extern "C" fn lox_value_number(n: f64) -> *mut LoxValue {
+    guard(ptr::null_mut(), || boxed(Value::Number(n)))
+}
+
+// A string value, or null if `s` isn't UTF-8.
+/// # Safety
+///
+/// `s` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_string(s: *const c_char) -> *mut LoxValue {
+    guard(ptr::null_mut(), || {
+        borrow_str(s).map_or(ptr::null_mut(), |s| boxed(Value::Str(s.to_string())))
+    })
+}
+
+// For a native function to return, failing its call with `message`.
+/// # Safety
+///
+/// `message` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_error(message: *const c_char) -> *mut LoxValue {
+    let message = borrow_str(message).unwrap_or("Native function failed.");
+    guard(ptr::null_mut(), || {
+        Box::into_raw(Box::new(LoxValue {
+            value: Value::Nil,
+            error: Some(message.to_string()),
+            text: None,
+        }))
+    })
+}
+
+/// # Safety
+///
+/// `value` must be from this library and not yet freed, or null.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_free(value: *mut LoxValue) {
+    if !value.is_null() {
+        guard((), || drop(Box::from_raw(value)));
+    }
+}
+
+/// # Safety
+///
+/// `value` must be a live value.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_type(value: *const LoxValue) -> c_int {
+    guard(LOX_OTHER, || match (*value).value {
+        Value::Nil => LOX_NIL,
+        Value::Bool(_) => LOX_BOOL,
+        Value::Number(_) => LOX_NUMBER,
+        Value::Str(_) => LOX_STRING,
+        _ => LOX_OTHER,
+    })
+}
+
+// Whether the value is truthy, as `if` would decide.
+/// # Safety
+///
+/// `value` must be a live value.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_as_bool(value: *const LoxValue) -> c_int {
+    guard(0, || {
+        !matches!((*value).value, Value::Nil | Value::Bool(false)) as c_int
+    })
+}
+
+// The number, or 0 for other types.
+/// # Safety
+///
+/// `value` must be a live value.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_as_number(value: *const LoxValue) -> f64 {
+    guard(0.0, || match (*value).value {
+        Value::Number(n) => n,
+        _ => 0.0,
+    })
+}
+
+// The string, owned by the value, or null for other types and strings
+// holding a NUL.
+/// # Safety
+///
+/// `value` must be a live value.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_as_string(value: *const LoxValue) -> *const c_char {
+    guard(ptr::null(), || {
+        (*value)
+            .text
+            .as_ref()
+            .map_or(ptr::null(), |text| text.as_ptr())
+    })
+}
+
+// The value as `print` shows it, which the host frees with
+// `lox_string_free`.
+/// # Safety
+///
+/// `value` must be a live value.
+#[no_mangle]
+pub unsafe extern "C" fn lox_value_to_string(value: *const LoxValue) -> *mut c_char {
+    guard(ptr::null_mut(), || {
+        CString::new((*value).value.to_string().replace('\0', ""))
+            .map_or(ptr::null_mut(), CString::into_raw)
+    })
+}
+
+/// # Safety
+///
+/// `s` must be from `lox_value_to_string` and not yet freed, or null.
+#[no_mangle]
+pub unsafe extern "C" fn lox_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        guard((), || drop(CString::from_raw(s)));
+    }
+}
@@ -0,0 +1,770 @@
+use crate::ast_json::{expr_span, stmt_span};
+use crate::error::LoxError;
+use crate::parser::{Expr, FunctionDecl, Parser, Stmt};
+use crate::scanner::{Scanner, Token, TokenType, Trivia, TriviaKind, TriviaToken};
+use crate::span::Span;
+use std::sync::Arc;
+
+const INDENT: &str = "  ";
+
+// Prints `source` back out in the one canonical layout: two-space indents,
+// one statement per line, and braces on the line that opens them. Comments
+// are kept, and so is a single blank line wherever the source had any.
+// Literals are copied from the source as written.
+pub fn format(source: &str) -> Result<String, LoxError> {
+    let (mut tokens, mut comments, mut errors) = (Vec::new(), Vec::new(), Vec::new());
+    for item in Scanner::with_trivia(source) {
+        match item {
+            Ok(TriviaToken { leading, token }) => {
+                let is_comment = |trivia: &Trivia| trivia.kind == TriviaKind::Comment;
+                comments.extend(leading.into_iter().filter(is_comment));
+                tokens.push(token);
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(LoxError::Scan(errors));
+    }
+    let statements = Parser::new(tokens.clone())
+        .parse()
+        .map_err(LoxError::Parse)?;
+    let mut formatter = Formatter {
+        source,
+        tokens,
+        comments,
+        next_comment: 0,
+        last: 0,
+        indent: 0,
+        out: String::new(),
+    };
+    formatter.statements(&statements);
+    formatter.comments_before(source.len(), statements.is_empty());
+    Ok(formatter.out)
+}
+
+struct Formatter<'src> {
+    source: &'src str,
+    tokens: Vec<Token>,
+    comments: Vec<Trivia>,
+    next_comment: usize,
+    // The source offset everything before which has been printed, used to
+    // place comments and find the braces the AST doesn't record.
+    last: usize,
+    indent: usize,
+    out: String,
+}
+impl Formatter<'_> {
+    fn write(&mut self, text: &str) {
+        self.out.push_str(text);
+    }
+    fn start_line(&mut self) {
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+    }
+    // Copies the source text at `span` as it was written.
+    fn copy(&mut self, start: usize, end: usize) {
+        self.out.push_str(&self.source[start..end]);
+        self.last = self.last.max(end);
+    }
+    // The first token of type `tty` at or after `last`.
+    fn next_token(&self, tty: TokenType) -> Option<&Token> {
+        self.token_after(self.last, tty)
+    }
+    fn token_after(&self, offset: usize, tty: TokenType) -> Option<&Token> {
+        let from = self.tokens.partition_point(|t| t.span.start < offset);
+        self.tokens[from..].iter().find(|t| t.tty == tty)
+    }
+    // Where the bracket of type `tty` that closes the items is.
+    fn closing(&self, items: Option<Span>, tty: TokenType) -> usize {
+        let from = items.map_or(self.last, |span| self.last.max(span.end));
+        self.token_after(from, tty)
+            .map_or(self.source.len(), |bracket| bracket.span.start)
+    }
+    // A blank line if the source had one between what was printed last and
+    // `start`.
+    fn blank_line(&mut self, start: usize, first: bool) {
+        let gap = self.source.get(self.last..start).unwrap_or("");
+        if !first && gap.matches('\n').count() > 1 {
+            self.out.push('\n');
+        }
+    }
+    // Whether a comment not yet printed starts before `end`.
+    fn comment_before(&self, end: usize) -> bool {
+        self.comments
+            .get(self.next_comment)
+            .is_some_and(|comment| comment.span.start < end)
+    }
+    // Prints the comments that start before `start`, each on its own line.
+    fn comments_before(&mut self, start: usize, mut first: bool) {
+        while let Some(comment) = self
+            .comments
+            .get(self.next_comment)
+            .filter(|comment| comment.span.start < start)
+            .cloned()
+        {
+            self.blank_line(comment.span.start, first);
+            self.start_line();
+            self.write(&comment.text);
+            self.out.push('\n');
+            self.last = comment.span.end;
+            self.next_comment += 1;
+            first = false;
+        }
+    }
+    // Ends the line, keeping a comment that followed on the same source
+    // line with nothing but closing punctuation or a comma before it.
+    fn end_line(&mut self) {
+        if let Some(comment) = self.comments.get(self.next_comment).cloned() {
+            let from = self.tokens.partition_point(|t| t.span.start < self.last);
+            let only_punctuation = self.tokens[from..]
+                .iter()
+                .take_while(|t| t.span.start < comment.span.start)
+                .all(|t| {
+                    matches!(
+                        t.tty,
+                        TokenType::Semicolon
+                            | TokenType::Comma
+                            | TokenType::Colon
+                            | TokenType::RightParen
+                            | TokenType::RightBracket
+                    )
+                });
+            let gap = self.source.get(self.last..comment.span.start).unwrap_or("");
+            if comment.span.start >= self.last && only_punctuation && !gap.contains('\n') {
+                self.write(" ");
+                self.write(&comment.text);
+                self.last = comment.span.end;
+                self.next_comment += 1;
+            }
+        }
+        self.out.push('\n');
+    }
+
+    fn statements(&mut self, statements: &[Stmt]) {
+        for (i, statement) in statements.iter().enumerate() {
+            let start = self.next_start();
+            let before = self.next_comment;
+            self.comments_before(start, i == 0);
+            self.blank_line(start, i == 0 && self.next_comment == before);
+            self.start_line();
+            self.statement(statement);
+            self.last = self.last.max(stmt_span(statement).end);
+            self.end_line();
+        }
+    }
+    // Where the next statement begins, for placing the comments before it.
+    // Statement spans leave out leading keywords and braces, so this is the
+    // first token after the end of the last one.
+    fn next_start(&self) -> usize {
+        let from = self.tokens.partition_point(|t| t.span.start < self.last);
+        self.tokens[from..]
+            .iter()
+            .find(|t| !matches!(t.tty, TokenType::Semicolon | TokenType::RightParen))
+            .map_or(self.source.len(), |token| token.span.start)
+    }
+    fn open_brace(&mut self) {
+        if let Some(brace) = self.next_token(TokenType::LeftBrace) {
+            self.last = brace.span.end;
+        }
+        self.write("{");
+    }
+    // `{`, the statements, and a `}` on a line of its own, or `{}` when
+    // there is nothing in between.
+    fn block(&mut self, statements: &[Stmt]) {
+        self.open_brace();
+        let close = self
+            .next_token(TokenType::RightBrace)
+            .map_or(self.source.len(), |brace| brace.span.start);
+        if statements.is_empty() && !self.comment_before(close) {
+            self.close_brace();
+            return;
+        }
+        self.out.push('\n');
+        self.indent += 1;
+        self.statements(statements);
+        self.close_block();
+    }
+    // Flushes the comments left before the `}` that ends a block, then
+    // prints it.
+    fn close_block(&mut self) {
+        let close = self
+            .next_token(TokenType::RightBrace)
+            .map_or(self.source.len(), |brace| brace.span.start);
+        self.comments_before(close, self.out.ends_with("{\n"));
+        self.indent -= 1;
+        self.start_line();
+        self.close_brace();
+    }
+    fn close_brace(&mut self) {
+        if let Some(brace) = self.next_token(TokenType::RightBrace) {
+            self.last = brace.span.end;
+        }
+        self.write("}");
+    }
+    // A loop or `if` body: a block on the same line, or any other
+    // statement after a space.
+    fn body(&mut self, body: &Stmt) {
+        self.write(" ");
+        self.statement(body);
+    }
+
+    fn statement(&mut self, statement: &Stmt) {
+        match statement {
+            Stmt::Block(statements) => self.block(statements),
+            Stmt::DoWhile { body, condition } => {
+                self.write("do");
+                self.body(body);
+                self.write(" while (");
+                self.expr(condition);
+                self.write(");");
+            }
+            Stmt::Destructure {
+                names, initializer, ..
+            } => {
+                self.write("var (");
+                for (i, name) in names.iter().enumerate() {
+                    if i > 0 {
+                        self.write(", ");
+                    }
+                    self.write(&name.lexeme);
+                }
+                self.write(") = ");
+                self.expr(initializer);
+                self.write(";");
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                traits,
+                methods,
+                class_methods,
+            } => {
+                self.write("class ");
+                self.write(&name.lexeme);
+                if let Some(superclass) = superclass {
+                    self.write(" < ");
+                    self.expr(superclass);
+                }
+                for (i, used) in traits.iter().enumerate() {
+                    self.write(if i == 0 { " with " } else { ", " });
+                    self.expr(used);
+                }
+                let mut members: Vec<(bool, &Arc<FunctionDecl>)> = methods
+                    .iter()
+                    .map(|method| (false, method))
+                    .chain(class_methods.iter().map(|method| (true, method)))
+                    .collect();
+                members.sort_by_key(|(_, method)| method.name.span.start);
+                self.members(&members);
+            }
+            Stmt::Expression(expr) => {
+                self.expr(expr);
+                self.write(";");
+            }
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+            } => {
+                self.write("for (");
+                match initializer {
+                    Some(initializer) => self.statement(initializer),
+                    None => self.write(";"),
+                }
+                // A missing condition is parsed as a `true` with no text.
+                let span = expr_span(condition);
+                if span.start < span.end {
+                    self.write(" ");
+                    self.expr(condition);
+                }
+                self.write(";");
+                if let Some(increment) = increment {
+                    self.write(" ");
+                    self.expr(increment);
+                }
+                self.write(")");
+                self.body(body);
+            }
+            Stmt::ForEach {
+                name,
+                iterable,
+                body,
+                ..
+            } => {
+                self.write("for (var ");
+                self.write(&name.lexeme);
+                self.write(" in ");
+                self.expr(iterable);
+                self.write(")");
+                self.body(body);
+            }
+            Stmt::Function(declaration) => {
+                self.write("fun ");
+                self.function(declaration);
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.write("if (");
+                self.expr(condition);
+                self.write(")");
+                self.body(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.last = self.last.max(stmt_span(then_branch).end);
+                    let keyword = self
+                        .next_token(TokenType::Else)
+                        .map_or(self.source.len(), |keyword| keyword.span.start);
+                    // Comments after the then branch stay there, so `else`
+                    // starts a line of its own after them.
+                    if self.comment_before(keyword) {
+                        self.end_line();
+                        self.comments_before(keyword, true);
+                        self.start_line();
+                        self.write("else");
+                    } else if matches!(**then_branch, Stmt::Block(_)) {
+                        self.write(" else");
+                    } else {
+                        self.out.push('\n');
+                        self.start_line();
+                        self.write("else");
+                    }
+                    self.body(else_branch);
+                }
+            }
+            Stmt::Import { span, .. } => {
+                self.write("import ");
+                self.copy(span.start, span.end);
+                self.write(";");
+            }
+            Stmt::Print(expr) => {
+                self.write("print ");
+                self.expr(expr);
+                self.write(";");
+            }
+            Stmt::Return { value, .. } => {
+                self.write("return");
+                if let Some(value) = value {
+                    self.write(" ");
+                    self.expr(value);
+                }
+                self.write(";");
+            }
+            Stmt::Switch {
+                subject,
+                cases,
+                default,
+            } => {
+                self.write("switch (");
+                self.expr(subject);
+                self.write(") ");
+                self.open_brace();
+                self.out.push('\n');
+                self.indent += 1;
+                for (i, (value, body)) in cases.iter().enumerate() {
+                    let start = expr_span(value).start;
+                    let before = self.next_comment;
+                    self.comments_before(start, i == 0);
+                    self.blank_line(start, i == 0 && self.next_comment == before);
+                    self.start_line();
+                    self.write("case ");
+                    self.expr(value);
+                    self.write(":");
+                    self.end_line();
+                    self.indent += 1;
+                    self.statements(body);
+                    self.indent -= 1;
+                }
+                if let Some(body) = default {
+                    self.start_line();
+                    self.write("default:\n");
+                    self.indent += 1;
+                    self.statements(body);
+                    self.indent -= 1;
+                }
+                self.close_block();
+            }
+            Stmt::Trait { name, methods } => {
+                self.write("trait ");
+                self.write(&name.lexeme);
+                let members: Vec<(bool, &Arc<FunctionDecl>)> =
+                    methods.iter().map(|method| (false, method)).collect();
+                self.members(&members);
+            }
+            Stmt::Throw { value, .. } => {
+                self.write("throw ");
+                self.expr(value);
+                self.write(";");
+            }
+            Stmt::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                self.write("try ");
+                self.block(body);
+                if let Some((name, handler)) = catch {
+                    self.write(" catch (");
+                    self.write(&name.lexeme);
+                    self.last = self.last.max(name.span.end);
+                    self.write(") ");
+                    self.block(handler);
+                }
+                if let Some(finally) = finally {
+                    self.write(" finally ");
+                    self.block(finally);
+                }
+            }
+            Stmt::Var {
+                name,
+                annotation,
+                initializer,
+            } => {
+                self.write("var ");
+                self.write(&name.lexeme);
+                if let Some(annotation) = annotation {
+                    self.write(": ");
+                    self.write(&annotation.lexeme);
+                }
+                if let Some(initializer) = initializer {
+                    self.write(" = ");
+                    self.expr(initializer);
+                }
+                self.write(";");
+            }
+            Stmt::While { condition, body } => {
+                self.write("while (");
+                self.expr(condition);
+                self.write(")");
+                self.body(body);
+            }
+            Stmt::Yield { value, .. } => {
+                self.write("yield");
+                if let Some(value) = value {
+                    self.write(" ");
+                    self.expr(value);
+                }
+                self.write(";");
+            }
+        }
+    }
+    // The body of a class or trait, whose members are methods, each marked
+    // with whether it is a class method.
+    fn members(&mut self, members: &[(bool, &Arc<FunctionDecl>)]) {
+        self.write(" ");
+        self.open_brace();
+        if members.is_empty() {
+            self.close_brace();
+            return;
+        }
+        self.out.push('\n');
+        self.indent += 1;
+        for (i, (is_class_method, method)) in members.iter().enumerate() {
+            let start = method.name.span.start;
+            let before = self.next_comment;
+            self.comments_before(start, i == 0);
+            self.blank_line(start, i == 0 && self.next_comment == before);
+            self.start_line();
+            if *is_class_method {
+                self.write("class ");
+            }
+            self.function(method);
+            self.end_line();
+        }
+        self.close_block();
+    }
+    // A function's name, parameters and body, as declared after `fun`.
+    fn function(&mut self, declaration: &FunctionDecl) {
+        self.write(&declaration.name.lexeme);
+        self.last = self.last.max(declaration.name.span.end);
+        if !declaration.is_getter {
+            self.parameters(declaration);
+        }
+        self.write(" ");
+        self.block(&declaration.body);
+    }
+    fn parameters(&mut self, declaration: &FunctionDecl) {
+        self.write("(");
+        for (i, ((param, annotation), default)) in declaration
+            .params
+            .iter()
+            .zip(&declaration.param_types)
+            .zip(&declaration.defaults)
+            .enumerate()
+        {
+            if i > 0 {
+                self.write(", ");
+            }
+            self.write(&param.lexeme);
+            if let Some(annotation) = annotation {
+                self.write(": ");
+                self.write(&annotation.lexeme);
+            }
+            if let Some(default) = default {
+                self.write(" = ");
+                self.expr(default);
+            }
+        }
+        self.write(")");
+        if let Some(return_type) = &declaration.return_type {
+            self.write(" -> ");
+            self.write(&return_type.lexeme);
+        }
+        let end = declaration
+            .params
+            .iter()
+            .chain(declaration.param_types.iter().flatten())
+            .chain(&declaration.return_type)
+            .map(|token| token.span.end)
+            .max();
+        self.last = self.last.max(end.unwrap_or(0));
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Assign { name, value, .. } => {
+                self.write(&name.lexeme);
+                self.assigned(value);
+            }
+            Expr::Compound {
+                target,
+                operator,
+                value,
+            } => {
+                self.expr(target);
+                self.write(" ");
+                self.write(&operator.lexeme);
+                self.write("= ");
+                self.expr(value);
+            }
+            Expr::Destructure {
+                targets,
+                equals,
+                value,
+            } => {
+                self.write("(");
+                self.list(targets, equals.span.start);
+                self.write(") = ");
+                self.expr(value);
+            }
+            // Interpolated strings are copied whole rather than rebuilt
+            // from the concatenations they become.
+            Expr::Binary { operator, .. } if self.interpolates(operator) => {
+                let span = expr_span(expr);
+                self.copy(span.start, span.end);
+            }
+            Expr::Binary {
+                left,
+                operator,
+                right,
+            } => {
+                self.expr(left);
+                match operator.tty {
+                    TokenType::DotDot | TokenType::DotDotEqual => self.write(&operator.lexeme),
+                    TokenType::Comma => self.write(", "),
+                    _ => {
+                        self.write(" ");
+                        self.write(&operator.lexeme);
+                        self.write(" ");
+                    }
+                }
+                self.expr(right);
+            }
+            Expr::Call {
+                callee,
+                paren,
+                arguments,
+            } => {
+                self.expr(callee);
+                self.write("(");
+                self.list(arguments, paren.span.start);
+                self.write(")");
+            }
+            Expr::Conditional {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expr(condition);
+                self.write(" ? ");
+                self.expr(then_branch);
+                self.write(" : ");
+                self.expr(else_branch);
+            }
+            Expr::Get { object, name, safe } => {
+                self.expr(object);
+                self.write(if *safe { "?." } else { "." });
+                self.write(&name.lexeme);
+            }
+            Expr::Grouping(inner) => {
+                self.write("(");
+                self.expr(inner);
+                self.write(")");
+            }
+            Expr::Index { object, index, .. } => {
+                self.expr(object);
+                self.write("[");
+                self.expr(index);
+                self.write("]");
+            }
+            Expr::IndexSet {
+                object,
+                index,
+                value,
+                ..
+            } => {
+                self.expr(object);
+                self.write("[");
+                self.expr(index);
+                self.write("]");
+                self.assigned(value);
+            }
+            Expr::Lambda(declaration) => {
+                self.write("lambda");
+                self.last = self.last.max(declaration.name.span.end);
+                self.parameters(declaration);
+                self.write(" ");
+                self.block(&declaration.body);
+            }
+            Expr::List(elements) => {
+                self.write("[");
+                let close = self.closing(elements.last().map(expr_span), TokenType::RightBracket);
+                self.list(elements, close);
+                self.write("]");
+            }
+            Expr::Map { brace, entries } => {
+                self.last = self.last.max(brace.span.end);
+                self.write("{");
+                let last = entries.last().map(|(_, value)| expr_span(value));
+                let close = self.closing(last, TokenType::RightBrace);
+                self.separated(
+                    entries,
+                    close,
+                    |(key, value)| expr_span(key).to(expr_span(value)),
+                    |formatter, (key, value)| {
+                        formatter.expr(key);
+                        formatter.write(": ");
+                        formatter.expr(value);
+                    },
+                );
+                self.close_brace();
+            }
+            Expr::Literal { span, .. } => self.copy(span.start, span.end),
+            Expr::Logical {
+                left,
+                operator,
+                right,
+            } => {
+                self.expr(left);
+                self.write(" ");
+                self.write(&operator.lexeme);
+                self.write(" ");
+                self.expr(right);
+            }
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => {
+                self.expr(object);
+                self.write(".");
+                self.write(&name.lexeme);
+                self.assigned(value);
+            }
+            Expr::Super { method, .. } => {
+                self.write("super.");
+                self.write(&method.lexeme);
+            }
+            Expr::This { .. } => self.write("this"),
+            Expr::Unary { operator, right } => {
+                self.write(&operator.lexeme);
+                // `- -x` and `- --x` must not run together into `--x`
+                // and `---x`.
+                let inner = match &**right {
+                    Expr::Unary { operator, .. } => Some(operator),
+                    Expr::Update {
+                        operator,
+                        prefix: true,
+                        ..
+                    } => Some(operator),
+                    _ => None,
+                };
+                if inner.is_some_and(|inner| inner.lexeme.starts_with(&*operator.lexeme)) {
+                    self.write(" ");
+                }
+                self.expr(right);
+            }
+            Expr::Update {
+                operator,
+                prefix,
+                target,
+                ..
+            } => {
+                if *prefix {
+                    self.write(&operator.lexeme);
+                    self.expr(target);
+                } else {
+                    self.expr(target);
+                    self.write(&operator.lexeme);
+                }
+            }
+            Expr::Variable { name, .. } => self.write(&name.lexeme),
+        }
+    }
+    // ` = value` after an assignment target.
+    fn assigned(&mut self, value: &Expr) {
+        self.write(" = ");
+        self.expr(value);
+    }
+    // Whether `operator` joins the pieces of an interpolated string, which
+    // reuse the token of the `${` they come from.
+    fn interpolates(&self, operator: &Token) -> bool {
+        self.source[operator.span.start..operator.span.end].ends_with("${")
+    }
+    fn list(&mut self, elements: &[Expr], close: usize) {
+        self.separated(elements, close, expr_span, Formatter::expr);
+    }
+    // Comma-separated items ending before the bracket at `close`, all on
+    // one line unless there are comments among them. Then each item goes
+    // on a line of its own between the comments that were around it, and
+    // the bracket on the line after.
+    fn separated<T>(
+        &mut self,
+        items: &[T],
+        close: usize,
+        span: fn(&T) -> Span,
+        item: fn(&mut Self, &T),
+    ) {
+        if !self.comment_before(close) {
+            for (i, element) in items.iter().enumerate() {
+                if i > 0 {
+                    self.write(", ");
+                }
+                item(self, element);
+            }
+            return;
+        }
+        self.out.push('\n');
+        self.indent += 1;
+        for (i, element) in items.iter().enumerate() {
+            let span = span(element);
+            let before = self.next_comment;
+            self.comments_before(span.start, i == 0);
+            self.blank_line(span.start, i == 0 && self.next_comment == before);
+            self.start_line();
+            item(self, element);
+            self.last = self.last.max(span.end);
+            if i + 1 < items.len() {
+                self.write(",");
+            }
+            self.end_line();
+        }
+        self.comments_before(close, items.is_empty());
+        self.indent -= 1;
+        self.start_line();
+    }
+}
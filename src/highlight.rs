@@ -0,0 +1,181 @@
+use crate::scanner::{Scanner, TokenType, TriviaKind, TriviaToken};
+use crate::span::Span;
+
+// What a piece of source is, for coloring it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    Keyword,
+    Identifier,
+    String,
+    Number,
+    Comment,
+    Operator,
+    Punctuation,
+    Whitespace,
+    // Text the scanner rejected, such as an unexpected character or an
+    // unterminated string.
+    Error,
+}
+impl Class {
+    // A lowercase name, usable as a CSS class or a semantic token type.
+    pub fn name(self) -> &'static str {
+        match self {
+            Class::Keyword => "keyword",
+            Class::Identifier => "identifier",
+            Class::String => "string",
+            Class::Number => "number",
+            Class::Comment => "comment",
+            Class::Operator => "operator",
+            Class::Punctuation => "punctuation",
+            Class::Whitespace => "whitespace",
+            Class::Error => "error",
+        }
+    }
+}
+
+// Splits all of `source` into consecutive spans, each with its class. This
+// never fails: whatever the scanner can't make a token of is an error
+// span, and scanning carries on after it as it always does.
+pub fn highlight(source: &str) -> Vec<(Span, Class)> {
+    let mut spans = Vec::new();
+    let mut last = 0;
+    for TriviaToken { leading, token } in Scanner::with_trivia(source).flatten() {
+        for trivia in leading {
+            let class = match trivia.kind {
+                TriviaKind::Whitespace => Class::Whitespace,
+                TriviaKind::Comment => Class::Comment,
+            };
+            gap(source, last, trivia.span.start, &mut spans);
+            spans.push((trivia.span, class));
+            last = trivia.span.end;
+        }
+        if token.tty == TokenType::Eof {
+            break;
+        }
+        gap(source, last, token.span.start, &mut spans);
+        spans.push((token.span, class(token.tty)));
+        last = token.span.end;
+    }
+    gap(source, last, source.len(), &mut spans);
+    spans
+}
+
+// Classifies text that is neither a token nor trivia, which the scanner
+// reported an error for, though it may hold whitespace it skipped along
+// with it.
+fn gap(source: &str, start: usize, end: usize, spans: &mut Vec<(Span, Class)>) {
+    let mut run = start;
+    let mut chars = source[start..end].char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let whitespace = c.is_whitespace();
+        let same = chars
+            .peek()
+            .is_some_and(|(_, next)| next.is_whitespace() == whitespace);
+        if !same {
+            let class = if whitespace {
+                Class::Whitespace
+            } else {
+                Class::Error
+            };
+            let next = start + i + c.len_utf8();
+            spans.push((Span::new(run, next), class));
+            run = next;
+        }
+    }
+}
+
+fn class(tty: TokenType) -> Class {
+    match tty {
+        TokenType::Identifier => Class::Identifier,
+        TokenType::String | TokenType::Interpolation => Class::String,
+        TokenType::Number => Class::Number,
+        TokenType::LeftParen
+        | TokenType::RightParen
+        | TokenType::LeftBrace
+        | TokenType::RightBrace
+        | TokenType::LeftBracket
+        | TokenType::RightBracket
+        | TokenType::Comma
+        | TokenType::Colon
+        | TokenType::Dot
+        | TokenType::Semicolon
+        | TokenType::Arrow
+        | TokenType::Eof => Class::Punctuation,
+        TokenType::Minus
+        | TokenType::Percent
+        | TokenType::Plus
+        | TokenType::Question
+        | TokenType::QuestionDot
+        | TokenType::Slash
+        | TokenType::Star
+        | TokenType::MinusEqual
+        | TokenType::MinusMinus
+        | TokenType::PlusEqual
+        | TokenType::PlusPlus
+        | TokenType::SlashEqual
+        | TokenType::StarEqual
+        | TokenType::StarStar
+        | TokenType::Bang
+        | TokenType::BangEqual
+        | TokenType::Equal
+        | TokenType::EqualEqual
+        | TokenType::Greater
+        | TokenType::GreaterEqual
+        | TokenType::Less
+        | TokenType::LessEqual
+        | TokenType::DotDot
+        | TokenType::DotDotEqual => Class::Operator,
+        TokenType::And
+        | TokenType::Class
+        | TokenType::Else
+        | TokenType::False
+        | TokenType::Fun
+        | TokenType::For
+        | TokenType::If
+        | TokenType::Nil
+        | TokenType::Or
+        | TokenType::Print
+        | TokenType::Return
+        | TokenType::Super
+        | TokenType::This
+        | TokenType::True
+        | TokenType::Var
+        | TokenType::While
+        | TokenType::Lambda
+        | TokenType::Import
+        | TokenType::Throw
+        | TokenType::Try
+        | TokenType::Catch
+        | TokenType::Finally
+        | TokenType::Switch
+        | TokenType::Case
+        | TokenType::Default
+        | TokenType::Do
+        | TokenType::In
+        | TokenType::Trait
+        | TokenType::With
+        | TokenType::Yield => Class::Keyword,
+    }
+}
+
+// `source` as HTML, with each token that isn't whitespace in a
+// `<span class="...">` named after its class.
+pub fn to_html(source: &str) -> String {
+    let mut html = String::new();
+    for (span, class) in highlight(source) {
+        let text = escape(&source[span.start..span.end]);
+        if class == Class::Whitespace {
+            html.push_str(&text);
+        } else {
+            html.push_str(&format!("<span class=\"{}\">{}</span>", class.name(), text));
+        }
+    }
+    html
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
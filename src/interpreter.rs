@@ -0,0 +1,2379 @@
+use crate::ast_json::{expr_span, stmt_span};
+use crate::display;
+use crate::environment::Environment;
+use crate::error::{Diagnostic, Frame};
+use crate::limits::{Budget, Limits};
+use crate::lox::Lox;
+use crate::modules::Modules;
+use crate::natives;
+use crate::parser::{update_operator, Expr, FunctionDecl, LiteralValue, Stmt};
+use crate::resolver::Resolver;
+use crate::scanner::{Token, TokenType};
+use crate::span::{SourceMap, Sources, Span};
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+
+// How many Lox calls may be active at once before a call fails with a stack
+// overflow, unless the embedder chooses otherwise.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1024;
+
+// Stack kept free below the limit for what runs without checking it:
+// natives, and finding the span of the error, which recurses through
+// expressions as deeply as the parser allows them to nest.
+const STACK_RESERVE: usize = 1024 * 1024;
+
+thread_local! {
+    // The lowest address this thread's stack may grow down to before a run
+    // on it fails with a stack overflow, if the thread's size is known.
+    static STACK_LIMIT: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+// Tells interpreters later created on this thread that it has `size` bytes
+// of stack below the caller, so that running out is reported as a stack
+// overflow rather than crashing. The call depth limit alone can't promise
+// that, as nested expressions use stack without making calls.
+pub fn set_stack_size(size: usize) {
+    let limit = stack_address().saturating_sub(size.saturating_sub(STACK_RESERVE));
+    STACK_LIMIT.with(|cell| cell.set(Some(limit)));
+}
+
+fn stack_address() -> usize {
+    let marker = 0u8;
+    &marker as *const u8 as usize
+}
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    List(Rc<RefCell<Vec<Value>>>),
+    Map(Rc<RefCell<BTreeMap<MapKey, Value>>>),
+    Range(Range),
+    Function(Rc<LoxFunction>),
+    Native(Rc<NativeFunction>),
+    Class(Rc<LoxClass>),
+    Trait(Rc<LoxTrait>),
+    Instance(Rc<RefCell<LoxInstance>>),
+    Coroutine(Rc<Coroutine>),
+}
+impl Value {
+    // Lox follows Ruby: only `nil` and `false` are falsey.
+    fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Nil | Value::Bool(false))
+    }
+    fn is_equal(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::List(a), Value::List(b)) => Rc::ptr_eq(a, b),
+            (Value::Map(a), Value::Map(b)) => Rc::ptr_eq(a, b),
+            (Value::Range(a), Value::Range(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Native(a), Value::Native(b)) => Rc::ptr_eq(a, b),
+            (Value::Class(a), Value::Class(b)) => Rc::ptr_eq(a, b),
+            (Value::Trait(a), Value::Trait(b)) => Rc::ptr_eq(a, b),
+            (Value::Instance(a), Value::Instance(b)) => Rc::ptr_eq(a, b),
+            (Value::Coroutine(a), Value::Coroutine(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::Str(_) => "string",
+            Value::List(_) => "list",
+            Value::Map(_) => "map",
+            Value::Range(_) => "range",
+            Value::Function(_) | Value::Native(_) => "function",
+            Value::Class(_) => "class",
+            Value::Trait(_) => "trait",
+            Value::Instance(_) => "instance",
+            Value::Coroutine(_) => "coroutine",
+        }
+    }
+}
+// The numbers from `start` up to `end`, counting by one, made by `a..b`
+// and `a..=b`. Ranges are values, not lists: nothing is stored for the
+// numbers in between.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub start: f64,
+    pub end: f64,
+    pub inclusive: bool,
+}
+impl Range {
+    fn contains(&self, n: f64) -> bool {
+        if self.inclusive {
+            n <= self.end
+        } else {
+            n < self.end
+        }
+    }
+    pub fn len(&self) -> usize {
+        let span = (self.end - self.start).ceil();
+        let count = if self.inclusive && (self.end - self.start).fract() == 0.0 {
+            span + 1.0
+        } else {
+            span
+        };
+        count.max(0.0) as usize
+    }
+    pub fn is_empty(&self) -> bool {
+        !self.contains(self.start)
+    }
+}
+
+// The iteration protocol behind `for-in`: a value that can be iterated
+// over produces one of these. Lists are read by index as the loop goes, so
+// a loop sees elements its body pushes. Maps are walked over a snapshot of
+// their keys, so changing a map while looping over it is safe.
+pub enum Iteration {
+    List {
+        list: Rc<RefCell<Vec<Value>>>,
+        index: usize,
+    },
+    Keys(std::vec::IntoIter<Value>),
+    Range {
+        range: Range,
+        next: f64,
+    },
+}
+impl Iterator for Iteration {
+    type Item = Value;
+    fn next(&mut self) -> Option<Value> {
+        match self {
+            Iteration::List { list, index } => {
+                let element = list.borrow().get(*index).cloned()?;
+                *index += 1;
+                Some(element)
+            }
+            Iteration::Keys(keys) => keys.next(),
+            Iteration::Range { range, next } => {
+                if !range.contains(*next) {
+                    return None;
+                }
+                let n = *next;
+                *next += 1.0;
+                Some(Value::Number(n))
+            }
+        }
+    }
+}
+impl Value {
+    // None for values that can't be iterated over.
+    pub fn iterate(&self) -> Option<Iteration> {
+        match self {
+            Value::List(list) => Some(Iteration::List {
+                list: Rc::clone(list),
+                index: 0,
+            }),
+            Value::Map(map) => {
+                let keys: Vec<Value> = map.borrow().keys().map(MapKey::to_value).collect();
+                Some(Iteration::Keys(keys.into_iter()))
+            }
+            Value::Range(range) => Some(Iteration::Range {
+                range: *range,
+                next: range.start,
+            }),
+            _ => None,
+        }
+    }
+}
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) => write!(f, "{}", display::number(*n)),
+            Value::Str(s) => write!(f, "{}", s),
+            Value::List(list) => {
+                write!(f, "[")?;
+                for (i, element) in list.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, "]")
+            }
+            Value::Map(map) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key.to_value(), value)?;
+                }
+                write!(f, "}}")
+            }
+            Value::Range(range) => {
+                let operator = if range.inclusive { "..=" } else { ".." };
+                write!(
+                    f,
+                    "{}{}{}",
+                    display::number(range.start),
+                    operator,
+                    display::number(range.end)
+                )
+            }
+            Value::Function(function) => write!(f, "<fn {}>", lexeme(&function.declaration.name)),
+            Value::Native(_) => write!(f, "<native fn>"),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Trait(used) => write!(f, "<trait {}>", used.name),
+            Value::Instance(instance) => write!(f, "{} instance", instance.borrow().class.name),
+            Value::Coroutine(_) => write!(f, "<coroutine>"),
+        }
+    }
+}
+
+// Conversions for embedders passing values between Rust and Lox. Numbers
+// are always f64 in Lox, so only lossless integer types convert in.
+impl From<()> for Value {
+    fn from(_: ()) -> Self {
+        Value::Nil
+    }
+}
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
+}
+impl From<i32> for Value {
+    fn from(n: i32) -> Self {
+        Value::Number(n.into())
+    }
+}
+impl From<u32> for Value {
+    fn from(n: u32) -> Self {
+        Value::Number(n.into())
+    }
+}
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Str(s.to_string())
+    }
+}
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Str(s)
+    }
+}
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Nil, Into::into)
+    }
+}
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(values: Vec<T>) -> Self {
+        Value::List(Rc::new(RefCell::new(
+            values.into_iter().map(Into::into).collect(),
+        )))
+    }
+}
+impl TryFrom<Value> for bool {
+    type Error = String;
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Bool(b) => Ok(b),
+            _ => Err(expected("a boolean", &value)),
+        }
+    }
+}
+impl TryFrom<Value> for f64 {
+    type Error = String;
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Number(n) => Ok(n),
+            _ => Err(expected("a number", &value)),
+        }
+    }
+}
+impl TryFrom<Value> for String {
+    type Error = String;
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Str(s) => Ok(s),
+            _ => Err(expected("a string", &value)),
+        }
+    }
+}
+// Copies the list's current elements; later changes on either side are not
+// shared.
+impl TryFrom<Value> for Vec<Value> {
+    type Error = String;
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::List(list) => Ok(list.borrow().clone()),
+            _ => Err(expected("a list", &value)),
+        }
+    }
+}
+fn expected(what: &str, got: &Value) -> String {
+    format!("Expected {}, got {}.", what, got.type_name())
+}
+
+// The subset of values usable as map keys. Maps are ordered by key so that
+// printing and iterating them is deterministic.
+#[derive(Debug, Clone)]
+pub enum MapKey {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+}
+impl MapKey {
+    pub fn from_value(value: &Value) -> Option<MapKey> {
+        match value {
+            Value::Nil => Some(MapKey::Nil),
+            Value::Bool(b) => Some(MapKey::Bool(*b)),
+            Value::Number(n) if n.is_nan() => None,
+            // Normalize -0 so it finds the same entry as 0.
+            Value::Number(n) => Some(MapKey::Number(n + 0.0)),
+            Value::Str(s) => Some(MapKey::Str(s.clone())),
+            _ => None,
+        }
+    }
+    pub fn to_value(&self) -> Value {
+        match self {
+            MapKey::Nil => Value::Nil,
+            MapKey::Bool(b) => Value::Bool(*b),
+            MapKey::Number(n) => Value::Number(*n),
+            MapKey::Str(s) => Value::Str(s.clone()),
+        }
+    }
+    fn rank(&self) -> u8 {
+        match self {
+            MapKey::Nil => 0,
+            MapKey::Bool(_) => 1,
+            MapKey::Number(_) => 2,
+            MapKey::Str(_) => 3,
+        }
+    }
+}
+impl Ord for MapKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (MapKey::Bool(a), MapKey::Bool(b)) => a.cmp(b),
+            (MapKey::Number(a), MapKey::Number(b)) => a.total_cmp(b),
+            (MapKey::Str(a), MapKey::Str(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
+}
+impl PartialOrd for MapKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for MapKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for MapKey {}
+
+// Anything that can be invoked with call syntax. Arity is checked by the
+// interpreter before `call` runs, against the range of argument counts the
+// callable takes; `paren` locates errors raised by the call.
+pub trait LoxCallable {
+    fn arity(&self) -> RangeInclusive<usize>;
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &Token,
+        arguments: Vec<Value>,
+    ) -> Result<Value, Diagnostic>;
+}
+
+type NativeFn = dyn Fn(&mut Interpreter, Vec<Value>) -> Result<Value, String>;
+
+// A function implemented in Rust. Errors are plain messages; the
+// interpreter attaches the call site's location.
+pub struct NativeFunction {
+    name: String,
+    arity: usize,
+    function: Box<NativeFn>,
+}
+impl NativeFunction {
+    pub fn new(
+        name: &str,
+        arity: usize,
+        function: impl Fn(&mut Interpreter, Vec<Value>) -> Result<Value, String> + 'static,
+    ) -> NativeFunction {
+        NativeFunction {
+            name: name.to_string(),
+            arity,
+            function: Box::new(function),
+        }
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "NativeFunction {{ name: {:?}, arity: {} }}",
+            self.name, self.arity
+        )
+    }
+}
+impl LoxCallable for NativeFunction {
+    fn arity(&self) -> RangeInclusive<usize> {
+        self.arity..=self.arity
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &Token,
+        arguments: Vec<Value>,
+    ) -> Result<Value, Diagnostic> {
+        (self.function)(interpreter, arguments).map_err(|what| match interpreter.failure.take() {
+            Some((mut e, function)) => {
+                e.trace.push(Frame {
+                    function,
+                    call_site: paren.span,
+                    import: false,
+                });
+                e
+            }
+            None => Diagnostic::at(paren, &what),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct LoxFunction {
+    declaration: Arc<FunctionDecl>,
+    closure: Rc<RefCell<Environment>>,
+    // The globals of the module the function was defined in, which its
+    // body sees wherever it is called from.
+    globals: Rc<RefCell<Environment>>,
+    is_initializer: bool,
+}
+impl LoxFunction {
+    // Methods are looked up unbound on the class; binding wraps the closure
+    // in a scope where `this` is the receiving instance, or the class for a
+    // class method.
+    fn bind(&self, this: Value) -> LoxFunction {
+        let mut environment = Environment::with_enclosing(Rc::clone(&self.closure));
+        environment.define("this".to_string(), this);
+        LoxFunction {
+            declaration: Arc::clone(&self.declaration),
+            closure: Rc::new(RefCell::new(environment)),
+            globals: Rc::clone(&self.globals),
+            is_initializer: self.is_initializer,
+        }
+    }
+}
+impl LoxCallable for LoxFunction {
+    fn arity(&self) -> RangeInclusive<usize> {
+        let declaration = &self.declaration;
+        let required = declaration
+            .defaults
+            .iter()
+            .take_while(|d| d.is_none())
+            .count();
+        required..=declaration.params.len()
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &Token,
+        arguments: Vec<Value>,
+    ) -> Result<Value, Diagnostic> {
+        interpreter
+            .budget
+            .step()
+            .map_err(|message| Diagnostic::at(paren, message))?;
+        // Each call nests on the Rust stack, so the depth is capped well
+        // before that runs out.
+        if interpreter.call_depth >= interpreter.max_call_depth {
+            return Err(Diagnostic::at(paren, "Stack overflow."));
+        }
+        interpreter.call_depth += 1;
+        let reported = interpreter.notify_call(&lexeme(&self.declaration.name), paren.span);
+        let result = self.run(interpreter, paren, arguments);
+        if reported {
+            interpreter.notify_return();
+        }
+        interpreter.call_depth -= 1;
+        result
+    }
+}
+impl LoxFunction {
+    // The scope a call runs the body in, with the parameters bound to the
+    // arguments passed.
+    fn environment(&self, arguments: Vec<Value>) -> Environment {
+        let mut environment = Environment::with_enclosing(Rc::clone(&self.closure));
+        for (param, argument) in self.declaration.params.iter().zip(arguments) {
+            environment.define(lexeme(param), argument);
+        }
+        environment
+    }
+    // A tail call replaces the running function instead of nesting inside
+    // it, so this loops rather than recursing, and an error's trace shows
+    // only the function that was running, called from the original site.
+    fn run(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &Token,
+        arguments: Vec<Value>,
+    ) -> Result<Value, Diagnostic> {
+        let mut tail_callee: Option<Rc<LoxFunction>> = None;
+        let mut arguments = arguments;
+        loop {
+            let function = tail_callee.as_deref().unwrap_or(self);
+            let supplied = arguments.len();
+            let environment = function.environment(arguments);
+            let enclosing_globals =
+                mem::replace(&mut interpreter.globals, Rc::clone(&function.globals));
+            let enclosing_try_depth = mem::take(&mut interpreter.try_depth);
+            let result = interpreter.execute_call(&function.declaration, supplied, environment);
+            interpreter.globals = enclosing_globals;
+            interpreter.try_depth = enclosing_try_depth;
+            let value = match result {
+                Ok(()) => Value::Nil,
+                Err(Unwind::Return(value)) => value,
+                Err(Unwind::TailCall {
+                    function: callee,
+                    arguments: next,
+                }) => {
+                    tail_callee = Some(callee);
+                    arguments = next;
+                    continue;
+                }
+                Err(Unwind::Error(mut e)) => {
+                    e.trace.push(Frame {
+                        function: lexeme(&function.declaration.name),
+                        call_site: paren.span,
+                        import: false,
+                    });
+                    return Err(e);
+                }
+                Err(Unwind::Yield(_)) => unreachable!("only a coroutine's own function yields"),
+            };
+            // `init()` always hands back the instance, even when called
+            // directly or exited through a bare `return;`.
+            if function.is_initializer {
+                return Ok(function
+                    .closure
+                    .borrow()
+                    .get_at(0, "this")
+                    .unwrap_or(Value::Nil));
+            }
+            return Ok(value);
+        }
+    }
+}
+
+// A function run a piece at a time, made by `coroutine`. Each `resume`
+// runs it up to its next `yield` and hands back the value yielded, until
+// the function returns.
+pub struct Coroutine {
+    state: RefCell<CoroutineState>,
+}
+enum CoroutineState {
+    // `function` carries on from where `path` says it yielded, or starts
+    // from the top if it hasn't run yet and `path` is empty. A tail call
+    // hands the coroutine over to the function called.
+    Suspended {
+        function: Rc<LoxFunction>,
+        path: Vec<Resume>,
+    },
+    Running,
+    Dead,
+}
+impl Coroutine {
+    pub(crate) fn new(function: Rc<LoxFunction>) -> Coroutine {
+        Coroutine {
+            state: RefCell::new(CoroutineState::Suspended {
+                function,
+                path: Vec::new(),
+            }),
+        }
+    }
+    pub fn status(&self) -> &'static str {
+        match &*self.state.borrow() {
+            CoroutineState::Suspended { .. } => "suspended",
+            CoroutineState::Running => "running",
+            CoroutineState::Dead => "dead",
+        }
+    }
+}
+impl fmt::Debug for Coroutine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Coroutine {{ status: {:?} }}", self.status())
+    }
+}
+
+#[derive(Debug)]
+pub struct LoxClass {
+    name: String,
+    superclass: Option<Rc<LoxClass>>,
+    methods: HashMap<String, Rc<LoxFunction>>,
+    // Class methods are inherited like methods. Fields set on the class
+    // belong to it alone.
+    class_methods: HashMap<String, Rc<LoxFunction>>,
+    fields: RefCell<HashMap<String, Value>>,
+}
+impl LoxClass {
+    fn find_method(&self, name: &str) -> Option<Rc<LoxFunction>> {
+        match self.methods.get(name) {
+            Some(method) => Some(Rc::clone(method)),
+            None => self.superclass.as_ref()?.find_method(name),
+        }
+    }
+    fn find_class_method(&self, name: &str) -> Option<Rc<LoxFunction>> {
+        match self.class_methods.get(name) {
+            Some(method) => Some(Rc::clone(method)),
+            None => self.superclass.as_ref()?.find_class_method(name),
+        }
+    }
+}
+// Implemented on the `Rc` because each new instance keeps a shared
+// reference to its class.
+impl LoxCallable for Rc<LoxClass> {
+    fn arity(&self) -> RangeInclusive<usize> {
+        self.find_method("init").map_or(0..=0, |init| init.arity())
+    }
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        paren: &Token,
+        arguments: Vec<Value>,
+    ) -> Result<Value, Diagnostic> {
+        let instance = Rc::new(RefCell::new(LoxInstance {
+            class: Rc::clone(self),
+            fields: HashMap::new(),
+        }));
+        if let Some(init) = self.find_method("init") {
+            init.bind(Value::Instance(Rc::clone(&instance)))
+                .call(interpreter, paren, arguments)?;
+        }
+        Ok(Value::Instance(instance))
+    }
+}
+
+// A named set of methods that classes copy in with `with`. The methods
+// are kept in declaration order so clashes are reported consistently.
+#[derive(Debug)]
+pub struct LoxTrait {
+    name: String,
+    methods: Vec<(String, Rc<LoxFunction>)>,
+}
+
+pub(crate) fn trait_conflict(method: &str, first: &str, second: &str) -> String {
+    format!(
+        "Method '{}' is defined by both traits '{}' and '{}'.",
+        method, first, second
+    )
+}
+
+#[derive(Debug)]
+pub struct LoxInstance {
+    class: Rc<LoxClass>,
+    fields: HashMap<String, Value>,
+}
+
+// Anything that stops a statement from completing normally. `return` is
+// modelled as an unwind so it can cross any number of nested blocks.
+enum Unwind {
+    Error(Diagnostic),
+    Return(Value),
+    // `return f(...)` with `f` a Lox function, already checked for arity.
+    // The function being returned from calls `f` in its own place.
+    TailCall {
+        function: Rc<LoxFunction>,
+        arguments: Vec<Value>,
+    },
+    // A `yield`, on its way out to the `resume` that ran the coroutine.
+    // Each statement it leaves records in `suspended` how to carry on.
+    Yield(Value),
+}
+impl From<Diagnostic> for Unwind {
+    fn from(e: Diagnostic) -> Self {
+        Unwind::Error(e)
+    }
+}
+
+// An assignment target with its object and index evaluated, so that it
+// can be read and then written without running them again.
+enum Place {
+    Variable,
+    Field(Value),
+    Element(Value, Value),
+}
+
+// Where a statement was when a `yield` inside it suspended its coroutine.
+// Resuming re-enters each statement the yield left, outermost first, at
+// the point recorded, rather than running it from the top.
+enum Resume {
+    // At the statement `index` of a block or function body, run in
+    // `environment`.
+    Block {
+        environment: Rc<RefCell<Environment>>,
+        index: usize,
+    },
+    // In the body of a loop.
+    Loop,
+    // In the body of a `for` loop with an initializer, whose variable is
+    // in `environment`.
+    Scope(Rc<RefCell<Environment>>),
+    // In the body of a `for-in` loop, with the elements still to come.
+    Iteration(Iteration),
+    // In the then branch of an `if`, or else the else branch.
+    Branch(bool),
+    // In the body of the case at this index of a `switch`, or the default.
+    Case(Option<usize>),
+    Try(TryStage),
+    // At the `yield` itself, which is done once resumed.
+    Yielded,
+}
+enum TryStage {
+    Body,
+    Handler,
+    // With what the body and handler came to, and the value of any
+    // exception still on its way out.
+    Finally {
+        result: Result<(), Unwind>,
+        pending: Option<Value>,
+    },
+}
+
+// Follows the interpreter through a program, for debuggers. Only the entry
+// script's statements are reported, since spans in an imported module are
+// into another file.
+pub trait DebugHook {
+    // Called before each statement other than a block runs, with the
+    // number of Lox calls in progress and the innermost scope. An `Err`
+    // stops the program with that message as a runtime error.
+    fn statement(
+        &mut self,
+        span: Span,
+        depth: usize,
+        scope: &Rc<RefCell<Environment>>,
+    ) -> Result<(), String>;
+    // Called as a Lox function is called from the entry script, and as
+    // that call returns.
+    fn call(&mut self, _function: &str, _call_site: Span) {}
+    fn ret(&mut self) {}
+}
+
+// The global variables of the entry script at some point, to go back to
+// with `Interpreter::restore`. Values are shared rather than copied, so
+// taking one is cheap, but changes made inside an instance, list or map
+// since then aren't undone.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    globals: HashMap<String, Value>,
+}
+
+pub struct Interpreter {
+    // The globals of the module whose code is running. Those of the entry
+    // script, which the host sees, are `main_globals`.
+    globals: Rc<RefCell<Environment>>,
+    main_globals: Rc<RefCell<Environment>>,
+    environment: Rc<RefCell<Environment>>,
+    // Scope depth of each resolved local variable expression, keyed by
+    // expression id. Expressions missing from the map are globals.
+    locals: HashMap<usize, usize>,
+    // Lox functions currently being called.
+    call_depth: usize,
+    max_call_depth: usize,
+    stack_limit: Option<usize>,
+    limits: Limits,
+    budget: Budget,
+    modules: Modules,
+    // Every source run so far, modules included, which error spans may
+    // point into.
+    sources: Sources,
+    // The value of the `throw` being unwound, if any. It travels as a
+    // runtime error so that it passes through calls like any other, and is
+    // taken back out by the `catch` that handles it.
+    thrown: Option<Value>,
+    // `try` statements around the code running in the current function.
+    // Calls in them can't be tail calls, or the callee would run outside.
+    try_depth: usize,
+    // The call depth the innermost running coroutine's function runs at,
+    // the only place a `yield` may happen.
+    coroutine_depth: Option<usize>,
+    // Where the statements a `yield` is leaving stopped, innermost first,
+    // and while a coroutine is being resumed, those still to re-enter.
+    suspended: Vec<Resume>,
+    resuming: Vec<Resume>,
+    // An error raised by Lox code that a native ran, with the function it
+    // was raised in. The native's call fails with it instead.
+    failure: Option<(Diagnostic, String)>,
+    debug_hook: Option<Box<dyn DebugHook>>,
+    // Where `print` writes, and `eprint`.
+    output: Box<dyn Write>,
+    error_output: Box<dyn Write>,
+}
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        let globals = Rc::new(RefCell::new(Environment::new()));
+        natives::define_natives(&mut globals.borrow_mut());
+        Interpreter {
+            environment: Rc::clone(&globals),
+            main_globals: Rc::clone(&globals),
+            globals,
+            locals: HashMap::new(),
+            call_depth: 0,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            stack_limit: STACK_LIMIT.with(Cell::get),
+            limits: Limits::default(),
+            budget: Budget::default(),
+            modules: Modules::default(),
+            sources: Sources::default(),
+            thrown: None,
+            try_depth: 0,
+            coroutine_depth: None,
+            suspended: Vec::new(),
+            resuming: Vec::new(),
+            failure: None,
+            debug_hook: None,
+            output: Box::new(io::stdout()),
+            error_output: Box::new(io::stderr()),
+        }
+    }
+    // Sends what `print` prints to `output` instead of stdout.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+    // Sends what `eprint` prints to `output` instead of stderr.
+    pub fn set_error_output(&mut self, output: Box<dyn Write>) {
+        self.error_output = output;
+    }
+    pub(crate) fn eprint(&mut self, text: &str) {
+        let _ = writeln!(self.error_output, "{}", text);
+    }
+    pub fn allow_imports(&mut self) {
+        self.modules.allow();
+    }
+    // The directory `import` paths are relative to. Defaults to the
+    // current directory.
+    pub fn set_module_root(&mut self, root: PathBuf) {
+        self.modules.set_root(root);
+    }
+    pub fn set_entry_script(&mut self, script: &Path) {
+        self.modules.set_entry_script(script);
+    }
+    // Parses the modules `statements` import ahead of running them; see
+    // `Modules::preload`.
+    pub(crate) fn preload(&mut self, statements: &[Stmt]) {
+        self.modules.preload(statements, &mut self.sources);
+    }
+    pub(crate) fn sources(&mut self) -> &mut Sources {
+        &mut self.sources
+    }
+    // Locates spans in any source run so far.
+    pub fn source_map(&self) -> SourceMap<'_> {
+        self.sources.map()
+    }
+    // Applies to each later call of `interpret`, which starts with the full
+    // fuel and time allowed.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+    // Whether the last `interpret` failed because it ran out of fuel or
+    // time.
+    pub fn exhausted_limits(&self) -> bool {
+        self.budget.exhausted()
+    }
+    // A deeper call fails with "Stack overflow." instead of running. The
+    // thread must have enough stack for the calls allowed.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+    pub fn set_debug_hook(&mut self, hook: Box<dyn DebugHook>) {
+        self.debug_hook = Some(hook);
+    }
+    pub fn resolve(&mut self, id: usize, depth: usize) {
+        self.locals.insert(id, depth);
+    }
+    // Returns the value of the last statement if it is an expression
+    // statement, and nil otherwise.
+    pub fn interpret(&mut self, statements: &[Stmt]) -> Result<Value, Diagnostic> {
+        self.budget = Budget::start(self.limits);
+        self.thrown = None;
+        let mut value = Value::Nil;
+        for statement in statements {
+            value = Value::Nil;
+            let result = match statement {
+                Stmt::Expression(expr) => self
+                    .notify_debugger(statement)
+                    .and_then(|()| self.evaluate(expr))
+                    .map(|v| value = v),
+                _ => match self.execute(statement) {
+                    // The resolver rejects `return` and `yield` outside a
+                    // function.
+                    Ok(())
+                    | Err(Unwind::Return(_) | Unwind::TailCall { .. } | Unwind::Yield(_)) => Ok(()),
+                    Err(Unwind::Error(e)) => Err(e),
+                },
+            };
+            result?;
+        }
+        Ok(value)
+    }
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.main_globals.borrow().get(name)
+    }
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        self.main_globals.borrow().bindings()
+    }
+    pub fn define_global(&mut self, name: &str, value: Value) {
+        self.main_globals
+            .borrow_mut()
+            .define(name.to_string(), value);
+    }
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            globals: self.main_globals.borrow().snapshot(),
+        }
+    }
+    // Puts the globals back as they were when `snapshot` was taken. The
+    // scope itself is kept, so functions defined before then still see it.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.main_globals
+            .borrow_mut()
+            .restore(snapshot.globals.clone());
+    }
+    // Runs the module at `path` unless it has been already, then defines
+    // its globals in the importing module. These are copies: functions from
+    // the module keep seeing its own globals, but the importer doesn't see
+    // later assignments to them.
+    fn import(&mut self, path: &str, span: Span) -> Result<(), Diagnostic> {
+        let file = self
+            .modules
+            .find(path)
+            .map_err(|message| Diagnostic::new(&message, span))?;
+        let globals = match self.modules.get(&file) {
+            Some(globals) => globals,
+            None => {
+                self.modules
+                    .begin(file.clone(), path)
+                    .map_err(|message| Diagnostic::new(&message, span))?;
+                let result = self.run_module(path, &file, span);
+                self.modules.finish(result.as_ref().ok().cloned());
+                result?
+            }
+        };
+        let natives = self.natives();
+        for (name, value) in globals.borrow().bindings() {
+            let is_native =
+                |(native, function): &(String, Value)| *native == name && function.is_equal(&value);
+            if !natives.iter().any(is_native) {
+                self.globals.borrow_mut().define(name, value);
+            }
+        }
+        Ok(())
+    }
+    // Every module starts out with the natives the host gave the entry
+    // script.
+    fn natives(&self) -> Vec<(String, Value)> {
+        let mut natives = self.main_globals.borrow().bindings();
+        natives.retain(|(_, value)| matches!(value, Value::Native(_)));
+        natives
+    }
+    // Errors in the module are reported where they happened in it, with a
+    // trace frame for the import.
+    fn run_module(
+        &mut self,
+        path: &str,
+        file: &Path,
+        span: Span,
+    ) -> Result<Rc<RefCell<Environment>>, Diagnostic> {
+        let imported = |mut e: Diagnostic| {
+            e.trace.push(Frame {
+                function: path.to_string(),
+                call_site: span,
+                import: true,
+            });
+            e
+        };
+        let parsed = match self.modules.take_parsed(file) {
+            Some(parsed) => parsed,
+            None => {
+                let source = fs::read_to_string(file).map_err(|e| {
+                    Diagnostic::new(&format!("Could not read module '{}': {}", path, e), span)
+                })?;
+                let base = self.sources.add(Some(path), &source);
+                Lox::parse_at(&source, base)
+            }
+        };
+        let statements = parsed.map_err(|e| imported(e.diagnostics()[0].clone()))?;
+        Resolver::new(self).resolve(&statements).map_err(imported)?;
+        let mut globals = Environment::new();
+        for (name, value) in self.natives() {
+            globals.define(name, value);
+        }
+        let globals = Rc::new(RefCell::new(globals));
+        let enclosing_globals = mem::replace(&mut self.globals, Rc::clone(&globals));
+        let enclosing = mem::replace(&mut self.environment, Rc::clone(&globals));
+        let result = statements.iter().try_for_each(|stmt| self.execute(stmt));
+        self.globals = enclosing_globals;
+        self.environment = enclosing;
+        match result {
+            Ok(()) | Err(Unwind::Return(_) | Unwind::TailCall { .. } | Unwind::Yield(_)) => {
+                Ok(globals)
+            }
+            Err(Unwind::Error(e)) => Err(imported(e)),
+        }
+    }
+    fn notify_debugger(&mut self, stmt: &Stmt) -> Result<(), Diagnostic> {
+        if let Some(hook) = &mut self.debug_hook {
+            if !matches!(stmt, Stmt::Block(_)) && Rc::ptr_eq(&self.globals, &self.main_globals) {
+                let span = stmt_span(stmt);
+                hook.statement(span, self.call_depth, &self.environment)
+                    .map_err(|message| Diagnostic::new(&message, span))?;
+            }
+        }
+        Ok(())
+    }
+    // Whether the call was reported, and so its return must be.
+    fn notify_call(&mut self, function: &str, call_site: Span) -> bool {
+        match &mut self.debug_hook {
+            Some(hook) if Rc::ptr_eq(&self.globals, &self.main_globals) => {
+                hook.call(function, call_site);
+                true
+            }
+            _ => false,
+        }
+    }
+    fn notify_return(&mut self) {
+        if let Some(hook) = &mut self.debug_hook {
+            hook.ret();
+        }
+    }
+    fn check_stack(&self, span: impl FnOnce() -> Span) -> Result<(), Diagnostic> {
+        match self.stack_limit {
+            Some(limit) if stack_address() < limit => {
+                Err(Diagnostic::new("Stack overflow.", span()))
+            }
+            _ => Ok(()),
+        }
+    }
+    // Every Lox call nests `execute` and `evaluate` frames, so the larger
+    // arms live in their own methods to keep these two frames small.
+    fn execute(&mut self, stmt: &Stmt) -> Result<(), Unwind> {
+        // Where the statement stopped, if it is being re-entered to resume
+        // a coroutine.
+        let resume = self.resuming.pop();
+        if resume.is_none() {
+            self.check_stack(|| stmt_span(stmt))?;
+            self.notify_debugger(stmt)?;
+        }
+        match stmt {
+            Stmt::Block(statements) => match resume {
+                Some(Resume::Block { environment, index }) => {
+                    self.continue_block(statements, environment, index)
+                }
+                _ => self.execute_scoped(statements),
+            },
+            // Fuel is spent per jump back to the body, as in the VM.
+            Stmt::DoWhile { body, condition } => loop {
+                self.execute(body)
+                    .map_err(|unwind| self.suspend(unwind, Resume::Loop))?;
+                if !self.evaluate(condition)?.is_truthy() {
+                    return Ok(());
+                }
+                self.budget
+                    .step()
+                    .map_err(|message| Diagnostic::new(message, expr_span(condition)))?;
+            },
+            Stmt::Class {
+                name,
+                superclass,
+                traits,
+                methods,
+                class_methods,
+            } => self.class_declaration(name, superclass.as_ref(), traits, methods, class_methods),
+            Stmt::Trait { name, methods } => self.trait_declaration(name, methods),
+            Stmt::Destructure {
+                names,
+                equals,
+                initializer,
+            } => self.destructure(names, equals, initializer),
+            Stmt::Expression(expr) => {
+                self.evaluate(expr)?;
+                Ok(())
+            }
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+            } => self.for_statement(
+                initializer.as_deref(),
+                condition,
+                increment.as_ref(),
+                body,
+                resume,
+            ),
+            Stmt::ForEach {
+                name,
+                keyword,
+                iterable,
+                body,
+            } => self.for_each(name, keyword, iterable, body, resume),
+            Stmt::Function(declaration) => {
+                let function = LoxFunction {
+                    declaration: Arc::clone(declaration),
+                    closure: Rc::clone(&self.environment),
+                    globals: Rc::clone(&self.globals),
+                    is_initializer: false,
+                };
+                self.environment.borrow_mut().define(
+                    lexeme(&declaration.name),
+                    Value::Function(Rc::new(function)),
+                );
+                Ok(())
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                let then = match resume {
+                    Some(Resume::Branch(then)) => then,
+                    _ => self.evaluate(condition)?.is_truthy(),
+                };
+                let branch = if then {
+                    Some(then_branch)
+                } else {
+                    else_branch.as_ref()
+                };
+                match branch {
+                    Some(branch) => self
+                        .execute(branch)
+                        .map_err(|unwind| self.suspend(unwind, Resume::Branch(then))),
+                    None => Ok(()),
+                }
+            }
+            Stmt::Import { path, span, .. } => Ok(self.import(path, *span)?),
+            Stmt::Print(expr) => self.print_statement(expr),
+            Stmt::Return { value, .. } => self.return_statement(value.as_ref()),
+            Stmt::Switch {
+                subject,
+                cases,
+                default,
+            } => self.switch_statement(subject, cases, default.as_ref(), resume),
+            Stmt::Throw { keyword, value } => {
+                let value = self.evaluate(value)?;
+                let message = format!("Uncaught exception: {}", value);
+                self.thrown = Some(value);
+                Err(Diagnostic::at(keyword, &message).into())
+            }
+            Stmt::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                let stage = match resume {
+                    Some(Resume::Try(stage)) => Some(stage),
+                    _ => None,
+                };
+                self.try_statement(body, catch.as_ref(), finally.as_deref(), stage)
+            }
+            Stmt::Var {
+                name, initializer, ..
+            } => {
+                let value = match initializer {
+                    Some(expr) => self.evaluate(expr)?,
+                    None => Value::Nil,
+                };
+                self.environment.borrow_mut().define(lexeme(name), value);
+                Ok(())
+            }
+            // A resumed loop carries on in its body before checking the
+            // condition again.
+            Stmt::While { condition, body } => {
+                let mut resumed = resume.is_some();
+                while resumed || self.evaluate(condition)?.is_truthy() {
+                    resumed = false;
+                    self.execute(body)
+                        .map_err(|unwind| self.suspend(unwind, Resume::Loop))?;
+                    self.budget
+                        .step()
+                        .map_err(|message| Diagnostic::new(message, expr_span(condition)))?;
+                }
+                Ok(())
+            }
+            Stmt::Yield { keyword, value } => self.yield_statement(keyword, value.as_ref(), resume),
+        }
+    }
+    fn class_declaration(
+        &mut self,
+        name: &Token,
+        superclass: Option<&Expr>,
+        traits: &[Expr],
+        methods: &[Arc<FunctionDecl>],
+        class_methods: &[Arc<FunctionDecl>],
+    ) -> Result<(), Unwind> {
+        let superclass = match superclass {
+            Some(expr) => match self.evaluate(expr)? {
+                Value::Class(class) => Some(class),
+                _ => {
+                    let token = match expr {
+                        Expr::Variable { name, .. } => name,
+                        _ => name,
+                    };
+                    return Err(Diagnostic::at(token, "Superclass must be a class.").into());
+                }
+            },
+            None => None,
+        };
+        // Trait methods come before the class's own, which replace
+        // any of the same name.
+        let mut mixed = Vec::new();
+        let mut sources: HashMap<String, String> = HashMap::new();
+        for used in traits {
+            let token = match used {
+                Expr::Variable { name, .. } => name,
+                _ => name,
+            };
+            let Value::Trait(used) = self.evaluate(used)? else {
+                return Err(Diagnostic::at(token, "Only traits can be mixed in.").into());
+            };
+            for (method, _) in &used.methods {
+                if let Some(earlier) = sources.get(method) {
+                    let message = trait_conflict(method, earlier, &used.name);
+                    return Err(Diagnostic::at(token, &message).into());
+                }
+            }
+            for (method, function) in &used.methods {
+                sources.insert(method.clone(), used.name.clone());
+                mixed.push((method.clone(), Rc::clone(function)));
+            }
+        }
+        // Methods of a subclass close over an extra scope that binds
+        // `super`, mirroring the scope the resolver created.
+        let enclosing = Rc::clone(&self.environment);
+        if let Some(superclass) = &superclass {
+            let mut environment = Environment::with_enclosing(Rc::clone(&enclosing));
+            environment.define("super".to_string(), Value::Class(Rc::clone(superclass)));
+            self.environment = Rc::new(RefCell::new(environment));
+        }
+        let function = |method: &Arc<FunctionDecl>, is_initializer: bool| {
+            let function = LoxFunction {
+                declaration: Arc::clone(method),
+                closure: Rc::clone(&self.environment),
+                globals: Rc::clone(&self.globals),
+                is_initializer,
+            };
+            (lexeme(&method.name), Rc::new(function))
+        };
+        let class = LoxClass {
+            name: lexeme(name),
+            superclass,
+            methods: mixed
+                .into_iter()
+                .chain(
+                    methods
+                        .iter()
+                        .map(|method| function(method, lexeme(&method.name) == "init")),
+                )
+                .collect(),
+            class_methods: class_methods
+                .iter()
+                .map(|method| function(method, false))
+                .collect(),
+            fields: RefCell::new(HashMap::new()),
+        };
+        self.environment = enclosing;
+        self.environment
+            .borrow_mut()
+            .define(lexeme(name), Value::Class(Rc::new(class)));
+        Ok(())
+    }
+    fn trait_declaration(
+        &mut self,
+        name: &Token,
+        methods: &[Arc<FunctionDecl>],
+    ) -> Result<(), Unwind> {
+        let methods = methods
+            .iter()
+            .map(|method| {
+                let function = LoxFunction {
+                    declaration: Arc::clone(method),
+                    closure: Rc::clone(&self.environment),
+                    globals: Rc::clone(&self.globals),
+                    is_initializer: lexeme(&method.name) == "init",
+                };
+                (lexeme(&method.name), Rc::new(function))
+            })
+            .collect();
+        let used = LoxTrait {
+            name: lexeme(name),
+            methods,
+        };
+        self.environment
+            .borrow_mut()
+            .define(lexeme(name), Value::Trait(Rc::new(used)));
+        Ok(())
+    }
+    fn destructure(
+        &mut self,
+        names: &[Token],
+        equals: &Token,
+        initializer: &Expr,
+    ) -> Result<(), Unwind> {
+        let value = self.evaluate(initializer)?;
+        for (name, element) in names.iter().zip(unpack(equals, &value, names.len())?) {
+            self.environment.borrow_mut().define(lexeme(name), element);
+        }
+        Ok(())
+    }
+    fn for_statement(
+        &mut self,
+        initializer: Option<&Stmt>,
+        condition: &Expr,
+        increment: Option<&Expr>,
+        body: &Stmt,
+        resume: Option<Resume>,
+    ) -> Result<(), Unwind> {
+        let resumed = resume.is_some();
+        let Some(initializer) = initializer else {
+            return self
+                .for_loop(condition, increment, body, resumed)
+                .map_err(|unwind| self.suspend(unwind, Resume::Loop));
+        };
+        let environment = match resume {
+            Some(Resume::Scope(environment)) => environment,
+            _ => Rc::new(RefCell::new(Environment::with_enclosing(Rc::clone(
+                &self.environment,
+            )))),
+        };
+        let previous = std::mem::replace(&mut self.environment, environment);
+        let result = if resumed {
+            self.for_loop(condition, increment, body, true)
+        } else {
+            self.execute(initializer)
+                .and_then(|()| self.for_loop(condition, increment, body, false))
+        };
+        let result = result
+            .map_err(|unwind| self.suspend(unwind, Resume::Scope(Rc::clone(&self.environment))));
+        self.environment = previous;
+        result
+    }
+    fn for_each(
+        &mut self,
+        name: &Token,
+        keyword: &Token,
+        iterable: &Expr,
+        body: &Stmt,
+        resume: Option<Resume>,
+    ) -> Result<(), Unwind> {
+        let (mut iteration, mut resumed) = match resume {
+            Some(Resume::Iteration(iteration)) => (iteration, true),
+            _ => match self.evaluate(iterable)?.iterate() {
+                Some(iteration) => (iteration, false),
+                None => {
+                    return Err(Diagnostic::at(
+                        keyword,
+                        "Can only iterate over lists, maps and ranges.",
+                    )
+                    .into())
+                }
+            },
+        };
+        let body = std::slice::from_ref(body);
+        loop {
+            let result = if resumed {
+                resumed = false;
+                self.resume_block(body)
+            } else {
+                let Some(element) = iteration.next() else {
+                    return Ok(());
+                };
+                let mut environment = Environment::with_enclosing(Rc::clone(&self.environment));
+                environment.define(lexeme(name), element);
+                self.execute_block(body, environment)
+            };
+            if let Err(unwind) = result {
+                return Err(self.suspend(unwind, Resume::Iteration(iteration)));
+            }
+            self.budget
+                .step()
+                .map_err(|message| Diagnostic::at(keyword, message))?;
+        }
+    }
+    fn print_statement(&mut self, expr: &Expr) -> Result<(), Unwind> {
+        let value = self.evaluate(expr)?;
+        let site = synthetic_token("print", expr_span(expr));
+        let text = self.stringify(value, &site)?;
+        // Like `println!`, but a closed pipe doesn't stop the program.
+        let _ = writeln!(self.output, "{}", text);
+        Ok(())
+    }
+    fn return_statement(&mut self, value: Option<&Expr>) -> Result<(), Unwind> {
+        let value = match value {
+            Some(Expr::Call {
+                callee,
+                paren,
+                arguments,
+            }) => {
+                let Some(callee) = self.evaluate_callee(callee)? else {
+                    return Err(Unwind::Return(Value::Nil));
+                };
+                let arguments = self.evaluate_arguments(arguments)?;
+                match callee {
+                    Value::Function(function) if self.try_depth == 0 => {
+                        check_arity(paren, function.arity(), arguments.len())?;
+                        // The call is made by the loop in `LoxFunction::run`,
+                        // so its fuel is spent here, where it is reported.
+                        self.budget
+                            .step()
+                            .map_err(|message| Diagnostic::at(paren, message))?;
+                        return Err(Unwind::TailCall {
+                            function,
+                            arguments,
+                        });
+                    }
+                    callee => self.call_value(callee, paren, arguments)?,
+                }
+            }
+            Some(expr) => self.evaluate(expr)?,
+            None => Value::Nil,
+        };
+        Err(Unwind::Return(value))
+    }
+    fn switch_statement(
+        &mut self,
+        subject: &Expr,
+        cases: &[(Expr, Vec<Stmt>)],
+        default: Option<&Vec<Stmt>>,
+        resume: Option<Resume>,
+    ) -> Result<(), Unwind> {
+        let resumed = resume.is_some();
+        let case = match resume {
+            Some(Resume::Case(case)) => case,
+            _ => self.switch_case(subject, cases)?,
+        };
+        let body = match (case, default) {
+            (Some(case), _) => &cases[case].1,
+            (None, Some(default)) => default,
+            (None, None) => return Ok(()),
+        };
+        let result = if resumed {
+            self.resume_block(body)
+        } else {
+            self.execute_scoped(body)
+        };
+        result.map_err(|unwind| self.suspend(unwind, Resume::Case(case)))
+    }
+    fn yield_statement(
+        &mut self,
+        keyword: &Token,
+        value: Option<&Expr>,
+        resume: Option<Resume>,
+    ) -> Result<(), Unwind> {
+        if resume.is_some() {
+            return Ok(());
+        }
+        let value = match value {
+            Some(value) => self.evaluate(value)?,
+            None => Value::Nil,
+        };
+        if self.coroutine_depth != Some(self.call_depth) {
+            let message = "Can only yield from the function a coroutine runs.";
+            return Err(Diagnostic::at(keyword, message).into());
+        }
+        self.suspended.push(Resume::Yielded);
+        Err(Unwind::Yield(value))
+    }
+    // Records `resume` as where the statement running stopped if `unwind`
+    // is a yield leaving it.
+    fn suspend(&mut self, unwind: Unwind, resume: Resume) -> Unwind {
+        if let Unwind::Yield(_) = unwind {
+            self.suspended.push(resume);
+        }
+        unwind
+    }
+    // The index of the first case whose value equals `subject`, or None to
+    // run the default.
+    fn switch_case(
+        &mut self,
+        subject: &Expr,
+        cases: &[(Expr, Vec<Stmt>)],
+    ) -> Result<Option<usize>, Diagnostic> {
+        let subject = self.evaluate(subject)?;
+        for (index, (case, _)) in cases.iter().enumerate() {
+            let value = self.evaluate(case)?;
+            let site = synthetic_token("case", expr_span(case));
+            let matched = match self.overload(&subject, "equals", vec![value.clone()], &site)? {
+                Some(result) => result.is_truthy(),
+                None => subject.is_equal(&value),
+            };
+            if matched {
+                return Ok(Some(index));
+            }
+        }
+        Ok(None)
+    }
+    // Runs a `try` statement, or carries on with one from the `stage` a
+    // yield left it at. A yield leaves without running `finally`, since
+    // the statement hasn't finished.
+    fn try_statement(
+        &mut self,
+        body: &[Stmt],
+        catch: Option<&(Token, Vec<Stmt>)>,
+        finally: Option<&[Stmt]>,
+        mut stage: Option<TryStage>,
+    ) -> Result<(), Unwind> {
+        let mut result = Ok(());
+        if let None | Some(TryStage::Body) = stage {
+            self.try_depth += 1;
+            result = match stage.take() {
+                Some(_) => self.resume_block(body),
+                None => self.execute_scoped(body),
+            };
+            self.try_depth -= 1;
+            if let Err(Unwind::Yield(_)) = result {
+                return result.map_err(|unwind| self.suspend(unwind, Resume::Try(TryStage::Body)));
+            }
+        }
+        if let Some((name, handler)) = catch {
+            if let Some(TryStage::Handler) = stage {
+                stage = None;
+                result = self.resume_block(handler);
+            } else if let Err(Unwind::Error(e)) = &result {
+                // Running out of fuel or time can't be caught, or untrusted
+                // code could carry on regardless.
+                if !self.budget.exhausted() {
+                    let value = match self.thrown.take() {
+                        Some(value) => value,
+                        None => Value::Str(e.message.clone()),
+                    };
+                    let mut environment = Environment::with_enclosing(Rc::clone(&self.environment));
+                    environment.define(lexeme(name), value);
+                    result = self.execute_block(handler, environment);
+                }
+            }
+            if let Err(Unwind::Yield(_)) = result {
+                return result
+                    .map_err(|unwind| self.suspend(unwind, Resume::Try(TryStage::Handler)));
+            }
+        }
+        match finally {
+            // A `catch` inside `finally` mustn't take the value of an
+            // exception still on its way out.
+            Some(finally) if !self.budget.exhausted() => {
+                let (pending, finished) = match stage {
+                    Some(TryStage::Finally {
+                        result: earlier,
+                        pending,
+                    }) => {
+                        result = earlier;
+                        (pending, self.resume_block(finally))
+                    }
+                    _ => {
+                        let pending = self.thrown.take();
+                        (pending, self.execute_scoped(finally))
+                    }
+                };
+                if let Err(unwind @ Unwind::Yield(_)) = finished {
+                    let stage = TryStage::Finally { result, pending };
+                    return Err(self.suspend(unwind, Resume::Try(stage)));
+                }
+                finished?;
+                self.thrown = pending;
+                result
+            }
+            _ => result,
+        }
+    }
+    // Calls the method `name` on `receiver` if it is an instance whose class
+    // defines one, for the operators a class can overload.
+    fn overload(
+        &mut self,
+        receiver: &Value,
+        name: &str,
+        arguments: Vec<Value>,
+        token: &Token,
+    ) -> Result<Option<Value>, Diagnostic> {
+        let Value::Instance(instance) = receiver else {
+            return Ok(None);
+        };
+        let Some(method) = instance.borrow().class.find_method(name) else {
+            return Ok(None);
+        };
+        let method = Value::Function(Rc::new(method.bind(receiver.clone())));
+        self.call_value(method, token, arguments).map(Some)
+    }
+    // The text `print` and string interpolation show for a value, from its
+    // `toString` method if it has one.
+    fn stringify(&mut self, value: Value, token: &Token) -> Result<String, Diagnostic> {
+        match self.overload(&value, "toString", Vec::new(), token)? {
+            Some(text) => Ok(text.to_string()),
+            None => Ok(value.to_string()),
+        }
+    }
+    // A property that is a getter is called as soon as it is read, so
+    // getters never escape as values.
+    fn run_getter(&mut self, property: Value, name: &Token) -> Result<Value, Diagnostic> {
+        match &property {
+            Value::Function(getter) if getter.declaration.is_getter => {
+                getter.call(self, name, Vec::new())
+            }
+            _ => Ok(property),
+        }
+    }
+    fn for_loop(
+        &mut self,
+        condition: &Expr,
+        increment: Option<&Expr>,
+        body: &Stmt,
+        mut resumed: bool,
+    ) -> Result<(), Unwind> {
+        while resumed || self.evaluate(condition)?.is_truthy() {
+            resumed = false;
+            self.execute(body)?;
+            if let Some(increment) = increment {
+                self.evaluate(increment)?;
+            }
+            self.budget
+                .step()
+                .map_err(|message| Diagnostic::new(message, expr_span(condition)))?;
+        }
+        Ok(())
+    }
+    fn execute_scoped(&mut self, statements: &[Stmt]) -> Result<(), Unwind> {
+        let environment = Environment::with_enclosing(Rc::clone(&self.environment));
+        self.execute_block(statements, environment)
+    }
+    // Runs `statements` in `environment`, restoring the current scope
+    // afterwards even when a statement fails.
+    fn execute_block(
+        &mut self,
+        statements: &[Stmt],
+        environment: Environment,
+    ) -> Result<(), Unwind> {
+        self.continue_block(statements, Rc::new(RefCell::new(environment)), 0)
+    }
+    // Carries on with `statements` in the scope, and from the statement,
+    // where a yield left them.
+    fn resume_block(&mut self, statements: &[Stmt]) -> Result<(), Unwind> {
+        let Some(Resume::Block { environment, index }) = self.resuming.pop() else {
+            unreachable!("a yield records the block it leaves");
+        };
+        self.continue_block(statements, environment, index)
+    }
+    fn continue_block(
+        &mut self,
+        statements: &[Stmt],
+        environment: Rc<RefCell<Environment>>,
+        start: usize,
+    ) -> Result<(), Unwind> {
+        let previous = std::mem::replace(&mut self.environment, environment);
+        let result = self.execute_from(statements, start);
+        self.environment = previous;
+        result
+    }
+    // Runs `statements` from the one at `start` in the current scope.
+    fn execute_from(&mut self, statements: &[Stmt], start: usize) -> Result<(), Unwind> {
+        for (index, stmt) in statements.iter().enumerate().skip(start) {
+            if let Err(unwind) = self.execute(stmt) {
+                let environment = Rc::clone(&self.environment);
+                return Err(self.suspend(unwind, Resume::Block { environment, index }));
+            }
+        }
+        Ok(())
+    }
+    // Runs a function's body in `environment`, which holds the `supplied`
+    // arguments, after defining the parameters left out there. Their
+    // defaults are evaluated in order, so each can use those before it.
+    fn execute_call(
+        &mut self,
+        declaration: &FunctionDecl,
+        supplied: usize,
+        environment: Environment,
+    ) -> Result<(), Unwind> {
+        let previous = std::mem::replace(&mut self.environment, Rc::new(RefCell::new(environment)));
+        let result = declaration
+            .params
+            .iter()
+            .zip(&declaration.defaults)
+            .skip(supplied)
+            .try_for_each(|(param, default)| {
+                let value = match default {
+                    Some(default) => self.evaluate(default)?,
+                    None => Value::Nil,
+                };
+                self.environment.borrow_mut().define(lexeme(param), value);
+                Ok(())
+            })
+            .and_then(|()| self.execute_from(&declaration.body, 0));
+        self.environment = previous;
+        result
+    }
+    fn evaluate(&mut self, expr: &Expr) -> Result<Value, Diagnostic> {
+        self.check_stack(|| expr_span(expr))?;
+        match expr {
+            Expr::Assign { id, name, value } => {
+                let value = self.evaluate(value)?;
+                self.assign(*id, name, value.clone())?;
+                Ok(value)
+            }
+            Expr::Binary {
+                left,
+                operator,
+                right,
+            } => self.binary(left, operator, right),
+            Expr::Compound {
+                target,
+                operator,
+                value,
+            } => self
+                .compound_assign(target, operator, Some(value))
+                .map(|(_, new)| new),
+            Expr::Call {
+                callee,
+                paren,
+                arguments,
+            } => {
+                let Some(callee) = self.evaluate_callee(callee)? else {
+                    return Ok(Value::Nil);
+                };
+                let arguments = self.evaluate_arguments(arguments)?;
+                self.call_value(callee, paren, arguments)
+            }
+            Expr::Conditional {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                if self.evaluate(condition)?.is_truthy() {
+                    self.evaluate(then_branch)
+                } else {
+                    self.evaluate(else_branch)
+                }
+            }
+            Expr::Grouping(expr) => self.evaluate(expr),
+            Expr::Index {
+                object,
+                bracket,
+                index,
+            } => self.index(object, bracket, index),
+            Expr::IndexSet {
+                object,
+                bracket,
+                index,
+                value,
+            } => {
+                let object = self.evaluate(object)?;
+                let index = self.evaluate(index)?;
+                let value = self.evaluate(value)?;
+                set_index(object, bracket, &index, value.clone())?;
+                Ok(value)
+            }
+            Expr::Map { brace, entries } => self.map(brace, entries),
+            Expr::List(elements) => {
+                let elements = elements
+                    .iter()
+                    .map(|element| self.evaluate(element))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(Value::List(Rc::new(RefCell::new(elements))))
+            }
+            Expr::Lambda(declaration) => Ok(Value::Function(Rc::new(LoxFunction {
+                declaration: Arc::clone(declaration),
+                closure: Rc::clone(&self.environment),
+                globals: Rc::clone(&self.globals),
+                is_initializer: false,
+            }))),
+            Expr::Destructure {
+                targets,
+                equals,
+                value,
+            } => self.destructure_assign(targets, equals, value),
+            Expr::Get { object, name, safe } => match self.evaluate(object)? {
+                Value::Nil if *safe => Ok(Value::Nil),
+                object => self.property(object, name),
+            },
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => {
+                let object = self.evaluate(object)?;
+                let value = self.evaluate(value)?;
+                set_field(object, name, value.clone())?;
+                Ok(value)
+            }
+            Expr::Super {
+                id,
+                keyword,
+                method,
+            } => self.super_method(*id, keyword, method),
+            Expr::This { id, keyword } => self.look_up_variable(*id, keyword),
+            Expr::Literal { value, .. } => Ok(match value {
+                LiteralValue::Nil => Value::Nil,
+                LiteralValue::Bool(b) => Value::Bool(*b),
+                LiteralValue::Number(n) => Value::Number(*n),
+                LiteralValue::Str(s) => Value::Str(s.clone()),
+            }),
+            Expr::Logical {
+                left,
+                operator,
+                right,
+            } => {
+                let left = self.evaluate(left)?;
+                let short_circuits = match operator.tty {
+                    TokenType::Or => left.is_truthy(),
+                    _ => !left.is_truthy(),
+                };
+                if short_circuits {
+                    Ok(left)
+                } else {
+                    self.evaluate(right)
+                }
+            }
+            Expr::Unary { operator, right } => self.unary(operator, right),
+            Expr::Update {
+                operator,
+                prefix,
+                target,
+            } => {
+                let (old, new) = self.compound_assign(target, &update_operator(operator), None)?;
+                Ok(if *prefix { new } else { old })
+            }
+            Expr::Variable { id, name } => self.look_up_variable(*id, name),
+        }
+    }
+
+    fn binary(&mut self, left: &Expr, operator: &Token, right: &Expr) -> Result<Value, Diagnostic> {
+        let left = self.evaluate(left)?;
+        let right = self.evaluate(right)?;
+        self.operate(left, operator, right)
+    }
+    fn operate(
+        &mut self,
+        left: Value,
+        operator: &Token,
+        right: Value,
+    ) -> Result<Value, Diagnostic> {
+        if let Some(name) = operator_method(operator.tty) {
+            if let Some(result) = self.overload(&left, name, vec![right.clone()], operator)? {
+                return Ok(match operator.tty {
+                    TokenType::EqualEqual => Value::Bool(result.is_truthy()),
+                    TokenType::BangEqual => Value::Bool(!result.is_truthy()),
+                    _ => result,
+                });
+            }
+        }
+        binary(operator, left, right)
+    }
+
+    fn index(&mut self, object: &Expr, bracket: &Token, index: &Expr) -> Result<Value, Diagnostic> {
+        let object = self.evaluate(object)?;
+        let index = self.evaluate(index)?;
+        get_index(object, bracket, &index)
+    }
+
+    fn map(&mut self, brace: &Token, entries: &[(Expr, Expr)]) -> Result<Value, Diagnostic> {
+        let mut map = BTreeMap::new();
+        for (key, value) in entries {
+            let key = self.evaluate(key)?;
+            let key = map_key(brace, &key)?;
+            let value = self.evaluate(value)?;
+            map.insert(key, value);
+        }
+        Ok(Value::Map(Rc::new(RefCell::new(map))))
+    }
+
+    // The objects and indexes of the targets are evaluated before the
+    // value, left to right, as they are for a single assignment.
+    fn destructure_assign(
+        &mut self,
+        targets: &[Expr],
+        equals: &Token,
+        value: &Expr,
+    ) -> Result<Value, Diagnostic> {
+        let places = targets
+            .iter()
+            .map(|target| self.place(target))
+            .collect::<Result<Vec<_>, _>>()?;
+        let value = self.evaluate(value)?;
+        let elements = unpack(equals, &value, targets.len())?;
+        for ((target, place), element) in targets.iter().zip(places).zip(elements) {
+            self.store(target, place, element)?;
+        }
+        Ok(value)
+    }
+
+    // `target op= value`, or an update when there is no value and the
+    // operand is 1. Returns the target's value from before and after.
+    fn compound_assign(
+        &mut self,
+        target: &Expr,
+        operator: &Token,
+        value: Option<&Expr>,
+    ) -> Result<(Value, Value), Diagnostic> {
+        let place = self.place(target)?;
+        let old = self.load(target, &place)?;
+        let operand = match value {
+            Some(value) => self.evaluate(value)?,
+            None => Value::Number(1.0),
+        };
+        let new = self.operate(old.clone(), operator, operand)?;
+        self.store(target, place, new.clone())?;
+        Ok((old, new))
+    }
+    fn place(&mut self, target: &Expr) -> Result<Place, Diagnostic> {
+        Ok(match target {
+            Expr::Get { object, .. } => Place::Field(self.evaluate(object)?),
+            Expr::Index { object, index, .. } => {
+                Place::Element(self.evaluate(object)?, self.evaluate(index)?)
+            }
+            _ => Place::Variable,
+        })
+    }
+    fn load(&mut self, target: &Expr, place: &Place) -> Result<Value, Diagnostic> {
+        match (target, place) {
+            (Expr::Variable { id, name }, _) => self.look_up_variable(*id, name),
+            (Expr::Get { name, .. }, Place::Field(object)) => self.property(object.clone(), name),
+            (Expr::Index { bracket, .. }, Place::Element(object, index)) => {
+                get_index(object.clone(), bracket, index)
+            }
+            _ => unreachable!("the parser only accepts assignable targets"),
+        }
+    }
+    fn store(&mut self, target: &Expr, place: Place, value: Value) -> Result<(), Diagnostic> {
+        match (target, place) {
+            (Expr::Variable { id, name }, _) => self.assign(*id, name, value),
+            (Expr::Get { name, .. }, Place::Field(object)) => set_field(object, name, value),
+            (Expr::Index { bracket, .. }, Place::Element(object, index)) => {
+                set_index(object, bracket, &index, value)
+            }
+            _ => unreachable!("the parser only accepts assignable targets"),
+        }
+    }
+
+    fn super_method(
+        &mut self,
+        id: usize,
+        keyword: &Token,
+        method: &Token,
+    ) -> Result<Value, Diagnostic> {
+        // The resolver always places `this` one scope inside `super`.
+        let distance = self.locals.get(&id).copied().unwrap_or(1).max(1);
+        let superclass = self.environment.borrow().get_at(distance, "super");
+        let object = self.environment.borrow().get_at(distance - 1, "this");
+        // In a class method `this` is the class, and `super` finds
+        // the superclass's class methods.
+        let found = match (superclass, object) {
+            (Some(Value::Class(superclass)), Some(object @ Value::Instance(_))) => {
+                superclass.find_method(&lexeme(method)).map(|m| (m, object))
+            }
+            (Some(Value::Class(superclass)), Some(object @ Value::Class(_))) => superclass
+                .find_class_method(&lexeme(method))
+                .map(|m| (m, object)),
+            _ => return Err(Diagnostic::at(keyword, "Invalid use of 'super'.")),
+        };
+        match found {
+            Some((found, object)) => {
+                self.run_getter(Value::Function(Rc::new(found.bind(object))), method)
+            }
+            None => Err(Diagnostic::at(
+                method,
+                &format!("Undefined property '{}'.", lexeme(method)),
+            )),
+        }
+    }
+
+    fn unary(&mut self, operator: &Token, right: &Expr) -> Result<Value, Diagnostic> {
+        let right = self.evaluate(right)?;
+        match operator.tty {
+            TokenType::Bang => Ok(Value::Bool(!right.is_truthy())),
+            TokenType::Minus => match right {
+                Value::Number(n) => Ok(Value::Number(-n)),
+                _ => match self.overload(&right, "negate", Vec::new(), operator)? {
+                    Some(result) => Ok(result),
+                    None => Err(Diagnostic::at(operator, "Operand must be a number.")),
+                },
+            },
+            // The parser's desugaring of `${..}` in string literals.
+            TokenType::Interpolation => Ok(Value::Str(self.stringify(right, operator)?)),
+            _ => Err(Diagnostic::at(operator, "Unknown unary operator.")),
+        }
+    }
+    // What a call calls, or None if that is `object?.name` and the object
+    // is nil, when the call is skipped.
+    fn evaluate_callee(&mut self, callee: &Expr) -> Result<Option<Value>, Diagnostic> {
+        match callee {
+            Expr::Get {
+                object,
+                name,
+                safe: true,
+            } => match self.evaluate(object)? {
+                Value::Nil => Ok(None),
+                object => self.property(object, name).map(Some),
+            },
+            _ => self.evaluate(callee).map(Some),
+        }
+    }
+    fn assign(&mut self, id: usize, name: &Token, value: Value) -> Result<(), Diagnostic> {
+        let assigned = match self.locals.get(&id) {
+            Some(distance) => {
+                self.environment
+                    .borrow_mut()
+                    .assign_at(*distance, &lexeme(name), value)
+            }
+            None => self.globals.borrow_mut().assign(&lexeme(name), value),
+        };
+        if assigned {
+            Ok(())
+        } else {
+            Err(undefined_variable(name))
+        }
+    }
+    fn property(&mut self, object: Value, name: &Token) -> Result<Value, Diagnostic> {
+        let property = match object {
+            Value::Instance(instance) => get_property(&instance, name)?,
+            Value::Class(class) => get_class_property(&class, name)?,
+            _ => return Err(Diagnostic::at(name, "Only instances have properties.")),
+        };
+        self.run_getter(property, name)
+    }
+    fn evaluate_arguments(&mut self, arguments: &[Expr]) -> Result<Vec<Value>, Diagnostic> {
+        arguments
+            .iter()
+            .map(|argument| self.evaluate(argument))
+            .collect()
+    }
+    fn call_value(
+        &mut self,
+        callee: Value,
+        paren: &Token,
+        arguments: Vec<Value>,
+    ) -> Result<Value, Diagnostic> {
+        let callable: &dyn LoxCallable = match &callee {
+            Value::Function(function) => function.as_ref(),
+            Value::Native(native) => native.as_ref(),
+            Value::Class(class) => class,
+            _ => {
+                return Err(Diagnostic::at(
+                    paren,
+                    "Can only call functions and classes.",
+                ))
+            }
+        };
+        check_arity(paren, callable.arity(), arguments.len())?;
+        callable.call(self, paren, arguments)
+    }
+    // Runs `coroutine` until it yields or its function returns, and hands
+    // back the value yielded or returned. An error in the function ends the
+    // coroutine and is left in `failure` for `resume` to fail with.
+    pub(crate) fn resume(&mut self, coroutine: &Coroutine) -> Result<Value, String> {
+        let (mut function, path) = match coroutine.state.replace(CoroutineState::Running) {
+            CoroutineState::Suspended { function, path } => (function, path),
+            state => {
+                let message = match state {
+                    CoroutineState::Running => "Can't resume a running coroutine.",
+                    _ => "Can't resume a dead coroutine.",
+                };
+                coroutine.state.replace(state);
+                return Err(message.to_string());
+            }
+        };
+        if self.call_depth >= self.max_call_depth {
+            coroutine
+                .state
+                .replace(CoroutineState::Suspended { function, path });
+            return Err("Stack overflow.".to_string());
+        }
+        self.call_depth += 1;
+        let enclosing_coroutine = self.coroutine_depth.replace(self.call_depth);
+        let enclosing_globals = mem::replace(&mut self.globals, Rc::clone(&function.globals));
+        let enclosing_try_depth = mem::take(&mut self.try_depth);
+        let mut result = if path.is_empty() {
+            let environment = function.environment(Vec::new());
+            self.execute_call(&function.declaration, 0, environment)
+        } else {
+            self.resuming = path;
+            self.resume_block(&function.declaration.body)
+        };
+        while let Err(Unwind::TailCall {
+            function: callee,
+            arguments,
+        }) = result
+        {
+            function = callee;
+            self.globals = Rc::clone(&function.globals);
+            let supplied = arguments.len();
+            let environment = function.environment(arguments);
+            result = self.execute_call(&function.declaration, supplied, environment);
+        }
+        self.call_depth -= 1;
+        self.coroutine_depth = enclosing_coroutine;
+        self.globals = enclosing_globals;
+        self.try_depth = enclosing_try_depth;
+        let (state, result) = match result {
+            Err(Unwind::Yield(value)) => {
+                let path = mem::take(&mut self.suspended);
+                (CoroutineState::Suspended { function, path }, Ok(value))
+            }
+            Ok(()) => (CoroutineState::Dead, Ok(Value::Nil)),
+            Err(Unwind::Return(value)) => (CoroutineState::Dead, Ok(value)),
+            Err(Unwind::Error(e)) => {
+                let message = e.message.clone();
+                self.failure = Some((e, lexeme(&function.declaration.name)));
+                (CoroutineState::Dead, Err(message))
+            }
+            Err(Unwind::TailCall { .. }) => unreachable!("tail calls are made above"),
+        };
+        coroutine.state.replace(state);
+        result
+    }
+    fn look_up_variable(&self, id: usize, name: &Token) -> Result<Value, Diagnostic> {
+        let value = match self.locals.get(&id) {
+            Some(distance) => self.environment.borrow().get_at(*distance, &lexeme(name)),
+            None => self.globals.borrow().get(&lexeme(name)),
+        };
+        value.ok_or_else(|| undefined_variable(name))
+    }
+}
+
+// The method a class defines to overload a binary operator. `!=` is the
+// negation of `equals`.
+fn operator_method(tty: TokenType) -> Option<&'static str> {
+    match tty {
+        TokenType::Plus => Some("plus"),
+        TokenType::Minus => Some("minus"),
+        TokenType::Star => Some("times"),
+        TokenType::Slash => Some("divide"),
+        TokenType::Percent => Some("modulo"),
+        TokenType::EqualEqual | TokenType::BangEqual => Some("equals"),
+        _ => None,
+    }
+}
+
+fn binary(operator: &Token, left: Value, right: Value) -> Result<Value, Diagnostic> {
+    match operator.tty {
+        TokenType::EqualEqual => return Ok(Value::Bool(left.is_equal(&right))),
+        TokenType::BangEqual => return Ok(Value::Bool(!left.is_equal(&right))),
+        TokenType::Comma => return Ok(right),
+        TokenType::Plus => {
+            return match (left, right) {
+                (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
+                (Value::Str(a), Value::Str(b)) => Ok(Value::Str(a + &b)),
+                _ => Err(Diagnostic::at(
+                    operator,
+                    "Operands must be two numbers or two strings.",
+                )),
+            }
+        }
+        _ => {}
+    }
+    let (a, b) = match (left, right) {
+        (Value::Number(a), Value::Number(b)) => (a, b),
+        _ => return Err(Diagnostic::at(operator, "Operands must be numbers.")),
+    };
+    match operator.tty {
+        TokenType::Minus => Ok(Value::Number(a - b)),
+        TokenType::Slash => Ok(Value::Number(a / b)),
+        TokenType::Star => Ok(Value::Number(a * b)),
+        // Like C's fmod: the result takes the sign of the dividend.
+        TokenType::Percent => Ok(Value::Number(a % b)),
+        TokenType::StarStar => Ok(Value::Number(a.powf(b))),
+        TokenType::Greater => Ok(Value::Bool(a > b)),
+        TokenType::GreaterEqual => Ok(Value::Bool(a >= b)),
+        TokenType::Less => Ok(Value::Bool(a < b)),
+        TokenType::LessEqual => Ok(Value::Bool(a <= b)),
+        TokenType::DotDot | TokenType::DotDotEqual => Ok(Value::Range(Range {
+            start: a,
+            end: b,
+            inclusive: operator.tty == TokenType::DotDotEqual,
+        })),
+        _ => Err(Diagnostic::at(operator, "Unknown binary operator.")),
+    }
+}
+
+fn set_field(object: Value, name: &Token, value: Value) -> Result<(), Diagnostic> {
+    match object {
+        Value::Instance(instance) => instance.borrow_mut().fields.insert(lexeme(name), value),
+        Value::Class(class) => class.fields.borrow_mut().insert(lexeme(name), value),
+        _ => return Err(Diagnostic::at(name, "Only instances have fields.")),
+    };
+    Ok(())
+}
+
+fn get_index(object: Value, bracket: &Token, index: &Value) -> Result<Value, Diagnostic> {
+    match object {
+        Value::List(list) => {
+            let list = list.borrow();
+            let i = list_index(bracket, index, list.len())?;
+            Ok(list[i].clone())
+        }
+        Value::Map(map) => {
+            let key = map_key(bracket, index)?;
+            Ok(map.borrow().get(&key).cloned().unwrap_or(Value::Nil))
+        }
+        _ => Err(Diagnostic::at(
+            bracket,
+            "Only lists and maps can be indexed.",
+        )),
+    }
+}
+
+fn set_index(
+    object: Value,
+    bracket: &Token,
+    index: &Value,
+    value: Value,
+) -> Result<(), Diagnostic> {
+    match object {
+        Value::List(list) => {
+            let mut list = list.borrow_mut();
+            let i = list_index(bracket, index, list.len())?;
+            list[i] = value;
+        }
+        Value::Map(map) => {
+            let key = map_key(bracket, index)?;
+            map.borrow_mut().insert(key, value);
+        }
+        _ => {
+            return Err(Diagnostic::at(
+                bracket,
+                "Only lists and maps can be indexed.",
+            ))
+        }
+    }
+    Ok(())
+}
+
+// The elements of the list `value`, which must have exactly `count`.
+fn unpack(equals: &Token, value: &Value, count: usize) -> Result<Vec<Value>, Diagnostic> {
+    let Value::List(list) = value else {
+        return Err(Diagnostic::at(equals, "Only lists can be destructured."));
+    };
+    let list = list.borrow();
+    if list.len() != count {
+        let message = format!(
+            "Expected {} values to unpack but got {}.",
+            count,
+            list.len()
+        );
+        return Err(Diagnostic::at(equals, &message));
+    }
+    Ok(list.clone())
+}
+
+fn list_index(bracket: &Token, index: &Value, len: usize) -> Result<usize, Diagnostic> {
+    let Value::Number(n) = index else {
+        return Err(Diagnostic::at(bracket, "List index must be a number."));
+    };
+    if n.fract() != 0.0 {
+        return Err(Diagnostic::at(bracket, "List index must be an integer."));
+    }
+    if *n < 0.0 || *n >= len as f64 {
+        return Err(Diagnostic::at(bracket, "List index out of range."));
+    }
+    Ok(*n as usize)
+}
+
+fn map_key(token: &Token, key: &Value) -> Result<MapKey, Diagnostic> {
+    MapKey::from_value(key)
+        .ok_or_else(|| Diagnostic::at(token, "Map keys must be nil, booleans, numbers or strings."))
+}
+
+fn check_arity(paren: &Token, arity: RangeInclusive<usize>, got: usize) -> Result<(), Diagnostic> {
+    if !arity.contains(&got) {
+        return Err(Diagnostic::at(paren, &arity_mismatch(&arity, got)));
+    }
+    Ok(())
+}
+
+pub(crate) fn arity_mismatch(arity: &RangeInclusive<usize>, got: usize) -> String {
+    if arity.start() == arity.end() {
+        format!("Expected {} arguments but got {}.", arity.start(), got)
+    } else {
+        format!(
+            "Expected {} to {} arguments but got {}.",
+            arity.start(),
+            arity.end(),
+            got
+        )
+    }
+}
+
+// Fields shadow methods, so they are checked first.
+fn get_property(instance: &Rc<RefCell<LoxInstance>>, name: &Token) -> Result<Value, Diagnostic> {
+    let key = lexeme(name);
+    if let Some(value) = instance.borrow().fields.get(&key) {
+        return Ok(value.clone());
+    }
+    let method = instance.borrow().class.find_method(&key);
+    match method {
+        Some(method) => Ok(Value::Function(Rc::new(
+            method.bind(Value::Instance(Rc::clone(instance))),
+        ))),
+        None => Err(Diagnostic::at(
+            name,
+            &format!("Undefined property '{}'.", key),
+        )),
+    }
+}
+
+fn get_class_property(class: &Rc<LoxClass>, name: &Token) -> Result<Value, Diagnostic> {
+    let key = lexeme(name);
+    if let Some(value) = class.fields.borrow().get(&key) {
+        return Ok(value.clone());
+    }
+    match class.find_class_method(&key) {
+        Some(method) => Ok(Value::Function(Rc::new(
+            method.bind(Value::Class(Rc::clone(class))),
+        ))),
+        None => Err(Diagnostic::at(
+            name,
+            &format!("Undefined property '{}'.", key),
+        )),
+    }
+}
+
+// A token for where the interpreter calls a method the source doesn't,
+// such as `toString` for `print`, to locate errors in the call.
+fn synthetic_token(lexeme: &str, span: Span) -> Token {
+    Token {
+        tty: TokenType::Identifier,
+        lexeme: Arc::from(lexeme),
+        literal: None,
+        span,
+    }
+}
+
+fn lexeme(token: &Token) -> String {
+    token.lexeme.to_string()
+}
+
+fn undefined_variable(name: &Token) -> Diagnostic {
+    Diagnostic::at(name, &format!("Undefined variable '{}'.", lexeme(name)))
+}
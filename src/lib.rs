@@ -1 +1,30 @@
+pub mod ast_json;
+pub mod ast_printer;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod display;
+pub mod environment;
+pub mod error;
+#[cfg(all(feature = "ffi", not(target_arch = "wasm32")))]
+mod ffi;
+pub mod formatter;
+pub mod highlight;
+pub mod interpreter;
+mod json;
+pub mod limits;
+pub mod lint;
+mod lox;
+mod modules;
+pub mod natives;
+pub mod parser;
+pub mod profiler;
+pub mod resolver;
 pub mod scanner;
+pub mod span;
+pub mod typecheck;
+pub mod vm;
+#[cfg(target_arch = "wasm32")]
+mod wasm;
+
+pub use lox::Lox;
@@ -0,0 +1,62 @@
+use std::time::{Duration, Instant};
+
+// Bounds on how much work one run of a program may do, for hosts running
+// code they don't trust. Fuel is spent on loop iterations and function
+// calls, the only ways a program can run for longer than it is long. The
+// VM also spends a step jumping back to a `for` loop's increment, so the
+// same program may use a little more fuel there than in the tree-walker.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    pub fuel: Option<u64>,
+    pub timeout: Option<Duration>,
+}
+
+// Reading the clock costs more than a loop iteration, so it is only read
+// once per this many steps.
+const CLOCK_INTERVAL: u32 = 1024;
+
+// What remains of the limits during a run.
+#[derive(Debug, Default)]
+pub(crate) struct Budget {
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
+    until_clock: u32,
+    exhausted: bool,
+}
+impl Budget {
+    pub(crate) fn start(limits: Limits) -> Budget {
+        Budget {
+            fuel: limits.fuel,
+            deadline: limits.timeout.map(|timeout| Instant::now() + timeout),
+            until_clock: 0,
+            exhausted: false,
+        }
+    }
+    // Spends one step, failing with the message to report once a limit is
+    // reached.
+    pub(crate) fn step(&mut self) -> Result<(), &'static str> {
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel == 0 {
+                self.exhausted = true;
+                return Err("Execution ran out of fuel.");
+            }
+            *fuel -= 1;
+        }
+        if let Some(deadline) = self.deadline {
+            if self.until_clock == 0 {
+                self.until_clock = CLOCK_INTERVAL;
+                if Instant::now() >= deadline {
+                    self.exhausted = true;
+                    return Err("Execution timed out.");
+                }
+            }
+            self.until_clock -= 1;
+        }
+        Ok(())
+    }
+    // Whether the run was stopped by a limit rather than an error in the
+    // program.
+    pub(crate) fn exhausted(&self) -> bool {
+        self.exhausted
+    }
+}
@@ -0,0 +1,398 @@
+use crate::ast_json::stmt_span;
+use crate::error::{Diagnostic, Severity};
+use crate::parser::{Expr, FunctionDecl, Stmt};
+use crate::scanner::Token;
+
+// Finds code that runs but is probably a mistake: locals that are never
+// read, locals hiding another in the same function, statements after a
+// `return` or `throw`, and variables assigned to themselves. Meant for a
+// program the resolver has accepted. Locals whose names start with `_`
+// are allowed to go unused.
+pub fn lint(statements: &[Stmt]) -> Vec<Diagnostic> {
+    let mut linter = Linter {
+        scopes: Vec::new(),
+        functions: 0,
+        function: 0,
+        warnings: Vec::new(),
+    };
+    linter.statements(statements);
+    // Unused locals are found when their scope ends, after what follows
+    // them.
+    linter.warnings.sort_by_key(|warning| warning.span.start);
+    linter.warnings
+}
+
+struct Local {
+    name: Token,
+    used: bool,
+}
+
+struct Scope {
+    locals: Vec<Local>,
+    // Which function body the scope belongs to, numbered in the order
+    // they are entered.
+    function: usize,
+}
+
+struct Linter {
+    // Innermost last. Globals are not tracked.
+    scopes: Vec<Scope>,
+    functions: usize,
+    function: usize,
+    warnings: Vec<Diagnostic>,
+}
+impl Linter {
+    fn statements(&mut self, statements: &[Stmt]) {
+        let mut terminated = false;
+        for statement in statements {
+            if terminated {
+                self.warn(Diagnostic::new("Unreachable code.", stmt_span(statement)));
+                terminated = false;
+            } else {
+                terminated = terminates(statement);
+            }
+            self.stmt(statement);
+        }
+    }
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Block(statements) => self.block(statements, &[]),
+            Stmt::DoWhile { body, condition } => {
+                self.stmt(body);
+                self.expr(condition);
+            }
+            Stmt::Destructure {
+                names, initializer, ..
+            } => {
+                self.expr(initializer);
+                for name in names {
+                    self.declare(name);
+                }
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                traits,
+                methods,
+                class_methods,
+            } => {
+                self.declare(name);
+                for parent in superclass.iter().chain(traits) {
+                    self.expr(parent);
+                }
+                for method in methods.iter().chain(class_methods) {
+                    self.function(method);
+                }
+            }
+            Stmt::Expression(expr) | Stmt::Print(expr) | Stmt::Throw { value: expr, .. } => {
+                self.expr(expr)
+            }
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+            } => {
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.stmt(initializer);
+                }
+                self.expr(condition);
+                self.stmt(body);
+                if let Some(increment) = increment {
+                    self.expr(increment);
+                }
+                self.end_scope();
+            }
+            Stmt::ForEach {
+                name,
+                iterable,
+                body,
+                ..
+            } => {
+                self.expr(iterable);
+                self.begin_scope();
+                self.declare(name);
+                self.stmt(body);
+                self.end_scope();
+            }
+            Stmt::Function(declaration) => {
+                self.declare(&declaration.name);
+                self.function(declaration);
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expr(condition);
+                self.stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.stmt(else_branch);
+                }
+            }
+            Stmt::Import { .. } => {}
+            Stmt::Return { value, .. } => {
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+            }
+            Stmt::Switch {
+                subject,
+                cases,
+                default,
+            } => {
+                self.expr(subject);
+                for (value, body) in cases {
+                    self.expr(value);
+                    self.block(body, &[]);
+                }
+                if let Some(default) = default {
+                    self.block(default, &[]);
+                }
+            }
+            Stmt::Trait { name, methods } => {
+                self.declare(name);
+                for method in methods {
+                    self.function(method);
+                }
+            }
+            Stmt::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                self.block(body, &[]);
+                if let Some((name, handler)) = catch {
+                    self.block(handler, std::slice::from_ref(name));
+                }
+                if let Some(finally) = finally {
+                    self.block(finally, &[]);
+                }
+            }
+            Stmt::Var {
+                name, initializer, ..
+            } => {
+                if let Some(initializer) = initializer {
+                    self.expr(initializer);
+                }
+                self.declare(name);
+            }
+            Stmt::While { condition, body } => {
+                self.expr(condition);
+                self.stmt(body);
+            }
+            Stmt::Yield { value, .. } => {
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+            }
+        }
+    }
+    // A block whose scope starts with `names` already declared.
+    fn block(&mut self, statements: &[Stmt], names: &[Token]) {
+        self.begin_scope();
+        for name in names {
+            self.declare(name);
+        }
+        self.statements(statements);
+        self.end_scope();
+    }
+    // Parameters are part of a function's signature, so they may go
+    // unused.
+    fn function(&mut self, declaration: &FunctionDecl) {
+        let enclosing = self.function;
+        self.functions += 1;
+        self.function = self.functions;
+        self.begin_scope();
+        for (param, default) in declaration.params.iter().zip(&declaration.defaults) {
+            if let Some(default) = default {
+                self.expr(default);
+            }
+            self.declare(param);
+            self.read(param);
+        }
+        self.statements(&declaration.body);
+        self.end_scope();
+        self.function = enclosing;
+    }
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Assign { name, value, .. } => {
+                if let Expr::Variable { name: source, .. } = &**value {
+                    if source.lexeme == name.lexeme {
+                        let message = format!("'{}' is assigned to itself.", name.lexeme);
+                        self.warn(Diagnostic::at_token(name, &message));
+                    }
+                }
+                self.expr(value);
+            }
+            Expr::Compound { target, value, .. } => {
+                self.expr(target);
+                self.expr(value);
+            }
+            Expr::Destructure { targets, value, .. } => {
+                self.expr(value);
+                // Storing to a variable doesn't read it.
+                for target in targets {
+                    if !matches!(target, Expr::Variable { .. }) {
+                        self.expr(target);
+                    }
+                }
+            }
+            Expr::Binary { left, right, .. } | Expr::Logical { left, right, .. } => {
+                self.expr(left);
+                self.expr(right);
+            }
+            Expr::Call {
+                callee, arguments, ..
+            } => {
+                self.expr(callee);
+                for argument in arguments {
+                    self.expr(argument);
+                }
+            }
+            Expr::Conditional {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expr(condition);
+                self.expr(then_branch);
+                self.expr(else_branch);
+            }
+            Expr::Get { object, .. } => self.expr(object),
+            Expr::Grouping(expr) => self.expr(expr),
+            Expr::Index { object, index, .. } => {
+                self.expr(object);
+                self.expr(index);
+            }
+            Expr::IndexSet {
+                object,
+                index,
+                value,
+                ..
+            } => {
+                self.expr(object);
+                self.expr(index);
+                self.expr(value);
+            }
+            Expr::Lambda(declaration) => self.function(declaration),
+            Expr::List(elements) => {
+                for element in elements {
+                    self.expr(element);
+                }
+            }
+            Expr::Map { entries, .. } => {
+                for (key, value) in entries {
+                    self.expr(key);
+                    self.expr(value);
+                }
+            }
+            Expr::Literal { .. } | Expr::Super { .. } | Expr::This { .. } => {}
+            Expr::Set {
+                object,
+                name,
+                value,
+            } => {
+                if let Expr::Get {
+                    object: source,
+                    name: property,
+                    ..
+                } = &**value
+                {
+                    if property.lexeme == name.lexeme && same_object(object, source) {
+                        let message = format!("'{}' is assigned to itself.", name.lexeme);
+                        self.warn(Diagnostic::at_token(name, &message));
+                    }
+                }
+                self.expr(object);
+                self.expr(value);
+            }
+            Expr::Unary { right, .. } => self.expr(right),
+            Expr::Update { target, .. } => self.expr(target),
+            Expr::Variable { name, .. } => self.read(name),
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(Scope {
+            locals: Vec::new(),
+            function: self.function,
+        });
+    }
+    fn end_scope(&mut self) {
+        let Some(scope) = self.scopes.pop() else {
+            return;
+        };
+        for local in scope.locals {
+            if !local.used && !local.name.lexeme.starts_with('_') {
+                let message = format!("Local variable '{}' is never used.", local.name.lexeme);
+                self.warn(Diagnostic::at_token(&local.name, &message));
+            }
+        }
+    }
+    fn declare(&mut self, name: &Token) {
+        let Some((scope, enclosing)) = self.scopes.split_last_mut() else {
+            return;
+        };
+        let shadows = enclosing.iter().any(|outer| {
+            outer.function == scope.function
+                && outer
+                    .locals
+                    .iter()
+                    .any(|local| local.name.lexeme == name.lexeme)
+        });
+        scope.locals.push(Local {
+            name: name.clone(),
+            used: false,
+        });
+        if shadows {
+            let message = format!(
+                "'{}' shadows a local variable of the same name in an enclosing block.",
+                name.lexeme
+            );
+            self.warn(Diagnostic::at_token(name, &message));
+        }
+    }
+    fn read(&mut self, name: &Token) {
+        let found = self.scopes.iter_mut().rev().find_map(|scope| {
+            scope
+                .locals
+                .iter_mut()
+                .rev()
+                .find(|local| local.name.lexeme == name.lexeme)
+        });
+        if let Some(local) = found {
+            local.used = true;
+        }
+    }
+    fn warn(&mut self, mut diagnostic: Diagnostic) {
+        diagnostic.severity = Severity::Warning;
+        self.warnings.push(diagnostic);
+    }
+}
+
+// Whether running `stmt` never carries on to the statement after it.
+fn terminates(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Return { .. } | Stmt::Throw { .. } => true,
+        Stmt::Block(statements) => statements.iter().any(terminates),
+        Stmt::If {
+            then_branch,
+            else_branch: Some(else_branch),
+            ..
+        } => terminates(then_branch) && terminates(else_branch),
+        _ => false,
+    }
+}
+
+// Whether two expressions certainly name the same object: the same
+// variable, or both `this`.
+fn same_object(a: &Expr, b: &Expr) -> bool {
+    match (a, b) {
+        (Expr::Variable { name: a, .. }, Expr::Variable { name: b, .. }) => a.lexeme == b.lexeme,
+        (Expr::This { .. }, Expr::This { .. }) => true,
+        _ => false,
+    }
+}
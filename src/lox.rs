@@ -0,0 +1,201 @@
+use crate::error::{Diagnostic, LoxError};
+use crate::interpreter::{DebugHook, Interpreter, NativeFunction, Snapshot, Value};
+use crate::limits::Limits;
+use crate::lint;
+use crate::natives;
+use crate::parser::{Parser, Stmt};
+use crate::resolver::Resolver;
+use crate::scanner::{Scanner, Token};
+use crate::span::{SourceMap, Span};
+use crate::typecheck;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+// The whole scan, parse, resolve and interpret pipeline behind one handle,
+// for programs that embed Lox. Globals persist from one `run` to the next.
+pub struct Lox {
+    interpreter: Interpreter,
+}
+impl Default for Lox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Lox {
+    pub fn new() -> Lox {
+        Lox {
+            interpreter: Interpreter::new(),
+        }
+    }
+    // Runs `source` as a program. The result is the value of its final
+    // statement if that is an expression statement, and nil otherwise.
+    // A copy of `source` is kept for `source_map` until `release_sources`,
+    // so a host running many programs on one `Lox` should call that now
+    // and then.
+    pub fn run(&mut self, source: &str) -> Result<Value, LoxError> {
+        let base = self.interpreter.sources().add(None, source);
+        let statements = Lox::parse_at(source, base)?;
+        self.execute(&statements)
+    }
+    // Reports every compile error in `source` without running it, and
+    // returns its warnings.
+    pub fn check(&mut self, source: &str) -> Result<Vec<Diagnostic>, LoxError> {
+        let statements = Lox::parse(source)?;
+        self.resolve(&statements)?;
+        Ok(lint::lint(&statements))
+    }
+    // Like `check`, but also checks the program against its type
+    // annotations.
+    pub fn check_types(&mut self, source: &str) -> Result<Vec<Diagnostic>, LoxError> {
+        let statements = Lox::parse(source)?;
+        self.resolve(&statements)?;
+        typecheck::check(&statements).map_err(LoxError::Type)?;
+        Ok(lint::lint(&statements))
+    }
+    pub fn parse(source: &str) -> Result<Vec<Stmt>, LoxError> {
+        Lox::parse_at(source, 0)
+    }
+    // Parses `source` with spans starting from `base`, where `Sources`
+    // placed it.
+    pub(crate) fn parse_at(source: &str, base: usize) -> Result<Vec<Stmt>, LoxError> {
+        let tokens = scan(source, base)?;
+        Parser::new(tokens).parse().map_err(LoxError::Parse)
+    }
+    // Runs a line typed at the prompt. Input that fails to parse as
+    // statements but is a lone expression, like `1 + 2`, is evaluated and
+    // its value returned so the REPL can echo it. Errors are located in
+    // `repl`, whose lines are numbered across the whole session.
+    pub fn run_line(&mut self, source: &str) -> Result<Option<Value>, LoxError> {
+        let base = self.interpreter.sources().append("repl", source);
+        let tokens = scan(source, base)?;
+        let errors = match Parser::new(tokens.clone()).parse() {
+            Ok(statements) => return self.execute(&statements).map(|_| None),
+            Err(errors) => errors,
+        };
+        match Parser::new(tokens).parse_expression() {
+            Ok(expr) => self.execute(&[Stmt::Expression(expr)]).map(Some),
+            Err(_) => Err(LoxError::Parse(errors)),
+        }
+    }
+    // How many calls may be nested before one fails with a stack overflow.
+    // The default, `DEFAULT_MAX_CALL_DEPTH`, fits the Rust stack of a spawned
+    // thread; a higher limit needs a thread with a bigger one.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.interpreter.set_max_call_depth(depth)
+    }
+    // Bounds every later `run` by fuel, time or both. A run that exceeds
+    // them fails with `LoxError::Timeout`.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.interpreter.set_limits(limits)
+    }
+    // Reports every later statement to `hook` before it runs.
+    pub fn set_debug_hook(&mut self, hook: impl DebugHook + 'static) {
+        self.interpreter.set_debug_hook(Box::new(hook))
+    }
+    // Sends what the program prints to `output` instead of stdout.
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.interpreter.set_output(Box::new(output))
+    }
+    // Sends what `eprint` prints to `output` instead of stderr.
+    pub fn set_error_output(&mut self, output: impl Write + 'static) {
+        self.interpreter.set_error_output(Box::new(output))
+    }
+    // Defines `readLine`, `readFile`, `writeFile` and `eprint`. They are
+    // left out by default so that scripts can't touch the host's files or
+    // streams unless it allows them to.
+    pub fn allow_io(&mut self) {
+        for native in natives::io_natives() {
+            let name = native.name().to_string();
+            self.set_global(&name, Value::Native(Rc::new(native)));
+        }
+    }
+    // Lets `import` load modules from the module root and the directories
+    // under it. Left out by default like `allow_io`, since it reads files.
+    pub fn allow_imports(&mut self) {
+        self.interpreter.allow_imports()
+    }
+    // The directory `import` paths are relative to. Defaults to the
+    // current directory.
+    pub fn set_module_root(&mut self, root: impl Into<PathBuf>) {
+        self.interpreter.set_module_root(root.into())
+    }
+    // Roots imports at the directory of `script`, the file being run, and
+    // reports an import of it as a cycle.
+    pub fn set_entry_script(&mut self, script: impl AsRef<Path>) {
+        self.interpreter.set_entry_script(script.as_ref())
+    }
+    // Locates the spans of errors from any source run so far. Those from
+    // `run_line` are in `repl` and from imported modules in their path.
+    // Every source is held for this, so they add up over a long session.
+    pub fn source_map(&self) -> SourceMap<'_> {
+        self.interpreter.source_map()
+    }
+    // Frees every source kept for `source_map` so far. An error later met
+    // in code from one of them is placed at its first line, without the
+    // line's text.
+    pub fn release_sources(&mut self) {
+        self.interpreter.sources().release()
+    }
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.interpreter.get_global(name)
+    }
+    // Every global variable, sorted by name.
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        self.interpreter.globals()
+    }
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.interpreter.define_global(name, value)
+    }
+    // Saves the globals, so that whatever later runs define or assign can
+    // be undone by `restore`.
+    pub fn snapshot(&self) -> Snapshot {
+        self.interpreter.snapshot()
+    }
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.interpreter.restore(snapshot)
+    }
+    // Makes `function` callable from Lox as the global `name`. Calls with
+    // the wrong number of arguments are rejected before it runs, and an
+    // `Err` message becomes a runtime error at the call site.
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(Vec<Value>) -> Result<Value, String> + 'static,
+    ) {
+        let native = NativeFunction::new(name, arity, move |_, args| function(args));
+        self.set_global(name, Value::Native(Rc::new(native)));
+    }
+    fn resolve(&mut self, statements: &[Stmt]) -> Result<(), LoxError> {
+        Resolver::new(&mut self.interpreter)
+            .resolve(statements)
+            .map_err(|e| LoxError::Resolve(vec![e]))
+    }
+    fn execute(&mut self, statements: &[Stmt]) -> Result<Value, LoxError> {
+        self.resolve(statements)?;
+        self.interpreter.preload(statements);
+        self.interpreter.interpret(statements).map_err(|e| {
+            if self.interpreter.exhausted_limits() {
+                LoxError::Timeout(e)
+            } else {
+                LoxError::Runtime(e)
+            }
+        })
+    }
+}
+
+fn scan(source: &str, base: usize) -> Result<Vec<Token>, LoxError> {
+    let mut scanner = Scanner::new();
+    scanner.scan_tokens(source.to_string());
+    let shift = |span: &mut Span| *span = Span::new(span.start + base, span.end + base);
+    if scanner.errors.is_empty() {
+        let mut tokens = scanner.tokens;
+        tokens.iter_mut().for_each(|token| shift(&mut token.span));
+        Ok(tokens)
+    } else {
+        let mut errors = scanner.errors;
+        errors.iter_mut().for_each(|error| shift(&mut error.span));
+        Err(LoxError::Scan(errors))
+    }
+}
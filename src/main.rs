@@ -1,22 +1,733 @@
+mod bench;
+mod debug_prompt;
+mod paste;
+mod test_runner;
+
+use debug_prompt::DebugPrompt;
+use paste::BracketedPaste;
 use std::env;
-use std::io::{self, BufRead, Write};
+use std::fs;
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
 use std::process;
-use tree::scanner::{Scanner, ScannerError, Token};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use tree::ast_json;
+use tree::ast_printer;
+use tree::coverage::Coverage;
+use tree::dap;
+use tree::error::{Diagnostic, LoxError, Severity};
+use tree::formatter;
+use tree::highlight;
+use tree::interpreter::{self, Snapshot, DEFAULT_MAX_CALL_DEPTH};
+use tree::limits::Limits;
+use tree::parser::{Parser, Stmt};
+use tree::profiler::Profiler;
+use tree::scanner::{self, Scanner};
+use tree::span::SourceMap;
+use tree::vm::Vm;
+use tree::Lox;
+
+const USAGE: &str = "Usage: jlox [script | -]
+       jlox repl
+       jlox run <script> [--backend tree|vm] [--disassemble] [--trace]
+                    [--stress-gc] [--gc-threshold <bytes>] [--opt|--no-opt]
+                    [--max-depth <calls>] [--fuel <steps>] [--timeout <ms>]
+                    [--allow-io] [--deny-warnings]
+                    [--coverage <file>] [--coverage-format lcov|annotate]
+                    [--profile] [--cache]
+       jlox tokenize <script>
+       jlox parse <script> [--format sexpr|tree|json|debug]
+       jlox check <script> [--types] [--deny-warnings]
+       jlox debug <script> [--break <line>]...
+       jlox dap [--port <port>]
+       jlox fmt [--check] <path>...
+       jlox highlight <script>
+       jlox bench <path>... [--iterations <n>] [--backend tree|vm|both]
+       jlox test <dir> [--backend tree|vm] [--stress-gc]
+Any command takes --error-format human|json.";
+
+// The Rust stack one Lox call needs in a debug build of the tree-walk
+// interpreter, with room to spare. Calls that nest expressions deeply
+// enough to need more are stopped by the interpreter's stack check.
+const STACK_PER_CALL: usize = 64 * 1024;
+
+// Runs `f` on a thread with enough stack for `depth` nested Lox calls, so
+// hitting the call depth limit is reported as a stack overflow rather than
+// crashing the process. Interpreters on it also know the stack's size, for
+// code that nests expressions deeply enough to run out first.
+fn with_stack_for<T: Send>(depth: usize, f: impl FnOnce() -> T + Send) -> T {
+    let size = depth.saturating_mul(STACK_PER_CALL).max(8 * 1024 * 1024);
+    thread::scope(|scope| {
+        let thread = thread::Builder::new()
+            .stack_size(size)
+            .spawn_scoped(scope, || {
+                interpreter::set_stack_size(size);
+                f()
+            })
+            .unwrap_or_else(|e| {
+                eprintln!("Could not allocate a {} byte stack: {}", size, e);
+                process::exit(70);
+            });
+        // A panic has already been reported by the hook; exit as Rust would.
+        thread.join().unwrap_or_else(|_| process::exit(101))
+    })
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() > 1 {
-        eprintln!("Usage: jlox [script]");
+    with_stack_for(DEFAULT_MAX_CALL_DEPTH, dispatch);
+}
+
+fn dispatch() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args = error_format(args.iter().map(String::as_str).collect());
+    match args[..] {
+        // Piped input is a program, not an interactive session, unless
+        // `repl` asks for one.
+        [] if !io::stdin().is_terminal() => run_file("-"),
+        [] | ["repl"] => run_prompt().unwrap(),
+        ["run", path, ref options @ ..] => run_with_options(path, options),
+        ["tokenize", path] => tokenize_file(path),
+        ["parse", path] => parse_file(path, "sexpr"),
+        ["parse", path, "--format", format] => parse_file(path, format),
+        ["check", path, ref options @ ..] => check_file(path, options),
+        ["debug", path, ref options @ ..] => debug_file(path, options),
+        ["dap"] => serve_dap(DEFAULT_DAP_PORT),
+        ["dap", "--port", port] => match port.parse() {
+            Ok(port) => serve_dap(port),
+            Err(_) => {
+                eprintln!("{}", USAGE);
+                process::exit(64);
+            }
+        },
+        ["fmt", ref options @ ..] => format_files(options),
+        ["bench", ref options @ ..] => bench_files(options),
+        ["highlight", path] => print!("{}", highlight::to_html(&read_source(path))),
+        ["test" | "--test", dir, ref options @ ..] => test_files(dir, options),
+        [path] if path == "-" || !path.starts_with('-') => run_file(path),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(64);
+        }
+    }
+}
+
+// Takes `--error-format=human|json`, which may come anywhere among the
+// arguments, out of them.
+fn error_format(args: Vec<&str>) -> Vec<&str> {
+    let mut rest = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let format = match arg {
+            "--error-format" => args.next(),
+            _ => match arg.strip_prefix("--error-format=") {
+                Some(format) => Some(format),
+                None => {
+                    rest.push(arg);
+                    continue;
+                }
+            },
+        };
+        match format {
+            Some("human") => JSON_ERRORS.store(false, Ordering::Relaxed),
+            Some("json") => JSON_ERRORS.store(true, Ordering::Relaxed),
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(64);
+            }
+        }
+    }
+    rest
+}
+
+// A path of `-` reads the program from stdin.
+fn read_source(path: &str) -> String {
+    let source = if path == "-" {
+        io::read_to_string(io::stdin())
+    } else {
+        fs::read_to_string(path)
+    };
+    source.unwrap_or_else(|e| {
+        eprintln!("Could not read {}: {}", path, e);
+        process::exit(66);
+    })
+}
+
+// Set by `--error-format=json`, for editors and CI that read diagnostics
+// from stderr rather than people.
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+fn report(e: LoxError, path: &str, source: &str) -> ! {
+    report_with(e, path, &SourceMap::new(source))
+}
+
+// Like `report`, for errors from running a program, which can be in any
+// module it imported.
+fn report_with(e: LoxError, path: &str, map: &SourceMap) -> ! {
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+        eprintln!("{}", e.to_json_with(map, path));
+    } else {
+        eprintln!("{}", e.render_with(map));
+    }
+    process::exit(e.exit_code());
+}
+
+// Imports are relative to the script's directory, or the current one for
+// stdin.
+fn new_lox(path: &str) -> Lox {
+    let mut lox = Lox::new();
+    lox.allow_imports();
+    if path != "-" {
+        lox.set_entry_script(path);
+    }
+    lox
+}
+
+fn run_file(path: &str) {
+    let source = read_source(path);
+    let mut lox = new_lox(path);
+    if let Err(e) = lox.run(&source) {
+        report_with(e, path, &lox.source_map());
+    }
+}
+
+fn run_file_with(
+    path: &str,
+    source: &str,
+    depth: usize,
+    limits: Limits,
+    allow_io: bool,
+    coverage: Option<&CoverageReport>,
+    profile: bool,
+) {
+    let mut lox = new_lox(path);
+    lox.set_max_call_depth(depth);
+    lox.set_limits(limits);
+    if allow_io {
+        lox.allow_io();
+    }
+    let covered = Coverage::new();
+    let profiler = Profiler::new();
+    if coverage.is_some() {
+        lox.set_debug_hook(covered.clone());
+    } else if profile {
+        lox.set_debug_hook(profiler.clone());
+    }
+    let result = lox.run(source);
+    // A failed run still covered the lines it got through.
+    if let Some(coverage) = coverage {
+        coverage.write(&covered, path, source);
+    }
+    if profile {
+        eprint!("{}", profiler.report());
+    }
+    if let Err(e) = result {
+        report_with(e, path, &lox.source_map());
+    }
+}
+
+// Where `run --coverage` writes its report, and in which format.
+struct CoverageReport<'a> {
+    file: &'a str,
+    lcov: bool,
+}
+impl CoverageReport<'_> {
+    fn write(&self, coverage: &Coverage, path: &str, source: &str) {
+        let report = if self.lcov {
+            let path = fs::canonicalize(path)
+                .map_or_else(|_| path.to_string(), |path| path.display().to_string());
+            coverage.lcov(&path, source)
+        } else {
+            coverage.annotate(source)
+        };
+        // A script that doesn't parse never ran, and its error is reported
+        // by the run.
+        let Ok(report) = report else {
+            return;
+        };
+        if let Err(e) = fs::write(self.file, report) {
+            eprintln!("Could not write {}: {}", self.file, e);
+            process::exit(74);
+        }
+    }
+}
+
+// Options taking a value may be written `--name=value` or `--name value`.
+// The debugging, GC and optimizer options imply the VM backend.
+fn run_with_options(path: &str, options: &[&str]) {
+    let mut backend = "tree";
+    let mut depth = DEFAULT_MAX_CALL_DEPTH;
+    let mut limits = Limits::default();
+    let mut allow_io = false;
+    let mut deny_warnings = false;
+    let (mut coverage_file, mut lcov) = (None, true);
+    let mut profile = false;
+    let mut cache = false;
+    let mut vm = Vm::new();
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None if matches!(
+                *option,
+                "--backend"
+                    | "--gc-threshold"
+                    | "--max-depth"
+                    | "--fuel"
+                    | "--timeout"
+                    | "--coverage"
+                    | "--coverage-format"
+            ) =>
+            {
+                (*option, options.next().copied())
+            }
+            None => (*option, None),
+        };
+        match (name, value) {
+            ("--backend", Some(value @ ("tree" | "vm"))) => backend = value,
+            ("--disassemble", None) => {
+                vm.set_disassemble(true);
+                backend = "vm";
+            }
+            ("--trace", None) => {
+                vm.set_trace(true);
+                backend = "vm";
+            }
+            ("--opt" | "--no-opt", None) => {
+                vm.set_optimize(name == "--opt");
+                backend = "vm";
+            }
+            ("--stress-gc", None) => {
+                vm.set_stress_gc(true);
+                backend = "vm";
+            }
+            ("--allow-io", None) => allow_io = true,
+            ("--deny-warnings", None) => deny_warnings = true,
+            ("--profile", None) => profile = true,
+            ("--cache", None) if path != "-" => {
+                cache = true;
+                backend = "vm";
+            }
+            ("--coverage", Some(file)) => coverage_file = Some(file),
+            ("--coverage-format", Some(format @ ("lcov" | "annotate"))) => {
+                lcov = format == "lcov";
+            }
+            ("--max-depth", Some(calls)) if calls.parse::<usize>().is_ok() => {
+                depth = calls.parse().unwrap_or_default();
+            }
+            ("--fuel", Some(steps)) if steps.parse::<u64>().is_ok() => {
+                limits.fuel = steps.parse().ok();
+            }
+            ("--timeout", Some(ms)) if ms.parse::<u64>().is_ok() => {
+                limits.timeout = ms.parse().ok().map(Duration::from_millis);
+            }
+            ("--gc-threshold", Some(bytes)) if bytes.parse::<usize>().is_ok() => {
+                vm.set_gc_threshold(bytes.parse().unwrap_or_default());
+                backend = "vm";
+            }
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(64);
+            }
+        }
+    }
+    let source = read_source(path);
+    // Errors are left for the run itself to report.
+    if deny_warnings {
+        if let Ok(warnings) = new_lox(path).check(&source) {
+            report_warnings(warnings, true, path, &source);
+        }
+    }
+    let coverage = coverage_file.map(|file| CoverageReport { file, lcov });
+    if backend == "vm" && coverage.is_some() {
+        eprintln!("Coverage is only measured by the tree backend.");
         process::exit(64);
+    }
+    // Both are measured through the interpreter's one debug hook.
+    if coverage.is_some() && profile {
+        eprintln!("Coverage and profiling can't be measured in the same run.");
+        process::exit(64);
+    }
+    if backend == "vm" {
+        // The VM keeps its frames on the heap, so needs no bigger stack.
+        vm.set_max_call_depth(depth);
+        vm.set_limits(limits);
+        if allow_io {
+            vm.allow_io();
+        }
+        vm.set_profile(profile);
+        let result = if cache {
+            interpret_cached(&mut vm, path, &source)
+        } else {
+            vm.interpret(&source)
+        };
+        if let Some(report) = vm.profile_report() {
+            eprint!("{}", report);
+        }
+        if let Err(e) = result {
+            report(e, path, &source);
+        }
+    } else if depth > DEFAULT_MAX_CALL_DEPTH {
+        with_stack_for(depth, || {
+            run_file_with(
+                path,
+                &source,
+                depth,
+                limits,
+                allow_io,
+                coverage.as_ref(),
+                profile,
+            )
+        });
+    } else {
+        run_file_with(
+            path,
+            &source,
+            depth,
+            limits,
+            allow_io,
+            coverage.as_ref(),
+            profile,
+        );
+    }
+}
+
+// Runs the script from the compiled copy beside it, `script.loxc` for
+// `script.lox`, compiling it afresh if that is missing or out of date.
+fn interpret_cached(vm: &mut Vm, path: &str, source: &str) -> Result<(), LoxError> {
+    let cached = Path::new(path).with_extension("loxc");
+    if let Ok(bytes) = fs::read(&cached) {
+        if let Some(result) = vm.interpret_compiled(&bytes, source) {
+            return result;
+        }
+    }
+    let bytes = vm.compile(source)?;
+    // The cache only saves time; the script runs all the same without it.
+    if let Err(e) = fs::write(&cached, &bytes) {
+        eprintln!("Could not write {}: {}", cached.display(), e);
+    }
+    vm.interpret_compiled(&bytes, source)
+        .unwrap_or_else(|| vm.interpret(source))
+}
+
+fn tokenize_file(path: &str) {
+    let source = read_source(path);
+    let mut errors = Vec::new();
+    for item in Scanner::from_source(&source) {
+        match item {
+            Ok(token) => println!("{:?}", token),
+            Err(e) => errors.push(e),
+        }
+    }
+    if !errors.is_empty() {
+        report(LoxError::Scan(errors), path, &source);
+    }
+}
+
+fn parse_file(path: &str, format: &str) {
+    let render = match format {
+        "sexpr" => ast_printer::to_sexpr,
+        "tree" => ast_printer::to_tree,
+        "json" => ast_json::to_json,
+        "debug" => |statements: &[Stmt]| format!("{:#?}", statements),
+        _ => {
+            eprintln!("Unknown format {}.\n{}", format, USAGE);
+            process::exit(64);
+        }
+    };
+    let source = read_source(path);
+    match Lox::parse(&source) {
+        Ok(statements) => println!("{}", render(&statements)),
+        Err(e) => report(e, path, &source),
+    }
+}
+
+// Scans, parses and resolves without running anything, then prints any
+// warnings. `--types` checks the type annotations too.
+fn check_file(path: &str, options: &[&str]) {
+    let (mut types, mut deny) = (false, false);
+    for option in options {
+        match *option {
+            "--types" => types = true,
+            "--deny-warnings" => deny = true,
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(64);
+            }
+        }
+    }
+    let source = read_source(path);
+    let mut lox = new_lox(path);
+    let result = if types {
+        lox.check_types(&source)
     } else {
-        run_prompt().unwrap();
+        lox.check(&source)
+    };
+    match result {
+        Ok(warnings) => report_warnings(warnings, deny, path, &source),
+        Err(e) => report(e, path, &source),
+    }
+}
+
+// Runs the script under the terminal debugger, which pauses before the
+// first line.
+fn debug_file(path: &str, options: &[&str]) {
+    let mut breakpoints = Vec::new();
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let line = match *option {
+            "--break" => options.next().and_then(|line| line.parse().ok()),
+            _ => option
+                .strip_prefix("--break=")
+                .and_then(|line| line.parse().ok()),
+        };
+        match line {
+            Some(line) => breakpoints.push(line),
+            None => {
+                eprintln!("{}", USAGE);
+                process::exit(64);
+            }
+        }
+    }
+    // The debugger lasts as long as the process, and keeps the source to
+    // show lines from.
+    let source: &'static str = read_source(path).leak();
+    let mut lox = new_lox(path);
+    lox.set_debug_hook(DebugPrompt::new(source, &breakpoints));
+    if let Err(e) = lox.run(source) {
+        report_with(e, path, &lox.source_map());
+    }
+}
+
+const DEFAULT_DAP_PORT: u16 = 4711;
+
+// Waits for an editor to connect, then serves it one Debug Adapter Protocol
+// session. The protocol runs over TCP rather than stdio, leaving stdout to
+// the program being debugged.
+fn serve_dap(port: u16) {
+    let served = TcpListener::bind(("127.0.0.1", port)).and_then(|listener| {
+        eprintln!("Listening for a debugger on port {}.", port);
+        let (stream, _) = listener.accept()?;
+        dap::serve(BufReader::new(stream.try_clone()?), stream)
+    });
+    if let Err(e) = served {
+        eprintln!("Debug session failed: {}", e);
+        process::exit(74);
+    }
+}
+
+// Rewrites each file, or every script in each directory, in the canonical
+// layout. `-` formats stdin to stdout. With `--check`, nothing is written
+// and the exit status says whether anything would have changed.
+fn format_files(options: &[&str]) {
+    let check = options.contains(&"--check");
+    let paths: Vec<&str> = options
+        .iter()
+        .copied()
+        .filter(|option| *option != "--check")
+        .collect();
+    if paths.is_empty() || paths.iter().any(|path| path.starts_with("--")) {
+        eprintln!("{}", USAGE);
+        process::exit(64);
+    }
+    let files = expand_paths(&paths);
+    let (mut unformatted, mut failed) = (false, None);
+    for file in &files {
+        let source = read_source(file);
+        let formatted = match formatter::format(&source) {
+            Ok(formatted) => formatted,
+            Err(e) if JSON_ERRORS.load(Ordering::Relaxed) => {
+                eprintln!("{}", e.to_json(&source, file));
+                failed = Some(e.exit_code());
+                continue;
+            }
+            Err(e) => {
+                eprintln!("In {}:", file);
+                eprintln!("{}", e.render(&source));
+                failed = Some(e.exit_code());
+                continue;
+            }
+        };
+        if check {
+            if formatted != source {
+                println!("{} is not formatted.", file);
+                unformatted = true;
+            }
+        } else if file == "-" {
+            print!("{}", formatted);
+        } else if formatted != source {
+            fs::write(file, formatted).unwrap_or_else(|e| {
+                eprintln!("Could not write {}: {}", file, e);
+                process::exit(74);
+            });
+        }
+    }
+    if let Some(code) = failed {
+        process::exit(code);
+    }
+    if unformatted {
+        process::exit(1);
+    }
+}
+
+// The `.lox` files under each directory in `paths`, in order, and the
+// other paths as they are.
+fn expand_paths(paths: &[&str]) -> Vec<String> {
+    let mut files = Vec::new();
+    for path in paths {
+        if Path::new(path).is_dir() {
+            let mut scripts = Vec::new();
+            test_runner::collect_scripts(Path::new(path), &mut scripts).unwrap_or_else(|e| {
+                eprintln!("Could not read {}: {}", path, e);
+                process::exit(66);
+            });
+            scripts.sort();
+            files.extend(scripts.iter().map(|script| script.display().to_string()));
+        } else {
+            files.push(path.to_string());
+        }
+    }
+    files
+}
+
+// `--stress-gc` implies the VM backend, as it does for `run`.
+fn test_files(dir: &str, options: &[&str]) {
+    let mut backend = test_runner::Backend {
+        name: "tree",
+        stress_gc: false,
+    };
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None if *option == "--backend" => (*option, options.next().copied()),
+            None => (*option, None),
+        };
+        match (name, value) {
+            ("--backend", Some("tree")) => backend.name = "tree",
+            ("--backend", Some("vm")) => backend.name = "vm",
+            ("--stress-gc", None) => {
+                backend.name = "vm";
+                backend.stress_gc = true;
+            }
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(64);
+            }
+        }
+    }
+    match test_runner::run_tests(dir, backend) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("Could not run tests in {}: {}", dir, e);
+            process::exit(66);
+        }
     }
 }
 
+fn bench_files(options: &[&str]) {
+    let mut iterations = 10;
+    let mut backends = vec!["tree"];
+    let mut paths = Vec::new();
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None if matches!(*option, "--iterations" | "--backend") => {
+                (*option, options.next().copied())
+            }
+            None => (*option, None),
+        };
+        match (name, value) {
+            ("--iterations", Some(n)) if n.parse::<usize>().is_ok_and(|n| n > 0) => {
+                iterations = n.parse().unwrap_or_default();
+            }
+            ("--backend", Some(backend @ ("tree" | "vm"))) => backends = vec![backend],
+            ("--backend", Some("both")) => backends = vec!["tree", "vm"],
+            (path, None) if !path.starts_with("--") => paths.push(path),
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(64);
+            }
+        }
+    }
+    if paths.is_empty() {
+        eprintln!("{}", USAGE);
+        process::exit(64);
+    }
+    let scripts = expand_paths(&paths);
+    match bench::run_benchmarks(&scripts, &backends, iterations) {
+        Ok(true) => {}
+        Ok(false) => process::exit(70),
+        Err(e) => {
+            eprintln!("Could not run benchmarks: {}", e);
+            process::exit(71);
+        }
+    }
+}
+
+// Denied warnings are reported as errors, and stop the program from
+// running.
+fn report_warnings(warnings: Vec<Diagnostic>, deny: bool, path: &str, source: &str) {
+    if deny && !warnings.is_empty() {
+        let errors = warnings
+            .into_iter()
+            .map(|mut warning| {
+                warning.severity = Severity::Error;
+                warning
+            })
+            .collect();
+        report(LoxError::Lint(errors), path, source);
+    }
+    let map = SourceMap::new(source);
+    for warning in warnings {
+        if JSON_ERRORS.load(Ordering::Relaxed) {
+            eprintln!("{}", warning.to_json(&map, path, "lint"));
+        } else {
+            eprintln!("{}", warning.render(&map));
+        }
+    }
+}
+
+// The startup script is `$LOXRC` if set, otherwise `~/.loxrc`.
+fn startup_file() -> Option<PathBuf> {
+    match env::var_os("LOXRC") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => env::var_os("HOME").map(|home| Path::new(&home).join(".loxrc")),
+    }
+}
+
+fn run_startup_file(lox: &mut Lox) {
+    let Some(path) = startup_file() else {
+        return;
+    };
+    let Ok(source) = fs::read_to_string(&path) else {
+        return;
+    };
+    if let Err(e) = lox.run(&source) {
+        eprintln!(
+            "In {}:\n{}",
+            path.display(),
+            e.render_with(&lox.source_map())
+        );
+    }
+}
+
+// One interpreter serves the whole session, so definitions from the
+// startup file and earlier lines stay visible. The globals from before each
+// of the last few lines are kept for `:undo`.
 fn run_prompt() -> io::Result<()> {
+    let mut lox = new_lox("-");
+    run_startup_file(&mut lox);
+    let mut history = Vec::new();
+
     let stdin = io::stdin();
     let mut stdout = io::stdout();
     let mut reader = stdin.lock();
+    let _bracketed_paste = stdout.is_terminal().then(BracketedPaste::enable);
 
     loop {
         print!("> ");
@@ -27,29 +738,139 @@ fn run_prompt() -> io::Result<()> {
         if reader.read_line(&mut line)? == 0 {
             break;
         }
-        if let Err(e) = run(&line) {
-            eprintln!("Error: {:#?}", e);
+        let pasted = paste::starts(&line);
+        let trimmed = line.trim();
+        if !pasted && trimmed.starts_with(':') && trimmed != ":paste" {
+            if !meta_command(&mut lox, &mut history, trimmed) {
+                break;
+            }
+            continue;
+        }
+        if pasted {
+            line = paste::read(&mut reader, line)?;
+        } else if trimmed == ":paste" {
+            line = read_paste(&mut reader)?;
+        } else {
+            while scanner::is_incomplete(&line) {
+                print!(".. ");
+                stdout.flush()?;
+                let mut more = String::new();
+                if reader.read_line(&mut more)? == 0 {
+                    break;
+                }
+                if paste::starts(&more) {
+                    more = paste::read(&mut reader, more)?;
+                }
+                line.push_str(&more);
+            }
+        }
+        // Without the trailing newline, errors at the end of input point
+        // at the line just typed rather than an empty one after it.
+        let source = line.trim_end();
+        remember(&mut history, lox.snapshot());
+        match lox.run_line(source) {
+            Ok(Some(value)) => println!("{}", value),
+            Ok(None) => {}
+            Err(e) => eprintln!("{}", e.render_with(&lox.source_map())),
         }
     }
-
     Ok(())
 }
 
-fn run(source: &str) -> Result<(), ScannerError> {
-    let tokens = scan_tokens(source.to_string())?;
-    for token in tokens {
-        println!("{:?}", token);
+// Handles a `:command` typed at the prompt. Returns false when the session
+// should end.
+fn meta_command(lox: &mut Lox, history: &mut Vec<Snapshot>, line: &str) -> bool {
+    let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+    let arg = arg.trim();
+    match command {
+        ":tokens" => {
+            for item in Scanner::from_source(arg) {
+                match item {
+                    Ok(token) => println!("{:?}", token),
+                    Err(e) => eprintln!("{}", LoxError::Scan(vec![e]).render(arg)),
+                }
+            }
+        }
+        ":ast" => match parse(arg) {
+            Ok(statements) => println!("{}", ast_printer::to_sexpr(&statements)),
+            Err(e) => eprintln!("{}", e.render(arg)),
+        },
+        ":env" => {
+            for (name, value) in lox.globals() {
+                println!("{} = {}", name, value);
+            }
+        }
+        ":load" => match fs::read_to_string(arg) {
+            Ok(source) => {
+                remember(history, lox.snapshot());
+                if let Err(e) = lox.run(&source) {
+                    eprintln!("{}", e.render_with(&lox.source_map()));
+                }
+            }
+            Err(e) => eprintln!("Could not read {}: {}", arg, e),
+        },
+        ":undo" => match history.pop() {
+            Some(snapshot) => lox.restore(&snapshot),
+            None => eprintln!("Nothing to undo."),
+        },
+        ":reset" => {
+            *lox = new_lox("-");
+            run_startup_file(lox);
+            history.clear();
+        }
+        ":quit" => return false,
+        _ => eprintln!(
+            "Unknown command {}. Try :tokens, :ast, :env, :load, :undo, :reset, :paste or :quit.",
+            command
+        ),
     }
-    Ok(())
+    true
 }
 
-fn scan_tokens(input: String) -> Result<Vec<Token>, ScannerError> {
-    let mut scanner = Scanner::new();
+// Each snapshot copies every global, and keeps alive any value that has
+// been replaced since, so only the most recent are kept for `:undo`.
+const UNDO_LIMIT: usize = 100;
 
-    scanner.scan_tokens(input);
+fn remember(history: &mut Vec<Snapshot>, snapshot: Snapshot) {
+    if history.len() == UNDO_LIMIT {
+        history.remove(0);
+    }
+    history.push(snapshot);
+}
 
-    match scanner.err {
-        Some(err) => Err(err),
-        None => Ok(scanner.tokens),
+// Parses REPL input the way `run_line` would, wrapping a lone expression in
+// an expression statement.
+fn parse(source: &str) -> Result<Vec<Stmt>, LoxError> {
+    let mut scanner = Scanner::new();
+    scanner.scan_tokens(source.to_string());
+    if !scanner.errors.is_empty() {
+        return Err(LoxError::Scan(scanner.errors));
+    }
+    match Parser::new(scanner.tokens.clone()).parse() {
+        Ok(statements) => Ok(statements),
+        Err(errors) => match Parser::new(scanner.tokens).parse_expression() {
+            Ok(expr) => Ok(vec![Stmt::Expression(expr)]),
+            Err(_) => Err(LoxError::Parse(errors)),
+        },
+    }
+}
+
+// Collects lines verbatim until a lone `:end` (or EOF) so a pasted block
+// runs as a single program. This is the by-hand form of bracketed paste,
+// for terminals without it; a paste made inside it loses its markers.
+fn read_paste(reader: &mut impl BufRead) -> io::Result<String> {
+    println!("// Entering paste mode (`:end` to finish)");
+    let mut block = String::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = paste::strip(&line);
+        if line.trim() == ":end" {
+            break;
+        }
+        block.push_str(&line);
     }
+    Ok(block)
 }
@@ -0,0 +1,261 @@
+use crate::environment::Environment;
+use crate::error::LoxError;
+use crate::lox::Lox;
+use crate::parser::Stmt;
+use crate::span::Sources;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+// The stack each parsing thread gets: as much as the main thread's, since
+// the parser recurses once per level of nesting.
+const PARSER_STACK: usize = 8 * 1024 * 1024;
+
+// Finds and caches the files loaded by `import`. Every import path is
+// relative to the root, the entry script's directory, whichever module the
+// import is in, and may not lead outside it.
+#[derive(Debug, Default)]
+pub(crate) struct Modules {
+    // Imports fail unless the host allows them, so that embedded scripts
+    // can't read its files.
+    allowed: bool,
+    root: PathBuf,
+    // The globals of each module that finished loading, by canonical path.
+    loaded: HashMap<PathBuf, Rc<RefCell<Environment>>>,
+    // Modules still being loaded, outermost first, with the path each was
+    // imported as.
+    loading: Vec<(PathBuf, String)>,
+    // Modules parsed by `preload` that haven't been imported yet, with
+    // their syntax errors if they have any.
+    parsed: HashMap<PathBuf, Result<Vec<Stmt>, LoxError>>,
+}
+impl Modules {
+    pub(crate) fn allow(&mut self) {
+        self.allowed = true;
+    }
+    pub(crate) fn set_root(&mut self, root: PathBuf) {
+        self.root = root;
+    }
+    // Roots imports at `script`'s directory and counts it as loading for
+    // as long as it runs, so importing it back is a cycle.
+    pub(crate) fn set_entry_script(&mut self, script: &Path) {
+        self.root = script.parent().map(Path::to_path_buf).unwrap_or_default();
+        if let (Ok(file), Some(name)) = (script.canonicalize(), script.file_name()) {
+            self.loading = vec![(file, name.to_string_lossy().into_owned())];
+        }
+    }
+    pub(crate) fn find(&self, path: &str) -> Result<PathBuf, String> {
+        if !self.allowed {
+            return Err("Imports are not allowed.".to_string());
+        }
+        if Path::new(path).is_absolute() {
+            return Err(format!("Module path '{}' must be relative.", path));
+        }
+        let root = if self.root.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            &self.root
+        };
+        let not_found = |_| format!("Could not find module '{}'.", path);
+        let root = root.canonicalize().map_err(not_found)?;
+        let file = root.join(path).canonicalize().map_err(not_found)?;
+        // Checked once `..` and links are resolved, which could lead
+        // anywhere.
+        if !file.starts_with(&root) {
+            return Err(format!(
+                "Module '{}' is outside the script's directory.",
+                path
+            ));
+        }
+        Ok(file)
+    }
+    // Reads every module `statements` import, and those they import in
+    // turn, and parses them in parallel, so that the imports only have to
+    // resolve and run them. Modules are added to `sources` in the order
+    // they are found and errors kept until the import runs, so what is
+    // reported doesn't depend on which thread finished first. Imports that
+    // can't be found, or are only in lambdas, are read when they run.
+    pub(crate) fn preload(&mut self, statements: &[Stmt], sources: &mut Sources) {
+        let mut paths = Vec::new();
+        imports(statements, &mut paths);
+        while !paths.is_empty() {
+            let mut batch: Vec<(PathBuf, String, usize)> = Vec::new();
+            for path in paths.drain(..) {
+                let Ok(file) = self.find(&path) else {
+                    continue;
+                };
+                let seen = self.loaded.contains_key(&file)
+                    || self.parsed.contains_key(&file)
+                    || self.loading.iter().any(|(loading, _)| *loading == file)
+                    || batch.iter().any(|(queued, _, _)| *queued == file);
+                if seen {
+                    continue;
+                }
+                if let Ok(source) = fs::read_to_string(&file) {
+                    let base = sources.add(Some(&path), &source);
+                    batch.push((file, source, base));
+                }
+            }
+            for ((file, _, _), result) in batch.iter().zip(parse_all(&batch)) {
+                if let Ok(statements) = &result {
+                    imports(statements, &mut paths);
+                }
+                self.parsed.insert(file.clone(), result);
+            }
+        }
+    }
+    // The statements of `file` if `preload` parsed it.
+    pub(crate) fn take_parsed(&mut self, file: &Path) -> Option<Result<Vec<Stmt>, LoxError>> {
+        self.parsed.remove(file)
+    }
+    pub(crate) fn get(&self, file: &Path) -> Option<Rc<RefCell<Environment>>> {
+        self.loaded.get(file).cloned()
+    }
+    // Marks `file` as loading, failing if it already is: a module that
+    // imports itself, directly or not, would never finish.
+    pub(crate) fn begin(&mut self, file: PathBuf, path: &str) -> Result<(), String> {
+        if let Some(start) = self.loading.iter().position(|(f, _)| *f == file) {
+            let cycle: Vec<&str> = self.loading[start..]
+                .iter()
+                .map(|(_, path)| path.as_str())
+                .chain(std::iter::once(path))
+                .collect();
+            return Err(format!("Import cycle: {}.", cycle.join(" -> ")));
+        }
+        self.loading.push((file, path.to_string()));
+        Ok(())
+    }
+    // Ends the load `begin` started, caching the module's globals if it
+    // succeeded so later imports of it don't run it again.
+    pub(crate) fn finish(&mut self, globals: Option<Rc<RefCell<Environment>>>) {
+        if let (Some((file, _)), Some(globals)) = (self.loading.pop(), globals) {
+            self.loaded.insert(file, globals);
+        }
+    }
+}
+
+// Parses each module of the batch on a pool of threads, returning the
+// results in the batch's order.
+fn parse_all(batch: &[(PathBuf, String, usize)]) -> Vec<Result<Vec<Stmt>, LoxError>> {
+    let parse = |(_, source, base): &(PathBuf, String, usize)| Lox::parse_at(source, *base);
+    let workers = thread::available_parallelism()
+        .map_or(1, NonZeroUsize::get)
+        .min(batch.len());
+    if workers <= 1 {
+        return batch.iter().map(parse).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<_> = thread::scope(|scope| {
+        let worker = || {
+            let mut done = Vec::new();
+            loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(module) = batch.get(index) else {
+                    return done;
+                };
+                done.push((index, parse(module)));
+            }
+        };
+        let threads: Vec<_> = (0..workers)
+            .filter_map(|_| {
+                thread::Builder::new()
+                    .stack_size(PARSER_STACK)
+                    .spawn_scoped(scope, worker)
+                    .ok()
+            })
+            .collect();
+        threads
+            .into_iter()
+            .flat_map(|thread| {
+                thread
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect()
+    });
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+// The path of every import in `statements`, outside of lambdas, in order.
+fn imports(statements: &[Stmt], paths: &mut Vec<String>) {
+    for statement in statements {
+        stmt_imports(statement, paths);
+    }
+}
+
+fn stmt_imports(statement: &Stmt, paths: &mut Vec<String>) {
+    match statement {
+        Stmt::Import { path, .. } => paths.push(path.clone()),
+        Stmt::Block(body) => imports(body, paths),
+        Stmt::DoWhile { body, .. } | Stmt::ForEach { body, .. } | Stmt::While { body, .. } => {
+            stmt_imports(body, paths)
+        }
+        Stmt::For {
+            initializer, body, ..
+        } => {
+            if let Some(initializer) = initializer {
+                stmt_imports(initializer, paths);
+            }
+            stmt_imports(body, paths);
+        }
+        Stmt::If {
+            then_branch,
+            else_branch,
+            ..
+        } => {
+            stmt_imports(then_branch, paths);
+            if let Some(else_branch) = else_branch {
+                stmt_imports(else_branch, paths);
+            }
+        }
+        Stmt::Switch { cases, default, .. } => {
+            for (_, body) in cases {
+                imports(body, paths);
+            }
+            if let Some(default) = default {
+                imports(default, paths);
+            }
+        }
+        Stmt::Try {
+            body,
+            catch,
+            finally,
+        } => {
+            imports(body, paths);
+            if let Some((_, handler)) = catch {
+                imports(handler, paths);
+            }
+            if let Some(finally) = finally {
+                imports(finally, paths);
+            }
+        }
+        Stmt::Function(declaration) => imports(&declaration.body, paths),
+        Stmt::Class {
+            methods,
+            class_methods,
+            ..
+        } => {
+            for method in methods.iter().chain(class_methods) {
+                imports(&method.body, paths);
+            }
+        }
+        Stmt::Trait { methods, .. } => {
+            for method in methods {
+                imports(&method.body, paths);
+            }
+        }
+        Stmt::Destructure { .. }
+        | Stmt::Expression(_)
+        | Stmt::Print(_)
+        | Stmt::Return { .. }
+        | Stmt::Throw { .. }
+        | Stmt::Var { .. }
+        | Stmt::Yield { .. } => {}
+    }
+}
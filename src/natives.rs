@@ -0,0 +1,393 @@
+use crate::environment::Environment;
+use crate::interpreter::{Coroutine, LoxCallable, MapKey, NativeFunction, Value};
+use std::cell::{Cell, RefCell};
+use std::f64::consts::PI;
+use std::fs;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Registers the built-in functions every program starts with.
+pub fn define_natives(globals: &mut Environment) {
+    define(globals, NativeFunction::new("clock", 0, |_, _| clock()));
+    define(
+        globals,
+        NativeFunction::new("assert", 2, |_, args| match &args[0] {
+            Value::Nil | Value::Bool(false) => Err(format!("Assertion failed: {}", args[1])),
+            _ => Ok(Value::Nil),
+        }),
+    );
+    define(
+        globals,
+        NativeFunction::new("len", 1, |_, args| len(&args[0])),
+    );
+    define(
+        globals,
+        NativeFunction::new("push", 2, |_, mut args| {
+            let value = args.pop().unwrap_or(Value::Nil);
+            push(&args[0], value)
+        }),
+    );
+    define(
+        globals,
+        NativeFunction::new("keys", 1, |_, args| keys(&args[0])),
+    );
+    define(
+        globals,
+        NativeFunction::new("toList", 1, |_, args| to_list(&args[0])),
+    );
+    define(
+        globals,
+        NativeFunction::new("has", 2, |_, args| has(&args[0], &args[1])),
+    );
+    define(
+        globals,
+        NativeFunction::new("remove", 2, |_, args| remove(&args[0], &args[1])),
+    );
+    define(
+        globals,
+        NativeFunction::new("substring", 3, |_, args| match &args[..] {
+            [Value::Str(s), Value::Number(start), Value::Number(end)] => {
+                substring(s, *start, *end).map(Value::Str)
+            }
+            _ => Err("substring() expects a string and two numbers.".to_string()),
+        }),
+    );
+    define(
+        globals,
+        NativeFunction::new("indexOf", 2, |_, args| match &args[..] {
+            [Value::Str(s), Value::Str(needle)] => Ok(Value::Number(index_of(s, needle))),
+            _ => Err("indexOf() expects two strings.".to_string()),
+        }),
+    );
+    define(
+        globals,
+        NativeFunction::new("split", 2, |_, args| match &args[..] {
+            [Value::Str(s), Value::Str(separator)] => {
+                let parts = split(s, separator).map(Value::Str).collect();
+                Ok(Value::List(Rc::new(RefCell::new(parts))))
+            }
+            _ => Err("split() expects two strings.".to_string()),
+        }),
+    );
+    define(
+        globals,
+        NativeFunction::new("toUpper", 1, |_, args| match &args[0] {
+            Value::Str(s) => Ok(Value::Str(s.to_uppercase())),
+            _ => Err("toUpper() expects a string.".to_string()),
+        }),
+    );
+    define(
+        globals,
+        NativeFunction::new("toLower", 1, |_, args| match &args[0] {
+            Value::Str(s) => Ok(Value::Str(s.to_lowercase())),
+            _ => Err("toLower() expects a string.".to_string()),
+        }),
+    );
+    define(
+        globals,
+        NativeFunction::new("parseNumber", 1, |_, args| match &args[0] {
+            Value::Str(s) => Ok(parse_number(s).map_or(Value::Nil, Value::Number)),
+            _ => Err("parseNumber() expects a string.".to_string()),
+        }),
+    );
+    define(
+        globals,
+        NativeFunction::new("num", 1, |_, args| match &args[0] {
+            Value::Number(n) => Ok(Value::Number(*n)),
+            Value::Str(s) => to_number(s).map(Value::Number),
+            _ => Err("num() expects a number or a string.".to_string()),
+        }),
+    );
+    define(
+        globals,
+        NativeFunction::new("str", 1, |_, args| Ok(Value::Str(args[0].to_string()))),
+    );
+    define(
+        globals,
+        NativeFunction::new("chr", 1, |_, args| match &args[0] {
+            Value::Number(code) => chr(*code).map(Value::Str),
+            _ => Err("chr() expects a number.".to_string()),
+        }),
+    );
+    define(
+        globals,
+        NativeFunction::new("ord", 1, |_, args| match &args[0] {
+            Value::Str(s) => ord(s).map(Value::Number),
+            _ => Err("ord() expects a string.".to_string()),
+        }),
+    );
+    define(
+        globals,
+        NativeFunction::new("coroutine", 1, |_, args| match &args[0] {
+            Value::Function(function) if function.arity().contains(&0) => Ok(Value::Coroutine(
+                Rc::new(Coroutine::new(Rc::clone(function))),
+            )),
+            _ => Err("coroutine() expects a function that takes no arguments.".to_string()),
+        }),
+    );
+    define(
+        globals,
+        NativeFunction::new("resume", 1, |interpreter, args| match &args[0] {
+            Value::Coroutine(coroutine) => interpreter.resume(coroutine),
+            _ => Err("resume() expects a coroutine.".to_string()),
+        }),
+    );
+    define(
+        globals,
+        NativeFunction::new("status", 1, |_, args| match &args[0] {
+            Value::Coroutine(coroutine) => Ok(Value::Str(coroutine.status().to_string())),
+            _ => Err("status() expects a coroutine.".to_string()),
+        }),
+    );
+    globals.define("pi".to_string(), Value::Number(PI));
+    define(globals, unary("sqrt", f64::sqrt));
+    define(globals, unary("abs", f64::abs));
+    define(globals, unary("floor", f64::floor));
+    define(globals, unary("ceil", f64::ceil));
+    define(globals, binary("pow", f64::powf));
+    define(globals, binary("min", f64::min));
+    define(globals, binary("max", f64::max));
+    define(
+        globals,
+        NativeFunction::new("random", 0, |_, _| Ok(Value::Number(random()))),
+    );
+}
+
+// Natives that reach outside the interpreter, which a host has to opt
+// into.
+pub fn io_natives() -> Vec<NativeFunction> {
+    vec![
+        NativeFunction::new("readLine", 0, |_, _| {
+            Ok(read_line()?.map_or(Value::Nil, Value::Str))
+        }),
+        NativeFunction::new("readFile", 1, |_, args| match &args[0] {
+            Value::Str(path) => read_file(path).map(Value::Str),
+            _ => Err("readFile() expects a path.".to_string()),
+        }),
+        NativeFunction::new("writeFile", 2, |_, args| match &args[..] {
+            [Value::Str(path), Value::Str(text)] => write_file(path, text).map(|_| Value::Nil),
+            _ => Err("writeFile() expects a path and a string.".to_string()),
+        }),
+        NativeFunction::new("eprint", 1, |interpreter, args| {
+            interpreter.eprint(&args[0].to_string());
+            Ok(Value::Nil)
+        }),
+    ]
+}
+
+fn unary(name: &'static str, f: fn(f64) -> f64) -> NativeFunction {
+    NativeFunction::new(name, 1, move |_, args| match args[0] {
+        Value::Number(n) => Ok(Value::Number(f(n))),
+        _ => Err(format!("{}() expects a number.", name)),
+    })
+}
+
+fn binary(name: &'static str, f: fn(f64, f64) -> f64) -> NativeFunction {
+    NativeFunction::new(name, 2, move |_, args| match args[..] {
+        [Value::Number(a), Value::Number(b)] => Ok(Value::Number(f(a, b))),
+        _ => Err(format!("{}() expects two numbers.", name)),
+    })
+}
+
+fn define(globals: &mut Environment, native: NativeFunction) {
+    globals.define(native.name().to_string(), Value::Native(Rc::new(native)));
+}
+
+// Seconds since the Unix epoch, for timing benchmarks.
+fn clock() -> Result<Value, String> {
+    Ok(Value::Number(since_epoch().as_secs_f64()))
+}
+
+// The time since the Unix epoch, for both backends. A browser has no clock
+// std can read, so under WebAssembly the host is asked for it.
+pub(crate) fn since_epoch() -> Duration {
+    #[cfg(target_arch = "wasm32")]
+    return crate::wasm::since_epoch();
+    #[cfg(not(target_arch = "wasm32"))]
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+fn len(value: &Value) -> Result<Value, String> {
+    match value {
+        Value::List(list) => Ok(Value::Number(list.borrow().len() as f64)),
+        Value::Map(map) => Ok(Value::Number(map.borrow().len() as f64)),
+        Value::Str(s) => Ok(Value::Number(s.chars().count() as f64)),
+        Value::Range(range) => Ok(Value::Number(range.len() as f64)),
+        _ => Err("len() expects a list, map, string or range.".to_string()),
+    }
+}
+
+fn push(list: &Value, value: Value) -> Result<Value, String> {
+    match list {
+        Value::List(list) => {
+            list.borrow_mut().push(value);
+            Ok(Value::Nil)
+        }
+        _ => Err("push() expects a list.".to_string()),
+    }
+}
+
+fn keys(map: &Value) -> Result<Value, String> {
+    match map {
+        Value::Map(map) => {
+            let keys = map.borrow().keys().map(MapKey::to_value).collect();
+            Ok(Value::List(Rc::new(RefCell::new(keys))))
+        }
+        _ => Err("keys() expects a map.".to_string()),
+    }
+}
+
+// A new list of whatever iterating over the value yields.
+fn to_list(value: &Value) -> Result<Value, String> {
+    match value.iterate() {
+        Some(iteration) => Ok(Value::List(Rc::new(RefCell::new(iteration.collect())))),
+        None => Err("toList() expects a list, map or range.".to_string()),
+    }
+}
+
+fn has(map: &Value, key: &Value) -> Result<Value, String> {
+    match (map, MapKey::from_value(key)) {
+        (Value::Map(map), Some(key)) => Ok(Value::Bool(map.borrow().contains_key(&key))),
+        (Value::Map(_), None) => Ok(Value::Bool(false)),
+        _ => Err("has() expects a map.".to_string()),
+    }
+}
+
+// Returns the removed value, or nil if the key was absent.
+fn remove(map: &Value, key: &Value) -> Result<Value, String> {
+    match (map, MapKey::from_value(key)) {
+        (Value::Map(map), Some(key)) => Ok(map.borrow_mut().remove(&key).unwrap_or(Value::Nil)),
+        (Value::Map(_), None) => Ok(Value::Nil),
+        _ => Err("remove() expects a map.".to_string()),
+    }
+}
+
+// The string natives below work on characters rather than bytes, like
+// `len`, and are shared with the VM.
+
+// The characters from `start` up to but not including `end`.
+pub(crate) fn substring(s: &str, start: f64, end: f64) -> Result<String, String> {
+    let len = s.chars().count();
+    match (char_index(start, len), char_index(end, len)) {
+        (Some(start), Some(end)) if start <= end => {
+            Ok(s.chars().skip(start).take(end - start).collect())
+        }
+        _ => Err("substring() index out of range.".to_string()),
+    }
+}
+
+fn char_index(n: f64, len: usize) -> Option<usize> {
+    (n.fract() == 0.0 && n >= 0.0 && n <= len as f64).then_some(n as usize)
+}
+
+// Where `needle` first occurs in `s`, or -1 if it doesn't.
+pub(crate) fn index_of(s: &str, needle: &str) -> f64 {
+    match s.find(needle) {
+        Some(byte) => s[..byte].chars().count() as f64,
+        None => -1.0,
+    }
+}
+
+// An empty separator splits `s` into its characters.
+pub(crate) fn split<'a>(s: &'a str, separator: &'a str) -> Box<dyn Iterator<Item = String> + 'a> {
+    if separator.is_empty() {
+        Box::new(s.chars().map(String::from))
+    } else {
+        Box::new(s.split(separator).map(String::from))
+    }
+}
+
+// Accepts what a number literal or `print` would show, with optional
+// surrounding whitespace and sign. Anything else is nil rather than an
+// error, so programs can validate input with it.
+pub(crate) fn parse_number(s: &str) -> Option<f64> {
+    let s = s.trim();
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    if !digits.starts_with(|c: char| c.is_ascii_digit()) || digits.ends_with('.') {
+        return None;
+    }
+    digits
+        .chars()
+        .all(|c| c.is_ascii_digit() || c == '.')
+        .then(|| s.parse().ok())
+        .flatten()
+}
+
+// Like `parseNumber`, but failing rather than giving nil.
+pub(crate) fn to_number(s: &str) -> Result<f64, String> {
+    parse_number(s).ok_or_else(|| format!("Can't convert '{}' to a number.", s))
+}
+
+// The one-character string for a Unicode code point.
+pub(crate) fn chr(code: f64) -> Result<String, String> {
+    if code.fract() != 0.0 || code < 0.0 || code > u32::MAX as f64 {
+        return Err("chr() expects a code point.".to_string());
+    }
+    char::from_u32(code as u32)
+        .map(String::from)
+        .ok_or_else(|| "chr() expects a code point.".to_string())
+}
+
+// The code point of a one-character string.
+pub(crate) fn ord(s: &str) -> Result<f64, String> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c as u32 as f64),
+        _ => Err("ord() expects a string of one character.".to_string()),
+    }
+}
+
+thread_local! {
+    static RANDOM_STATE: Cell<u64> = Cell::new(random_seed());
+}
+
+fn random_seed() -> u64 {
+    let nanos = since_epoch().as_nanos() as u64;
+    // Runs close together have close clocks; one round of SplitMix64 spreads
+    // them apart before xorshift, which never leaves zero, takes over.
+    let mut z = nanos.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (z ^ (z >> 31)) | 1
+}
+
+// A number in [0, 1) from a xorshift generator. Good enough for games and
+// examples, not for anything that must be unpredictable.
+pub(crate) fn random() -> f64 {
+    RANDOM_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
+
+// The next line of standard input without its line ending, or None at the
+// end of input.
+pub(crate) fn read_line() -> Result<Option<String>, String> {
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) => Ok(None),
+        Ok(_) => {
+            let len = line.trim_end_matches(['\n', '\r']).len();
+            line.truncate(len);
+            Ok(Some(line))
+        }
+        Err(e) => Err(format!("Could not read input: {}", e)),
+    }
+}
+
+pub(crate) fn read_file(path: &str) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))
+}
+
+pub(crate) fn write_file(path: &str, text: &str) -> Result<(), String> {
+    fs::write(path, text).map_err(|e| format!("Could not write {}: {}", path, e))
+}
@@ -0,0 +1,1374 @@
+use crate::error::Diagnostic;
+use crate::scanner::{Literal, Token, TokenType};
+use crate::span::Span;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// Identifies variable-referencing expressions so the resolver can record
+// their scope depth. Ids come from a process-wide counter so that ASTs
+// parsed separately (e.g. successive REPL lines) never collide.
+static NEXT_EXPR_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn next_expr_id() -> usize {
+    NEXT_EXPR_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone)]
+pub enum LiteralValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+}
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Assign {
+        id: usize,
+        name: Token,
+        value: Box<Expr>,
+    },
+    Binary {
+        left: Box<Expr>,
+        operator: Token,
+        right: Box<Expr>,
+    },
+    Call {
+        callee: Box<Expr>,
+        paren: Token,
+        arguments: Vec<Expr>,
+    },
+    // `target op= value`, where `operator` is the `+`, `-`, `*` or `/`
+    // applied, spanning the `op=`. Any object and index in the target are
+    // evaluated once, for both reading and writing it.
+    Compound {
+        target: Box<Expr>,
+        operator: Token,
+        value: Box<Expr>,
+    },
+    Conditional {
+        condition: Box<Expr>,
+        then_branch: Box<Expr>,
+        else_branch: Box<Expr>,
+    },
+    // `(a, b) = value`, which stores each element of the list `value` in
+    // the target at the same position. The targets are the variables,
+    // properties and indexes written, as they would be read.
+    Destructure {
+        targets: Vec<Expr>,
+        equals: Token,
+        value: Box<Expr>,
+    },
+    // `object.name`, or `object?.name` if `safe`, which is nil when the
+    // object is and skips the call it may be the callee of.
+    Get {
+        object: Box<Expr>,
+        name: Token,
+        safe: bool,
+    },
+    Grouping(Box<Expr>),
+    Index {
+        object: Box<Expr>,
+        bracket: Token,
+        index: Box<Expr>,
+    },
+    IndexSet {
+        object: Box<Expr>,
+        bracket: Token,
+        index: Box<Expr>,
+        value: Box<Expr>,
+    },
+    Lambda(Arc<FunctionDecl>),
+    List(Vec<Expr>),
+    Map {
+        brace: Token,
+        entries: Vec<(Expr, Expr)>,
+    },
+    Literal {
+        value: LiteralValue,
+        span: Span,
+    },
+    Logical {
+        left: Box<Expr>,
+        operator: Token,
+        right: Box<Expr>,
+    },
+    Set {
+        object: Box<Expr>,
+        name: Token,
+        value: Box<Expr>,
+    },
+    Super {
+        id: usize,
+        keyword: Token,
+        method: Token,
+    },
+    This {
+        id: usize,
+        keyword: Token,
+    },
+    Unary {
+        operator: Token,
+        right: Box<Expr>,
+    },
+    // `++x`, `x--` and so on, which add or subtract 1 like a compound
+    // assignment. A postfix operator's result is the value from before.
+    Update {
+        operator: Token,
+        prefix: bool,
+        target: Box<Expr>,
+    },
+    Variable {
+        id: usize,
+        name: Token,
+    },
+}
+
+// Shared with the function values created from it at runtime, so the body
+// is not cloned each time the declaration is executed. Lambdas use their
+// `lambda` keyword token as the name.
+#[derive(Debug)]
+pub struct FunctionDecl {
+    pub name: Token,
+    pub params: Vec<Token>,
+    // The type name written after each parameter, if any, and after the
+    // parameter list with `->`. Only the type checker reads them.
+    pub param_types: Vec<Option<Token>>,
+    pub return_type: Option<Token>,
+    // The value after each parameter's `=`, if any, used when a call
+    // leaves it out. Only the last parameters may have one.
+    pub defaults: Vec<Option<Expr>>,
+    pub body: Vec<Stmt>,
+    // A method declared without a parameter list, which runs when the
+    // property is read.
+    pub is_getter: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum Stmt {
+    Block(Vec<Stmt>),
+    // Like `While`, but the condition is checked after the body.
+    DoWhile {
+        body: Box<Stmt>,
+        condition: Expr,
+    },
+    Class {
+        name: Token,
+        superclass: Option<Expr>,
+        // The traits named after `with`, whose methods are copied in.
+        traits: Vec<Expr>,
+        methods: Vec<Arc<FunctionDecl>>,
+        // Declared with `class`, and called on the class itself.
+        class_methods: Vec<Arc<FunctionDecl>>,
+    },
+    // `var (a, b) = list;`, declaring a variable for each element.
+    Destructure {
+        names: Vec<Token>,
+        equals: Token,
+        initializer: Expr,
+    },
+    Expression(Expr),
+    // A missing condition is an empty `true` literal. Runs like
+    // `{ initializer; while (condition) { body; increment; } }`, with the
+    // initializer's variable in a scope of its own.
+    For {
+        initializer: Option<Box<Stmt>>,
+        condition: Expr,
+        increment: Option<Expr>,
+        body: Box<Stmt>,
+    },
+    // `for (var name in iterable) body`. Each pass binds `name` afresh.
+    ForEach {
+        name: Token,
+        keyword: Token,
+        iterable: Expr,
+        body: Box<Stmt>,
+    },
+    Function(Arc<FunctionDecl>),
+    If {
+        condition: Expr,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    // `span` is the path string's.
+    Import {
+        keyword: Token,
+        path: String,
+        span: Span,
+    },
+    Print(Expr),
+    Return {
+        keyword: Token,
+        value: Option<Expr>,
+    },
+    // Runs the body of the first case whose value equals the subject, or
+    // else the default. Control never falls through to the next case.
+    Switch {
+        subject: Expr,
+        cases: Vec<(Expr, Vec<Stmt>)>,
+        default: Option<Vec<Stmt>>,
+    },
+    Trait {
+        name: Token,
+        methods: Vec<Arc<FunctionDecl>>,
+    },
+    Throw {
+        keyword: Token,
+        value: Expr,
+    },
+    // At least one of `catch` and `finally` is present. `catch` is the
+    // name bound to what was thrown, and the handler.
+    Try {
+        body: Vec<Stmt>,
+        catch: Option<(Token, Vec<Stmt>)>,
+        finally: Option<Vec<Stmt>>,
+    },
+    Var {
+        name: Token,
+        // The type name after `:`, if any.
+        annotation: Option<Token>,
+        initializer: Option<Expr>,
+    },
+    While {
+        condition: Expr,
+        body: Box<Stmt>,
+    },
+    // Suspends the coroutine running the enclosing function, handing
+    // `value`, or nil, to the `resume` that ran it.
+    Yield {
+        keyword: Token,
+        value: Option<Expr>,
+    },
+}
+
+// How deeply statements and expressions may nest. Every phase after the
+// parser recurses over the tree too, so this bounds all of them well short
+// of overflowing the stack.
+pub const MAX_NESTING: usize = 256;
+// The most arguments a call may pass, and parameters a function declare,
+// so that the bytecode can count them in a byte.
+pub const MAX_ARGUMENTS: usize = 255;
+
+pub struct Parser {
+    tokens: Vec<Token>,
+    current: usize,
+    // How many guarded constructs enclose the one being parsed.
+    depth: usize,
+}
+impl Parser {
+    pub fn new(tokens: Vec<Token>) -> Parser {
+        Parser {
+            tokens,
+            current: 0,
+            depth: 0,
+        }
+    }
+    // Parses the whole input as one expression with no trailing `;`, for
+    // REPL lines that are not statements.
+    pub fn parse_expression(&mut self) -> Result<Expr, Diagnostic> {
+        let expr = self.expression()?;
+        if !self.is_at_end() {
+            return Err(self.error(self.peek(), "Expect end of expression."));
+        }
+        Ok(expr)
+    }
+    // Parses the whole program, recovering at statement boundaries after a
+    // syntax error so that every error in the source is reported together.
+    pub fn parse(&mut self) -> Result<Vec<Stmt>, Vec<Diagnostic>> {
+        let mut statements = Vec::new();
+        let mut errors = Vec::new();
+        while !self.is_at_end() {
+            match self.declaration() {
+                Ok(statement) => statements.push(statement),
+                Err(e) => {
+                    errors.push(e);
+                    // An error leaves the constructs it unwound through
+                    // counted.
+                    self.depth = 0;
+                    self.synchronize();
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(statements)
+        } else {
+            Err(errors)
+        }
+    }
+    // Discards tokens until the start of what is probably the next
+    // statement.
+    fn synchronize(&mut self) {
+        self.advance();
+        while !self.is_at_end() {
+            if self.previous().tty == TokenType::Semicolon {
+                return;
+            }
+            match self.peek().tty {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return
+                | TokenType::Import
+                | TokenType::Trait
+                | TokenType::Throw
+                | TokenType::Try
+                | TokenType::Switch
+                | TokenType::Yield
+                | TokenType::Do => return,
+                _ => {
+                    self.advance();
+                }
+            }
+        }
+    }
+    fn declaration(&mut self) -> Result<Stmt, Diagnostic> {
+        if self.matches(&[TokenType::Class]) {
+            return self.class_declaration();
+        }
+        if self.matches(&[TokenType::Trait]) {
+            return self.trait_declaration();
+        }
+        if self.matches(&[TokenType::Fun]) {
+            return Ok(Stmt::Function(Arc::new(self.function("function")?)));
+        }
+        if self.matches(&[TokenType::Var]) {
+            return self.var_declaration();
+        }
+        if self.matches(&[TokenType::Import]) {
+            return self.import_declaration();
+        }
+        self.statement()
+    }
+    fn class_declaration(&mut self) -> Result<Stmt, Diagnostic> {
+        let name = self
+            .consume(TokenType::Identifier, "Expect class name.")?
+            .clone();
+        let superclass = if self.matches(&[TokenType::Less]) {
+            let name = self
+                .consume(TokenType::Identifier, "Expect superclass name.")?
+                .clone();
+            Some(Expr::Variable {
+                id: next_expr_id(),
+                name,
+            })
+        } else {
+            None
+        };
+        let mut traits = Vec::new();
+        if self.matches(&[TokenType::With]) {
+            loop {
+                let name = self
+                    .consume(TokenType::Identifier, "Expect trait name.")?
+                    .clone();
+                traits.push(Expr::Variable {
+                    id: next_expr_id(),
+                    name,
+                });
+                if !self.matches(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.")?;
+        let mut methods = Vec::new();
+        let mut class_methods = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            if self.matches(&[TokenType::Class]) {
+                class_methods.push(Arc::new(self.method()?));
+            } else {
+                methods.push(Arc::new(self.method()?));
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.")?;
+        Ok(Stmt::Class {
+            name,
+            superclass,
+            traits,
+            methods,
+            class_methods,
+        })
+    }
+    fn trait_declaration(&mut self) -> Result<Stmt, Diagnostic> {
+        let name = self
+            .consume(TokenType::Identifier, "Expect trait name.")?
+            .clone();
+        self.consume(TokenType::LeftBrace, "Expect '{' before trait body.")?;
+        let mut methods = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            methods.push(Arc::new(self.method()?));
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after trait body.")?;
+        Ok(Stmt::Trait { name, methods })
+    }
+    fn function(&mut self, kind: &str) -> Result<FunctionDecl, Diagnostic> {
+        let name = self
+            .consume(TokenType::Identifier, &format!("Expect {} name.", kind))?
+            .clone();
+        self.consume(
+            TokenType::LeftParen,
+            &format!("Expect '(' after {} name.", kind),
+        )?;
+        self.function_body(name, kind)
+    }
+    fn method(&mut self) -> Result<FunctionDecl, Diagnostic> {
+        let name = self
+            .consume(TokenType::Identifier, "Expect method name.")?
+            .clone();
+        if self.matches(&[TokenType::LeftBrace]) {
+            return Ok(FunctionDecl {
+                name,
+                params: Vec::new(),
+                param_types: Vec::new(),
+                return_type: None,
+                defaults: Vec::new(),
+                body: self.block()?,
+                is_getter: true,
+            });
+        }
+        self.consume(TokenType::LeftParen, "Expect '(' after method name.")?;
+        self.function_body(name, "method")
+    }
+    fn function_body(&mut self, name: Token, kind: &str) -> Result<FunctionDecl, Diagnostic> {
+        let mut params = Vec::new();
+        let mut param_types = Vec::new();
+        let mut defaults: Vec<Option<Expr>> = Vec::new();
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if params.len() == MAX_ARGUMENTS {
+                    return Err(self.error(self.peek(), "Can't have more than 255 parameters."));
+                }
+                let param = self
+                    .consume(TokenType::Identifier, "Expect parameter name.")?
+                    .clone();
+                param_types.push(self.type_annotation()?);
+                if self.matches(&[TokenType::Equal]) {
+                    defaults.push(Some(self.element()?));
+                } else if defaults.iter().any(Option::is_some) {
+                    return Err(self.error(
+                        &param,
+                        "A parameter without a default value can't follow one with a default.",
+                    ));
+                } else {
+                    defaults.push(None);
+                }
+                params.push(param);
+                if !self.matches(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.")?;
+        let return_type = if self.matches(&[TokenType::Arrow]) {
+            Some(
+                self.consume(TokenType::Identifier, "Expect type name.")?
+                    .clone(),
+            )
+        } else {
+            None
+        };
+        self.consume(
+            TokenType::LeftBrace,
+            &format!("Expect '{{' before {} body.", kind),
+        )?;
+        let body = self.block()?;
+        Ok(FunctionDecl {
+            name,
+            params,
+            param_types,
+            return_type,
+            defaults,
+            body,
+            is_getter: false,
+        })
+    }
+    // The type name after a `:`, if there is one.
+    fn type_annotation(&mut self) -> Result<Option<Token>, Diagnostic> {
+        if !self.matches(&[TokenType::Colon]) {
+            return Ok(None);
+        }
+        let name = self.consume(TokenType::Identifier, "Expect type name.")?;
+        Ok(Some(name.clone()))
+    }
+    fn var_declaration(&mut self) -> Result<Stmt, Diagnostic> {
+        if self.matches(&[TokenType::LeftParen]) {
+            return self.destructure_declaration();
+        }
+        let name = self
+            .consume(TokenType::Identifier, "Expect variable name.")?
+            .clone();
+        let annotation = self.type_annotation()?;
+        let initializer = if self.matches(&[TokenType::Equal]) {
+            Some(self.expression()?)
+        } else {
+            None
+        };
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        )?;
+        Ok(Stmt::Var {
+            name,
+            annotation,
+            initializer,
+        })
+    }
+    fn destructure_declaration(&mut self) -> Result<Stmt, Diagnostic> {
+        let mut names = Vec::new();
+        loop {
+            let name = self.consume(TokenType::Identifier, "Expect variable name.")?;
+            names.push(name.clone());
+            if !self.matches(&[TokenType::Comma]) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after variable names.")?;
+        let equals = self
+            .consume(TokenType::Equal, "Expect '=' after variable names.")?
+            .clone();
+        let initializer = self.expression()?;
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        )?;
+        Ok(Stmt::Destructure {
+            names,
+            equals,
+            initializer,
+        })
+    }
+    fn import_declaration(&mut self) -> Result<Stmt, Diagnostic> {
+        let keyword = self.previous().clone();
+        let token = self.consume(TokenType::String, "Expect module path after 'import'.")?;
+        let span = token.span;
+        let path = match &token.literal {
+            Some(Literal::Str(path)) => path.clone(),
+            _ => String::new(),
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after module path.")?;
+        Ok(Stmt::Import {
+            keyword,
+            path,
+            span,
+        })
+    }
+    fn statement(&mut self) -> Result<Stmt, Diagnostic> {
+        if self.matches(&[TokenType::For]) {
+            return self.for_statement();
+        }
+        if self.matches(&[TokenType::If]) {
+            return self.if_statement();
+        }
+        if self.matches(&[TokenType::Print]) {
+            return self.print_statement();
+        }
+        if self.matches(&[TokenType::Return]) {
+            return self.return_statement();
+        }
+        if self.matches(&[TokenType::While]) {
+            return self.while_statement();
+        }
+        if self.matches(&[TokenType::Do]) {
+            return self.do_while_statement();
+        }
+        if self.matches(&[TokenType::Switch]) {
+            return self.switch_statement();
+        }
+        if self.matches(&[TokenType::Throw]) {
+            return self.throw_statement();
+        }
+        if self.matches(&[TokenType::Try]) {
+            return self.try_statement();
+        }
+        if self.matches(&[TokenType::Yield]) {
+            return self.yield_statement();
+        }
+        if self.matches(&[TokenType::LeftBrace]) {
+            return Ok(Stmt::Block(self.block()?));
+        }
+        self.expression_statement()
+    }
+    // `for` has no node of its own; it is desugared into a `while` loop
+    // wrapped in blocks for the initializer and increment.
+    fn for_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
+        if self.check(TokenType::Var) && self.check_at(2, TokenType::In) {
+            return self.for_each_statement();
+        }
+        let initializer = if self.matches(&[TokenType::Semicolon]) {
+            None
+        } else if self.matches(&[TokenType::Var]) {
+            Some(self.var_declaration()?)
+        } else {
+            Some(self.expression_statement()?)
+        };
+        let condition = if self.check(TokenType::Semicolon) {
+            let at = self.peek().span.start;
+            Expr::Literal {
+                value: LiteralValue::Bool(true),
+                span: Span::new(at, at),
+            }
+        } else {
+            self.expression()?
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after loop condition.")?;
+        let increment = if self.check(TokenType::RightParen) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.")?;
+
+        let body = self.nested(Parser::statement)?;
+        Ok(Stmt::For {
+            initializer: initializer.map(Box::new),
+            condition,
+            increment,
+            body: Box::new(body),
+        })
+    }
+    fn for_each_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        self.advance();
+        let name = self
+            .consume(TokenType::Identifier, "Expect variable name.")?
+            .clone();
+        let keyword = self.advance().clone();
+        let iterable = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after for clauses.")?;
+        let body = Box::new(self.nested(Parser::statement)?);
+        Ok(Stmt::ForEach {
+            name,
+            keyword,
+            iterable,
+            body,
+        })
+    }
+    fn if_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.")?;
+        let condition = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after if condition.")?;
+        let then_branch = Box::new(self.nested(Parser::statement)?);
+        let else_branch = if self.matches(&[TokenType::Else]) {
+            Some(Box::new(self.nested(Parser::statement)?))
+        } else {
+            None
+        };
+        Ok(Stmt::If {
+            condition,
+            then_branch,
+            else_branch,
+        })
+    }
+    fn print_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        let value = self.expression()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after value.")?;
+        Ok(Stmt::Print(value))
+    }
+    fn return_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        let keyword = self.previous().clone();
+        let value = if self.check(TokenType::Semicolon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after return value.")?;
+        Ok(Stmt::Return { keyword, value })
+    }
+    fn yield_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        let keyword = self.previous().clone();
+        let value = if self.check(TokenType::Semicolon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.consume(TokenType::Semicolon, "Expect ';' after yielded value.")?;
+        Ok(Stmt::Yield { keyword, value })
+    }
+    fn while_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
+        let condition = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
+        let body = Box::new(self.nested(Parser::statement)?);
+        Ok(Stmt::While { condition, body })
+    }
+    fn do_while_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        let body = Box::new(self.nested(Parser::statement)?);
+        self.consume(TokenType::While, "Expect 'while' after do loop body.")?;
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
+        let condition = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
+        self.consume(TokenType::Semicolon, "Expect ';' after do-while loop.")?;
+        Ok(Stmt::DoWhile { body, condition })
+    }
+    fn switch_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'switch'.")?;
+        let subject = self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after switch value.")?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before switch cases.")?;
+        let mut cases = Vec::new();
+        let mut default = None;
+        // A misplaced case is reported once the switch has been parsed, so
+        // the parser doesn't lose its place.
+        let mut misplaced = None;
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            if self.matches(&[TokenType::Case]) {
+                if default.is_some() && misplaced.is_none() {
+                    misplaced = Some(
+                        self.error(self.previous(), "Can't have a case after the default case."),
+                    );
+                }
+                let value = self.expression()?;
+                self.consume(TokenType::Colon, "Expect ':' after case value.")?;
+                cases.push((value, self.case_body()?));
+            } else if self.matches(&[TokenType::Default]) {
+                if default.is_some() && misplaced.is_none() {
+                    misplaced =
+                        Some(self.error(self.previous(), "Can't have more than one default case."));
+                }
+                self.consume(TokenType::Colon, "Expect ':' after 'default'.")?;
+                default = Some(self.case_body()?);
+            } else {
+                return Err(self.error(self.peek(), "Expect 'case' or 'default'."));
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after switch cases.")?;
+        if let Some(error) = misplaced {
+            return Err(error);
+        }
+        Ok(Stmt::Switch {
+            subject,
+            cases,
+            default,
+        })
+    }
+    // The statements up to the next case, the default or the closing brace.
+    fn case_body(&mut self) -> Result<Vec<Stmt>, Diagnostic> {
+        let mut statements = Vec::new();
+        while !self.check(TokenType::Case)
+            && !self.check(TokenType::Default)
+            && !self.check(TokenType::RightBrace)
+            && !self.is_at_end()
+        {
+            statements.push(self.nested(Parser::declaration)?);
+        }
+        Ok(statements)
+    }
+    fn throw_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        let keyword = self.previous().clone();
+        let value = self.expression()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value.")?;
+        Ok(Stmt::Throw { keyword, value })
+    }
+    fn try_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.")?;
+        let body = self.block()?;
+        let catch = if self.matches(&[TokenType::Catch]) {
+            self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.")?;
+            let name = self
+                .consume(TokenType::Identifier, "Expect exception variable name.")?
+                .clone();
+            self.consume(
+                TokenType::RightParen,
+                "Expect ')' after exception variable.",
+            )?;
+            self.consume(TokenType::LeftBrace, "Expect '{' before catch body.")?;
+            Some((name, self.block()?))
+        } else {
+            None
+        };
+        let finally = if self.matches(&[TokenType::Finally]) {
+            self.consume(TokenType::LeftBrace, "Expect '{' after 'finally'.")?;
+            Some(self.block()?)
+        } else {
+            None
+        };
+        if catch.is_none() && finally.is_none() {
+            return Err(self.error(self.peek(), "Expect 'catch' or 'finally' after try block."));
+        }
+        Ok(Stmt::Try {
+            body,
+            catch,
+            finally,
+        })
+    }
+    fn block(&mut self) -> Result<Vec<Stmt>, Diagnostic> {
+        let mut statements = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
+            statements.push(self.nested(Parser::declaration)?);
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.")?;
+        Ok(statements)
+    }
+    fn expression_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        let expr = self.expression()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.")?;
+        Ok(Stmt::Expression(expr))
+    }
+    fn expression(&mut self) -> Result<Expr, Diagnostic> {
+        self.nested(Parser::comma)
+    }
+    // An expression in a comma-separated list, where a comma ends it rather
+    // than being an operator.
+    fn element(&mut self) -> Result<Expr, Diagnostic> {
+        self.nested(Parser::assignment)
+    }
+    // `a, b` evaluates `a` for its effects and then `b`, as in C.
+    fn comma(&mut self) -> Result<Expr, Diagnostic> {
+        let mut expr = self.assignment()?;
+        while self.matches(&[TokenType::Comma]) {
+            let operator = self.previous().clone();
+            let right = self.assignment()?;
+            expr = Expr::Binary {
+                left: Box::new(expr),
+                operator,
+                right: Box::new(right),
+            };
+        }
+        Ok(expr)
+    }
+    fn assignment(&mut self) -> Result<Expr, Diagnostic> {
+        let expr = self.conditional()?;
+        if self.matches(&[TokenType::Equal]) {
+            let equals = self.previous().clone();
+            let value = self.element()?;
+            if let Expr::Grouping(targets) = expr {
+                return self.destructure(*targets, value, equals);
+            }
+            return self.assignment_target(expr, value, &equals);
+        }
+        if self.matches(&[
+            TokenType::PlusEqual,
+            TokenType::MinusEqual,
+            TokenType::StarEqual,
+            TokenType::SlashEqual,
+        ]) {
+            let equals = self.previous().clone();
+            let (tty, lexeme) = match equals.tty {
+                TokenType::PlusEqual => (TokenType::Plus, "+"),
+                TokenType::MinusEqual => (TokenType::Minus, "-"),
+                TokenType::StarEqual => (TokenType::Star, "*"),
+                _ => (TokenType::Slash, "/"),
+            };
+            let operator = Token {
+                tty,
+                lexeme: Arc::from(lexeme),
+                literal: None,
+                span: equals.span,
+            };
+            let value = self.element()?;
+            if !assignable(&expr) {
+                return Err(self.error(&equals, "Invalid assignment target."));
+            }
+            return Ok(Expr::Compound {
+                target: Box::new(expr),
+                operator,
+                value: Box::new(value),
+            });
+        }
+        Ok(expr)
+    }
+    fn assignment_target(
+        &self,
+        target: Expr,
+        value: Expr,
+        equals: &Token,
+    ) -> Result<Expr, Diagnostic> {
+        match target {
+            Expr::Variable { name, .. } => Ok(Expr::Assign {
+                id: next_expr_id(),
+                name,
+                value: Box::new(value),
+            }),
+            Expr::Get {
+                object,
+                name,
+                safe: false,
+            } => Ok(Expr::Set {
+                object,
+                name,
+                value: Box::new(value),
+            }),
+            Expr::Index {
+                object,
+                bracket,
+                index,
+            } => Ok(Expr::IndexSet {
+                object,
+                bracket,
+                index,
+                value: Box::new(value),
+            }),
+            _ => Err(self.error(equals, "Invalid assignment target.")),
+        }
+    }
+    // `(a, b) = value`, whose parenthesized targets have parsed as a comma
+    // expression. A single target in parentheses is no target at all.
+    fn destructure(&self, targets: Expr, value: Expr, equals: Token) -> Result<Expr, Diagnostic> {
+        let mut operands = Vec::new();
+        comma_operands(targets, &mut operands);
+        if operands.len() < 2 || !operands.iter().all(assignable) {
+            return Err(self.error(&equals, "Invalid assignment target."));
+        }
+        Ok(Expr::Destructure {
+            targets: operands,
+            equals,
+            value: Box::new(value),
+        })
+    }
+    // The else branch recurses into `conditional` so `?:` chains associate
+    // to the right; the then branch is delimited by `:` and may be any
+    // expression.
+    fn conditional(&mut self) -> Result<Expr, Diagnostic> {
+        let condition = self.or()?;
+        if self.matches(&[TokenType::Question]) {
+            let then_branch = self.expression()?;
+            self.consume(
+                TokenType::Colon,
+                "Expect ':' after then branch of conditional expression.",
+            )?;
+            let else_branch = self.nested(Parser::conditional)?;
+            return Ok(Expr::Conditional {
+                condition: Box::new(condition),
+                then_branch: Box::new(then_branch),
+                else_branch: Box::new(else_branch),
+            });
+        }
+        Ok(condition)
+    }
+    fn or(&mut self) -> Result<Expr, Diagnostic> {
+        let mut expr = self.and()?;
+        while self.matches(&[TokenType::Or]) {
+            let operator = self.previous().clone();
+            let right = self.and()?;
+            expr = Expr::Logical {
+                left: Box::new(expr),
+                operator,
+                right: Box::new(right),
+            };
+        }
+        Ok(expr)
+    }
+    fn and(&mut self) -> Result<Expr, Diagnostic> {
+        let mut expr = self.equality()?;
+        while self.matches(&[TokenType::And]) {
+            let operator = self.previous().clone();
+            let right = self.equality()?;
+            expr = Expr::Logical {
+                left: Box::new(expr),
+                operator,
+                right: Box::new(right),
+            };
+        }
+        Ok(expr)
+    }
+    fn equality(&mut self) -> Result<Expr, Diagnostic> {
+        let mut expr = self.comparison()?;
+        while self.matches(&[TokenType::BangEqual, TokenType::EqualEqual]) {
+            let operator = self.previous().clone();
+            let right = self.comparison()?;
+            expr = Expr::Binary {
+                left: Box::new(expr),
+                operator,
+                right: Box::new(right),
+            };
+        }
+        Ok(expr)
+    }
+    fn comparison(&mut self) -> Result<Expr, Diagnostic> {
+        let mut expr = self.range()?;
+        while self.matches(&[
+            TokenType::Greater,
+            TokenType::GreaterEqual,
+            TokenType::Less,
+            TokenType::LessEqual,
+        ]) {
+            let operator = self.previous().clone();
+            let right = self.range()?;
+            expr = Expr::Binary {
+                left: Box::new(expr),
+                operator,
+                right: Box::new(right),
+            };
+        }
+        Ok(expr)
+    }
+    // Ranges don't chain: `a..b..c` means nothing.
+    fn range(&mut self) -> Result<Expr, Diagnostic> {
+        let expr = self.term()?;
+        if self.matches(&[TokenType::DotDot, TokenType::DotDotEqual]) {
+            let operator = self.previous().clone();
+            let right = self.term()?;
+            return Ok(Expr::Binary {
+                left: Box::new(expr),
+                operator,
+                right: Box::new(right),
+            });
+        }
+        Ok(expr)
+    }
+    fn term(&mut self) -> Result<Expr, Diagnostic> {
+        let mut expr = self.factor()?;
+        while self.matches(&[TokenType::Minus, TokenType::Plus]) {
+            let operator = self.previous().clone();
+            let right = self.factor()?;
+            expr = Expr::Binary {
+                left: Box::new(expr),
+                operator,
+                right: Box::new(right),
+            };
+        }
+        Ok(expr)
+    }
+    fn factor(&mut self) -> Result<Expr, Diagnostic> {
+        let mut expr = self.unary()?;
+        while self.matches(&[TokenType::Slash, TokenType::Star, TokenType::Percent]) {
+            let operator = self.previous().clone();
+            let right = self.nested(Parser::unary)?;
+            expr = Expr::Binary {
+                left: Box::new(expr),
+                operator,
+                right: Box::new(right),
+            };
+        }
+        Ok(expr)
+    }
+    fn unary(&mut self) -> Result<Expr, Diagnostic> {
+        if self.matches(&[TokenType::Bang, TokenType::Minus]) {
+            let operator = self.previous().clone();
+            let right = self.nested(Parser::unary)?;
+            return Ok(Expr::Unary {
+                operator,
+                right: Box::new(right),
+            });
+        }
+        self.power()
+    }
+    // `**` binds tighter than unary minus on its left (`-2 ** 2` is -4) and
+    // is right-associative, so its right operand is parsed as a unary.
+    fn power(&mut self) -> Result<Expr, Diagnostic> {
+        let expr = self.update()?;
+        if self.matches(&[TokenType::StarStar]) {
+            let operator = self.previous().clone();
+            let right = self.nested(Parser::unary)?;
+            return Ok(Expr::Binary {
+                left: Box::new(expr),
+                operator,
+                right: Box::new(right),
+            });
+        }
+        Ok(expr)
+    }
+    fn update(&mut self) -> Result<Expr, Diagnostic> {
+        if self.matches(&[TokenType::PlusPlus, TokenType::MinusMinus]) {
+            let operator = self.previous().clone();
+            let target = self.nested(Parser::update)?;
+            return self.updated(target, operator, true);
+        }
+        let expr = self.call()?;
+        if self.matches(&[TokenType::PlusPlus, TokenType::MinusMinus]) {
+            let operator = self.previous().clone();
+            return self.updated(expr, operator, false);
+        }
+        Ok(expr)
+    }
+    fn updated(&self, target: Expr, operator: Token, prefix: bool) -> Result<Expr, Diagnostic> {
+        if !assignable(&target) {
+            return Err(self.error(&operator, "Invalid assignment target."));
+        }
+        Ok(Expr::Update {
+            operator,
+            prefix,
+            target: Box::new(target),
+        })
+    }
+    fn call(&mut self) -> Result<Expr, Diagnostic> {
+        let mut expr = self.primary()?;
+        loop {
+            if self.matches(&[TokenType::LeftParen]) {
+                expr = self.finish_call(expr)?;
+            } else if self.matches(&[TokenType::LeftBracket]) {
+                let bracket = self.previous().clone();
+                let index = self.expression()?;
+                self.consume(TokenType::RightBracket, "Expect ']' after index.")?;
+                expr = Expr::Index {
+                    object: Box::new(expr),
+                    bracket,
+                    index: Box::new(index),
+                };
+            } else if self.matches(&[TokenType::Dot, TokenType::QuestionDot]) {
+                let safe = self.previous().tty == TokenType::QuestionDot;
+                let message = if safe {
+                    "Expect property name after '?.'."
+                } else {
+                    "Expect property name after '.'."
+                };
+                let name = self.consume(TokenType::Identifier, message)?.clone();
+                expr = Expr::Get {
+                    object: Box::new(expr),
+                    name,
+                    safe,
+                };
+            } else {
+                break;
+            }
+        }
+        Ok(expr)
+    }
+    fn finish_call(&mut self, callee: Expr) -> Result<Expr, Diagnostic> {
+        let mut arguments = Vec::new();
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if arguments.len() == MAX_ARGUMENTS {
+                    return Err(self.error(self.peek(), "Can't have more than 255 arguments."));
+                }
+                arguments.push(self.element()?);
+                if !self.matches(&[TokenType::Comma]) {
+                    break;
+                }
+            }
+        }
+        let paren = self
+            .consume(TokenType::RightParen, "Expect ')' after arguments.")?
+            .clone();
+        Ok(Expr::Call {
+            callee: Box::new(callee),
+            paren,
+            arguments,
+        })
+    }
+    // `"a ${b} c"` scans as Interpolation("a "), b, String(" c") and becomes
+    // `"a " + str(b) + " c"`, where the stringifying step is a unary operator
+    // using the Interpolation token itself.
+    fn interpolation(&mut self) -> Result<Expr, Diagnostic> {
+        let mut expr = self.segment();
+        loop {
+            let operator = self.previous().clone();
+            let embedded = Expr::Unary {
+                operator: operator.clone(),
+                right: Box::new(self.expression()?),
+            };
+            expr = concat(expr, &operator, embedded);
+            let done = !self.matches(&[TokenType::Interpolation]);
+            if done {
+                self.consume(
+                    TokenType::String,
+                    "Expect '}' after interpolated expression.",
+                )?;
+            }
+            expr = concat(expr, &operator, self.segment());
+            if done {
+                return Ok(expr);
+            }
+        }
+    }
+    fn segment(&self) -> Expr {
+        let value = match &self.previous().literal {
+            Some(Literal::Str(s)) => s.clone(),
+            _ => String::new(),
+        };
+        self.literal(LiteralValue::Str(value))
+    }
+    // A literal located at the token just consumed.
+    fn literal(&self, value: LiteralValue) -> Expr {
+        Expr::Literal {
+            value,
+            span: self.previous().span,
+        }
+    }
+    fn primary(&mut self) -> Result<Expr, Diagnostic> {
+        if self.matches(&[TokenType::False]) {
+            return Ok(self.literal(LiteralValue::Bool(false)));
+        }
+        if self.matches(&[TokenType::True]) {
+            return Ok(self.literal(LiteralValue::Bool(true)));
+        }
+        if self.matches(&[TokenType::Nil]) {
+            return Ok(self.literal(LiteralValue::Nil));
+        }
+        if self.matches(&[TokenType::Number, TokenType::String]) {
+            let value = match &self.previous().literal {
+                Some(Literal::Number(n)) => LiteralValue::Number(*n),
+                Some(Literal::Str(s)) => LiteralValue::Str(s.clone()),
+                _ => return Err(self.error(self.previous(), "Malformed literal.")),
+            };
+            return Ok(self.literal(value));
+        }
+        if self.matches(&[TokenType::Interpolation]) {
+            return self.interpolation();
+        }
+        if self.matches(&[TokenType::Super]) {
+            let keyword = self.previous().clone();
+            self.consume(TokenType::Dot, "Expect '.' after 'super'.")?;
+            let method = self
+                .consume(TokenType::Identifier, "Expect superclass method name.")?
+                .clone();
+            return Ok(Expr::Super {
+                id: next_expr_id(),
+                keyword,
+                method,
+            });
+        }
+        if self.matches(&[TokenType::This]) {
+            return Ok(Expr::This {
+                id: next_expr_id(),
+                keyword: self.previous().clone(),
+            });
+        }
+        if self.matches(&[TokenType::Identifier]) {
+            return Ok(Expr::Variable {
+                id: next_expr_id(),
+                name: self.previous().clone(),
+            });
+        }
+        if self.matches(&[TokenType::Lambda]) {
+            let keyword = self.previous().clone();
+            self.consume(TokenType::LeftParen, "Expect '(' after 'lambda'.")?;
+            return Ok(Expr::Lambda(Arc::new(
+                self.function_body(keyword, "lambda")?,
+            )));
+        }
+        if self.matches(&[TokenType::LeftBracket]) {
+            let mut elements = Vec::new();
+            if !self.check(TokenType::RightBracket) {
+                loop {
+                    elements.push(self.element()?);
+                    if !self.matches(&[TokenType::Comma]) {
+                        break;
+                    }
+                }
+            }
+            self.consume(TokenType::RightBracket, "Expect ']' after list elements.")?;
+            return Ok(Expr::List(elements));
+        }
+        if self.matches(&[TokenType::LeftBrace]) {
+            let brace = self.previous().clone();
+            let mut entries = Vec::new();
+            if !self.check(TokenType::RightBrace) {
+                loop {
+                    let key = self.element()?;
+                    self.consume(TokenType::Colon, "Expect ':' after map key.")?;
+                    let value = self.element()?;
+                    entries.push((key, value));
+                    if !self.matches(&[TokenType::Comma]) {
+                        break;
+                    }
+                }
+            }
+            self.consume(TokenType::RightBrace, "Expect '}' after map entries.")?;
+            return Ok(Expr::Map { brace, entries });
+        }
+        if self.matches(&[TokenType::LeftParen]) {
+            let expr = self.expression()?;
+            self.consume(TokenType::RightParen, "Expect ')' after expression.")?;
+            return Ok(Expr::Grouping(Box::new(expr)));
+        }
+        Err(self.error(self.peek(), "Expect expression."))
+    }
+    // Parses with `parse`, failing instead if that would nest too deeply.
+    fn nested<T>(
+        &mut self,
+        parse: fn(&mut Parser) -> Result<T, Diagnostic>,
+    ) -> Result<T, Diagnostic> {
+        if self.depth == MAX_NESTING {
+            return Err(self.error(self.peek(), "Too much nesting."));
+        }
+        self.depth += 1;
+        let result = parse(self)?;
+        self.depth -= 1;
+        Ok(result)
+    }
+    fn matches(&mut self, types: &[TokenType]) -> bool {
+        if types.iter().any(|tty| self.check(*tty)) {
+            self.advance();
+            return true;
+        }
+        false
+    }
+    fn consume(&mut self, tty: TokenType, message: &str) -> Result<&Token, Diagnostic> {
+        if self.check(tty) {
+            return Ok(self.advance());
+        }
+        Err(self.error(self.peek(), message))
+    }
+    fn check(&self, tty: TokenType) -> bool {
+        !self.is_at_end() && self.peek().tty == tty
+    }
+    // Whether the token `distance` past the current one has type `tty`.
+    fn check_at(&self, distance: usize, tty: TokenType) -> bool {
+        self.tokens
+            .get(self.current + distance)
+            .is_some_and(|token| token.tty == tty)
+    }
+    fn advance(&mut self) -> &Token {
+        if !self.is_at_end() {
+            self.current += 1;
+        }
+        self.previous()
+    }
+    fn is_at_end(&self) -> bool {
+        self.peek().tty == TokenType::Eof
+    }
+    fn peek(&self) -> &Token {
+        &self.tokens[self.current]
+    }
+    fn previous(&self) -> &Token {
+        &self.tokens[self.current - 1]
+    }
+    fn error(&self, token: &Token, message: &str) -> Diagnostic {
+        Diagnostic::at_token(token, message)
+    }
+}
+
+// Joins two already-string operands with a `+` positioned at `at`.
+fn concat(left: Expr, at: &Token, right: Expr) -> Expr {
+    Expr::Binary {
+        left: Box::new(left),
+        operator: Token {
+            tty: TokenType::Plus,
+            lexeme: Arc::from("+"),
+            literal: None,
+            ..at.clone()
+        },
+        right: Box::new(right),
+    }
+}
+
+// Whether `expr` names somewhere a value can be stored.
+fn assignable(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::Variable { .. } | Expr::Get { safe: false, .. } | Expr::Index { .. }
+    )
+}
+
+// The `+` or `-` an update's `++` or `--` applies to its target and 1.
+pub fn update_operator(operator: &Token) -> Token {
+    let (tty, lexeme) = match operator.tty {
+        TokenType::PlusPlus => (TokenType::Plus, "+"),
+        _ => (TokenType::Minus, "-"),
+    };
+    Token {
+        tty,
+        lexeme: Arc::from(lexeme),
+        literal: None,
+        span: operator.span,
+    }
+}
+
+// Collects the operands of `a, b, c` in order.
+fn comma_operands(expr: Expr, operands: &mut Vec<Expr>) {
+    match expr {
+        Expr::Binary {
+            left,
+            operator,
+            right,
+        } if operator.tty == TokenType::Comma => {
+            comma_operands(*left, operands);
+            operands.push(*right);
+        }
+        expr => operands.push(expr),
+    }
+}
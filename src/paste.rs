@@ -0,0 +1,95 @@
+use std::io::{self, BufRead, Write};
+
+// Terminals in bracketed paste mode wrap whatever is pasted in these, so
+// that a pasted program can run all at once rather than line by line.
+// `:paste` does the same by hand for terminals without the mode, reading
+// up to a line of `:end` instead of the closing marker.
+const ON: &str = "\x1b[?2004h";
+const OFF: &str = "\x1b[?2004l";
+const START: &str = "\x1b[200~";
+const END: &str = "\x1b[201~";
+
+// Keeps the terminal in bracketed paste mode while it lives. The mode is
+// turned off again however the prompt ends: by dropping the guard when it
+// returns, even with an error, or from a SIGINT handler for Ctrl-C, which
+// exits without unwinding.
+pub struct BracketedPaste;
+impl BracketedPaste {
+    pub fn enable() -> BracketedPaste {
+        print!("{}", ON);
+        interrupt::catch();
+        BracketedPaste
+    }
+}
+impl Drop for BracketedPaste {
+    fn drop(&mut self) {
+        interrupt::release();
+        print!("{}", OFF);
+        let _ = io::stdout().flush();
+    }
+}
+
+// Whether `line` begins a paste.
+pub fn starts(line: &str) -> bool {
+    line.contains(START)
+}
+
+// Reads on from `line`, which starts a paste, to the end of it, returning
+// what was pasted without the markers.
+pub fn read(reader: &mut impl BufRead, mut line: String) -> io::Result<String> {
+    while !line.contains(END) {
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+    }
+    Ok(strip(&line))
+}
+
+// Removes any paste markers from `line`.
+pub fn strip(line: &str) -> String {
+    line.replace(START, "").replace(END, "")
+}
+
+#[cfg(unix)]
+mod interrupt {
+    use std::os::raw::c_int;
+
+    const SIGINT: c_int = 2;
+    const SIG_DFL: usize = 0;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+        fn write(fd: c_int, buf: *const u8, count: usize) -> isize;
+        fn _exit(status: c_int) -> !;
+    }
+
+    // Only async-signal-safe calls may be made here, so the mode is
+    // turned off with a raw write, and the exit status is the one the
+    // signal would have given.
+    extern "C" fn turn_off_and_exit(_: c_int) {
+        let off = super::OFF.as_bytes();
+        unsafe {
+            write(1, off.as_ptr(), off.len());
+            _exit(128 + SIGINT);
+        }
+    }
+
+    pub fn catch() {
+        let handler: extern "C" fn(c_int) = turn_off_and_exit;
+        unsafe {
+            signal(SIGINT, handler as usize);
+        }
+    }
+
+    pub fn release() {
+        unsafe {
+            signal(SIGINT, SIG_DFL);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod interrupt {
+    pub fn catch() {}
+    pub fn release() {}
+}
@@ -0,0 +1,173 @@
+use crate::environment::Environment;
+use crate::interpreter::DebugHook;
+use crate::span::Span;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// Times each Lox function the entry script calls, installed as the
+// interpreter's debug hook. Clones share their timings, so one can be
+// handed to the interpreter and another report once the run is over.
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    state: Rc<RefCell<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    started: Instant,
+    // The calls in progress, innermost last.
+    calls: Vec<Call>,
+    // Time spent in calls made from the top level of the script.
+    in_calls: Duration,
+    functions: HashMap<String, FunctionTime>,
+}
+
+#[derive(Debug)]
+struct Call {
+    function: String,
+    started: Instant,
+    in_callees: Duration,
+}
+
+// What one function cost over the run. Self time leaves out the functions
+// it called; total time counts a recursive function's outermost calls only.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FunctionTime {
+    pub calls: u64,
+    pub self_time: Duration,
+    pub total_time: Duration,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            state: Rc::new(RefCell::new(State {
+                started: Instant::now(),
+                calls: Vec::new(),
+                in_calls: Duration::ZERO,
+                functions: HashMap::new(),
+            })),
+        }
+    }
+    // Each function called so far, most self time first. The top level of
+    // the script is listed as `<script>`.
+    pub fn functions(&self) -> Vec<(String, FunctionTime)> {
+        let state = self.state.borrow();
+        let elapsed = state.started.elapsed();
+        let mut functions: Vec<_> = state
+            .functions
+            .iter()
+            .map(|(name, time)| (name.clone(), *time))
+            .collect();
+        functions.push((
+            "<script>".to_string(),
+            FunctionTime {
+                calls: 1,
+                self_time: elapsed.saturating_sub(state.in_calls),
+                total_time: elapsed,
+            },
+        ));
+        functions.sort_by(|a, b| b.1.self_time.cmp(&a.1.self_time).then(a.0.cmp(&b.0)));
+        functions
+    }
+    pub fn report(&self) -> String {
+        let functions = self.functions();
+        let elapsed = self.state.borrow().started.elapsed();
+        let rows = functions
+            .into_iter()
+            .map(|(name, time)| {
+                vec![
+                    name,
+                    time.calls.to_string(),
+                    millis(time.self_time),
+                    percent(time.self_time, elapsed),
+                    millis(time.total_time),
+                ]
+            })
+            .collect();
+        table(
+            &["Function", "Calls", "Self ms", "Self %", "Total ms"],
+            rows,
+        )
+    }
+}
+impl DebugHook for Profiler {
+    fn statement(
+        &mut self,
+        _span: Span,
+        _depth: usize,
+        _scope: &Rc<RefCell<Environment>>,
+    ) -> Result<(), String> {
+        Ok(())
+    }
+    fn call(&mut self, function: &str, _call_site: Span) {
+        self.state.borrow_mut().calls.push(Call {
+            function: function.to_string(),
+            started: Instant::now(),
+            in_callees: Duration::ZERO,
+        });
+    }
+    fn ret(&mut self) {
+        let mut state = self.state.borrow_mut();
+        let Some(call) = state.calls.pop() else {
+            return;
+        };
+        let elapsed = call.started.elapsed();
+        match state.calls.last_mut() {
+            Some(caller) => caller.in_callees += elapsed,
+            None => state.in_calls += elapsed,
+        }
+        let outermost = !state.calls.iter().any(|c| c.function == call.function);
+        let time = state.functions.entry(call.function).or_default();
+        time.calls += 1;
+        time.self_time += elapsed.saturating_sub(call.in_callees);
+        if outermost {
+            time.total_time += elapsed;
+        }
+    }
+}
+
+pub(crate) fn millis(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64() * 1000.0)
+}
+
+pub(crate) fn percent(part: Duration, whole: Duration) -> String {
+    if whole.is_zero() {
+        return "0.0".to_string();
+    }
+    format!("{:.1}", part.as_secs_f64() * 100.0 / whole.as_secs_f64())
+}
+
+// Lines up `rows` under `headers`, the first column to the left and the
+// rest, being numbers, to the right.
+pub(crate) fn table(headers: &[&str], rows: Vec<Vec<String>>) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|header| header.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let headers = headers.iter().map(|header| header.to_string()).collect();
+    let mut out = String::new();
+    for row in std::iter::once(headers).chain(rows) {
+        let mut line = String::new();
+        for (i, (cell, width)) in row.iter().zip(&widths).enumerate() {
+            if i == 0 {
+                let _ = write!(line, "{:<width$}", cell, width = width);
+            } else {
+                let _ = write!(line, "  {:>width$}", cell, width = width);
+            }
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
@@ -0,0 +1,511 @@
+use crate::error::Diagnostic;
+use crate::interpreter::{trait_conflict, Interpreter};
+use crate::parser::{Expr, FunctionDecl, Stmt};
+use crate::scanner::Token;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    None,
+    Function,
+    Initializer,
+    Method,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ClassType {
+    None,
+    Class,
+    Subclass,
+    Trait,
+}
+
+// Walks the AST once before execution and tells the interpreter how many
+// scopes separate each local variable use from its declaration, so that
+// closures keep referring to the binding that was visible where they were
+// written.
+pub struct Resolver<'a> {
+    interpreter: &'a mut Interpreter,
+    // Innermost scope last. The flag records whether the variable's
+    // initializer has finished resolving.
+    scopes: Vec<HashMap<String, bool>>,
+    current_function: FunctionType,
+    current_class: ClassType,
+    // The method names of each trait declared so far, to catch traits that
+    // clash before the program runs. Traits from elsewhere, such as an
+    // import, are checked when the class is created instead.
+    traits: HashMap<String, Vec<String>>,
+}
+impl<'a> Resolver<'a> {
+    pub fn new(interpreter: &'a mut Interpreter) -> Resolver<'a> {
+        Resolver {
+            interpreter,
+            scopes: Vec::new(),
+            current_function: FunctionType::None,
+            current_class: ClassType::None,
+            traits: HashMap::new(),
+        }
+    }
+    pub fn resolve(&mut self, statements: &[Stmt]) -> Result<(), Diagnostic> {
+        for statement in statements {
+            self.resolve_stmt(statement)?;
+        }
+        Ok(())
+    }
+    fn resolve_stmt(&mut self, stmt: &Stmt) -> Result<(), Diagnostic> {
+        match stmt {
+            Stmt::Block(statements) => self.resolve_block(statements),
+            Stmt::DoWhile { body, condition } => {
+                self.resolve_stmt(body)?;
+                self.resolve_expr(condition)
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                traits,
+                methods,
+                class_methods,
+            } => {
+                self.declare(name)?;
+                self.define(name);
+                for used in traits {
+                    self.resolve_expr(used)?;
+                }
+                self.check_traits(traits)?;
+                let enclosing_class = self.current_class;
+                self.current_class = ClassType::Class;
+                if let Some(superclass) = superclass {
+                    if let Expr::Variable {
+                        name: superclass_name,
+                        ..
+                    } = superclass
+                    {
+                        if superclass_name.lexeme == name.lexeme {
+                            self.current_class = enclosing_class;
+                            return Err(error(
+                                superclass_name,
+                                "A class can't inherit from itself.",
+                            ));
+                        }
+                    }
+                    self.current_class = ClassType::Subclass;
+                    if let Err(e) = self.resolve_expr(superclass) {
+                        self.current_class = enclosing_class;
+                        return Err(e);
+                    }
+                    self.begin_scope();
+                    if let Some(scope) = self.scopes.last_mut() {
+                        scope.insert("super".to_string(), true);
+                    }
+                }
+                self.begin_scope();
+                if let Some(scope) = self.scopes.last_mut() {
+                    scope.insert("this".to_string(), true);
+                }
+                // In a class method, `this` is the class.
+                let result = self.resolve_methods(methods).and_then(|()| {
+                    class_methods
+                        .iter()
+                        .try_for_each(|method| self.resolve_function(method, FunctionType::Method))
+                });
+                self.end_scope();
+                if superclass.is_some() {
+                    self.end_scope();
+                }
+                self.current_class = enclosing_class;
+                result
+            }
+            Stmt::Trait { name, methods } => {
+                self.declare(name)?;
+                self.define(name);
+                let enclosing_class = self.current_class;
+                self.current_class = ClassType::Trait;
+                self.begin_scope();
+                if let Some(scope) = self.scopes.last_mut() {
+                    scope.insert("this".to_string(), true);
+                }
+                let result = self.resolve_methods(methods);
+                self.end_scope();
+                self.current_class = enclosing_class;
+                let names = methods.iter().map(|method| lexeme(&method.name));
+                self.traits.insert(lexeme(name), names.collect());
+                result
+            }
+            Stmt::Destructure {
+                names, initializer, ..
+            } => {
+                for name in names {
+                    self.declare(name)?;
+                }
+                self.resolve_expr(initializer)?;
+                for name in names {
+                    self.define(name);
+                }
+                Ok(())
+            }
+            Stmt::Expression(expr) | Stmt::Print(expr) => self.resolve_expr(expr),
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+            } => {
+                if let Some(initializer) = initializer {
+                    self.begin_scope();
+                    self.resolve_stmt(initializer)?;
+                }
+                self.resolve_expr(condition)?;
+                self.resolve_stmt(body)?;
+                if let Some(increment) = increment {
+                    self.resolve_expr(increment)?;
+                }
+                if initializer.is_some() {
+                    self.end_scope();
+                }
+                Ok(())
+            }
+            Stmt::ForEach {
+                name,
+                iterable,
+                body,
+                ..
+            } => {
+                self.resolve_expr(iterable)?;
+                self.begin_scope();
+                let result = self.declare(name).and_then(|()| {
+                    self.define(name);
+                    self.resolve_stmt(body)
+                });
+                self.end_scope();
+                result
+            }
+            Stmt::Function(declaration) => {
+                self.declare(&declaration.name)?;
+                self.define(&declaration.name);
+                self.resolve_function(declaration, FunctionType::Function)
+            }
+            // A module's globals are copied into the importer's, which
+            // only makes sense where they would be visible as globals.
+            Stmt::Import { keyword, .. } => {
+                if self.scopes.is_empty() {
+                    Ok(())
+                } else {
+                    Err(error(keyword, "Can only import at the top level."))
+                }
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.resolve_expr(condition)?;
+                self.resolve_stmt(then_branch)?;
+                match else_branch {
+                    Some(else_branch) => self.resolve_stmt(else_branch),
+                    None => Ok(()),
+                }
+            }
+            Stmt::Return { keyword, value } => {
+                if self.current_function == FunctionType::None {
+                    return Err(error(keyword, "Can't return from top-level code."));
+                }
+                match value {
+                    Some(_) if self.current_function == FunctionType::Initializer => {
+                        Err(error(keyword, "Can't return a value from an initializer."))
+                    }
+                    Some(value) => self.resolve_expr(value),
+                    None => Ok(()),
+                }
+            }
+            Stmt::Switch {
+                subject,
+                cases,
+                default,
+            } => {
+                self.resolve_expr(subject)?;
+                for (value, body) in cases {
+                    self.resolve_expr(value)?;
+                    self.resolve_block(body)?;
+                }
+                match default {
+                    Some(default) => self.resolve_block(default),
+                    None => Ok(()),
+                }
+            }
+            Stmt::Throw { value, .. } => self.resolve_expr(value),
+            // The caught value is bound in the handler's own scope, as a
+            // function's parameters are in its body's.
+            Stmt::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                self.resolve_block(body)?;
+                if let Some((name, handler)) = catch {
+                    self.begin_scope();
+                    let result = self.declare(name).and_then(|()| {
+                        self.define(name);
+                        self.resolve(handler)
+                    });
+                    self.end_scope();
+                    result?;
+                }
+                match finally {
+                    Some(finally) => self.resolve_block(finally),
+                    None => Ok(()),
+                }
+            }
+            Stmt::Var {
+                name, initializer, ..
+            } => {
+                self.declare(name)?;
+                if let Some(initializer) = initializer {
+                    self.resolve_expr(initializer)?;
+                }
+                self.define(name);
+                Ok(())
+            }
+            Stmt::While { condition, body } => {
+                self.resolve_expr(condition)?;
+                self.resolve_stmt(body)
+            }
+            Stmt::Yield { keyword, value } => {
+                if self.current_function == FunctionType::None {
+                    return Err(error(keyword, "Can't yield from top-level code."));
+                }
+                match value {
+                    Some(value) => self.resolve_expr(value),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+    fn resolve_expr(&mut self, expr: &Expr) -> Result<(), Diagnostic> {
+        match expr {
+            Expr::Assign { id, name, value } => {
+                self.resolve_expr(value)?;
+                self.resolve_local(*id, name);
+                Ok(())
+            }
+            Expr::Compound { target, value, .. } => {
+                self.resolve_expr(value)?;
+                self.resolve_expr(target)
+            }
+            Expr::Destructure { targets, value, .. } => {
+                self.resolve_expr(value)?;
+                for target in targets {
+                    match target {
+                        Expr::Variable { id, name } => self.resolve_local(*id, name),
+                        _ => self.resolve_expr(target)?,
+                    }
+                }
+                Ok(())
+            }
+            Expr::Binary { left, right, .. } | Expr::Logical { left, right, .. } => {
+                self.resolve_expr(left)?;
+                self.resolve_expr(right)
+            }
+            Expr::Call {
+                callee, arguments, ..
+            } => {
+                self.resolve_expr(callee)?;
+                for argument in arguments {
+                    self.resolve_expr(argument)?;
+                }
+                Ok(())
+            }
+            Expr::Conditional {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.resolve_expr(condition)?;
+                self.resolve_expr(then_branch)?;
+                self.resolve_expr(else_branch)
+            }
+            Expr::Get { object, .. } => self.resolve_expr(object),
+            Expr::Grouping(expr) => self.resolve_expr(expr),
+            Expr::Index { object, index, .. } => {
+                self.resolve_expr(object)?;
+                self.resolve_expr(index)
+            }
+            Expr::IndexSet {
+                object,
+                index,
+                value,
+                ..
+            } => {
+                self.resolve_expr(object)?;
+                self.resolve_expr(index)?;
+                self.resolve_expr(value)
+            }
+            Expr::Lambda(declaration) => self.resolve_function(declaration, FunctionType::Function),
+            Expr::List(elements) => {
+                for element in elements {
+                    self.resolve_expr(element)?;
+                }
+                Ok(())
+            }
+            Expr::Literal { .. } => Ok(()),
+            Expr::Map { entries, .. } => {
+                for (key, value) in entries {
+                    self.resolve_expr(key)?;
+                    self.resolve_expr(value)?;
+                }
+                Ok(())
+            }
+            Expr::Set { object, value, .. } => {
+                self.resolve_expr(value)?;
+                self.resolve_expr(object)
+            }
+            Expr::Super { id, keyword, .. } => {
+                match self.current_class {
+                    ClassType::None => {
+                        return Err(error(keyword, "Can't use 'super' outside of a class."))
+                    }
+                    ClassType::Class => {
+                        return Err(error(
+                            keyword,
+                            "Can't use 'super' in a class with no superclass.",
+                        ))
+                    }
+                    ClassType::Trait => {
+                        return Err(error(keyword, "Can't use 'super' in a trait."))
+                    }
+                    ClassType::Subclass => {}
+                }
+                self.resolve_local(*id, keyword);
+                Ok(())
+            }
+            Expr::This { id, keyword } => {
+                if self.current_class == ClassType::None {
+                    return Err(error(keyword, "Can't use 'this' outside of a class."));
+                }
+                self.resolve_local(*id, keyword);
+                Ok(())
+            }
+            Expr::Unary { right, .. } => self.resolve_expr(right),
+            // The target is read as well as assigned.
+            Expr::Update { target, .. } => self.resolve_expr(target),
+            Expr::Variable { id, name } => {
+                if let Some(scope) = self.scopes.last() {
+                    if scope.get(&lexeme(name)) == Some(&false) {
+                        return Err(error(
+                            name,
+                            "Can't read local variable in its own initializer.",
+                        ));
+                    }
+                }
+                self.resolve_local(*id, name);
+                Ok(())
+            }
+        }
+    }
+    fn resolve_block(&mut self, statements: &[Stmt]) -> Result<(), Diagnostic> {
+        self.begin_scope();
+        let result = self.resolve(statements);
+        self.end_scope();
+        result
+    }
+    fn resolve_methods(&mut self, methods: &[Arc<FunctionDecl>]) -> Result<(), Diagnostic> {
+        methods.iter().try_for_each(|method| {
+            let function_type = if lexeme(&method.name) == "init" {
+                FunctionType::Initializer
+            } else {
+                FunctionType::Method
+            };
+            self.resolve_function(method, function_type)
+        })
+    }
+    // Two traits a class uses can't both define a method.
+    fn check_traits(&self, traits: &[Expr]) -> Result<(), Diagnostic> {
+        let mut sources: HashMap<&str, &Token> = HashMap::new();
+        for used in traits {
+            let Expr::Variable { name, .. } = used else {
+                continue;
+            };
+            let Some(methods) = self.traits.get(&*name.lexeme) else {
+                continue;
+            };
+            for method in methods {
+                if let Some(earlier) = sources.get(&**method) {
+                    return Err(error(
+                        name,
+                        &trait_conflict(method, &earlier.lexeme, &name.lexeme),
+                    ));
+                }
+            }
+            for method in methods {
+                sources.insert(method, name);
+            }
+        }
+        Ok(())
+    }
+    fn resolve_function(
+        &mut self,
+        declaration: &FunctionDecl,
+        function_type: FunctionType,
+    ) -> Result<(), Diagnostic> {
+        let enclosing_function = self.current_function;
+        self.current_function = function_type;
+        self.begin_scope();
+        let result = declaration
+            .params
+            .iter()
+            .zip(&declaration.defaults)
+            .try_for_each(|(param, default)| {
+                self.declare(param)?;
+                if let Some(default) = default {
+                    self.resolve_expr(default)?;
+                }
+                self.define(param);
+                Ok(())
+            })
+            .and_then(|()| self.resolve(&declaration.body));
+        self.end_scope();
+        self.current_function = enclosing_function;
+        result
+    }
+    fn resolve_local(&mut self, id: usize, name: &Token) {
+        let name = lexeme(name);
+        for (depth, scope) in self.scopes.iter().rev().enumerate() {
+            if scope.contains_key(&name) {
+                self.interpreter.resolve(id, depth);
+                return;
+            }
+        }
+    }
+    fn begin_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+    fn end_scope(&mut self) {
+        self.scopes.pop();
+    }
+    fn declare(&mut self, name: &Token) -> Result<(), Diagnostic> {
+        let Some(scope) = self.scopes.last_mut() else {
+            return Ok(());
+        };
+        if scope.contains_key(&lexeme(name)) {
+            return Err(error(
+                name,
+                "Already a variable with this name in this scope.",
+            ));
+        }
+        scope.insert(lexeme(name), false);
+        Ok(())
+    }
+    fn define(&mut self, name: &Token) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(lexeme(name), true);
+        }
+    }
+}
+
+fn lexeme(token: &Token) -> String {
+    token.lexeme.to_string()
+}
+
+fn error(token: &Token, message: &str) -> Diagnostic {
+    Diagnostic::at_token(token, message)
+}
@@ -1,6 +1,9 @@
-use std::collections::HashMap;
+use crate::error::Diagnostic;
+use crate::span::Span;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::{self};
-//reimplment using iterators
+use std::mem;
+use std::sync::Arc;
 #[derive(Eq, PartialEq, Debug, Copy, Clone)]
 pub enum TokenType {
     // Single-character tokens.
@@ -11,14 +14,25 @@ pub enum TokenType {
     LeftBracket,
     RightBracket,
     Comma,
+    Colon,
     Dot,
     Minus,
+    Percent,
     Plus,
+    Question,
     Semicolon,
     Slash,
     Star,
 
     // One or two character tokens.
+    Arrow,
+    MinusEqual,
+    MinusMinus,
+    PlusEqual,
+    PlusPlus,
+    SlashEqual,
+    StarEqual,
+    StarStar,
     Bang,
     BangEqual,
     Equal,
@@ -27,10 +41,16 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    DotDot,
+    DotDotEqual,
+    QuestionDot,
 
     // Literals.
     Identifier,
     String,
+    // A string segment that ends in `${`; the embedded expression's tokens
+    // follow it.
+    Interpolation,
     Number,
 
     // Keywords.
@@ -51,52 +71,118 @@ pub enum TokenType {
     Var,
     While,
     Lambda,
+    Import,
+    Throw,
+    Try,
+    Catch,
+    Finally,
+    Switch,
+    Case,
+    Default,
+    Do,
+    In,
+    Trait,
+    With,
+    Yield,
 
     Eof,
 }
+// Characters that commonly sneak in when code is pasted from web pages or
+// word processors, with the ASCII the user almost certainly meant. An empty
+// suggestion means the character should simply be deleted.
+const CONFUSABLES: &[(char, &str, &str)] = &[
+    ('\u{201C}', "LEFT DOUBLE QUOTATION MARK", "\""),
+    ('\u{201D}', "RIGHT DOUBLE QUOTATION MARK", "\""),
+    ('\u{201E}', "DOUBLE LOW-9 QUOTATION MARK", "\""),
+    (
+        '\u{00AB}',
+        "LEFT-POINTING DOUBLE ANGLE QUOTATION MARK",
+        "\"",
+    ),
+    (
+        '\u{00BB}',
+        "RIGHT-POINTING DOUBLE ANGLE QUOTATION MARK",
+        "\"",
+    ),
+    ('\u{2018}', "LEFT SINGLE QUOTATION MARK", "\""),
+    ('\u{2019}', "RIGHT SINGLE QUOTATION MARK", "\""),
+    ('\u{00A0}', "NO-BREAK SPACE", " "),
+    ('\u{2007}', "FIGURE SPACE", " "),
+    ('\u{202F}', "NARROW NO-BREAK SPACE", " "),
+    ('\u{3000}', "IDEOGRAPHIC SPACE", " "),
+    ('\u{200B}', "ZERO WIDTH SPACE", ""),
+    ('\u{FEFF}', "ZERO WIDTH NO-BREAK SPACE", ""),
+    ('\u{2212}', "MINUS SIGN", "-"),
+    ('\u{2010}', "HYPHEN", "-"),
+    ('\u{2013}', "EN DASH", "-"),
+    ('\u{2014}', "EM DASH", "-"),
+    ('\u{00D7}', "MULTIPLICATION SIGN", "*"),
+    ('\u{00F7}', "DIVISION SIGN", "/"),
+    ('\u{2215}', "DIVISION SLASH", "/"),
+    ('\u{037E}', "GREEK QUESTION MARK", ";"),
+    ('\u{FF1B}', "FULLWIDTH SEMICOLON", ";"),
+    ('\u{FF0C}', "FULLWIDTH COMMA", ","),
+    ('\u{FF08}', "FULLWIDTH LEFT PARENTHESIS", "("),
+    ('\u{FF09}', "FULLWIDTH RIGHT PARENTHESIS", ")"),
+    ('\u{2260}', "NOT EQUAL TO", "!="),
+    ('\u{2264}', "LESS-THAN OR EQUAL TO", "<="),
+    ('\u{2265}', "GREATER-THAN OR EQUAL TO", ">="),
+];
+
 #[derive(Debug, Clone)]
 pub enum Literal {
     Identifier(String),
     Str(String),
     Number(f64),
 }
+// Tokens are cloned freely by the parser and AST, so the lexeme is a shared
+// string: cloning a token never copies its text. It is an `Arc` so that
+// imported modules can be parsed on other threads.
 #[derive(Clone)]
 pub struct Token {
     pub tty: TokenType,
-    pub lexeme: Vec<u8>,
+    pub lexeme: Arc<str>,
     pub literal: Option<Literal>,
-    pub line: usize,
-    pub col: i64,
+    pub span: Span,
 }
 impl fmt::Debug for Token {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "Token {{ ty: {:?}, lexeme: \"{}\", literal: {:?}, line: {:?}, col: {:?}}}",
-            self.tty,
-            String::from_utf8(self.lexeme.clone()).unwrap(),
-            self.literal,
-            self.line,
-            self.col
+            "Token {{ ty: {:?}, lexeme: \"{}\", literal: {:?}, span: {:?}}}",
+            self.tty, self.lexeme, self.literal, self.span
         )
     }
 }
-#[derive(Debug)]
-pub struct ScannerError {
-    pub what: String,
-    pub line: usize,
-    pub col: i64,
-}
 
 pub struct Scanner {
     pub source: Vec<u8>,
     pub tokens: Vec<Token>,
-    pub err: Option<ScannerError>,
+    pub errors: Vec<Diagnostic>,
     pub start: usize,
     pub current: usize,
-    pub line: usize,
-    pub col: i64,
     pub keywords: HashMap<String, TokenType>,
+    // One entry per `${` still open, counting the unmatched `{` inside it so
+    // the `}` that resumes the string can be told apart from a block's.
+    interpolations: Vec<usize>,
+    // Tokens and errors scanned but not yet handed out by `next`, and
+    // whether the final Eof has been queued.
+    pending: VecDeque<Result<Token, Diagnostic>>,
+    finished: bool,
+    unterminated_string: bool,
+    // Set by `with_trivia`: the comments and whitespace since the last
+    // token, and those before each token in `pending`.
+    keep_trivia: bool,
+    trivia: Vec<Trivia>,
+    leading: VecDeque<Vec<Trivia>>,
+    // Every distinct lexeme seen so far, so repeated names and operators
+    // share one allocation.
+    lexemes: HashSet<Arc<str>>,
+}
+impl Default for Scanner {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl Scanner {
     pub fn new() -> Scanner {
@@ -105,9 +191,15 @@ impl Scanner {
             tokens: Vec::new(),
             current: 0,
             start: 0,
-            err: None,
-            line: 1,
-            col: -1,
+            errors: Vec::new(),
+            interpolations: Vec::new(),
+            pending: VecDeque::new(),
+            finished: false,
+            unterminated_string: false,
+            keep_trivia: false,
+            trivia: Vec::new(),
+            leading: VecDeque::new(),
+            lexemes: HashSet::new(),
             keywords: vec![
                 ("and", TokenType::And),
                 ("class", TokenType::Class),
@@ -126,52 +218,162 @@ impl Scanner {
                 ("var", TokenType::Var),
                 ("while", TokenType::While),
                 ("lambda", TokenType::Lambda),
+                ("import", TokenType::Import),
+                ("throw", TokenType::Throw),
+                ("try", TokenType::Try),
+                ("catch", TokenType::Catch),
+                ("finally", TokenType::Finally),
+                ("switch", TokenType::Switch),
+                ("case", TokenType::Case),
+                ("default", TokenType::Default),
+                ("do", TokenType::Do),
+                ("in", TokenType::In),
+                ("trait", TokenType::Trait),
+                ("with", TokenType::With),
+                ("yield", TokenType::Yield),
             ]
             .into_iter()
             .map(|(k, v)| (String::from(k), v))
             .collect(),
         }
     }
+    // A scanner ready to be iterated over `source` one token at a time.
+    pub fn from_source(source: &str) -> Scanner {
+        let mut scanner = Scanner::new();
+        scanner.source = source.as_bytes().to_vec();
+        scanner
+    }
+    // Like `from_source`, but keeps comments and whitespace, attached to
+    // the token after them, for tools that print source back out.
+    pub fn with_trivia(source: &str) -> TriviaScanner {
+        let mut scanner = Scanner::from_source(source);
+        scanner.keep_trivia = true;
+        TriviaScanner { scanner }
+    }
+    // Scans all of `input` at once into `tokens` and `errors`. Errors are
+    // collected rather than fatal so that every problem in the source is
+    // reported in one pass.
     pub fn scan_tokens(&mut self, input: String) {
         self.source = input.into_bytes();
-        while !self.err.is_some() && !self.is_at_end() {
-            self.start = self.current;
-            self.scan_token();
-        }
-        match self.err {
-            Some(_) => {}
-            None => self.tokens.push(Token {
-                tty: TokenType::Eof,
-                lexeme: Vec::new(),
-                literal: None,
-                line: self.line,
-                col: self.col,
-            }),
+        while let Some(item) = self.next() {
+            match item {
+                Ok(token) => self.tokens.push(token),
+                Err(e) => self.errors.push(e),
+            }
         }
     }
     fn is_at_end(&self) -> bool {
-        return self.current >= self.source.len();
+        self.current >= self.source.len()
     }
+    // Source is kept as bytes so lexemes can be sliced out directly, but is
+    // walked a whole UTF-8 character at a time.
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.col += 1;
-        char::from(self.source[self.current - 1])
+        let (c, width) = self.char_at(self.current);
+        self.current += width;
+        c
+    }
+    // Decodes the character starting at byte `at`, returning it with its
+    // encoded width. `source` is public and may have been filled with
+    // arbitrary bytes, so anything malformed decodes as U+FFFD one byte at a
+    // time.
+    fn char_at(&self, at: usize) -> (char, usize) {
+        let Some(&lead) = self.source.get(at) else {
+            return ('\0', 0);
+        };
+        let width = match lead {
+            0x00..=0x7F => return (char::from(lead), 1),
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF7 => 4,
+            _ => 1,
+        };
+        let end = (at + width).min(self.source.len());
+        match std::str::from_utf8(&self.source[at..end]) {
+            Ok(text) => match text.chars().next() {
+                Some(c) => (c, c.len_utf8()),
+                None => (char::REPLACEMENT_CHARACTER, 1),
+            },
+            Err(_) => (char::REPLACEMENT_CHARACTER, 1),
+        }
     }
     fn scan_token(&mut self) {
         let c = self.advance();
         match c {
             '(' => self.add_token(TokenType::LeftParen),
             ')' => self.add_token(TokenType::RightParen),
-            '{' => self.add_token(TokenType::LeftBrace),
-            '}' => self.add_token(TokenType::RightBrace),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.add_token(TokenType::LeftBrace)
+            }
+            '}' => match self.interpolations.last_mut() {
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.string()
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    self.add_token(TokenType::RightBrace)
+                }
+                None => self.add_token(TokenType::RightBrace),
+            },
             '[' => self.add_token(TokenType::LeftBracket),
             ']' => self.add_token(TokenType::RightBracket),
             ',' => self.add_token(TokenType::Comma),
-            '.' => self.add_token(TokenType::Dot),
-            '-' => self.add_token(TokenType::Minus),
-            '+' => self.add_token(TokenType::Plus),
+            ':' => self.add_token(TokenType::Colon),
+            '.' => {
+                let tty = if !self.matches('.') {
+                    TokenType::Dot
+                } else if self.matches('=') {
+                    TokenType::DotDotEqual
+                } else {
+                    TokenType::DotDot
+                };
+                self.add_token(tty)
+            }
+            '-' => {
+                let tty = if self.matches('>') {
+                    TokenType::Arrow
+                } else if self.matches('=') {
+                    TokenType::MinusEqual
+                } else if self.matches('-') {
+                    TokenType::MinusMinus
+                } else {
+                    TokenType::Minus
+                };
+                self.add_token(tty)
+            }
+            '+' => {
+                let tty = if self.matches('=') {
+                    TokenType::PlusEqual
+                } else if self.matches('+') {
+                    TokenType::PlusPlus
+                } else {
+                    TokenType::Plus
+                };
+                self.add_token(tty)
+            }
+            '?' => {
+                let tty = if self.matches('.') {
+                    TokenType::QuestionDot
+                } else {
+                    TokenType::Question
+                };
+                self.add_token(tty)
+            }
             ';' => self.add_token(TokenType::Semicolon),
-            '*' => self.add_token(TokenType::Star),
+            '%' => self.add_token(TokenType::Percent),
+            '*' => {
+                let tty = if self.matches('*') {
+                    TokenType::StarStar
+                } else if self.matches('=') {
+                    TokenType::StarEqual
+                } else {
+                    TokenType::Star
+                };
+                self.add_token(tty)
+            }
             '!' => {
                 let matches_eq = self.matches('=');
                 self.add_token(if matches_eq {
@@ -209,56 +411,128 @@ impl Scanner {
                     while self.peek() != '\n' && !self.is_at_end() {
                         self.advance();
                     }
+                    if self.keep_trivia {
+                        while self.source[self.current - 1] == b'\r' {
+                            self.current -= 1;
+                        }
+                        self.add_trivia(TriviaKind::Comment);
+                    }
+                } else if self.matches('=') {
+                    self.add_token(TokenType::SlashEqual)
                 } else {
                     self.add_token(TokenType::Slash)
                 }
             }
-            ' ' | '\r' | '\t' => {}
-            '\n' => {
-                self.line += 1;
-                self.col = 0
+            ' ' | '\r' | '\t' | '\n' => {
+                if self.keep_trivia {
+                    while matches!(self.peek(), ' ' | '\r' | '\t' | '\n') {
+                        self.advance();
+                    }
+                    self.add_trivia(TriviaKind::Whitespace);
+                }
             }
 
             '"' => self.string(),
             _ => {
                 if c.is_ascii_digit() {
                     self.number()
-                } else if c.is_alphabetic() {
+                } else if is_identifier_start(c) {
                     self.identifier()
                 } else {
-                    self.err = Some(ScannerError {
-                        what: format!("Unexpected character at {}", c),
-                        line: self.line,
-                        col: self.col,
-                    })
+                    self.unexpected_character(c)
                 }
             }
         }
     }
-    fn number(&mut self) {
-        while self.peek().is_ascii_digit() {
-            self.advance();
+    fn unexpected_character(&mut self, ch: char) {
+        if ch.is_ascii() {
+            self.error(&format!("Unexpected character '{}'.", ch));
+            return;
         }
-        if self.peek() == '.' && self.peek().is_ascii_digit() {
+        let what = match CONFUSABLES
+            .iter()
+            .find(|(confusable, _, _)| *confusable == ch)
+        {
+            Some((_, name, "")) => format!(
+                "Unexpected character '{}' (U+{:04X} {}); remove it.",
+                ch, ch as u32, name
+            ),
+            Some((_, name, ascii)) => format!(
+                "Unexpected character '{}' (U+{:04X} {}); did you mean '{}'?",
+                ch, ch as u32, name, ascii
+            ),
+            None => format!("Unexpected character '{}' (U+{:04X}).", ch, ch as u32),
+        };
+        self.error(&what)
+    }
+    // Decimal literals may have a fraction and an exponent (`2.5e-3`), hex
+    // literals start with `0x`, and either kind may separate digits with `_`.
+    fn number(&mut self) {
+        let hex = self.source[self.start] == b'0' && matches!(self.peek(), 'x' | 'X');
+        if hex {
             self.advance();
-            while self.peek().is_ascii_digit() {
+            self.digits(16);
+        } else {
+            self.digits(10);
+            if self.peek() == '.' && self.peek_next().is_ascii_digit() {
                 self.advance();
+                self.digits(10);
+            }
+            if matches!(self.peek(), 'e' | 'E') {
+                self.advance();
+                if matches!(self.peek(), '+' | '-') {
+                    self.advance();
+                }
+                if !self.peek().is_ascii_digit() {
+                    self.error("Expect digits after exponent.");
+                    return;
+                }
+                self.digits(10);
             }
         }
-        let val: f64 = String::from_utf8(self.source[self.start..self.current].to_vec())
-            .unwrap()
-            .parse()
-            .unwrap();
-
-        self.add_token_literal(TokenType::Number, Some(Literal::Number(val)))
+        let text = String::from_utf8_lossy(&self.source[self.start..self.current]).into_owned();
+        // Every `_` has to sit between two digits.
+        let is_digit = |b: &u8| {
+            if hex {
+                b.is_ascii_hexdigit()
+            } else {
+                b.is_ascii_digit()
+            }
+        };
+        let bytes = text.as_bytes();
+        let misplaced = bytes.iter().enumerate().any(|(i, &b)| {
+            b == b'_'
+                && !(i > 0 && is_digit(&bytes[i - 1]) && bytes.get(i + 1).is_some_and(is_digit))
+        });
+        let digits = text.replace('_', "");
+        let value = match &digits.get(..2) {
+            _ if misplaced => None,
+            Some("0x" | "0X") if digits.len() > 2 => Some(
+                digits[2..]
+                    .chars()
+                    .filter_map(|c| c.to_digit(16))
+                    .fold(0.0, |n, d| n * 16.0 + d as f64),
+            ),
+            Some("0x" | "0X") => None,
+            _ => digits.parse().ok(),
+        };
+        match value {
+            Some(val) => self.add_token_literal(TokenType::Number, Some(Literal::Number(val))),
+            None => self.error(&format!("Invalid number literal {}.", text)),
+        }
+    }
+    fn digits(&mut self, radix: u32) {
+        while self.peek().is_digit(radix) || self.peek() == '_' {
+            self.advance();
+        }
     }
     fn identifier(&mut self) {
-        while self.peek().is_alphanumeric() {
+        while is_identifier_continue(self.peek()) {
             self.advance();
         }
 
         let literal_val =
-            String::from_utf8(self.source[self.start..self.current].to_vec()).unwrap();
+            String::from_utf8_lossy(&self.source[self.start..self.current]).into_owned();
 
         let token_type = match self.keywords.get(&literal_val) {
             Some(kw_token_type) => *kw_token_type,
@@ -274,63 +548,254 @@ impl Scanner {
         }
     }
     fn string(&mut self) {
+        let mut value = Vec::new();
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            match self.advance() {
+                '\\' => self.escape(&mut value),
+                '$' if self.peek() == '{' => {
+                    self.advance();
+                    self.interpolations.push(0);
+                    self.add_token_literal(
+                        TokenType::Interpolation,
+                        Some(Literal::Str(String::from_utf8_lossy(&value).into_owned())),
+                    );
+                    return;
+                }
+                c => {
+                    let mut buf = [0; 4];
+                    value.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
             }
-            self.advance();
         }
         if self.is_at_end() {
-            self.err = Some(ScannerError {
-                what: "Unterminated string.".to_string(),
-                line: self.line,
-                col: self.col,
-            })
+            self.unterminated_string = true;
+            self.error("Unterminated string.");
+            return;
         }
         self.advance();
         self.add_token_literal(
             TokenType::String,
-            Some(Literal::Str(
-                String::from_utf8(self.source[self.start + 1..self.current - 1].to_vec()).unwrap(),
-            )),
+            Some(Literal::Str(String::from_utf8_lossy(&value).into_owned())),
         )
     }
-    fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            '\0'
-        } else {
-            char::from(self.source[self.current + 1])
+    // Called just after the backslash. A bad escape is reported and dropped
+    // from the string so scanning can carry on to the closing quote.
+    fn escape(&mut self, value: &mut Vec<u8>) {
+        let start = self.current - 1;
+        if self.is_at_end() || self.peek() == '\n' {
+            self.error_at(start, "Unterminated escape sequence.");
+            return;
         }
+        let escaped = match self.advance() {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            '\\' => '\\',
+            '"' => '"',
+            '$' => '$',
+            'u' => match self.unicode_escape(start) {
+                Some(c) => c,
+                None => return,
+            },
+            c => {
+                self.error_at(start, &format!("Invalid escape sequence '\\{}'.", c));
+                return;
+            }
+        };
+        let mut buf = [0; 4];
+        value.extend_from_slice(escaped.encode_utf8(&mut buf).as_bytes());
     }
-    fn peek(&mut self) -> char {
-        if self.is_at_end() {
-            '\0'
-        } else {
-            char::from(self.source[self.current])
+    // `\u{XXXX}`: one to six hex digits naming a Unicode scalar value.
+    fn unicode_escape(&mut self, start: usize) -> Option<char> {
+        if !self.matches('{') {
+            self.error_at(start, "Expect '{' after '\\u'.");
+            return None;
+        }
+        let digits_start = self.current;
+        while self.peek().is_ascii_hexdigit() {
+            self.advance();
+        }
+        let digits = String::from_utf8_lossy(&self.source[digits_start..self.current]).into_owned();
+        if !self.matches('}') {
+            self.error_at(start, "Expect '}' after unicode escape digits.");
+            return None;
+        }
+        let c = match digits.len() {
+            1..=6 => u32::from_str_radix(&digits, 16)
+                .ok()
+                .and_then(char::from_u32),
+            _ => None,
+        };
+        if c.is_none() {
+            self.error_at(
+                start,
+                &format!("Invalid unicode escape '\\u{{{}}}'.", digits),
+            );
         }
+        c
+    }
+    fn peek_next(&self) -> char {
+        let (_, width) = self.char_at(self.current);
+        self.char_at(self.current + width).0
+    }
+    fn peek(&self) -> char {
+        self.char_at(self.current).0
     }
     fn matches(&mut self, c: char) -> bool {
         if self.is_at_end() {
             return false;
         }
-        if char::from(self.source[self.current]) != c {
+        if self.peek() != c {
             return false;
         }
-        self.current += 1;
-        self.col += 1;
+        self.advance();
         true
     }
+    // Reports a problem with the token scanned so far.
+    fn error(&mut self, message: &str) {
+        self.error_at(self.start, message)
+    }
+    fn error_at(&mut self, start: usize, message: &str) {
+        self.pending.push_back(Err(Diagnostic::new(
+            message,
+            Span::new(start, self.current),
+        )));
+    }
     fn add_token(&mut self, tty: TokenType) {
-        return self.add_token_literal(tty, None);
+        self.add_token_literal(tty, None)
     }
     fn add_token_literal(&mut self, tty: TokenType, literal: Option<Literal>) {
-        let text = self.source[self.start..self.current].to_vec();
-        self.tokens.push(Token {
+        let text = String::from_utf8_lossy(&self.source[self.start..self.current]);
+        let lexeme = match self.lexemes.get(text.as_ref()) {
+            Some(lexeme) => Arc::clone(lexeme),
+            None => {
+                let lexeme: Arc<str> = Arc::from(text.as_ref());
+                self.lexemes.insert(Arc::clone(&lexeme));
+                lexeme
+            }
+        };
+        self.pending.push_back(Ok(Token {
             tty,
-            lexeme: text,
+            lexeme,
             literal,
-            line: self.line,
-            col: self.col,
+            span: Span::new(self.start, self.current),
+        }));
+        if self.keep_trivia {
+            self.leading.push_back(mem::take(&mut self.trivia));
+        }
+    }
+    fn add_trivia(&mut self, kind: TriviaKind) {
+        self.trivia.push(Trivia {
+            kind,
+            text: String::from_utf8_lossy(&self.source[self.start..self.current]).into_owned(),
+            span: Span::new(self.start, self.current),
         });
     }
+    // The type of the token that `next` would return after `n` others,
+    // skipping errors, without handing any out.
+    pub fn peek_token(&mut self, n: usize) -> TokenType {
+        loop {
+            if let Some(token) = self.pending.iter().flatten().nth(n) {
+                return token.tty;
+            }
+            if !self.scan_more() {
+                return TokenType::Eof;
+            }
+        }
+    }
+    // Queues whatever the next lexeme scans to, if anything is left.
+    fn scan_more(&mut self) -> bool {
+        if self.finished {
+            return false;
+        }
+        if self.is_at_end() {
+            self.finished = true;
+            self.start = self.current;
+            self.add_token(TokenType::Eof);
+        } else {
+            self.start = self.current;
+            self.scan_token();
+        }
+        true
+    }
+}
+
+// Lexes lazily, yielding each token or error as soon as it is scanned and
+// finishing with a single Eof token.
+impl Iterator for Scanner {
+    type Item = Result<Token, Diagnostic>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(item) = self.pending.pop_front() {
+                return Some(item);
+            }
+            if !self.scan_more() {
+                return None;
+            }
+        }
+    }
+}
+
+// Comments and whitespace, which the parser never sees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriviaKind {
+    Whitespace,
+    Comment,
+}
+#[derive(Debug, Clone)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+    pub span: Span,
+}
+// A token with the trivia between it and the token before. Eof carries
+// whatever trails the last token, so for source that scans without errors
+// the trivia and lexemes together are all of it.
+#[derive(Debug, Clone)]
+pub struct TriviaToken {
+    pub leading: Vec<Trivia>,
+    pub token: Token,
+}
+
+// Lexes like `Scanner`, made by `Scanner::with_trivia`, but yields each
+// token with its leading trivia.
+pub struct TriviaScanner {
+    scanner: Scanner,
+}
+impl Iterator for TriviaScanner {
+    type Item = Result<TriviaToken, Diagnostic>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.scanner.next()?;
+        Some(item.map(|token| TriviaToken {
+            leading: self.scanner.leading.pop_front().unwrap_or_default(),
+            token,
+        }))
+    }
+}
+
+// Whether `source` stops partway through a string, or with a bracket, brace
+// or interpolation still open, so a REPL should read another line before
+// running it.
+pub fn is_incomplete(source: &str) -> bool {
+    let mut scanner = Scanner::from_source(source);
+    let mut depth = 0i64;
+    for token in scanner.by_ref().flatten() {
+        match token.tty {
+            TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => depth += 1,
+            TokenType::RightParen | TokenType::RightBrace | TokenType::RightBracket => depth -= 1,
+            _ => {}
+        }
+    }
+    depth > 0 || scanner.unterminated_string || !scanner.interpolations.is_empty()
+}
+
+// Identifiers may use any Unicode letter or digit, plus `_`.
+fn is_identifier_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+fn is_identifier_continue(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
 }
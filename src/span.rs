@@ -0,0 +1,182 @@
+// A half-open range of byte offsets into the source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+    // The smallest span covering both `self` and `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+// Converts byte offsets back to lines and columns. Built only when a
+// location has to be shown, so scanning never tracks them.
+pub struct SourceMap<'src> {
+    // Ordered by the offset each starts at.
+    files: Vec<FileMap<'src>>,
+}
+impl<'src> SourceMap<'src> {
+    // A map of `source` alone, with offsets from the start of it.
+    pub fn new(source: &'src str) -> SourceMap<'src> {
+        SourceMap {
+            files: vec![FileMap::new(None, 0, 1, source)],
+        }
+    }
+    // The source `offset` points into.
+    pub fn file(&self, offset: usize) -> &FileMap<'src> {
+        let index = self.files.partition_point(|file| file.base <= offset);
+        &self.files[index.saturating_sub(1)]
+    }
+    // 1-based line and 0-based column, in characters, of `offset`.
+    pub fn location(&self, offset: usize) -> (usize, usize) {
+        self.file(offset).location(offset)
+    }
+    pub fn line(&self, offset: usize) -> usize {
+        self.location(offset).0
+    }
+    // Text of the 1-based `line` of the first source.
+    pub fn line_text(&self, line: usize) -> &'src str {
+        self.files[0].line_text(line)
+    }
+    // Where `offset` is, for a person: `line 3` in an unnamed source, or
+    // the name and line, as in `lib/util.lox:12`.
+    pub fn place(&self, offset: usize) -> String {
+        let file = self.file(offset);
+        match file.name {
+            Some(name) => format!("{}:{}", name, file.line(offset)),
+            None => format!("line {}", file.line(offset)),
+        }
+    }
+}
+
+// One source of a `SourceMap`.
+pub struct FileMap<'src> {
+    pub name: Option<&'src str>,
+    // The offset its spans start from.
+    base: usize,
+    // The number of its first line, past 1 for later input at the prompt.
+    first_line: usize,
+    source: &'src str,
+    // Byte offset at which each line begins.
+    line_starts: Vec<usize>,
+}
+impl<'src> FileMap<'src> {
+    fn new(name: Option<&'src str>, base: usize, first_line: usize, source: &'src str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        FileMap {
+            name,
+            base,
+            first_line,
+            source,
+            line_starts,
+        }
+    }
+    // `offset` from the start of this source.
+    pub fn offset(&self, offset: usize) -> usize {
+        offset.saturating_sub(self.base).min(self.source.len())
+    }
+    pub fn location(&self, offset: usize) -> (usize, usize) {
+        let offset = self.offset(offset);
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let col = self
+            .source
+            .get(self.line_starts[line]..offset)
+            .map_or(0, |text| text.chars().count());
+        (line + self.first_line, col)
+    }
+    pub fn line(&self, offset: usize) -> usize {
+        self.location(offset).0
+    }
+    // Text of `line`, numbered as `location` numbers them, without its
+    // line ending.
+    pub fn line_text(&self, line: usize) -> &'src str {
+        let index = line.wrapping_sub(self.first_line);
+        let Some(&start) = self.line_starts.get(index) else {
+            return "";
+        };
+        let end = self
+            .line_starts
+            .get(index + 1)
+            .map_or(self.source.len(), |&next| next - 1);
+        self.source[start..end].trim_end_matches('\r')
+    }
+}
+
+// Every source a session has run, imported modules and earlier input at
+// the prompt included. Each is given its own range of offsets, so a span
+// alone says which source it is in. They are kept until released, since
+// functions from any of them may still be called and fail.
+#[derive(Debug, Default)]
+pub struct Sources {
+    files: Vec<Source>,
+}
+#[derive(Debug)]
+struct Source {
+    name: Option<String>,
+    base: usize,
+    first_line: usize,
+    // The length and line count of the text, which outlive it once it is
+    // released.
+    len: usize,
+    lines: usize,
+    text: String,
+}
+impl Sources {
+    // Adds `text`, returning the offset its spans have to start from.
+    pub fn add(&mut self, name: Option<&str>, text: &str) -> usize {
+        self.push(name.map(str::to_string), 1, text)
+    }
+    // Adds `text` as the continuation of the last source called `name`, so
+    // its lines are numbered on from there.
+    pub fn append(&mut self, name: &str, text: &str) -> usize {
+        let first_line = self
+            .files
+            .iter()
+            .rev()
+            .find(|file| file.name.as_deref() == Some(name))
+            .map_or(1, |file| file.first_line + file.lines);
+        self.push(Some(name.to_string()), first_line, text)
+    }
+    fn push(&mut self, name: Option<String>, first_line: usize, text: &str) -> usize {
+        // One past the end of the last source, where its EOF token is, so
+        // that that doesn't count as the start of this one.
+        let base = self.files.last().map_or(0, |last| last.base + last.len + 1);
+        self.files.push(Source {
+            name,
+            base,
+            first_line,
+            len: text.len(),
+            lines: text.matches('\n').count() + 1,
+            text: text.to_string(),
+        });
+        base
+    }
+    // Frees the text of every source so far, keeping its offsets and lines
+    // taken. Spans in it are then placed at its first line.
+    pub fn release(&mut self) {
+        for file in &mut self.files {
+            file.text = String::new();
+        }
+    }
+    pub fn map(&self) -> SourceMap<'_> {
+        if self.files.is_empty() {
+            return SourceMap::new("");
+        }
+        SourceMap {
+            files: self
+                .files
+                .iter()
+                .map(|file| {
+                    FileMap::new(file.name.as_deref(), file.base, file.first_line, &file.text)
+                })
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// How the tests are run: by which backend, and for the VM, whether it
+// collects garbage at every allocation to flush out missing roots.
+#[derive(Debug, Clone, Copy)]
+pub struct Backend {
+    pub name: &'static str,
+    pub stress_gc: bool,
+}
+
+// What a test file expects from running it, read from the comment
+// annotations used by the craftinginterpreters test suite.
+#[derive(Default)]
+struct Expectations {
+    output: Vec<String>,
+    // Full "[line N] Error..." lines for scan, parse and resolve errors.
+    compile_errors: Vec<String>,
+    // Where the error is, as the report names it, and its message.
+    runtime_error: Option<(String, String)>,
+    exit_code: i32,
+    // Options for `run` beyond the backend, like `--fuel 100`.
+    options: Vec<String>,
+}
+
+// An annotation may be limited to one backend where their output
+// legitimately differs, by starting it with `[tree]` or `[vm]`. A test of
+// something the VM doesn't support yet is marked `[vm] nontest`.
+fn parse_expectations(source: &str, backend: &str) -> Option<Expectations> {
+    let mut expected = Expectations::default();
+    for (i, line) in source.lines().enumerate() {
+        let line_number = i + 1;
+        let Some(start) = line.find("//") else {
+            continue;
+        };
+        let comment = line[start + 2..].trim_start();
+        let comment = match comment.strip_prefix("[tree] ") {
+            Some(rest) if backend == "tree" => rest,
+            Some(_) => continue,
+            None => match comment.strip_prefix("[vm] ") {
+                Some(rest) if backend == "vm" => rest,
+                Some(_) => continue,
+                None => comment,
+            },
+        };
+        if comment.starts_with("nontest") {
+            return None;
+        }
+        if let Some(options) = comment.strip_prefix("run with: ") {
+            expected
+                .options
+                .extend(options.split_whitespace().map(str::to_string));
+        } else if let Some(output) = comment.strip_prefix("expect: ") {
+            expected.output.push(output.to_string());
+        } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+            let place = format!("line {}", line_number);
+            expected.runtime_error = Some((place, message.to_string()));
+            expected.exit_code = 70;
+        } else if let Some(rest) = comment.strip_prefix("expect runtime error in ") {
+            // An error in another file, like a module the test imports:
+            // `expect runtime error in lib/util.lox:12: message`.
+            if let Some((place, message)) = rest.split_once(": ") {
+                expected.runtime_error = Some((place.to_string(), message.to_string()));
+                expected.exit_code = 70;
+            }
+        } else if comment.starts_with("Error") {
+            expected
+                .compile_errors
+                .push(format!("[line {}] {}", line_number, comment));
+            expected.exit_code = 65;
+        } else if let Some(rest) = comment.strip_prefix('[') {
+            // `[line N] Error...`, optionally tagged with the implementation
+            // it applies to: jlox (`java`) for the tree backend, and clox
+            // (`c`) for the VM.
+            let tag = if backend == "vm" { "c " } else { "java " };
+            let rest = rest.strip_prefix(tag).unwrap_or(rest);
+            let Some(rest) = rest.strip_prefix("line ") else {
+                continue;
+            };
+            let Some((line, error)) = rest.split_once("] ") else {
+                continue;
+            };
+            if error.starts_with("Error") {
+                expected
+                    .compile_errors
+                    .push(format!("[line {}] {}", line, error));
+                expected.exit_code = 65;
+            }
+        }
+    }
+    Some(expected)
+}
+
+// Returns a description of every way the run differed from what the file
+// expected.
+fn check(path: &Path, expected: &Expectations, backend: Backend) -> io::Result<Vec<String>> {
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .arg("run")
+        .arg(path)
+        .args(["--backend", backend.name]);
+    if backend.stress_gc {
+        command.arg("--stress-gc");
+    }
+    command.args(&expected.options);
+    let run = command.output()?;
+    let stdout = String::from_utf8_lossy(&run.stdout);
+    let stderr = String::from_utf8_lossy(&run.stderr);
+    let mut failures = Vec::new();
+
+    // Error reports are followed by source snippets and backtraces; only the
+    // headline of each is compared.
+    let mut errors: Vec<&str> = stderr
+        .lines()
+        .filter(|line| line.starts_with('['))
+        .collect();
+    match &expected.runtime_error {
+        Some((place, message)) => {
+            let want = format!("[{}] Error: {}", place, message);
+            match errors.first() {
+                Some(got) if *got == want => {}
+                Some(got) => failures.push(format!("Expected '{}', got '{}'.", want, got)),
+                None => failures.push(format!("Expected runtime error '{}'.", want)),
+            }
+        }
+        None => {
+            let mut want: Vec<&str> = expected.compile_errors.iter().map(|e| e.as_str()).collect();
+            want.sort_unstable();
+            errors.sort_unstable();
+            for error in &errors {
+                if !want.contains(error) {
+                    failures.push(format!("Unexpected error '{}'.", error));
+                }
+            }
+            for error in &want {
+                if !errors.contains(error) {
+                    failures.push(format!("Missing expected error '{}'.", error));
+                }
+            }
+        }
+    }
+
+    let code = run.status.code().unwrap_or(-1);
+    if code != expected.exit_code {
+        failures.push(format!(
+            "Expected exit code {}, got {}.",
+            expected.exit_code, code
+        ));
+    }
+
+    let output: Vec<&str> = stdout.lines().collect();
+    for (i, want) in expected.output.iter().enumerate() {
+        match output.get(i) {
+            Some(got) if got == want => {}
+            Some(got) => failures.push(format!("Expected output '{}', got '{}'.", want, got)),
+            None => failures.push(format!("Missing expected output '{}'.", want)),
+        }
+    }
+    for got in output.iter().skip(expected.output.len()) {
+        failures.push(format!("Unexpected output '{}'.", got));
+    }
+    Ok(failures)
+}
+
+// Every `.lox` file under `dir`, at any depth.
+pub fn collect_scripts(dir: &Path, scripts: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_scripts(&path, scripts)?;
+        } else if path.extension().is_some_and(|ext| ext == "lox") {
+            scripts.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Runs every `.lox` file under `dir` and returns whether all of them passed.
+pub fn run_tests(dir: &str, backend: Backend) -> io::Result<bool> {
+    let mut tests = Vec::new();
+    collect_scripts(Path::new(dir), &mut tests)?;
+    tests.sort();
+
+    let (mut passed, mut failed, mut skipped) = (0, 0, 0);
+    for path in &tests {
+        let source = fs::read_to_string(path)?;
+        let Some(expected) = parse_expectations(&source, backend.name) else {
+            skipped += 1;
+            continue;
+        };
+        let failures = check(path, &expected, backend)?;
+        if failures.is_empty() {
+            passed += 1;
+        } else {
+            failed += 1;
+            println!("FAIL {}", path.display());
+            for failure in failures {
+                println!("     {}", failure);
+            }
+        }
+    }
+    println!("{} passed, {} failed, {} skipped", passed, failed, skipped);
+    Ok(failed == 0)
+}
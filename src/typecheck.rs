@@ -0,0 +1,769 @@
+use crate::ast_json::expr_span;
+use crate::error::Diagnostic;
+use crate::interpreter::arity_mismatch;
+use crate::parser::{update_operator, Expr, FunctionDecl, LiteralValue, Stmt};
+use crate::scanner::{Token, TokenType};
+use crate::span::Span;
+use std::collections::HashMap;
+use std::fmt;
+use std::iter;
+use std::rc::Rc;
+use std::sync::Arc;
+
+// What the checker knows about a value. Anything unannotated is `Any`,
+// which fits every type and every type fits, so code without annotations
+// is checked no more than before.
+#[derive(Debug, Clone, PartialEq)]
+enum Type {
+    Any,
+    Nil,
+    Bool,
+    Number,
+    String,
+    List,
+    Map,
+    Range,
+    // With the signature when the function's declaration is known.
+    Function(Option<Rc<Signature>>),
+    // A class itself, which makes instances when called.
+    Class(Arc<str>),
+    Instance(Arc<str>),
+    Trait,
+}
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Any => write!(f, "Any"),
+            Type::Nil => write!(f, "Nil"),
+            Type::Bool => write!(f, "Bool"),
+            Type::Number => write!(f, "Number"),
+            Type::String => write!(f, "String"),
+            Type::List => write!(f, "List"),
+            Type::Map => write!(f, "Map"),
+            Type::Range => write!(f, "Range"),
+            Type::Function(_) => write!(f, "Function"),
+            Type::Class(name) => write!(f, "class {}", name),
+            Type::Instance(name) => write!(f, "{}", name),
+            Type::Trait => write!(f, "Trait"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Signature {
+    name: Arc<str>,
+    params: Vec<Type>,
+    // How many of the parameters come before those with defaults.
+    required: usize,
+    returns: Type,
+}
+
+// A class's own members by name. Getters have no signature, since they
+// are not called.
+struct ClassInfo {
+    superclass: Option<Arc<str>>,
+    // Traits may bring in methods the checker can't see, so lookups stop
+    // at a class that uses any.
+    has_traits: bool,
+    methods: HashMap<Arc<str>, Option<Rc<Signature>>>,
+    class_methods: HashMap<Arc<str>, Option<Rc<Signature>>>,
+}
+
+// A variable's declared type, which assignments must fit, and the type of
+// the value it is known to hold, such as a function's signature.
+#[derive(Clone)]
+struct Variable {
+    declared: Type,
+    known: Type,
+}
+
+// Checks a resolved program against its type annotations without running
+// it. Types are gradual: only values flowing into an annotated variable,
+// parameter or return are checked, along with calls to functions whose
+// declarations are known and operators whose operands can never work.
+pub fn check(statements: &[Stmt]) -> Result<(), Vec<Diagnostic>> {
+    let mut checker = TypeChecker {
+        scopes: vec![HashMap::new()],
+        classes: HashMap::new(),
+        returns: Vec::new(),
+        this: Vec::new(),
+        errors: Vec::new(),
+    };
+    checker.statements(statements);
+    if checker.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(checker.errors)
+    }
+}
+
+struct TypeChecker {
+    // Innermost scope last; the first holds the globals.
+    scopes: Vec<HashMap<Arc<str>, Variable>>,
+    classes: HashMap<Arc<str>, ClassInfo>,
+    // The name and return type of each enclosing function, innermost last.
+    returns: Vec<(Arc<str>, Type)>,
+    this: Vec<Type>,
+    errors: Vec<Diagnostic>,
+}
+impl TypeChecker {
+    fn statements(&mut self, statements: &[Stmt]) {
+        self.hoist(statements);
+        for statement in statements {
+            self.stmt(statement);
+        }
+    }
+    // Classes and functions can be used by code written above them, so
+    // their types are known before any of the statements are checked.
+    fn hoist(&mut self, statements: &[Stmt]) {
+        for statement in statements {
+            if let Stmt::Class {
+                name,
+                superclass,
+                traits,
+                ..
+            } = statement
+            {
+                let superclass = match superclass {
+                    Some(Expr::Variable { name, .. }) => Some(Arc::clone(&name.lexeme)),
+                    _ => None,
+                };
+                let info = ClassInfo {
+                    superclass,
+                    has_traits: !traits.is_empty(),
+                    methods: HashMap::new(),
+                    class_methods: HashMap::new(),
+                };
+                self.classes.insert(Arc::clone(&name.lexeme), info);
+            }
+        }
+        for statement in statements {
+            match statement {
+                Stmt::Class {
+                    name,
+                    methods,
+                    class_methods,
+                    ..
+                } => {
+                    let methods = self.members(methods);
+                    let class_methods = self.members(class_methods);
+                    if let Some(info) = self.classes.get_mut(&name.lexeme) {
+                        info.methods = methods;
+                        info.class_methods = class_methods;
+                    }
+                    let class = Type::Class(Arc::clone(&name.lexeme));
+                    self.declare(&name.lexeme, Type::Any, class);
+                }
+                Stmt::Function(declaration) => {
+                    let function = Type::Function(Some(self.signature(declaration)));
+                    self.declare(&declaration.name.lexeme, Type::Any, function);
+                }
+                _ => {}
+            }
+        }
+    }
+    fn members(
+        &self,
+        declarations: &[Arc<FunctionDecl>],
+    ) -> HashMap<Arc<str>, Option<Rc<Signature>>> {
+        declarations
+            .iter()
+            .map(|declaration| {
+                let signature = (!declaration.is_getter).then(|| self.signature(declaration));
+                (Arc::clone(&declaration.name.lexeme), signature)
+            })
+            .collect()
+    }
+    fn signature(&self, declaration: &FunctionDecl) -> Rc<Signature> {
+        Rc::new(Signature {
+            name: Arc::clone(&declaration.name.lexeme),
+            params: declaration
+                .param_types
+                .iter()
+                .map(|annotation| self.annotation(annotation))
+                .collect(),
+            required: declaration
+                .defaults
+                .iter()
+                .take_while(|default| default.is_none())
+                .count(),
+            returns: self.annotation(&declaration.return_type),
+        })
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Block(statements) => self.scoped(statements),
+            Stmt::DoWhile { body, condition } => {
+                self.stmt(body);
+                self.expr(condition);
+            }
+            Stmt::Destructure {
+                names,
+                equals,
+                initializer,
+            } => {
+                let actual = self.expr(initializer);
+                self.check_unpack(equals, &actual);
+                for name in names {
+                    self.declare(&name.lexeme, Type::Any, Type::Any);
+                }
+            }
+            Stmt::Class {
+                name,
+                superclass,
+                traits,
+                methods,
+                class_methods,
+            } => {
+                for parent in superclass.iter().chain(traits) {
+                    self.expr(parent);
+                }
+                self.this.push(Type::Instance(Arc::clone(&name.lexeme)));
+                for method in methods {
+                    self.function(method, &*method.name.lexeme == "init");
+                }
+                self.this.pop();
+                self.this.push(Type::Class(Arc::clone(&name.lexeme)));
+                for method in class_methods {
+                    self.function(method, false);
+                }
+                self.this.pop();
+            }
+            Stmt::Expression(expr) | Stmt::Print(expr) => {
+                self.expr(expr);
+            }
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+            } => {
+                self.scopes.push(HashMap::new());
+                if let Some(initializer) = initializer {
+                    self.stmt(initializer);
+                }
+                self.expr(condition);
+                self.stmt(body);
+                if let Some(increment) = increment {
+                    self.expr(increment);
+                }
+                self.scopes.pop();
+            }
+            Stmt::ForEach {
+                name,
+                iterable,
+                body,
+                ..
+            } => {
+                self.expr(iterable);
+                self.scopes.push(HashMap::new());
+                self.declare(&name.lexeme, Type::Any, Type::Any);
+                self.stmt(body);
+                self.scopes.pop();
+            }
+            // Declared when its block was hoisted.
+            Stmt::Function(declaration) => self.function(declaration, false),
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expr(condition);
+                self.stmt(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.stmt(else_branch);
+                }
+            }
+            Stmt::Import { .. } => {}
+            Stmt::Return { keyword, value } => {
+                let actual = match value {
+                    Some(value) => self.expr(value),
+                    None => Type::Nil,
+                };
+                let Some((function, returns)) = self.returns.last() else {
+                    return;
+                };
+                if !self.fits(&actual, returns) {
+                    let message = format!(
+                        "Can't return {} from '{}', which returns {}.",
+                        actual, function, returns
+                    );
+                    self.errors.push(Diagnostic::at_token(keyword, &message));
+                }
+            }
+            Stmt::Switch {
+                subject,
+                cases,
+                default,
+            } => {
+                self.expr(subject);
+                for (value, body) in cases {
+                    self.expr(value);
+                    self.scoped(body);
+                }
+                if let Some(default) = default {
+                    self.scoped(default);
+                }
+            }
+            Stmt::Trait { name, methods } => {
+                self.declare(&name.lexeme, Type::Any, Type::Trait);
+                self.this.push(Type::Any);
+                for method in methods {
+                    self.function(method, &*method.name.lexeme == "init");
+                }
+                self.this.pop();
+            }
+            Stmt::Throw { value, .. } => {
+                self.expr(value);
+            }
+            Stmt::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                self.scoped(body);
+                if let Some((name, handler)) = catch {
+                    self.scopes.push(HashMap::new());
+                    self.declare(&name.lexeme, Type::Any, Type::Any);
+                    self.statements(handler);
+                    self.scopes.pop();
+                }
+                if let Some(finally) = finally {
+                    self.scoped(finally);
+                }
+            }
+            Stmt::Var {
+                name,
+                annotation,
+                initializer,
+            } => {
+                self.check_annotation(annotation);
+                let declared = self.annotation(annotation);
+                if let Some(initializer) = initializer {
+                    let actual = self.expr(initializer);
+                    self.check_assign(name, &actual, &declared);
+                }
+                self.declare(&name.lexeme, declared.clone(), declared);
+            }
+            Stmt::While { condition, body } => {
+                self.expr(condition);
+                self.stmt(body);
+            }
+            Stmt::Yield { value, .. } => {
+                if let Some(value) = value {
+                    self.expr(value);
+                }
+            }
+        }
+    }
+    fn scoped(&mut self, statements: &[Stmt]) {
+        self.scopes.push(HashMap::new());
+        self.statements(statements);
+        self.scopes.pop();
+    }
+    // An initializer always returns its instance, whatever it declares.
+    fn function(&mut self, declaration: &FunctionDecl, is_initializer: bool) {
+        let annotations = declaration
+            .param_types
+            .iter()
+            .chain(iter::once(&declaration.return_type));
+        for annotation in annotations {
+            self.check_annotation(annotation);
+        }
+        let signature = self.signature(declaration);
+        let returns = if is_initializer {
+            Type::Any
+        } else {
+            signature.returns.clone()
+        };
+        self.returns
+            .push((Arc::clone(&declaration.name.lexeme), returns));
+        self.scopes.push(HashMap::new());
+        for ((param, declared), default) in declaration
+            .params
+            .iter()
+            .zip(&signature.params)
+            .zip(&declaration.defaults)
+        {
+            if let Some(default) = default {
+                let actual = self.expr(default);
+                self.check_assign(param, &actual, declared);
+            }
+            self.declare(&param.lexeme, declared.clone(), declared.clone());
+        }
+        self.statements(&declaration.body);
+        self.scopes.pop();
+        self.returns.pop();
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Type {
+        match expr {
+            Expr::Assign { name, value, .. } => {
+                let actual = self.expr(value);
+                self.assign(name, actual)
+            }
+            Expr::Compound {
+                target,
+                operator,
+                value,
+            } => {
+                let current = self.expr(target);
+                let operand = self.expr(value);
+                let result = self.binary(operator, current, operand);
+                self.store(target, result)
+            }
+            Expr::Destructure {
+                targets,
+                equals,
+                value,
+            } => {
+                let actual = self.expr(value);
+                self.check_unpack(equals, &actual);
+                for target in targets {
+                    match target {
+                        Expr::Variable { name, .. } => {
+                            if let Some(variable) = self.lookup_mut(&name.lexeme) {
+                                variable.known = variable.declared.clone();
+                            }
+                        }
+                        _ => {
+                            self.expr(target);
+                        }
+                    }
+                }
+                actual
+            }
+            Expr::Binary {
+                left,
+                operator,
+                right,
+            } => {
+                let left = self.expr(left);
+                let right = self.expr(right);
+                self.binary(operator, left, right)
+            }
+            Expr::Call {
+                callee,
+                paren,
+                arguments,
+            } => {
+                let callee = self.expr(callee);
+                let arguments: Vec<(Type, Span)> = arguments
+                    .iter()
+                    .map(|argument| (self.expr(argument), expr_span(argument)))
+                    .collect();
+                match callee {
+                    Type::Function(Some(signature)) => {
+                        self.check_call(&signature.name, &signature, paren, &arguments);
+                        signature.returns.clone()
+                    }
+                    Type::Class(name) => {
+                        if let Some(init) = self.method(&name, "init", false) {
+                            self.check_call(&name, &init, paren, &arguments);
+                        }
+                        Type::Instance(name)
+                    }
+                    _ => Type::Any,
+                }
+            }
+            Expr::Conditional {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expr(condition);
+                let then_type = self.expr(then_branch);
+                let else_type = self.expr(else_branch);
+                join(then_type, else_type)
+            }
+            Expr::Get { object, name, .. } => {
+                let method = match self.expr(object) {
+                    Type::Instance(class) => self.method(&class, &name.lexeme, false),
+                    Type::Class(class) => self.method(&class, &name.lexeme, true),
+                    _ => None,
+                };
+                method.map_or(Type::Any, |method| Type::Function(Some(method)))
+            }
+            Expr::Grouping(expr) => self.expr(expr),
+            Expr::Index { object, index, .. } => {
+                self.expr(object);
+                self.expr(index);
+                Type::Any
+            }
+            Expr::IndexSet {
+                object,
+                index,
+                value,
+                ..
+            } => {
+                self.expr(object);
+                self.expr(index);
+                self.expr(value)
+            }
+            Expr::Lambda(declaration) => {
+                self.function(declaration, false);
+                Type::Function(Some(self.signature(declaration)))
+            }
+            Expr::List(elements) => {
+                for element in elements {
+                    self.expr(element);
+                }
+                Type::List
+            }
+            Expr::Map { entries, .. } => {
+                for (key, value) in entries {
+                    self.expr(key);
+                    self.expr(value);
+                }
+                Type::Map
+            }
+            Expr::Literal { value, .. } => match value {
+                LiteralValue::Nil => Type::Nil,
+                LiteralValue::Bool(_) => Type::Bool,
+                LiteralValue::Number(_) => Type::Number,
+                LiteralValue::Str(_) => Type::String,
+            },
+            Expr::Logical { left, right, .. } => {
+                let left = self.expr(left);
+                let right = self.expr(right);
+                join(left, right)
+            }
+            Expr::Set { object, value, .. } => {
+                self.expr(object);
+                self.expr(value)
+            }
+            Expr::Super { .. } => Type::Any,
+            Expr::This { .. } => self.this.last().cloned().unwrap_or(Type::Any),
+            Expr::Unary { operator, right } => {
+                let right = self.expr(right);
+                match operator.tty {
+                    TokenType::Bang => Type::Bool,
+                    TokenType::Interpolation => Type::String,
+                    _ if right == Type::Number => Type::Number,
+                    _ if is_primitive(&right) => {
+                        self.errors
+                            .push(Diagnostic::at(operator, "Operand must be a number."));
+                        Type::Any
+                    }
+                    _ => Type::Any,
+                }
+            }
+            Expr::Update {
+                operator, target, ..
+            } => {
+                let current = self.expr(target);
+                let updated = self.binary(&update_operator(operator), current, Type::Number);
+                self.store(target, updated)
+            }
+            Expr::Variable { name, .. } => self
+                .lookup(&name.lexeme)
+                .map_or(Type::Any, |variable| variable.known.clone()),
+        }
+    }
+    fn assign(&mut self, name: &Token, actual: Type) -> Type {
+        if let Some(variable) = self.lookup_mut(&name.lexeme) {
+            // The variable may no longer hold what it was declared with.
+            variable.known = variable.declared.clone();
+            let declared = variable.declared.clone();
+            self.check_assign(name, &actual, &declared);
+        }
+        actual
+    }
+    // Stores the result of a compound assignment or update back in its
+    // target, whose object and index have already been checked.
+    fn store(&mut self, target: &Expr, actual: Type) -> Type {
+        match target {
+            Expr::Variable { name, .. } => self.assign(name, actual),
+            _ => actual,
+        }
+    }
+    // Operators a left-hand instance may overload are only checked when it
+    // is known not to be one.
+    fn binary(&mut self, operator: &Token, left: Type, right: Type) -> Type {
+        match operator.tty {
+            TokenType::EqualEqual | TokenType::BangEqual => return Type::Bool,
+            TokenType::Comma => return right,
+            TokenType::Plus => {
+                if left == right && matches!(left, Type::Number | Type::String) {
+                    return left;
+                }
+                if is_primitive(&left) && right != Type::Any {
+                    self.errors.push(Diagnostic::at(
+                        operator,
+                        "Operands must be two numbers or two strings.",
+                    ));
+                }
+                return Type::Any;
+            }
+            _ => {}
+        }
+        if is_primitive(&left)
+            && right != Type::Any
+            && (left != Type::Number || right != Type::Number)
+        {
+            self.errors
+                .push(Diagnostic::at(operator, "Operands must be numbers."));
+        }
+        match operator.tty {
+            TokenType::Greater
+            | TokenType::GreaterEqual
+            | TokenType::Less
+            | TokenType::LessEqual => Type::Bool,
+            TokenType::DotDot | TokenType::DotDotEqual => Type::Range,
+            _ if left == Type::Number && right == Type::Number => Type::Number,
+            _ => Type::Any,
+        }
+    }
+    fn check_call(
+        &mut self,
+        name: &str,
+        signature: &Signature,
+        paren: &Token,
+        arguments: &[(Type, Span)],
+    ) {
+        let arity = signature.required..=signature.params.len();
+        if !arity.contains(&arguments.len()) {
+            let message = arity_mismatch(&arity, arguments.len());
+            self.errors.push(Diagnostic::at(paren, &message));
+            return;
+        }
+        for (i, ((actual, span), declared)) in arguments.iter().zip(&signature.params).enumerate() {
+            if !self.fits(actual, declared) {
+                let message = format!(
+                    "Argument {} to '{}' must be {}, not {}.",
+                    i + 1,
+                    name,
+                    declared,
+                    actual
+                );
+                self.errors.push(Diagnostic::new(&message, *span));
+            }
+        }
+    }
+    fn check_assign(&mut self, name: &Token, actual: &Type, declared: &Type) {
+        if !self.fits(actual, declared) {
+            let message = format!(
+                "Can't assign {} to '{}', which has type {}.",
+                actual, name.lexeme, declared
+            );
+            self.errors.push(Diagnostic::at_token(name, &message));
+        }
+    }
+    fn check_unpack(&mut self, equals: &Token, actual: &Type) {
+        if !self.fits(actual, &Type::List) {
+            self.errors.push(Diagnostic::at_token(
+                equals,
+                "Only lists can be destructured.",
+            ));
+        }
+    }
+    fn check_annotation(&mut self, annotation: &Option<Token>) {
+        if let Some(name) = annotation {
+            if self.named_type(&name.lexeme).is_none() {
+                let message = format!("Unknown type '{}'.", name.lexeme);
+                self.errors.push(Diagnostic::at_token(name, &message));
+            }
+        }
+    }
+
+    // Unknown type names are reported where they are written, and treated
+    // as `Any` everywhere.
+    fn annotation(&self, annotation: &Option<Token>) -> Type {
+        annotation
+            .as_ref()
+            .and_then(|name| self.named_type(&name.lexeme))
+            .unwrap_or(Type::Any)
+    }
+    fn named_type(&self, name: &str) -> Option<Type> {
+        Some(match name {
+            "Any" => Type::Any,
+            "Nil" => Type::Nil,
+            "Bool" => Type::Bool,
+            "Number" => Type::Number,
+            "String" => Type::String,
+            "List" => Type::List,
+            "Map" => Type::Map,
+            "Range" => Type::Range,
+            "Function" => Type::Function(None),
+            _ => {
+                let (name, _) = self.classes.get_key_value(name)?;
+                Type::Instance(Arc::clone(name))
+            }
+        })
+    }
+    fn fits(&self, actual: &Type, declared: &Type) -> bool {
+        match (actual, declared) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::Function(_), Type::Function(_)) => true,
+            (Type::Instance(class), Type::Instance(ancestor)) => self.inherits(class, ancestor),
+            _ => actual == declared,
+        }
+    }
+    // A class whose superclass the checker can't see might inherit from
+    // anything.
+    fn inherits(&self, class: &str, ancestor: &str) -> bool {
+        let mut class = class;
+        loop {
+            if class == ancestor {
+                return true;
+            }
+            match self.classes.get(class) {
+                Some(info) => match &info.superclass {
+                    Some(superclass) => class = superclass,
+                    None => return false,
+                },
+                None => return true,
+            }
+        }
+    }
+    // The signature of the method `name` on `class` or its superclasses,
+    // if it is certain to be the one found at runtime.
+    fn method(&self, class: &str, name: &str, on_class: bool) -> Option<Rc<Signature>> {
+        let mut class = class;
+        loop {
+            let info = self.classes.get(class)?;
+            let members = if on_class {
+                &info.class_methods
+            } else {
+                &info.methods
+            };
+            if let Some(member) = members.get(name) {
+                return member.clone();
+            }
+            if info.has_traits {
+                return None;
+            }
+            class = info.superclass.as_deref()?;
+        }
+    }
+
+    fn declare(&mut self, name: &Arc<str>, declared: Type, known: Type) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(Arc::clone(name), Variable { declared, known });
+        }
+    }
+    fn lookup(&self, name: &str) -> Option<&Variable> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+    fn lookup_mut(&mut self, name: &str) -> Option<&mut Variable> {
+        self.scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.get_mut(name))
+    }
+}
+
+// A type that can't overload operators and is known for certain.
+fn is_primitive(t: &Type) -> bool {
+    !matches!(t, Type::Any | Type::Instance(_))
+}
+
+// The type of a value that is one of two others.
+fn join(a: Type, b: Type) -> Type {
+    if a == b {
+        a
+    } else {
+        Type::Any
+    }
+}
@@ -0,0 +1,181 @@
+use crate::span::Span;
+use crate::vm::object::ObjRef;
+use crate::vm::value::Value;
+
+// One-byte instructions. Operands, where an instruction has them, follow
+// it in the code stream: a constant or local slot index is one byte, and a
+// global slot or jump offset is two bytes, big-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum OpCode {
+    Constant,
+    Nil,
+    True,
+    False,
+    Pop,
+    // Pushes a copy of the top of the stack.
+    Dup,
+    // Copies the top of the stack beneath the value under it.
+    Tuck,
+    GetLocal,
+    SetLocal,
+    GetGlobal,
+    DefineGlobal,
+    SetGlobal,
+    GetUpvalue,
+    SetUpvalue,
+    Equal,
+    Greater,
+    Less,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Power,
+    Not,
+    Negate,
+    // Converts the top of the stack to its printed form, for string
+    // interpolation.
+    Stringify,
+    Print,
+    Jump,
+    JumpIfFalse,
+    // Jumps if the top of the stack is nil, leaving it there either way.
+    JumpIfNil,
+    Loop,
+    Call,
+    // A call whose result the caller returns at once. A closure reuses the
+    // caller's frame; anything else is called as usual.
+    TailCall,
+    // Followed by the function's constant index, then an is-local flag and
+    // index byte for each variable it captures.
+    Closure,
+    // Moves the local on top of the stack into the heap, for closures that
+    // captured it, and pops it.
+    CloseUpvalue,
+    Return,
+    // Suspends the running coroutine, handing the value on top of the
+    // stack to the `resume` that ran it.
+    Yield,
+    // Followed by the class name's constant index.
+    Class,
+    // Copies the superclass's methods into the subclass above it on the
+    // stack, then pops the subclass.
+    Inherit,
+    // Adds the closure on top of the stack to the class beneath it, under
+    // the name given by the constant operand.
+    Method,
+    // Like `Method`, for a method called on the class itself.
+    ClassMethod,
+    // Followed by the trait name's constant index.
+    Trait,
+    // Copies the methods of the trait on top of the stack into the class
+    // beneath it and the number of other traits given by the operand,
+    // which must not define any of the same methods.
+    Mixin,
+    GetProperty,
+    SetProperty,
+    // Reads the named superclass method, bound to the instance beneath the
+    // superclass.
+    GetSuper,
+    // Calls a method directly, without creating a bound method. Followed by
+    // the name's constant index and the argument count.
+    Invoke,
+    SuperInvoke,
+}
+impl OpCode {
+    const ALL: [OpCode; 48] = [
+        OpCode::Constant,
+        OpCode::Nil,
+        OpCode::True,
+        OpCode::False,
+        OpCode::Pop,
+        OpCode::Dup,
+        OpCode::Tuck,
+        OpCode::GetLocal,
+        OpCode::SetLocal,
+        OpCode::GetGlobal,
+        OpCode::DefineGlobal,
+        OpCode::SetGlobal,
+        OpCode::GetUpvalue,
+        OpCode::SetUpvalue,
+        OpCode::Equal,
+        OpCode::Greater,
+        OpCode::Less,
+        OpCode::Add,
+        OpCode::Subtract,
+        OpCode::Multiply,
+        OpCode::Divide,
+        OpCode::Modulo,
+        OpCode::Power,
+        OpCode::Not,
+        OpCode::Negate,
+        OpCode::Stringify,
+        OpCode::Print,
+        OpCode::Jump,
+        OpCode::JumpIfFalse,
+        OpCode::JumpIfNil,
+        OpCode::Loop,
+        OpCode::Call,
+        OpCode::TailCall,
+        OpCode::Closure,
+        OpCode::CloseUpvalue,
+        OpCode::Return,
+        OpCode::Yield,
+        OpCode::Class,
+        OpCode::Inherit,
+        OpCode::Method,
+        OpCode::ClassMethod,
+        OpCode::Trait,
+        OpCode::Mixin,
+        OpCode::GetProperty,
+        OpCode::SetProperty,
+        OpCode::GetSuper,
+        OpCode::Invoke,
+        OpCode::SuperInvoke,
+    ];
+}
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        OpCode::ALL.get(byte as usize).copied().ok_or(byte)
+    }
+}
+
+// What a property instruction found last time it ran: the index of the
+// field in the instance, or the method on the instance's class. Each is
+// checked before use, so a stale entry only costs a full lookup.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InlineCache {
+    Empty,
+    Field(usize),
+    Method { class: ObjRef, method: Value },
+}
+
+// A compiled function body: its code, the constants it refers to, and the
+// source span of every byte for error reporting.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub spans: Vec<Span>,
+    pub constants: Vec<Value>,
+    // One per constant. Every property access adds its own name constant,
+    // so the cache for its name is the cache for that instruction.
+    pub caches: Vec<InlineCache>,
+}
+impl Chunk {
+    pub fn write(&mut self, byte: u8, span: Span) {
+        self.code.push(byte);
+        self.spans.push(span);
+    }
+    pub fn write_op(&mut self, op: OpCode, span: Span) {
+        self.write(op as u8, span)
+    }
+    // Returns the new constant's index.
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        self.constants.push(value);
+        self.caches.push(InlineCache::Empty);
+        self.constants.len() - 1
+    }
+}
@@ -0,0 +1,1752 @@
+use crate::error::{Diagnostic, LoxError};
+use crate::interpreter::trait_conflict;
+use crate::parser::MAX_NESTING;
+use crate::scanner::{Literal, Scanner, Token, TokenType};
+use crate::span::Span;
+use crate::vm::chunk::{Chunk, OpCode};
+use crate::vm::globals::Globals;
+use crate::vm::object::{Function, Heap, Obj, ObjRef};
+use crate::vm::value::{Unboxed, Value};
+use std::collections::HashMap;
+use std::mem;
+use std::sync::Arc;
+
+// Compiles `source` straight from tokens to bytecode in a single pass, with
+// no AST in between. The result is the top-level script as a function.
+// With `optimize`, constant expressions are folded as they are emitted and
+// branches that can never run are dropped.
+pub fn compile(
+    source: &str,
+    heap: &mut Heap,
+    globals: &mut Globals,
+    optimize: bool,
+) -> Result<ObjRef, LoxError> {
+    let mut compiler = Compiler::new(source, heap, globals, optimize);
+    compiler.advance();
+    while !compiler.matches(TokenType::Eof) {
+        compiler.declaration();
+    }
+    let (function, _) = compiler.end_function();
+    // Scan errors are reported alone, as the tree-walk interpreter does;
+    // the syntax errors they cause would only be noise.
+    if !compiler.scan_errors.is_empty() {
+        return Err(LoxError::Scan(compiler.scan_errors));
+    }
+    if !compiler.errors.is_empty() {
+        return Err(LoxError::Parse(compiler.errors));
+    }
+    Ok(compiler.heap.alloc(Obj::Function(function)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Precedence {
+    None,
+    Comma,       // ,
+    Assignment,  // = += -= *= /=
+    Conditional, // ?:
+    Or,
+    And,
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Range,      // .. ..=
+    Term,       // + -
+    Factor,     // * / %
+    Unary,      // ! -
+    Power,      // **
+    Call,       // . () []
+    Primary,
+}
+impl Precedence {
+    fn next(self) -> Precedence {
+        match self {
+            Precedence::None => Precedence::Comma,
+            Precedence::Comma => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Conditional,
+            Precedence::Conditional => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Range,
+            Precedence::Range => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Power,
+            Precedence::Power => Precedence::Call,
+            Precedence::Call | Precedence::Primary => Precedence::Primary,
+        }
+    }
+}
+
+type ParseFn<'h> = fn(&mut Compiler<'h>, bool);
+
+struct ParseRule<'h> {
+    prefix: Option<ParseFn<'h>>,
+    infix: Option<ParseFn<'h>>,
+    precedence: Precedence,
+}
+
+fn rule<'h>(tty: TokenType) -> ParseRule<'h> {
+    let (prefix, infix, precedence): (Option<ParseFn>, Option<ParseFn>, _) = match tty {
+        TokenType::LeftParen => (
+            Some(Compiler::grouping),
+            Some(Compiler::call),
+            Precedence::Call,
+        ),
+        TokenType::LeftBracket => (
+            Some(Compiler::unsupported),
+            Some(Compiler::unsupported),
+            Precedence::Call,
+        ),
+        TokenType::LeftBrace => (Some(Compiler::unsupported), None, Precedence::None),
+        TokenType::Dot => (None, Some(Compiler::dot), Precedence::Call),
+        TokenType::QuestionDot => (None, Some(Compiler::safe_dot), Precedence::Call),
+        TokenType::Minus => (
+            Some(Compiler::unary),
+            Some(Compiler::binary),
+            Precedence::Term,
+        ),
+        TokenType::Plus => (None, Some(Compiler::binary), Precedence::Term),
+        TokenType::Slash | TokenType::Star | TokenType::Percent => {
+            (None, Some(Compiler::binary), Precedence::Factor)
+        }
+        TokenType::StarStar => (None, Some(Compiler::power), Precedence::Power),
+        TokenType::Bang => (Some(Compiler::unary), None, Precedence::None),
+        TokenType::BangEqual | TokenType::EqualEqual => {
+            (None, Some(Compiler::binary), Precedence::Equality)
+        }
+        TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => {
+            (None, Some(Compiler::binary), Precedence::Comparison)
+        }
+        TokenType::DotDot | TokenType::DotDotEqual => {
+            (None, Some(Compiler::unsupported), Precedence::Range)
+        }
+        TokenType::Question => (None, Some(Compiler::conditional), Precedence::Conditional),
+        TokenType::Comma => (None, Some(Compiler::comma), Precedence::Comma),
+        TokenType::PlusPlus | TokenType::MinusMinus => (
+            Some(Compiler::prefix_update),
+            Some(Compiler::invalid_update),
+            Precedence::Call,
+        ),
+        TokenType::Identifier => (Some(Compiler::variable), None, Precedence::None),
+        TokenType::String => (Some(Compiler::string), None, Precedence::None),
+        TokenType::Interpolation => (Some(Compiler::interpolation), None, Precedence::None),
+        TokenType::Number => (Some(Compiler::number), None, Precedence::None),
+        TokenType::And => (None, Some(Compiler::and), Precedence::And),
+        TokenType::Or => (None, Some(Compiler::or), Precedence::Or),
+        TokenType::False | TokenType::True | TokenType::Nil => {
+            (Some(Compiler::literal), None, Precedence::None)
+        }
+        TokenType::This => (Some(Compiler::this), None, Precedence::None),
+        TokenType::Super => (Some(Compiler::super_), None, Precedence::None),
+        TokenType::Lambda => (Some(Compiler::lambda), None, Precedence::None),
+        _ => (None, None, Precedence::None),
+    };
+    ParseRule {
+        prefix,
+        infix,
+        precedence,
+    }
+}
+
+// An identifier the source doesn't spell out, such as the `this` and
+// `super` that `super.m` refers to.
+fn synthetic_token(name: &str, span: Span) -> Token {
+    Token {
+        tty: TokenType::Identifier,
+        lexeme: Arc::from(name),
+        literal: None,
+        span,
+    }
+}
+
+// Constants are interchangeable if they are the same value, except that
+// numbers must have the same bits, keeping 0 and -0 apart.
+fn same_constant(a: Value, b: Value) -> bool {
+    match (a.unbox(), b.unbox()) {
+        (Unboxed::Number(a), Unboxed::Number(b)) => a.to_bits() == b.to_bits(),
+        (a, b) => a == b,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+struct Local {
+    name: Arc<str>,
+    // None while the variable's initializer is being compiled.
+    depth: Option<usize>,
+    // Whether a closure refers to it, in which case it has to be moved to
+    // the heap when it goes out of scope.
+    captured: bool,
+}
+
+// A variable a function captures: a local slot of the enclosing function,
+// or one of the enclosing function's own upvalues.
+#[derive(Clone, Copy, PartialEq)]
+struct UpvalueRef {
+    index: usize,
+    is_local: bool,
+}
+
+// The class whose body is being compiled, for checking uses of `this` and
+// `super`.
+struct ClassState {
+    has_superclass: bool,
+    is_trait: bool,
+}
+
+// The function currently being compiled. Nested declarations push a new
+// state and pop it when their body ends.
+struct FunctionState {
+    function: Function,
+    kind: FunctionKind,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueRef>,
+    scope_depth: usize,
+    // Where each instruction starts, for the optimizer to look back over.
+    ops: Vec<usize>,
+    // The furthest offset any forward jump lands on. Instructions before it
+    // may be reached from elsewhere, so they are never rewritten.
+    jump_target: usize,
+}
+impl FunctionState {
+    fn new(kind: FunctionKind, name: &str) -> FunctionState {
+        FunctionState {
+            function: Function {
+                name: name.to_string(),
+                ..Function::default()
+            },
+            kind,
+            // Slot zero holds the function being called, or the receiver in
+            // a method.
+            locals: vec![Local {
+                name: Arc::from(match kind {
+                    FunctionKind::Initializer | FunctionKind::Method => "this",
+                    FunctionKind::Function | FunctionKind::Script => "",
+                }),
+                depth: Some(0),
+                captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            ops: Vec::new(),
+            jump_target: 0,
+        }
+    }
+}
+
+struct Compiler<'h> {
+    heap: &'h mut Heap,
+    globals: &'h mut Globals,
+    scanner: Scanner,
+    current: Token,
+    previous: Token,
+    states: Vec<FunctionState>,
+    classes: Vec<ClassState>,
+    // The method names of each trait declared so far, to catch traits that
+    // clash before the program runs, as the resolver does.
+    traits: HashMap<Arc<str>, Vec<Arc<str>>>,
+    errors: Vec<Diagnostic>,
+    scan_errors: Vec<Diagnostic>,
+    // Set after a syntax error until the next statement boundary, so one
+    // mistake is not reported over and over.
+    panic_mode: bool,
+    optimize: bool,
+    // How many declarations, statements and expressions enclose the one
+    // being compiled.
+    depth: usize,
+    // A prefix `++` or `--` waiting for the variable or property it
+    // applies to, and the depth it was compiled at.
+    prefix: Option<(Token, usize)>,
+}
+impl<'h> Compiler<'h> {
+    fn new(
+        source: &str,
+        heap: &'h mut Heap,
+        globals: &'h mut Globals,
+        optimize: bool,
+    ) -> Compiler<'h> {
+        let eof = Token {
+            tty: TokenType::Eof,
+            lexeme: Arc::from(""),
+            literal: None,
+            span: Span::default(),
+        };
+        Compiler {
+            heap,
+            globals,
+            scanner: Scanner::from_source(source),
+            current: eof.clone(),
+            previous: eof,
+            states: vec![FunctionState::new(FunctionKind::Script, "")],
+            classes: Vec::new(),
+            traits: HashMap::new(),
+            errors: Vec::new(),
+            scan_errors: Vec::new(),
+            panic_mode: false,
+            optimize,
+            depth: 0,
+            prefix: None,
+        }
+    }
+
+    fn declaration(&mut self) {
+        self.nested(|compiler| {
+            if compiler.matches(TokenType::Class) {
+                compiler.class_declaration();
+            } else if compiler.matches(TokenType::Trait) {
+                compiler.trait_declaration();
+            } else if compiler.matches(TokenType::Fun) {
+                compiler.fun_declaration();
+            } else if compiler.matches(TokenType::Var) {
+                compiler.var_declaration();
+            } else if compiler.matches(TokenType::Import) {
+                compiler.error("Imports are not supported by the bytecode backend yet.");
+            } else if compiler.matches(TokenType::Do) {
+                compiler.do_while_statement();
+            } else if compiler.matches(TokenType::Switch) {
+                compiler.switch_statement();
+            } else if compiler.matches(TokenType::Throw) || compiler.matches(TokenType::Try) {
+                compiler.error("Exceptions are not supported by the bytecode backend yet.");
+            } else {
+                compiler.statement();
+            }
+            if compiler.panic_mode {
+                compiler.synchronize();
+            }
+        });
+    }
+    fn class_declaration(&mut self) {
+        let global = self.parse_variable("Expect class name.");
+        let class_name = self.previous.clone();
+        let constant = self.identifier_constant(&class_name);
+        self.emit_with(OpCode::Class, constant, class_name.span);
+        self.define_variable(global, class_name.span);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+            is_trait: false,
+        });
+        if self.matches(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            let superclass = self.previous.clone();
+            if superclass.lexeme == class_name.lexeme {
+                self.error("A class can't inherit from itself.");
+            }
+            self.named_variable(&superclass, false);
+            // Methods reach the superclass through a local named `super`,
+            // captured like any other variable.
+            self.begin_scope();
+            self.add_local(Arc::from("super"));
+            self.mark_initialized();
+            self.named_variable(&class_name, false);
+            self.emit_at(OpCode::Inherit, superclass.span);
+            if let Some(class) = self.classes.last_mut() {
+                class.has_superclass = true;
+            }
+        }
+        let traits = self.with_clause();
+
+        // The class stays on the stack while its methods are added. Traits
+        // are mixed in first, so the superclass's methods are overridden by
+        // theirs and theirs by the class's own. Each trait stays on the
+        // stack until the last is in, for the later ones to be checked
+        // against.
+        self.named_variable(&class_name, false);
+        for (i, used) in traits.iter().enumerate() {
+            self.named_variable(used, false);
+            self.emit_with(OpCode::Mixin, i, used.span);
+        }
+        for _ in &traits {
+            self.emit(OpCode::Pop);
+        }
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            if self.matches(TokenType::Class) {
+                self.method(OpCode::ClassMethod);
+            } else {
+                self.method(OpCode::Method);
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit(OpCode::Pop);
+
+        if self.classes.pop().is_some_and(|class| class.has_superclass) {
+            self.end_scope();
+        }
+    }
+    // The traits after `with`, checked against each other where all of
+    // them were declared in this script.
+    fn with_clause(&mut self) -> Vec<Token> {
+        let mut traits: Vec<Token> = Vec::new();
+        if !self.matches(TokenType::With) {
+            return traits;
+        }
+        let mut sources: HashMap<Arc<str>, Arc<str>> = HashMap::new();
+        loop {
+            self.consume(TokenType::Identifier, "Expect trait name.");
+            let used = self.previous.clone();
+            if traits.len() == 255 {
+                self.error("Can't mix in more than 255 traits.");
+            }
+            let methods = self.traits.get(&used.lexeme).cloned().unwrap_or_default();
+            if let Some((method, earlier)) = methods
+                .iter()
+                .find_map(|method| Some((method, sources.get(method)?)))
+            {
+                let message = trait_conflict(method, earlier, &used.lexeme);
+                self.error(&message);
+            }
+            for method in methods {
+                sources.insert(method, Arc::clone(&used.lexeme));
+            }
+            traits.push(used);
+            if !self.matches(TokenType::Comma) {
+                return traits;
+            }
+        }
+    }
+    fn trait_declaration(&mut self) {
+        let global = self.parse_variable("Expect trait name.");
+        let trait_name = self.previous.clone();
+        let constant = self.identifier_constant(&trait_name);
+        self.emit_with(OpCode::Trait, constant, trait_name.span);
+        self.define_variable(global, trait_name.span);
+
+        self.classes.push(ClassState {
+            has_superclass: false,
+            is_trait: true,
+        });
+        self.named_variable(&trait_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before trait body.");
+        let mut methods = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            if self.check(TokenType::Identifier) {
+                methods.push(Arc::clone(&self.current.lexeme));
+            }
+            self.method(OpCode::Method);
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after trait body.");
+        self.emit(OpCode::Pop);
+        self.classes.pop();
+        self.traits.insert(Arc::clone(&trait_name.lexeme), methods);
+    }
+    // A class method, even one named `init`, is an ordinary method with
+    // the class as `this`.
+    fn method(&mut self, op: OpCode) {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.previous.clone();
+        let constant = self.identifier_constant(&name);
+        let function_kind = if op == OpCode::Method && &*name.lexeme == "init" {
+            FunctionKind::Initializer
+        } else {
+            FunctionKind::Method
+        };
+        let kind = if self.check(TokenType::LeftBrace) {
+            "getter"
+        } else {
+            "method"
+        };
+        self.function_body(function_kind, &name.lexeme, kind);
+        self.emit_with(op, constant, name.span);
+    }
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // A function may refer to itself, so its name is usable at once.
+        self.mark_initialized();
+        let name = self.previous.clone();
+        self.function(&name.lexeme, "function");
+        self.define_variable(global, name.span);
+    }
+    fn var_declaration(&mut self) {
+        if self.matches(TokenType::LeftParen) {
+            self.error("Destructuring is not supported by the bytecode backend yet.");
+            return;
+        }
+        let global = self.parse_variable("Expect variable name.");
+        let span = self.previous.span;
+        self.type_annotation();
+        // Only `for (var name in ...)` puts `in` here.
+        if self.check(TokenType::In) {
+            self.error_at_current("Foreach loops are not supported by the bytecode backend yet.");
+            self.emit(OpCode::Nil);
+            self.define_variable(global, span);
+            return;
+        }
+        if self.matches(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit(OpCode::Nil);
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+        self.define_variable(global, span);
+    }
+
+    fn statement(&mut self) {
+        self.nested(|compiler| {
+            if compiler.matches(TokenType::Print) {
+                let start = compiler.current.span;
+                compiler.expression();
+                let span = start.to(compiler.previous.span);
+                compiler.consume(TokenType::Semicolon, "Expect ';' after value.");
+                compiler.emit_at(OpCode::Print, span);
+            } else if compiler.matches(TokenType::For) {
+                compiler.for_statement();
+            } else if compiler.matches(TokenType::If) {
+                compiler.if_statement();
+            } else if compiler.matches(TokenType::Return) {
+                compiler.return_statement();
+            } else if compiler.matches(TokenType::Yield) {
+                compiler.yield_statement();
+            } else if compiler.matches(TokenType::While) {
+                compiler.while_statement();
+            } else if compiler.matches(TokenType::LeftBrace) {
+                compiler.begin_scope();
+                compiler.block();
+                compiler.end_scope();
+            } else {
+                compiler.expression();
+                compiler.consume(TokenType::Semicolon, "Expect ';' after expression.");
+                compiler.emit(OpCode::Pop);
+            }
+        });
+    }
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.matches(TokenType::Semicolon) {
+        } else if self.matches(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+            self.emit(OpCode::Pop);
+        }
+
+        let mut loop_start = self.chunk().code.len();
+        let mut exit_jump = None;
+        let mut condition = None;
+        if !self.matches(TokenType::Semicolon) {
+            let start = self.current.span;
+            self.expression();
+            condition = Some(start.to(self.previous.span));
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit(OpCode::Pop);
+        }
+
+        // The increment is compiled before the body but runs after it, so
+        // the body jumps back to it and it jumps back to the condition.
+        if !self.matches(TokenType::RightParen) {
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.chunk().code.len();
+            self.expression();
+            self.emit(OpCode::Pop);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+            let span = condition.unwrap_or(self.previous.span);
+            self.emit_loop(loop_start, span);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        let span = condition.unwrap_or(self.previous.span);
+        self.emit_loop(loop_start, span);
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit(OpCode::Pop);
+        }
+        self.end_scope();
+    }
+    fn if_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        let condition_start = self.chunk().code.len();
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after if condition.");
+
+        // With a constant condition only one branch can run. The other is
+        // still compiled, for its errors, then thrown away.
+        if let Some(truthy) = self.constant_condition(condition_start) {
+            self.remove_ops(1);
+            if truthy {
+                self.statement();
+            } else {
+                self.dead_code(Self::statement);
+            }
+            if self.matches(TokenType::Else) {
+                if truthy {
+                    self.dead_code(Self::statement);
+                } else {
+                    self.statement();
+                }
+            }
+            return;
+        }
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit(OpCode::Pop);
+        self.statement();
+        let else_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(then_jump);
+        self.emit(OpCode::Pop);
+        if self.matches(TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+    fn do_while_statement(&mut self) {
+        let loop_start = self.chunk().code.len();
+        self.statement();
+        self.consume(TokenType::While, "Expect 'while' after do loop body.");
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        let start = self.current.span;
+        self.expression();
+        let condition = start.to(self.previous.span);
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+        self.consume(TokenType::Semicolon, "Expect ';' after do-while loop.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit(OpCode::Pop);
+        self.emit_loop(loop_start, condition);
+        self.patch_jump(exit_jump);
+        self.emit(OpCode::Pop);
+    }
+    // The subject stays on the stack while cases are compared with it and
+    // is popped before any body runs, so bodies can declare locals.
+    fn switch_statement(&mut self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'switch'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after switch value.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before switch cases.");
+        let mut end_jumps = Vec::new();
+        let mut has_default = false;
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            if self.matches(TokenType::Case) {
+                if has_default {
+                    self.error("Can't have a case after the default case.");
+                }
+                self.emit(OpCode::Dup);
+                let start = self.current.span;
+                self.expression();
+                let span = start.to(self.previous.span);
+                self.consume(TokenType::Colon, "Expect ':' after case value.");
+                self.emit_at(OpCode::Equal, span);
+                let next_case = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit(OpCode::Pop);
+                self.emit(OpCode::Pop);
+                self.case_body();
+                end_jumps.push(self.emit_jump(OpCode::Jump));
+                self.patch_jump(next_case);
+                self.emit(OpCode::Pop);
+            } else if self.matches(TokenType::Default) {
+                if has_default {
+                    self.error("Can't have more than one default case.");
+                }
+                has_default = true;
+                self.consume(TokenType::Colon, "Expect ':' after 'default'.");
+                self.emit(OpCode::Pop);
+                self.case_body();
+            } else {
+                self.error_at_current("Expect 'case' or 'default'.");
+                break;
+            }
+        }
+        if !has_default {
+            self.emit(OpCode::Pop);
+        }
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after switch cases.");
+    }
+    fn case_body(&mut self) {
+        self.begin_scope();
+        while !self.check(TokenType::Case)
+            && !self.check(TokenType::Default)
+            && !self.check(TokenType::RightBrace)
+            && !self.check(TokenType::Eof)
+        {
+            self.declaration();
+        }
+        self.end_scope();
+    }
+    fn return_statement(&mut self) {
+        if self.state().kind == FunctionKind::Script {
+            self.error("Can't return from top-level code.");
+        }
+        if self.matches(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.state().kind == FunctionKind::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            // Nothing is left to do in this function after a call that
+            // ends the returned expression, so it can take over the frame.
+            if self.last_op_is(OpCode::Call) {
+                if let Some(start) = self.op_start(1) {
+                    self.chunk().code[start] = OpCode::TailCall as u8;
+                }
+            }
+            self.emit(OpCode::Return);
+        }
+    }
+    fn yield_statement(&mut self) {
+        let keyword = self.previous.span;
+        if self.state().kind == FunctionKind::Script {
+            self.error("Can't yield from top-level code.");
+        }
+        if self.matches(TokenType::Semicolon) {
+            self.emit(OpCode::Nil);
+        } else {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after yielded value.");
+        }
+        self.emit_at(OpCode::Yield, keyword);
+    }
+    fn while_statement(&mut self) {
+        let loop_start = self.chunk().code.len();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        let start = self.current.span;
+        self.expression();
+        let condition = start.to(self.previous.span);
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit(OpCode::Pop);
+        self.statement();
+        self.emit_loop(loop_start, condition);
+        self.patch_jump(exit_jump);
+        self.emit(OpCode::Pop);
+    }
+
+    // Compiles a parameter list and body, then emits the finished function
+    // as a constant. `kind` names it in error messages, as in the parser.
+    fn function(&mut self, name: &str, kind: &str) {
+        self.function_body(FunctionKind::Function, name, kind);
+    }
+    fn function_body(&mut self, function_kind: FunctionKind, name: &str, kind: &str) {
+        self.states.push(FunctionState::new(function_kind, name));
+        self.begin_scope();
+        if kind == "getter" {
+            self.state_mut().function.is_getter = true;
+        } else {
+            self.parameters(kind);
+        }
+        self.consume(
+            TokenType::LeftBrace,
+            &format!("Expect '{{' before {} body.", kind),
+        );
+        self.block();
+
+        // No end_scope: the frame's slots are discarded all at once when
+        // the function returns.
+        let (function, upvalues) = self.end_function();
+        let handle = self.heap.alloc(Obj::Function(function));
+        let constant = self.make_constant(Value::obj(handle));
+        let span = self.previous.span;
+        self.emit_with(OpCode::Closure, constant, span);
+        for upvalue in upvalues {
+            self.chunk().write(upvalue.is_local as u8, span);
+            self.chunk().write(upvalue.index as u8, span);
+        }
+    }
+    fn parameters(&mut self, kind: &str) {
+        if kind != "lambda" {
+            self.consume(
+                TokenType::LeftParen,
+                &format!("Expect '(' after {} name.", kind),
+            );
+        }
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.state_mut().function.arity += 1;
+                if self.state().function.arity > 255 {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                let slot = self.parse_variable("Expect parameter name.");
+                let name = self.previous.clone();
+                self.type_annotation();
+                if self.matches(TokenType::Equal) {
+                    self.parameter_default();
+                } else if !self.state().function.entries.is_empty() {
+                    self.error_at(
+                        &name,
+                        "A parameter without a default value can't follow one with a default.",
+                    );
+                }
+                self.define_variable(slot, name.span);
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        if !self.state().function.entries.is_empty() {
+            self.add_entry();
+        }
+        if self.matches(TokenType::Arrow) {
+            self.consume(TokenType::Identifier, "Expect type name.");
+        }
+    }
+    // Stores the default in the slot of the parameter just declared. Calls
+    // that pass the parameter start past this code.
+    fn parameter_default(&mut self) {
+        self.add_entry();
+        let span = self.current.span;
+        self.element();
+        let slot = self.state().locals.len() - 1;
+        self.emit_variable(OpCode::SetLocal, slot, span);
+        self.emit_at(OpCode::Pop, span);
+    }
+    // Calls may start here, so nothing before it is rewritten.
+    fn add_entry(&mut self) {
+        let entry = self.chunk().code.len();
+        let state = self.state_mut();
+        state.function.entries.push(entry);
+        state.jump_target = entry;
+    }
+    // Types are only checked by `check --types`, so the compiler skips
+    // them.
+    fn type_annotation(&mut self) {
+        if self.matches(TokenType::Colon) {
+            self.consume(TokenType::Identifier, "Expect type name.");
+        }
+    }
+    fn end_function(&mut self) -> (Function, Vec<UpvalueRef>) {
+        self.emit_return();
+        let mut state = self.states.pop().expect("function state");
+        state.function.upvalue_count = state.upvalues.len();
+        (state.function, state.upvalues)
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Comma);
+    }
+    // An expression in a comma-separated list, where a comma ends it rather
+    // than being an operator.
+    fn element(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+    // Compiles with `compile` unless that would nest deeper than the
+    // tree-walk parser allows. Then a token is skipped instead, so loops
+    // over declarations still make progress.
+    fn nested(&mut self, compile: impl FnOnce(&mut Self)) {
+        if self.depth == MAX_NESTING {
+            self.error_at_current("Too much nesting.");
+            self.advance();
+            return;
+        }
+        self.depth += 1;
+        compile(self);
+        self.depth -= 1;
+    }
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        self.nested(|compiler| {
+            compiler.advance();
+            let Some(prefix) = rule(compiler.previous.tty).prefix else {
+                compiler.error("Expect expression.");
+                return;
+            };
+            let can_assign = precedence <= Precedence::Assignment;
+            prefix(compiler, can_assign);
+            while precedence <= rule(compiler.current.tty).precedence {
+                compiler.advance();
+                if let Some(infix) = rule(compiler.previous.tty).infix {
+                    infix(compiler, can_assign);
+                }
+            }
+            if can_assign && compiler.match_assignment() {
+                compiler.error("Invalid assignment target.");
+            }
+        });
+    }
+
+    fn grouping(&mut self, can_assign: bool) {
+        if can_assign && self.destructures() {
+            self.error("Destructuring is not supported by the bytecode backend yet.");
+            return;
+        }
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+    }
+    // Whether the parentheses just opened hold targets assigned all at
+    // once, as in `(a, b) = [b, a]` or `(p.x, p.y) = [1, 2]`. Only looking
+    // ahead can tell before they would be compiled as reads.
+    fn destructures(&mut self) -> bool {
+        let mut tty = self.current.tty;
+        let (mut ahead, mut nesting, mut commas) = (0, 0, 0);
+        loop {
+            match tty {
+                TokenType::LeftParen | TokenType::LeftBracket => nesting += 1,
+                TokenType::RightParen if nesting == 0 => {
+                    return commas > 0 && self.scanner.peek_token(ahead) == TokenType::Equal;
+                }
+                TokenType::RightParen | TokenType::RightBracket if nesting > 0 => nesting -= 1,
+                TokenType::Eof => return false,
+                _ if nesting > 0 => {}
+                TokenType::Comma => commas += 1,
+                TokenType::Identifier | TokenType::This | TokenType::Dot => {}
+                _ => return false,
+            }
+            tty = self.scanner.peek_token(ahead);
+            ahead += 1;
+        }
+    }
+    fn number(&mut self, _can_assign: bool) {
+        if let Some(Literal::Number(n)) = self.previous.literal {
+            self.emit_constant(Value::number(n));
+        }
+    }
+    fn string(&mut self, _can_assign: bool) {
+        let value = self.segment();
+        self.emit_constant(value);
+    }
+    // `"a ${b} c"` scans as Interpolation("a "), b, String(" c"). Each
+    // embedded value is converted to a string and concatenated in turn.
+    fn interpolation(&mut self, _can_assign: bool) {
+        let value = self.segment();
+        self.emit_constant(value);
+        loop {
+            let span = self.previous.span;
+            self.expression();
+            self.emit_at(OpCode::Stringify, span);
+            self.emit_at(OpCode::Add, span);
+            let done = !self.matches(TokenType::Interpolation);
+            if done {
+                self.consume(
+                    TokenType::String,
+                    "Expect '}' after interpolated expression.",
+                );
+            }
+            let value = self.segment();
+            self.emit_constant(value);
+            self.emit_at(OpCode::Add, span);
+            if done {
+                return;
+            }
+        }
+    }
+    fn segment(&mut self) -> Value {
+        match &self.previous.literal {
+            Some(Literal::Str(s)) => {
+                let s = s.clone();
+                self.heap.string(&s)
+            }
+            _ => self.heap.string(""),
+        }
+    }
+    fn literal(&mut self, _can_assign: bool) {
+        match self.previous.tty {
+            TokenType::False => self.emit(OpCode::False),
+            TokenType::True => self.emit(OpCode::True),
+            _ => self.emit(OpCode::Nil),
+        }
+    }
+    // `x++` leaves the old value of `x` behind its copy, which is updated
+    // and stored, then popped.
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.previous.clone();
+        let prefix = self.take_prefix();
+        if prefix.is_none() && !self.match_update() {
+            self.named_variable(&name, can_assign);
+            return;
+        }
+        let (get, set, operand) = self.resolve_variable(&name);
+        self.emit_variable(get, operand, name.span);
+        match prefix {
+            Some(operator) => {
+                self.emit_step(&operator);
+                self.emit_variable(set, operand, name.span);
+            }
+            None => {
+                let operator = self.previous.clone();
+                self.emit_at(OpCode::Dup, operator.span);
+                self.emit_step(&operator);
+                self.emit_variable(set, operand, name.span);
+                self.emit_at(OpCode::Pop, operator.span);
+            }
+        }
+    }
+    fn resolve_variable(&mut self, name: &Token) -> (OpCode, OpCode, usize) {
+        let innermost = self.states.len() - 1;
+        if let Some(slot) = self.resolve_local(innermost, name) {
+            (OpCode::GetLocal, OpCode::SetLocal, slot)
+        } else if let Some(index) = self.resolve_upvalue(innermost, name) {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, index)
+        } else {
+            let slot = self.global_slot(name);
+            (OpCode::GetGlobal, OpCode::SetGlobal, slot)
+        }
+    }
+    fn named_variable(&mut self, name: &Token, can_assign: bool) {
+        let (get, set, operand) = self.resolve_variable(name);
+        if can_assign && self.matches(TokenType::Equal) {
+            self.element();
+            self.emit_variable(set, operand, name.span);
+        } else if can_assign && self.match_compound() {
+            // `a op= b` is `a = a op b`, with the operator at the `op=`.
+            let operator = self.previous.clone();
+            self.emit_variable(get, operand, name.span);
+            self.element();
+            self.emit_compound(&operator);
+            self.emit_variable(set, operand, name.span);
+        } else {
+            self.emit_variable(get, operand, name.span);
+        }
+    }
+    // Adds or subtracts one for a `++` or `--`.
+    fn emit_step(&mut self, operator: &Token) {
+        let one = self.literal_constant(Value::number(1.0));
+        self.emit_with(OpCode::Constant, one, operator.span);
+        match operator.tty {
+            TokenType::PlusPlus => self.emit_at(OpCode::Add, operator.span),
+            _ => self.emit_at(OpCode::Subtract, operator.span),
+        }
+    }
+    // A prefix `++` or `--` applies to the variable or property that ends
+    // the call-level expression after it, which takes it from `prefix`.
+    // Any other operand is left to report.
+    fn prefix_update(&mut self, _can_assign: bool) {
+        let operator = self.previous.clone();
+        let outer = self.prefix.replace((operator, self.depth));
+        self.parse_precedence(Precedence::Call);
+        if let Some((operator, _)) = mem::replace(&mut self.prefix, outer) {
+            self.error_at(&operator, "Invalid assignment target.");
+        }
+    }
+    // A postfix `++` or `--` after something that isn't a variable or
+    // property, which would have taken it.
+    fn invalid_update(&mut self, _can_assign: bool) {
+        self.error("Invalid assignment target.");
+    }
+    // The pending prefix operator, if the variable or property just
+    // compiled is its operand rather than part of it.
+    fn take_prefix(&mut self) -> Option<Token> {
+        let (_, depth) = self.prefix.as_ref()?;
+        if *depth + 1 != self.depth
+            || matches!(
+                self.current.tty,
+                TokenType::Dot
+                    | TokenType::LeftParen
+                    | TokenType::LeftBracket
+                    | TokenType::PlusPlus
+                    | TokenType::MinusMinus
+            )
+        {
+            return None;
+        }
+        self.prefix.take().map(|(operator, _)| operator)
+    }
+    fn match_update(&mut self) -> bool {
+        self.matches(TokenType::PlusPlus) || self.matches(TokenType::MinusMinus)
+    }
+    fn emit_compound(&mut self, operator: &Token) {
+        match operator.tty {
+            TokenType::PlusEqual => self.emit_at(OpCode::Add, operator.span),
+            TokenType::MinusEqual => self.emit_at(OpCode::Subtract, operator.span),
+            TokenType::StarEqual => self.emit_at(OpCode::Multiply, operator.span),
+            _ => self.emit_at(OpCode::Divide, operator.span),
+        }
+    }
+    fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        let keyword = self.previous.clone();
+        self.named_variable(&keyword, false);
+    }
+    // `super.m` binds the superclass's method to `this`; `super.m(...)`
+    // calls it without creating the bound method.
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if class.is_trait => self.error("Can't use 'super' in a trait."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            Some(_) => {}
+        }
+        let keyword = self.previous.clone();
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.previous.clone();
+        let constant = self.identifier_constant(&name);
+        self.named_variable(&synthetic_token("this", keyword.span), false);
+        if self.matches(TokenType::LeftParen) {
+            let count = self.argument_list();
+            self.named_variable(&synthetic_token("super", keyword.span), false);
+            self.emit_invoke(OpCode::SuperInvoke, constant, name.span, count);
+        } else {
+            self.named_variable(&synthetic_token("super", keyword.span), false);
+            self.emit_with(OpCode::GetSuper, constant, name.span);
+        }
+    }
+    // A property access, assignment or method call. `a.b op= c` reads the
+    // property from a copy of the object, leaving the original for the
+    // assignment.
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.previous.clone();
+        let constant = self.identifier_constant(&name);
+        if let Some(operator) = self.take_prefix() {
+            self.emit_at(OpCode::Dup, name.span);
+            self.emit_with(OpCode::GetProperty, constant, name.span);
+            self.emit_step(&operator);
+            self.emit_with(OpCode::SetProperty, constant, name.span);
+        } else if can_assign && self.matches(TokenType::Equal) {
+            self.element();
+            self.emit_with(OpCode::SetProperty, constant, name.span);
+        } else if can_assign && self.match_compound() {
+            let operator = self.previous.clone();
+            self.emit_at(OpCode::Dup, name.span);
+            self.emit_with(OpCode::GetProperty, constant, name.span);
+            self.element();
+            self.emit_compound(&operator);
+            self.emit_with(OpCode::SetProperty, constant, name.span);
+        } else if self.matches(TokenType::LeftParen) {
+            let count = self.argument_list();
+            self.emit_invoke(OpCode::Invoke, constant, name.span, count);
+        } else if self.match_update() {
+            // The old value is tucked beneath the object to be left behind
+            // once the new one is stored and popped.
+            let operator = self.previous.clone();
+            self.emit_at(OpCode::Dup, name.span);
+            self.emit_with(OpCode::GetProperty, constant, name.span);
+            self.emit_at(OpCode::Tuck, operator.span);
+            self.emit_step(&operator);
+            self.emit_with(OpCode::SetProperty, constant, name.span);
+            self.emit_at(OpCode::Pop, operator.span);
+        } else {
+            self.emit_with(OpCode::GetProperty, constant, name.span);
+        }
+    }
+    // `a?.b` and `a?.b()` leave `a` itself if it is nil, skipping the
+    // access and the call. Neither can be assigned to.
+    fn safe_dot(&mut self, _can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '?.'.");
+        let name = self.previous.clone();
+        let constant = self.identifier_constant(&name);
+        let nil_jump = self.emit_jump(OpCode::JumpIfNil);
+        if self.matches(TokenType::LeftParen) {
+            let count = self.argument_list();
+            self.emit_invoke(OpCode::Invoke, constant, name.span, count);
+        } else {
+            self.emit_with(OpCode::GetProperty, constant, name.span);
+        }
+        self.patch_jump(nil_jump);
+    }
+    fn unary(&mut self, _can_assign: bool) {
+        let operator = self.previous.clone();
+        self.parse_precedence(Precedence::Unary);
+        match operator.tty {
+            TokenType::Bang => self.emit_at(OpCode::Not, operator.span),
+            _ => self.emit_at(OpCode::Negate, operator.span),
+        }
+    }
+    fn binary(&mut self, _can_assign: bool) {
+        let operator = self.previous.clone();
+        self.parse_precedence(rule(operator.tty).precedence.next());
+        let span = operator.span;
+        match operator.tty {
+            TokenType::BangEqual => {
+                self.emit_at(OpCode::Equal, span);
+                self.emit_at(OpCode::Not, span);
+            }
+            TokenType::EqualEqual => self.emit_at(OpCode::Equal, span),
+            TokenType::Greater => self.emit_at(OpCode::Greater, span),
+            TokenType::GreaterEqual => {
+                self.emit_at(OpCode::Less, span);
+                self.emit_at(OpCode::Not, span);
+            }
+            TokenType::Less => self.emit_at(OpCode::Less, span),
+            TokenType::LessEqual => {
+                self.emit_at(OpCode::Greater, span);
+                self.emit_at(OpCode::Not, span);
+            }
+            TokenType::Plus => self.emit_at(OpCode::Add, span),
+            TokenType::Minus => self.emit_at(OpCode::Subtract, span),
+            TokenType::Star => self.emit_at(OpCode::Multiply, span),
+            TokenType::Slash => self.emit_at(OpCode::Divide, span),
+            _ => self.emit_at(OpCode::Modulo, span),
+        }
+    }
+    // `**` is right-associative and its right operand may itself be a
+    // unary, as in `2 ** -1`.
+    fn power(&mut self, _can_assign: bool) {
+        let span = self.previous.span;
+        self.parse_precedence(Precedence::Unary);
+        self.emit_at(OpCode::Power, span);
+    }
+    // The else branch is parsed at conditional precedence so `?:` chains
+    // associate to the right.
+    fn conditional(&mut self, _can_assign: bool) {
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit(OpCode::Pop);
+        self.expression();
+        self.consume(
+            TokenType::Colon,
+            "Expect ':' after then branch of conditional expression.",
+        );
+        let else_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(then_jump);
+        self.emit(OpCode::Pop);
+        self.parse_precedence(Precedence::Conditional);
+        self.patch_jump(else_jump);
+    }
+    // The left operand is discarded once it has run.
+    fn comma(&mut self, _can_assign: bool) {
+        self.emit(OpCode::Pop);
+        self.element();
+    }
+    fn and(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit(OpCode::Pop);
+        self.parse_precedence(Precedence::And);
+        self.patch_jump(end_jump);
+    }
+    fn or(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(else_jump);
+        self.emit(OpCode::Pop);
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+    fn call(&mut self, _can_assign: bool) {
+        let count = self.argument_list();
+        self.emit_with(OpCode::Call, count, self.previous.span);
+    }
+    // Compiles arguments up to the closing parenthesis and returns how many
+    // there were.
+    fn argument_list(&mut self) -> usize {
+        let mut count = 0usize;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                // Checked before the argument compiles, so that its constant
+                // can't overflow the chunk's first.
+                if count == 255 {
+                    self.error_at_current("Can't have more than 255 arguments.");
+                }
+                self.element();
+                count += 1;
+                if !self.matches(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        count.min(255)
+    }
+    fn lambda(&mut self, _can_assign: bool) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'lambda'.");
+        self.function("lambda", "lambda");
+    }
+    // Lists, maps and ranges only exist in the tree-walk interpreter so far.
+    fn unsupported(&mut self, _can_assign: bool) {
+        let feature = match self.previous.tty {
+            TokenType::LeftBrace => "Maps are",
+            TokenType::DotDot | TokenType::DotDotEqual => "Ranges are",
+            _ => "Lists are",
+        };
+        self.error(&format!(
+            "{} not supported by the bytecode backend yet.",
+            feature
+        ));
+    }
+
+    // Declares a variable named by the next token. A global's slot in the
+    // globals table is returned; locals live in stack slots and need no
+    // operand.
+    fn parse_variable(&mut self, message: &str) -> usize {
+        self.consume(TokenType::Identifier, message);
+        if self.state().scope_depth > 0 {
+            self.declare_local();
+            return 0;
+        }
+        let name = self.previous.clone();
+        self.global_slot(&name)
+    }
+    fn declare_local(&mut self) {
+        let name = Arc::clone(&self.previous.lexeme);
+        let state = self.state();
+        let duplicate = state
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= state.scope_depth))
+            .any(|local| local.name == name);
+        if duplicate {
+            self.error("Already a variable with this name in this scope.");
+        }
+        self.add_local(name);
+    }
+    fn add_local(&mut self, name: Arc<str>) {
+        if self.state().locals.len() > u8::MAX as usize {
+            self.error("Too many local variables in function.");
+            return;
+        }
+        self.state_mut().locals.push(Local {
+            name,
+            depth: None,
+            captured: false,
+        });
+    }
+    fn define_variable(&mut self, global: usize, span: Span) {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+        self.emit_variable(OpCode::DefineGlobal, global, span);
+    }
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+        if state.scope_depth == 0 {
+            return;
+        }
+        let depth = state.scope_depth;
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = Some(depth);
+        }
+    }
+    // Looks `name` up among the locals of the function at `state` in the
+    // stack of functions being compiled.
+    fn resolve_local(&mut self, state: usize, name: &Token) -> Option<usize> {
+        let (slot, local) = self.states[state]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name.lexeme)?;
+        let uninitialized = local.depth.is_none();
+        if uninitialized {
+            self.error("Can't read local variable in its own initializer.");
+        }
+        Some(slot)
+    }
+    // Finds `name` in an enclosing function, capturing it in every function
+    // between there and `state` so each can hand it to the next.
+    fn resolve_upvalue(&mut self, state: usize, name: &Token) -> Option<usize> {
+        if state == 0 {
+            return None;
+        }
+        if let Some(slot) = self.resolve_local(state - 1, name) {
+            self.states[state - 1].locals[slot].captured = true;
+            return Some(self.add_upvalue(state, slot, true));
+        }
+        let index = self.resolve_upvalue(state - 1, name)?;
+        Some(self.add_upvalue(state, index, false))
+    }
+    fn add_upvalue(&mut self, state: usize, index: usize, is_local: bool) -> usize {
+        let upvalue = UpvalueRef { index, is_local };
+        let upvalues = &self.states[state].upvalues;
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return existing;
+        }
+        if upvalues.len() > u8::MAX as usize {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+        self.states[state].upvalues.push(upvalue);
+        self.states[state].upvalues.len() - 1
+    }
+    fn global_slot(&mut self, name: &Token) -> usize {
+        let Some(handle) = self.heap.string(&name.lexeme).as_obj() else {
+            return 0;
+        };
+        let slot = self.globals.slot(handle);
+        if slot > u16::MAX as usize {
+            self.error("Too many global variables.");
+            return 0;
+        }
+        slot
+    }
+    fn identifier_constant(&mut self, name: &Token) -> usize {
+        let value = self.heap.string(&name.lexeme);
+        self.make_constant(value)
+    }
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+    fn end_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+        loop {
+            let state = self.state();
+            match state.locals.last() {
+                Some(Local {
+                    depth: Some(depth),
+                    captured,
+                    ..
+                }) if *depth > state.scope_depth => {
+                    let op = if *captured {
+                        OpCode::CloseUpvalue
+                    } else {
+                        OpCode::Pop
+                    };
+                    self.state_mut().locals.pop();
+                    self.emit(op);
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn state(&self) -> &FunctionState {
+        self.states.last().expect("function state")
+    }
+    fn state_mut(&mut self) -> &mut FunctionState {
+        self.states.last_mut().expect("function state")
+    }
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state_mut().function.chunk
+    }
+    // Instructions are located at the token just consumed unless a span is
+    // given, so runtime errors point at the operator that failed.
+    fn emit(&mut self, op: OpCode) {
+        self.emit_at(op, self.previous.span);
+    }
+    fn emit_at(&mut self, op: OpCode, span: Span) {
+        if self.optimize && self.fold(op, span) {
+            return;
+        }
+        let start = self.chunk().code.len();
+        self.state_mut().ops.push(start);
+        self.chunk().write_op(op, span);
+    }
+    fn emit_with(&mut self, op: OpCode, operand: usize, span: Span) {
+        self.emit_at(op, span);
+        self.chunk().write(operand as u8, span);
+    }
+    // The name and constant are located at the method name, so property
+    // errors point there, and the argument count at the `)`, like a call.
+    fn emit_invoke(&mut self, op: OpCode, constant: usize, span: Span, count: usize) {
+        self.emit_with(op, constant, span);
+        let paren = self.previous.span;
+        self.chunk().write(count as u8, paren);
+    }
+    // Initializers always return the instance, in slot zero.
+    fn emit_return(&mut self) {
+        if self.state().kind == FunctionKind::Initializer {
+            self.emit_with(OpCode::GetLocal, 0, self.previous.span);
+        } else {
+            self.emit(OpCode::Nil);
+        }
+        self.emit(OpCode::Return);
+    }
+    // Global slots take two bytes; local and upvalue indexes one.
+    fn emit_variable(&mut self, op: OpCode, operand: usize, span: Span) {
+        if matches!(
+            op,
+            OpCode::GetGlobal | OpCode::SetGlobal | OpCode::DefineGlobal
+        ) {
+            self.emit_at(op, span);
+            self.chunk().write((operand >> 8) as u8, span);
+            self.chunk().write(operand as u8, span);
+        } else {
+            self.emit_with(op, operand, span);
+        }
+    }
+    fn emit_constant(&mut self, value: Value) {
+        let constant = self.literal_constant(value);
+        self.emit_with(OpCode::Constant, constant, self.previous.span);
+    }
+    // Literals share pool entries with equal constants already there.
+    fn literal_constant(&mut self, value: Value) -> usize {
+        let existing = match self.optimize {
+            true => self
+                .chunk()
+                .constants
+                .iter()
+                .position(|constant| same_constant(*constant, value)),
+            false => None,
+        };
+        match existing {
+            Some(constant) => constant,
+            None => self.make_constant(value),
+        }
+    }
+    fn make_constant(&mut self, value: Value) -> usize {
+        let constant = self.chunk().add_constant(value);
+        if constant > u8::MAX as usize {
+            self.error("Too many constants in one chunk.");
+            return 0;
+        }
+        constant
+    }
+    // Emits a jump with a placeholder offset and returns where the offset
+    // is, for `patch_jump` to fill in.
+    fn emit_jump(&mut self, op: OpCode) -> usize {
+        let span = self.previous.span;
+        self.emit_at(op, span);
+        self.chunk().write(0xff, span);
+        self.chunk().write(0xff, span);
+        self.chunk().code.len() - 2
+    }
+    fn patch_jump(&mut self, offset: usize) {
+        let target = self.chunk().code.len();
+        self.state_mut().jump_target = target;
+        let jump = target - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+        let code = &mut self.chunk().code;
+        code[offset] = (jump >> 8) as u8;
+        code[offset + 1] = jump as u8;
+    }
+    // `span` is the loop's condition, where running out of fuel is
+    // reported.
+    fn emit_loop(&mut self, loop_start: usize, span: Span) {
+        self.emit_at(OpCode::Loop, span);
+        let offset = self.chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+        self.chunk().write((offset >> 8) as u8, span);
+        self.chunk().write(offset as u8, span);
+    }
+
+    // Rewrites the instructions just emitted when `op` applied to them can
+    // be worked out now: arithmetic and comparison of number constants,
+    // `!` and `-` of constants, and a `!` that undoes the one before it.
+    // Returns whether `op` was absorbed. A `-` of a `-` is kept, since
+    // instances can overload `-` with a method.
+    fn fold(&mut self, op: OpCode, span: Span) -> bool {
+        match op {
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Modulo
+            | OpCode::Power
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Equal => {
+                let (Some(Unboxed::Number(a)), Some(Unboxed::Number(b))) =
+                    (self.constant_operand(2), self.constant_operand(1))
+                else {
+                    return false;
+                };
+                self.remove_ops(2);
+                let result = match op {
+                    OpCode::Add => a + b,
+                    OpCode::Subtract => a - b,
+                    OpCode::Multiply => a * b,
+                    OpCode::Divide => a / b,
+                    OpCode::Modulo => a % b,
+                    OpCode::Power => a.powf(b),
+                    OpCode::Greater => return self.emit_bool(a > b, span),
+                    OpCode::Less => return self.emit_bool(a < b, span),
+                    _ => return self.emit_bool(a == b, span),
+                };
+                self.emit_folded(Value::number(result), span)
+            }
+            OpCode::Negate => match self.constant_operand(1) {
+                Some(Unboxed::Number(n)) => {
+                    self.remove_ops(1);
+                    self.emit_folded(Value::number(-n), span)
+                }
+                _ => false,
+            },
+            OpCode::Not => match self.constant_operand(1) {
+                Some(value) => {
+                    self.remove_ops(1);
+                    self.emit_bool(matches!(value, Unboxed::Nil | Unboxed::Bool(false)), span)
+                }
+                // `!` of a boolean twice over gives back the boolean.
+                None if self.last_op_is(OpCode::Not) && self.produces_bool(2) => {
+                    self.remove_ops(1);
+                    true
+                }
+                None => false,
+            },
+            _ => false,
+        }
+    }
+    // The constant pushed by the `back`th instruction from the end, if
+    // that and every later instruction can only be reached in order.
+    fn constant_operand(&mut self, back: usize) -> Option<Unboxed> {
+        let start = self.op_start(back)?;
+        let code = &self.state().function.chunk.code;
+        match OpCode::try_from(code[start]) {
+            Ok(OpCode::Nil) => Some(Unboxed::Nil),
+            Ok(OpCode::True) => Some(Unboxed::Bool(true)),
+            Ok(OpCode::False) => Some(Unboxed::Bool(false)),
+            Ok(OpCode::Constant) => {
+                let constant = self.state().function.chunk.constants[code[start + 1] as usize];
+                // Strings are left alone, since concatenating them at
+                // compile time would allocate.
+                match constant.unbox() {
+                    Unboxed::Number(n) => Some(Unboxed::Number(n)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+    // Where the `back`th instruction from the end starts, if no jump lands
+    // after that.
+    fn op_start(&self, back: usize) -> Option<usize> {
+        let state = self.state();
+        let start = *state
+            .ops
+            .len()
+            .checked_sub(back)
+            .and_then(|i| state.ops.get(i))?;
+        (start >= state.jump_target).then_some(start)
+    }
+    fn last_op_is(&self, op: OpCode) -> bool {
+        self.op_start(1)
+            .is_some_and(|start| self.state().function.chunk.code[start] == op as u8)
+    }
+    fn produces_bool(&self, back: usize) -> bool {
+        self.op_start(back).is_some_and(|start| {
+            matches!(
+                OpCode::try_from(self.state().function.chunk.code[start]),
+                Ok(OpCode::Not
+                    | OpCode::Equal
+                    | OpCode::Greater
+                    | OpCode::Less
+                    | OpCode::True
+                    | OpCode::False)
+            )
+        })
+    }
+    fn emit_bool(&mut self, b: bool, span: Span) -> bool {
+        self.emit_at(if b { OpCode::True } else { OpCode::False }, span);
+        true
+    }
+    fn emit_folded(&mut self, value: Value, span: Span) -> bool {
+        let constant = self.literal_constant(value);
+        self.emit_with(OpCode::Constant, constant, span);
+        true
+    }
+    // Whether the condition compiled since `start` is a lone `true`,
+    // `false` or `nil`, and which.
+    fn constant_condition(&mut self, start: usize) -> Option<bool> {
+        if !self.optimize || self.op_start(1) != Some(start) {
+            return None;
+        }
+        match self.constant_operand(1)? {
+            Unboxed::Nil | Unboxed::Bool(false) => Some(false),
+            Unboxed::Bool(true) => Some(true),
+            _ => None,
+        }
+    }
+    fn remove_ops(&mut self, count: usize) {
+        let state = self.state_mut();
+        let start = state.ops[state.ops.len() - count];
+        state.ops.truncate(state.ops.len() - count);
+        state.function.chunk.code.truncate(start);
+        state.function.chunk.spans.truncate(start);
+    }
+    // Compiles code that can never run and discards its instructions.
+    fn dead_code(&mut self, compile: fn(&mut Compiler<'h>)) {
+        let (start, ops) = (self.chunk().code.len(), self.state().ops.len());
+        compile(self);
+        let state = self.state_mut();
+        state.ops.truncate(ops);
+        state.function.chunk.code.truncate(start);
+        state.function.chunk.spans.truncate(start);
+        state.jump_target = state.jump_target.min(start);
+    }
+
+    fn advance(&mut self) {
+        let next = loop {
+            match self.scanner.next() {
+                Some(Ok(token)) => break token,
+                Some(Err(e)) => self.scan_errors.push(e),
+                None => break self.current.clone(),
+            }
+        };
+        self.previous = mem::replace(&mut self.current, next);
+    }
+    fn consume(&mut self, tty: TokenType, message: &str) {
+        if self.current.tty == tty {
+            self.advance();
+        } else {
+            self.error_at_current(message);
+        }
+    }
+    fn check(&self, tty: TokenType) -> bool {
+        self.current.tty == tty
+    }
+    fn matches(&mut self, tty: TokenType) -> bool {
+        if !self.check(tty) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+    fn match_compound(&mut self) -> bool {
+        [
+            TokenType::PlusEqual,
+            TokenType::MinusEqual,
+            TokenType::StarEqual,
+            TokenType::SlashEqual,
+        ]
+        .into_iter()
+        .any(|tty| self.matches(tty))
+    }
+    fn match_assignment(&mut self) -> bool {
+        self.matches(TokenType::Equal) || self.match_compound()
+    }
+    // Discards tokens until the start of what is probably the next
+    // statement.
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+        while self.current.tty != TokenType::Eof {
+            if self.previous.tty == TokenType::Semicolon {
+                return;
+            }
+            match self.current.tty {
+                TokenType::Class
+                | TokenType::Trait
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return
+                | TokenType::Import
+                | TokenType::Throw
+                | TokenType::Try
+                | TokenType::Switch
+                | TokenType::Yield
+                | TokenType::Do => return,
+                _ => self.advance(),
+            }
+        }
+    }
+    fn error(&mut self, message: &str) {
+        let token = self.previous.clone();
+        self.error_at(&token, message);
+    }
+    fn error_at_current(&mut self, message: &str) {
+        let token = self.current.clone();
+        self.error_at(&token, message);
+    }
+    fn error_at(&mut self, token: &Token, message: &str) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        self.errors.push(Diagnostic::at_token(token, message));
+    }
+}
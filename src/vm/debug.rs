@@ -0,0 +1,162 @@
+use crate::span::SourceMap;
+use crate::vm::chunk::{Chunk, OpCode};
+use crate::vm::globals::Globals;
+use crate::vm::object::{Heap, Obj};
+use crate::vm::value::Value;
+
+// Lists every instruction in `chunk`, then the chunks of any functions
+// among its constants, in the style of clox's disassembler.
+pub fn disassemble_chunk(
+    chunk: &Chunk,
+    name: &str,
+    heap: &Heap,
+    globals: &Globals,
+    map: &SourceMap,
+) -> String {
+    let mut out = format!("== {} ==\n", name);
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let (text, next) = disassemble_instruction(chunk, offset, heap, globals, map);
+        out.push_str(&text);
+        out.push('\n');
+        offset = next;
+    }
+    for constant in &chunk.constants {
+        if let Some(handle) = constant.as_obj() {
+            if let Obj::Function(function) = heap.get(handle) {
+                out.push_str(&disassemble_chunk(
+                    &function.chunk,
+                    &function.name,
+                    heap,
+                    globals,
+                    map,
+                ));
+            }
+        }
+    }
+    out
+}
+
+// Renders the instruction at `offset` as its offset, source line, name and
+// operands, and returns the offset of the next instruction. The line is
+// shown as `|` when it is the same as the previous instruction's.
+pub fn disassemble_instruction(
+    chunk: &Chunk,
+    offset: usize,
+    heap: &Heap,
+    globals: &Globals,
+    map: &SourceMap,
+) -> (String, usize) {
+    let line = map.line(chunk.spans[offset].start);
+    let same_line = offset > 0 && map.line(chunk.spans[offset - 1].start) == line;
+    let prefix = if same_line {
+        format!("{:04}    | ", offset)
+    } else {
+        format!("{:04} {:4} ", offset, line)
+    };
+    let byte = chunk.code[offset];
+    let Ok(op) = OpCode::try_from(byte) else {
+        return (format!("{}Unknown opcode {}", prefix, byte), offset + 1);
+    };
+    let name = op_name(op);
+    let operand = |i: usize| chunk.code.get(offset + i).copied().unwrap_or(0) as usize;
+    let (text, size) = match op {
+        OpCode::GetGlobal | OpCode::DefineGlobal | OpCode::SetGlobal => {
+            let slot = operand(1) << 8 | operand(2);
+            let global = heap.format(Value::obj(globals.name(slot)));
+            (format!("{:<16} {:4} '{}'", name, slot, global), 3)
+        }
+        OpCode::Constant
+        | OpCode::Class
+        | OpCode::Method
+        | OpCode::ClassMethod
+        | OpCode::Trait
+        | OpCode::GetProperty
+        | OpCode::SetProperty
+        | OpCode::GetSuper => {
+            let constant = operand(1);
+            let value = chunk
+                .constants
+                .get(constant)
+                .map_or_else(String::new, |value| heap.format(*value));
+            (format!("{:<16} {:4} '{}'", name, constant, value), 2)
+        }
+        OpCode::Invoke | OpCode::SuperInvoke => {
+            let constant = operand(1);
+            let value = chunk
+                .constants
+                .get(constant)
+                .map_or_else(String::new, |value| heap.format(*value));
+            (
+                format!(
+                    "{:<16} ({} args) {:4} '{}'",
+                    name,
+                    operand(2),
+                    constant,
+                    value
+                ),
+                3,
+            )
+        }
+        // The function constant, then one line per captured variable.
+        OpCode::Closure => {
+            let constant = operand(1);
+            let value = chunk.constants.get(constant).copied();
+            let mut text = format!(
+                "{:<16} {:4} {}",
+                name,
+                constant,
+                value.map_or_else(String::new, |value| heap.format(value))
+            );
+            let count = match value.and_then(Value::as_obj).map(|handle| heap.get(handle)) {
+                Some(Obj::Function(function)) => function.upvalue_count,
+                _ => 0,
+            };
+            for i in 0..count {
+                let at = offset + 2 + i * 2;
+                let kind = if operand(2 + i * 2) == 1 {
+                    "local"
+                } else {
+                    "upvalue"
+                };
+                text.push_str(&format!(
+                    "\n{:04}      |                     {} {}",
+                    at,
+                    kind,
+                    operand(3 + i * 2)
+                ));
+            }
+            (text, 2 + count * 2)
+        }
+        OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call
+        | OpCode::TailCall
+        | OpCode::Mixin => (format!("{:<16} {:4}", name, operand(1)), 2),
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfNil | OpCode::Loop => {
+            let jump = operand(1) << 8 | operand(2);
+            let target = if op == OpCode::Loop {
+                (offset + 3).wrapping_sub(jump)
+            } else {
+                offset + 3 + jump
+            };
+            (format!("{:<16} {:4} -> {}", name, offset, target), 3)
+        }
+        _ => (name, 1),
+    };
+    (format!("{}{}", prefix, text), offset + size)
+}
+
+// `GetLocal` becomes `OP_GET_LOCAL`, as clox names its opcodes.
+pub(super) fn op_name(op: OpCode) -> String {
+    let mut name = String::from("OP");
+    for c in format!("{:?}", op).chars() {
+        if c.is_ascii_uppercase() {
+            name.push('_');
+        }
+        name.push(c.to_ascii_uppercase());
+    }
+    name
+}
@@ -0,0 +1,57 @@
+use crate::vm::object::ObjRef;
+use crate::vm::value::Value;
+use std::collections::HashMap;
+
+// Global variables, by slot. The compiler gives each name a slot the first
+// time it sees it, so instructions index the table instead of hashing the
+// name. Slots last as long as the VM, like the globals themselves.
+#[derive(Debug, Default)]
+pub struct Globals {
+    slots: HashMap<ObjRef, usize>,
+    names: Vec<ObjRef>,
+    // None until the variable is defined.
+    values: Vec<Option<Value>>,
+}
+impl Globals {
+    // The slot for the interned string `name`, allocated if it is new.
+    pub fn slot(&mut self, name: ObjRef) -> usize {
+        if let Some(slot) = self.slots.get(&name) {
+            return *slot;
+        }
+        self.names.push(name);
+        self.values.push(None);
+        self.slots.insert(name, self.names.len() - 1);
+        self.names.len() - 1
+    }
+    pub fn name(&self, slot: usize) -> ObjRef {
+        self.names[slot]
+    }
+    // The name of every slot, in order.
+    pub fn names(&self) -> impl Iterator<Item = ObjRef> + '_ {
+        self.names.iter().copied()
+    }
+    pub fn get(&self, slot: usize) -> Option<Value> {
+        self.values[slot]
+    }
+    pub fn define(&mut self, slot: usize, value: Value) {
+        self.values[slot] = Some(value);
+    }
+    // Assigns to an existing variable. Returns false if it is undefined.
+    pub fn set(&mut self, slot: usize, value: Value) -> bool {
+        match &mut self.values[slot] {
+            Some(current) => {
+                *current = value;
+                true
+            }
+            None => false,
+        }
+    }
+    // Every name, including those never defined, since slots refer to them
+    // by handle, and every value.
+    pub fn roots(&self) -> impl Iterator<Item = Value> + '_ {
+        self.names
+            .iter()
+            .map(|name| Value::obj(*name))
+            .chain(self.values.iter().flatten().copied())
+    }
+}
@@ -0,0 +1,1270 @@
+mod chunk;
+mod compiler;
+mod debug;
+mod globals;
+mod natives;
+mod object;
+mod profile;
+mod serialize;
+mod value;
+
+use crate::error::{Diagnostic, Frame, LoxError};
+use crate::interpreter::{arity_mismatch, trait_conflict, DEFAULT_MAX_CALL_DEPTH};
+use crate::limits::{Budget, Limits};
+use crate::natives::since_epoch;
+use crate::span::{SourceMap, Span};
+use chunk::{InlineCache, OpCode};
+use globals::Globals;
+use object::{
+    BoundMethod, Class, Closure, Coroutine, Fiber, Heap, Instance, Native, NativeFn, Obj, ObjRef,
+    Trait, Upvalue,
+};
+use profile::Profile;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::time::Instant;
+use value::{Unboxed, Value};
+
+// Where the argument count of `Invoke` and `SuperInvoke` is, relative to
+// the instruction. Call errors are located there, at the `)`.
+const INVOKE_CALL_SITE: usize = 2;
+
+// A function activation: which closure is running, the offset of its
+// next instruction, and where its slots start on the value stack. The
+// closure's function is kept alongside for quick access to its chunk.
+struct CallFrame {
+    closure: ObjRef,
+    function: ObjRef,
+    ip: usize,
+    base: usize,
+    after: AfterReturn,
+}
+
+// What to do with a frame's result when it returns. Most calls push it,
+// but the VM also calls methods on the program's behalf, for getters and
+// overloaded operators, and finishes their instruction with the result.
+#[derive(Clone, Copy, PartialEq)]
+enum AfterReturn {
+    Push,
+    // For a getter run by a method call such as `a.getter(x)`: the call
+    // site and argument count of the call to make with its result. The
+    // arguments wait on the stack beneath the frame.
+    Call(usize, usize),
+    // An `equals` method's result, as a boolean.
+    Bool,
+    Print,
+    Stringify,
+}
+
+// A stack-based virtual machine that runs bytecode compiled straight from
+// source. It implements the same language as the tree-walk interpreter
+// except for lists and maps. Globals persist from one `interpret` to the
+// next.
+pub struct Vm {
+    heap: Heap,
+    stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    // Upvalues still pointing into the stack, with the slot each refers to,
+    // so closures capturing the same variable share one upvalue.
+    open_upvalues: Vec<(usize, ObjRef)>,
+    // The coroutines running, innermost last, with the index of the frame
+    // each runs its function in.
+    coroutines: Vec<(ObjRef, usize)>,
+    // Set by `resume` for the call to switch to the coroutine once the
+    // native returns.
+    resuming: Option<ObjRef>,
+    globals: Globals,
+    // The interned name of initializers, looked up on every instantiation.
+    init_string: ObjRef,
+    optimize: bool,
+    max_call_depth: usize,
+    limits: Limits,
+    budget: Budget,
+    disassemble: bool,
+    trace: bool,
+    profile: Option<Profile>,
+    // Collect before every allocation, to shake out missing roots.
+    stress_gc: bool,
+    // Where `print` writes, and `eprint`.
+    output: Box<dyn Write>,
+    error_output: Box<dyn Write>,
+}
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Vm {
+    pub fn new() -> Vm {
+        let mut heap = Heap::default();
+        let init_string = heap.string("init").as_obj().expect("interned string");
+        let mut vm = Vm {
+            heap,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            open_upvalues: Vec::new(),
+            coroutines: Vec::new(),
+            resuming: None,
+            globals: Globals::default(),
+            init_string,
+            optimize: true,
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            limits: Limits::default(),
+            budget: Budget::default(),
+            disassemble: false,
+            trace: false,
+            profile: None,
+            stress_gc: false,
+            output: Box::new(io::stdout()),
+            error_output: Box::new(io::stderr()),
+        };
+        vm.define_native("clock", 0, |_, _| clock());
+        vm.define_native("assert", 2, |vm, args| {
+            if args[0].is_falsey() {
+                Err(format!("Assertion failed: {}", vm.heap.format(args[1])))
+            } else {
+                Ok(Value::nil())
+            }
+        });
+        natives::define_string_natives(&mut vm);
+        natives::define_math_natives(&mut vm);
+        natives::define_coroutine_natives(&mut vm);
+        vm
+    }
+    // Folds constants and drops dead branches while compiling. On by
+    // default.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+    // Deeper recursion than this is reported as a stack overflow.
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth = depth;
+    }
+    // Defines the natives for reading and writing files and the console,
+    // which are off by default.
+    pub fn allow_io(&mut self) {
+        natives::define_io_natives(self);
+    }
+    // Bounds each later `interpret` by fuel, time or both.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+    // Sends what `print` prints to `output` instead of stdout.
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+    // Sends what `eprint` prints to `output` instead of stderr.
+    pub fn set_error_output(&mut self, output: impl Write + 'static) {
+        self.error_output = Box::new(output);
+    }
+    // Prints the bytecode of each program before running it.
+    pub fn set_disassemble(&mut self, disassemble: bool) {
+        self.disassemble = disassemble;
+    }
+    // Prints the value stack and each instruction as it executes.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+    // Count and time the instructions run, for `profile_report`.
+    pub fn set_profile(&mut self, profile: bool) {
+        self.profile = profile.then(Profile::default);
+    }
+    // Time spent per function and per opcode, slowest first, if profiling.
+    pub fn profile_report(&self) -> Option<String> {
+        self.profile.as_ref().map(Profile::report)
+    }
+    pub fn set_stress_gc(&mut self, stress_gc: bool) {
+        self.stress_gc = stress_gc;
+    }
+    // How many bytes may be allocated before the first collection.
+    pub fn set_gc_threshold(&mut self, bytes: usize) {
+        self.heap.set_gc_threshold(bytes);
+    }
+    pub fn interpret(&mut self, source: &str) -> Result<(), LoxError> {
+        let function = compiler::compile(source, &mut self.heap, &mut self.globals, self.optimize)?;
+        self.execute(function, source)
+    }
+    // Compiles `source` without running it, as bytes for `interpret_compiled`
+    // to run later in this or another VM.
+    pub fn compile(&mut self, source: &str) -> Result<Vec<u8>, LoxError> {
+        let function = compiler::compile(source, &mut self.heap, &mut self.globals, self.optimize)?;
+        Ok(serialize::write(
+            function,
+            &self.heap,
+            &self.globals,
+            source,
+            self.optimize,
+        ))
+    }
+    // Runs `source` from the bytes `compile` made of it. Returns None,
+    // having run nothing, if `bytes` were made by another version of the VM,
+    // with the optimizer set otherwise, or from another source, or have been
+    // damaged. The source is still needed to report errors.
+    pub fn interpret_compiled(
+        &mut self,
+        bytes: &[u8],
+        source: &str,
+    ) -> Option<Result<(), LoxError>> {
+        let function = serialize::read(
+            bytes,
+            &mut self.heap,
+            &mut self.globals,
+            source,
+            self.optimize,
+        )?;
+        Some(self.execute(function, source))
+    }
+    fn execute(&mut self, function: ObjRef, source: &str) -> Result<(), LoxError> {
+        let map = SourceMap::new(source);
+        if self.disassemble {
+            let chunk = &self.heap.as_function(function).chunk;
+            print!(
+                "{}",
+                debug::disassemble_chunk(chunk, "<script>", &self.heap, &self.globals, &map)
+            );
+        }
+        let closure = self.heap.alloc(Obj::Closure(Closure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.stack.push(Value::obj(closure));
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            base: 0,
+            after: AfterReturn::Push,
+        });
+        self.budget = Budget::start(self.limits);
+        let result = self.run(&map);
+        if let Some(profile) = &mut self.profile {
+            profile.stop(Instant::now());
+        }
+        result.map_err(|e| {
+            self.stack.clear();
+            self.frames.clear();
+            self.open_upvalues.clear();
+            for (coroutine, _) in self.coroutines.drain(..) {
+                *self.heap.get_mut(coroutine) = Obj::Coroutine(Coroutine::Dead);
+            }
+            if self.budget.exhausted() {
+                LoxError::Timeout(e)
+            } else {
+                LoxError::Runtime(e)
+            }
+        })
+    }
+    fn define_native(&mut self, name: &'static str, arity: usize, function: NativeFn) {
+        let native = self.heap.alloc(Obj::Native(Native {
+            name,
+            arity,
+            function,
+        }));
+        self.define_global(name, Value::obj(native));
+    }
+    fn define_global(&mut self, name: &str, value: Value) {
+        let Some(key) = self.heap.string(name).as_obj() else {
+            return;
+        };
+        let slot = self.globals.slot(key);
+        self.globals.define(slot, value);
+    }
+
+    fn run(&mut self, map: &SourceMap) -> Result<(), Diagnostic> {
+        loop {
+            let start = self.frame().ip;
+            if self.trace {
+                self.trace_instruction(start, map);
+            }
+            let byte = self.read_byte();
+            let Ok(op) = OpCode::try_from(byte) else {
+                return Err(self.error(start, &format!("Unknown opcode {}.", byte)));
+            };
+            if let Some(profile) = &mut self.profile {
+                let function = self.frames.last().expect("call frame").function;
+                profile.instruction(op, function, &self.heap);
+            }
+            match op {
+                OpCode::Constant => {
+                    let constant = self.read_constant();
+                    self.push(constant);
+                }
+                OpCode::Nil => self.push(Value::nil()),
+                OpCode::True => self.push(Value::bool(true)),
+                OpCode::False => self.push(Value::bool(false)),
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::Dup => self.push(self.peek(0)),
+                OpCode::Tuck => {
+                    let top = self.peek(0);
+                    self.stack.insert(self.stack.len() - 2, top);
+                }
+                OpCode::GetLocal => {
+                    let slot = self.frame().base + self.read_byte() as usize;
+                    self.push(self.stack[slot]);
+                }
+                OpCode::SetLocal => {
+                    let slot = self.frame().base + self.read_byte() as usize;
+                    self.stack[slot] = self.peek(0);
+                }
+                OpCode::GetGlobal => {
+                    let slot = self.read_short();
+                    match self.globals.get(slot) {
+                        Some(value) => self.push(value),
+                        None => return Err(self.undefined_variable(start, slot)),
+                    }
+                }
+                OpCode::DefineGlobal => {
+                    let slot = self.read_short();
+                    let value = self.pop();
+                    self.globals.define(slot, value);
+                }
+                OpCode::SetGlobal => {
+                    let slot = self.read_short();
+                    if !self.globals.set(slot, self.peek(0)) {
+                        return Err(self.undefined_variable(start, slot));
+                    }
+                }
+                OpCode::GetUpvalue => {
+                    let index = self.read_byte();
+                    let upvalue = self.upvalue(index);
+                    let value = match self.heap.get(upvalue) {
+                        Obj::Upvalue(Upvalue::Open(slot)) => self.stack[*slot],
+                        Obj::Upvalue(Upvalue::Closed(value)) => *value,
+                        _ => Value::nil(),
+                    };
+                    self.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let index = self.read_byte();
+                    let upvalue = self.upvalue(index);
+                    let value = self.peek(0);
+                    match self.heap.get_mut(upvalue) {
+                        Obj::Upvalue(Upvalue::Open(slot)) => {
+                            let slot = *slot;
+                            self.stack[slot] = value;
+                        }
+                        Obj::Upvalue(closed) => *closed = Upvalue::Closed(value),
+                        _ => {}
+                    }
+                }
+                OpCode::Equal => {
+                    if !self.overload(start, "equals", 1, AfterReturn::Bool)? {
+                        let b = self.pop();
+                        let a = self.pop();
+                        self.push(Value::bool(a == b));
+                    }
+                }
+                OpCode::Greater => self.comparison(start, |a, b| a > b)?,
+                OpCode::Less => self.comparison(start, |a, b| a < b)?,
+                OpCode::Add => {
+                    if !self.overload(start, "plus", 1, AfterReturn::Push)? {
+                        self.add(start)?;
+                    }
+                }
+                OpCode::Subtract => {
+                    if !self.overload(start, "minus", 1, AfterReturn::Push)? {
+                        self.arithmetic(start, |a, b| a - b)?;
+                    }
+                }
+                OpCode::Multiply => {
+                    if !self.overload(start, "times", 1, AfterReturn::Push)? {
+                        self.arithmetic(start, |a, b| a * b)?;
+                    }
+                }
+                OpCode::Divide => {
+                    if !self.overload(start, "divide", 1, AfterReturn::Push)? {
+                        self.arithmetic(start, |a, b| a / b)?;
+                    }
+                }
+                // Like C's fmod: the result takes the sign of the dividend.
+                OpCode::Modulo => {
+                    if !self.overload(start, "modulo", 1, AfterReturn::Push)? {
+                        self.arithmetic(start, |a, b| a % b)?;
+                    }
+                }
+                OpCode::Power => self.arithmetic(start, f64::powf)?,
+                OpCode::Not => {
+                    let value = self.pop();
+                    self.push(Value::bool(value.is_falsey()));
+                }
+                OpCode::Negate => match self.peek(0).as_number() {
+                    Some(n) => {
+                        self.pop();
+                        self.push(Value::number(-n));
+                    }
+                    None => {
+                        if !self.overload(start, "negate", 0, AfterReturn::Push)? {
+                            return Err(self.error(start, "Operand must be a number."));
+                        }
+                    }
+                },
+                OpCode::Stringify => {
+                    if !self.overload(start, "toString", 0, AfterReturn::Stringify)? {
+                        self.stringify();
+                    }
+                }
+                OpCode::Print => {
+                    if !self.overload(start, "toString", 0, AfterReturn::Print)? {
+                        let value = self.pop();
+                        let _ = writeln!(self.output, "{}", self.heap.format(value));
+                    }
+                }
+                OpCode::Jump => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short();
+                    if self.peek(0).is_falsey() {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                OpCode::JumpIfNil => {
+                    let offset = self.read_short();
+                    if self.peek(0) == Value::nil() {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_short();
+                    self.budget
+                        .step()
+                        .map_err(|message| self.error(start, message))?;
+                    self.frame_mut().ip -= offset;
+                }
+                OpCode::Call => {
+                    let count = self.read_byte() as usize;
+                    self.call_value(start, self.peek(count), count)?;
+                }
+                // The callee and arguments slide down over the caller's
+                // slots and the caller's frame is dropped, so the callee
+                // returns straight to the caller's caller.
+                OpCode::TailCall => {
+                    let count = self.read_byte() as usize;
+                    let callee = self.peek(count);
+                    let closure = match callee.as_obj().map(|handle| self.heap.get(handle)) {
+                        Some(Obj::Closure(closure)) => Some(closure.function),
+                        _ => None,
+                    };
+                    let Some(function) = closure else {
+                        self.call_value(start, callee, count)?;
+                        continue;
+                    };
+                    // Anything that can fail is checked while the caller's
+                    // frame is still there to report it from.
+                    self.check_arity(start, self.heap.as_function(function).arities(), count)?;
+                    self.budget
+                        .step()
+                        .map_err(|message| self.error(start, message))?;
+                    let base = self.frame().base;
+                    self.close_upvalues(base);
+                    self.stack.drain(base..self.stack.len() - count - 1);
+                    let after = self
+                        .frames
+                        .pop()
+                        .map_or(AfterReturn::Push, |frame| frame.after);
+                    let handle = callee.as_obj().expect("a closure");
+                    self.push_frame(handle, function, count);
+                    self.frame_mut().after = after;
+                }
+                OpCode::Closure => {
+                    self.maybe_collect();
+                    let Some(function) = self.read_constant().as_obj() else {
+                        return Err(self.error(start, "Closure constant is not a function."));
+                    };
+                    let count = self.heap.as_function(function).upvalue_count;
+                    let mut upvalues = Vec::with_capacity(count);
+                    for _ in 0..count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte();
+                        upvalues.push(if is_local {
+                            self.capture_upvalue(self.frame().base + index as usize)
+                        } else {
+                            self.upvalue(index)
+                        });
+                    }
+                    let closure = self
+                        .heap
+                        .alloc(Obj::Closure(Closure { function, upvalues }));
+                    self.push(Value::obj(closure));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Class => {
+                    let name = self.read_string();
+                    self.maybe_collect();
+                    let name = self.heap.as_str(Value::obj(name)).unwrap_or_default();
+                    let class = self.heap.alloc(Obj::Class(Class {
+                        name: name.to_string(),
+                        methods: HashMap::new(),
+                        class_methods: HashMap::new(),
+                        fields: HashMap::new(),
+                    }));
+                    self.push(Value::obj(class));
+                }
+                OpCode::Inherit => {
+                    let (methods, class_methods) =
+                        match self.peek(1).as_obj().map(|handle| self.heap.get(handle)) {
+                            Some(Obj::Class(superclass)) => {
+                                (superclass.methods.clone(), superclass.class_methods.clone())
+                            }
+                            _ => return Err(self.error(start, "Superclass must be a class.")),
+                        };
+                    if let Some(Obj::Class(subclass)) = self
+                        .peek(0)
+                        .as_obj()
+                        .map(|handle| self.heap.get_mut(handle))
+                    {
+                        subclass.methods.extend(methods);
+                        subclass.class_methods.extend(class_methods);
+                    }
+                    self.pop();
+                }
+                OpCode::Method | OpCode::ClassMethod => {
+                    let name = self.read_string();
+                    let method = self.peek(0);
+                    match self
+                        .peek(1)
+                        .as_obj()
+                        .map(|handle| self.heap.get_mut(handle))
+                    {
+                        Some(Obj::Class(class)) if op == OpCode::Method => {
+                            class.methods.insert(name, method);
+                        }
+                        Some(Obj::Class(class)) => {
+                            class.class_methods.insert(name, method);
+                        }
+                        Some(Obj::Trait(used)) => used.methods.push((name, method)),
+                        _ => {}
+                    }
+                    self.pop();
+                }
+                OpCode::Trait => {
+                    let name = self.read_string();
+                    self.maybe_collect();
+                    let name = self.heap.as_str(Value::obj(name)).unwrap_or_default();
+                    let used = self.heap.alloc(Obj::Trait(Trait {
+                        name: name.to_string(),
+                        methods: Vec::new(),
+                    }));
+                    self.push(Value::obj(used));
+                }
+                OpCode::Mixin => {
+                    let earlier = self.read_byte() as usize;
+                    self.mixin(start, earlier)?;
+                }
+                // Fields shadow methods of the same name.
+                OpCode::GetProperty => {
+                    let (name, constant) = self.read_property();
+                    let cache = self.cache(constant);
+                    if let Some(member) = self.class_member(self.peek(0), name) {
+                        match member {
+                            (Some(field), _) => {
+                                self.pop();
+                                self.push(field);
+                            }
+                            (None, Some(method)) => self.get_method(start, method)?,
+                            (None, None) => return Err(self.undefined_property(start, name)),
+                        }
+                    } else {
+                        let Some(instance) = self.as_instance(self.peek(0)) else {
+                            return Err(self.error(start, "Only instances have properties."));
+                        };
+                        if let Some(slot) = instance.field_slot(name, cache) {
+                            let value = instance.fields[slot].1;
+                            self.set_cache(constant, InlineCache::Field(slot));
+                            self.pop();
+                            self.push(value);
+                        } else {
+                            let class = instance.class;
+                            match self.cached_method(constant, cache, class, name) {
+                                Some(method) => self.get_method(start, method)?,
+                                None => return Err(self.undefined_property(start, name)),
+                            }
+                        }
+                    }
+                }
+                OpCode::SetProperty => {
+                    let (name, constant) = self.read_property();
+                    let cache = self.cache(constant);
+                    let value = self.peek(0);
+                    // Fields set on a class are few and not cached.
+                    let slot = match self
+                        .peek(1)
+                        .as_obj()
+                        .map(|handle| self.heap.get_mut(handle))
+                    {
+                        Some(Obj::Instance(instance)) => match instance.field_slot(name, cache) {
+                            Some(slot) => {
+                                instance.fields[slot].1 = value;
+                                Some(slot)
+                            }
+                            None => {
+                                instance.fields.push((name, value));
+                                Some(instance.fields.len() - 1)
+                            }
+                        },
+                        Some(Obj::Class(class)) => {
+                            class.fields.insert(name, value);
+                            None
+                        }
+                        _ => return Err(self.error(start, "Only instances have fields.")),
+                    };
+                    if let Some(slot) = slot {
+                        self.set_cache(constant, InlineCache::Field(slot));
+                    }
+                    self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::GetSuper => {
+                    let name = self.read_string();
+                    let Some(superclass) = self.pop().as_obj() else {
+                        return Err(self.error(start, "Superclass must be a class."));
+                    };
+                    match self.find_super_method(superclass, self.peek(0), name) {
+                        Some(method) => self.get_method(start, method)?,
+                        None => return Err(self.undefined_property(start, name)),
+                    }
+                }
+                OpCode::Invoke => {
+                    let (name, constant) = self.read_property();
+                    let count = self.read_byte() as usize;
+                    self.invoke(start, name, constant, count)?;
+                }
+                OpCode::SuperInvoke => {
+                    let name = self.read_string();
+                    let count = self.read_byte() as usize;
+                    let Some(superclass) = self.pop().as_obj() else {
+                        return Err(self.error(start, "Superclass must be a class."));
+                    };
+                    self.invoke_from_class(start, superclass, name, count)?;
+                }
+                OpCode::Return => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().expect("call frame");
+                    self.close_upvalues(frame.base);
+                    self.stack.truncate(frame.base);
+                    // A coroutine whose function returns is finished, and
+                    // the result is what `resume` returns.
+                    if let Some(&(coroutine, index)) = self.coroutines.last() {
+                        if index == self.frames.len() {
+                            self.coroutines.pop();
+                            *self.heap.get_mut(coroutine) = Obj::Coroutine(Coroutine::Dead);
+                        }
+                    }
+                    if self.frames.is_empty() {
+                        return Ok(());
+                    }
+                    match frame.after {
+                        AfterReturn::Push => self.push(result),
+                        AfterReturn::Call(site, count) => {
+                            self.stack.insert(self.stack.len() - count, result);
+                            self.call_value(site, result, count)?;
+                        }
+                        AfterReturn::Bool => self.push(Value::bool(!result.is_falsey())),
+                        AfterReturn::Print => {
+                            let _ = writeln!(self.output, "{}", self.heap.format(result));
+                        }
+                        AfterReturn::Stringify => {
+                            self.push(result);
+                            self.stringify();
+                        }
+                    }
+                }
+                // The coroutine's frame and slots move into it, and the
+                // value yielded is the result of the `resume` beneath.
+                OpCode::Yield => {
+                    let value = self.pop();
+                    let index = self.frames.len() - 1;
+                    let Some(&(coroutine, _)) = self
+                        .coroutines
+                        .last()
+                        .filter(|(_, running)| *running == index)
+                    else {
+                        let message = "Can only yield from the function a coroutine runs.";
+                        return Err(self.error(start, message));
+                    };
+                    self.coroutines.pop();
+                    let frame = self.frames.pop().expect("call frame");
+                    let upvalues = self
+                        .close_upvalues(frame.base)
+                        .into_iter()
+                        .map(|(slot, upvalue)| (slot - frame.base, upvalue))
+                        .collect();
+                    let fiber = Fiber {
+                        closure: frame.closure,
+                        function: frame.function,
+                        ip: frame.ip,
+                        slots: self.stack.split_off(frame.base),
+                        upvalues,
+                    };
+                    *self.heap.get_mut(coroutine) = Obj::Coroutine(Coroutine::Suspended(fiber));
+                    self.push(value);
+                }
+            }
+        }
+    }
+
+    fn call_value(&mut self, start: usize, callee: Value, count: usize) -> Result<(), Diagnostic> {
+        let Some(handle) = callee.as_obj() else {
+            return Err(self.error(start, "Can only call functions and classes."));
+        };
+        match self.heap.get(handle) {
+            Obj::Closure(closure) => {
+                let function = closure.function;
+                let arities = self.heap.as_function(function).arities();
+                self.check_arity(start, arities, count)?;
+                self.budget
+                    .step()
+                    .map_err(|message| self.error(start, message))?;
+                // The script's own frame doesn't count as a call.
+                if self.frames.len() > self.max_call_depth {
+                    return Err(self.error(start, "Stack overflow."));
+                }
+                self.push_frame(handle, function, count);
+                Ok(())
+            }
+            Obj::Native(native) => {
+                let (arity, function) = (native.arity, native.function);
+                self.check_arity(start, arity..=arity, count)?;
+                let arguments = self.stack.split_off(self.stack.len() - count);
+                let result =
+                    function(self, &arguments).map_err(|message| self.error(start, &message))?;
+                self.pop();
+                match self.resuming.take() {
+                    Some(coroutine) => self.resume(start, coroutine),
+                    None => {
+                        self.push(result);
+                        Ok(())
+                    }
+                }
+            }
+            // The new instance takes the class's slot, where `init` finds
+            // it as `this`.
+            Obj::Class(_) => {
+                self.maybe_collect();
+                let instance = self.heap.alloc(Obj::Instance(Instance {
+                    class: handle,
+                    fields: Vec::new(),
+                }));
+                let slot = self.stack.len() - count - 1;
+                self.stack[slot] = Value::obj(instance);
+                match self.find_method(handle, self.init_string) {
+                    Some(init) => self.call_value(start, init, count),
+                    None => self.check_arity(start, 0..=0, count),
+                }
+            }
+            Obj::BoundMethod(bound) => {
+                let (receiver, method) = (bound.receiver, bound.method);
+                let slot = self.stack.len() - count - 1;
+                self.stack[slot] = receiver;
+                self.call_value(start, Value::obj(method), count)
+            }
+            Obj::Str(_)
+            | Obj::Function(_)
+            | Obj::Upvalue(_)
+            | Obj::Trait(_)
+            | Obj::Instance(_)
+            | Obj::Coroutine(_) => Err(self.error(start, "Can only call functions and classes.")),
+        }
+    }
+    // Enters `function` with its `count` arguments on the stack, starting
+    // past the defaults those arguments supply and padding the rest with nil.
+    fn push_frame(&mut self, closure: ObjRef, function: ObjRef, count: usize) {
+        let base = self.stack.len() - count - 1;
+        let arities = self.heap.as_function(function).arities();
+        let entries = &self.heap.as_function(function).entries;
+        let ip = entries.get(count - arities.start()).copied().unwrap_or(0);
+        for _ in count..*arities.end() {
+            self.push(Value::nil());
+        }
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip,
+            base,
+            after: AfterReturn::Push,
+        });
+    }
+    // Runs `coroutine`, which `resume` has checked can be, in a frame on top
+    // of the stack where the call to `resume` left its result to go. Its
+    // function starts afresh, or carries on from where it last yielded.
+    fn resume(&mut self, start: usize, coroutine: ObjRef) -> Result<(), Diagnostic> {
+        let base = self.stack.len();
+        match self.heap.get(coroutine) {
+            Obj::Coroutine(Coroutine::Fresh(callee)) => {
+                let callee = *callee;
+                self.push(callee);
+                self.call_value(start, callee, 0)?;
+            }
+            Obj::Coroutine(Coroutine::Suspended(_)) => {
+                if self.frames.len() > self.max_call_depth {
+                    return Err(self.error(start, "Stack overflow."));
+                }
+                let Obj::Coroutine(Coroutine::Suspended(fiber)) = std::mem::replace(
+                    self.heap.get_mut(coroutine),
+                    Obj::Coroutine(Coroutine::Running),
+                ) else {
+                    unreachable!("the coroutine was just found suspended");
+                };
+                self.stack.extend(fiber.slots);
+                // Assignments made through the upvalues while the coroutine
+                // was suspended are copied back to its slots.
+                for (slot, upvalue) in fiber.upvalues {
+                    if let Obj::Upvalue(Upvalue::Closed(value)) = self.heap.get(upvalue) {
+                        self.stack[base + slot] = *value;
+                    }
+                    *self.heap.get_mut(upvalue) = Obj::Upvalue(Upvalue::Open(base + slot));
+                    self.open_upvalues.push((base + slot, upvalue));
+                }
+                self.frames.push(CallFrame {
+                    closure: fiber.closure,
+                    function: fiber.function,
+                    ip: fiber.ip,
+                    base,
+                    after: AfterReturn::Push,
+                });
+            }
+            _ => unreachable!("resume() checks that the coroutine is suspended"),
+        }
+        *self.heap.get_mut(coroutine) = Obj::Coroutine(Coroutine::Running);
+        self.coroutines.push((coroutine, self.frames.len() - 1));
+        Ok(())
+    }
+    // Calls a method on the receiver beneath the arguments, as `GetProperty`
+    // followed by `Call` would but without the bound method in between.
+    fn invoke(
+        &mut self,
+        start: usize,
+        name: ObjRef,
+        constant: usize,
+        count: usize,
+    ) -> Result<(), Diagnostic> {
+        if let Some((field, method)) = self.class_member(self.peek(count), name) {
+            return match (field, method) {
+                (Some(field), _) => {
+                    let slot = self.stack.len() - count - 1;
+                    self.stack[slot] = field;
+                    self.call_value(start + INVOKE_CALL_SITE, field, count)
+                }
+                (None, Some(method)) => self.call_method(start, method, count),
+                (None, None) => Err(self.undefined_property(start, name)),
+            };
+        }
+        let cache = self.cache(constant);
+        let Some(instance) = self.as_instance(self.peek(count)) else {
+            return Err(self.error(start, "Only instances have properties."));
+        };
+        if let Some(slot) = instance.field_slot(name, cache) {
+            let field = instance.fields[slot].1;
+            self.set_cache(constant, InlineCache::Field(slot));
+            let slot = self.stack.len() - count - 1;
+            self.stack[slot] = field;
+            return self.call_value(start + INVOKE_CALL_SITE, field, count);
+        }
+        let class = instance.class;
+        match self.cached_method(constant, cache, class, name) {
+            Some(method) => self.call_method(start, method, count),
+            None => Err(self.undefined_property(start, name)),
+        }
+    }
+    fn invoke_from_class(
+        &mut self,
+        start: usize,
+        class: ObjRef,
+        name: ObjRef,
+        count: usize,
+    ) -> Result<(), Diagnostic> {
+        match self.find_super_method(class, self.peek(count), name) {
+            Some(method) => self.call_method(start, method, count),
+            None => Err(self.undefined_property(start, name)),
+        }
+    }
+    fn mixin(&mut self, start: usize, earlier: usize) -> Result<(), Diagnostic> {
+        let Some(Obj::Trait(used)) = self.peek(0).as_obj().map(|handle| self.heap.get(handle))
+        else {
+            return Err(self.error(start, "Only traits can be mixed in."));
+        };
+        for (name, _) in &used.methods {
+            for distance in 1..=earlier {
+                let Some(Obj::Trait(other)) = self
+                    .peek(distance)
+                    .as_obj()
+                    .map(|handle| self.heap.get(handle))
+                else {
+                    continue;
+                };
+                if other.methods.iter().any(|(method, _)| method == name) {
+                    let method = self.heap.as_str(Value::obj(*name)).unwrap_or_default();
+                    let message = trait_conflict(method, &other.name, &used.name);
+                    return Err(self.error(start, &message));
+                }
+            }
+        }
+        let methods = used.methods.clone();
+        if let Some(Obj::Class(class)) = self
+            .peek(earlier + 1)
+            .as_obj()
+            .map(|handle| self.heap.get_mut(handle))
+        {
+            class.methods.extend(methods);
+        }
+        Ok(())
+    }
+    // Calls `method` on the receiver beneath its `count` arguments, for the
+    // invoke instruction at `start`. A getter is run on the receiver alone,
+    // with the arguments set aside beneath its frame until it returns
+    // what to call with them.
+    fn call_method(&mut self, start: usize, method: Value, count: usize) -> Result<(), Diagnostic> {
+        if !self.is_getter(method) {
+            return self.call_value(start + INVOKE_CALL_SITE, method, count);
+        }
+        let receiver = self.stack.len() - count - 1;
+        self.stack[receiver..].rotate_left(1);
+        self.call_value(start, method, 0)?;
+        self.frame_mut().after = AfterReturn::Call(start + INVOKE_CALL_SITE, count);
+        Ok(())
+    }
+    // Replaces the receiver on top of the stack with `method` bound to it,
+    // or with what it returns if it is a getter.
+    fn get_method(&mut self, start: usize, method: Value) -> Result<(), Diagnostic> {
+        if self.is_getter(method) {
+            self.call_value(start, method, 0)
+        } else {
+            self.bind_method(method);
+            Ok(())
+        }
+    }
+    fn is_getter(&self, method: Value) -> bool {
+        match method.as_obj().map(|handle| self.heap.get(handle)) {
+            Some(Obj::Closure(closure)) => self.heap.as_function(closure.function).is_getter,
+            _ => false,
+        }
+    }
+    // Replaces the instance on top of the stack with `method` bound to it.
+    fn bind_method(&mut self, method: Value) {
+        let Some(method) = method.as_obj() else {
+            return;
+        };
+        self.maybe_collect();
+        let bound = self.heap.alloc(Obj::BoundMethod(BoundMethod {
+            receiver: self.peek(0),
+            method,
+        }));
+        self.pop();
+        self.push(Value::obj(bound));
+    }
+    // Looks up a method for the instruction whose name is `constant`,
+    // skipping the lookup if its cache holds the method for this class.
+    fn cached_method(
+        &mut self,
+        constant: usize,
+        cache: InlineCache,
+        class: ObjRef,
+        name: ObjRef,
+    ) -> Option<Value> {
+        if let InlineCache::Method {
+            class: cached,
+            method,
+        } = cache
+        {
+            if cached == class {
+                return Some(method);
+            }
+        }
+        let method = self.find_method(class, name)?;
+        self.set_cache(constant, InlineCache::Method { class, method });
+        Some(method)
+    }
+    fn find_method(&self, class: ObjRef, name: ObjRef) -> Option<Value> {
+        match self.heap.get(class) {
+            Obj::Class(class) => class.methods.get(&name).copied(),
+            _ => None,
+        }
+    }
+    // In a class method the receiver is a class, and `super` finds the
+    // superclass's class methods.
+    fn find_super_method(
+        &self,
+        superclass: ObjRef,
+        receiver: Value,
+        name: ObjRef,
+    ) -> Option<Value> {
+        match (
+            self.heap.get(superclass),
+            receiver.as_obj().map(|handle| self.heap.get(handle)),
+        ) {
+            (Obj::Class(superclass), Some(Obj::Class(_))) => {
+                superclass.class_methods.get(&name).copied()
+            }
+            (Obj::Class(superclass), _) => superclass.methods.get(&name).copied(),
+            _ => None,
+        }
+    }
+    // If `value` is a class, the field and the class method it has named
+    // `name`, if any. Fields shadow class methods.
+    fn class_member(&self, value: Value, name: ObjRef) -> Option<(Option<Value>, Option<Value>)> {
+        match self.heap.get(value.as_obj()?) {
+            Obj::Class(class) => Some((
+                class.fields.get(&name).copied(),
+                class.class_methods.get(&name).copied(),
+            )),
+            _ => None,
+        }
+    }
+    fn as_instance(&self, value: Value) -> Option<&Instance> {
+        match self.heap.get(value.as_obj()?) {
+            Obj::Instance(instance) => Some(instance),
+            _ => None,
+        }
+    }
+    // Called only where every live object is reachable from the VM, just
+    // before an instruction that allocates.
+    fn maybe_collect(&mut self) {
+        if self.stress_gc || self.heap.should_collect() {
+            self.collect_garbage();
+        }
+    }
+    fn collect_garbage(&mut self) {
+        let roots = self
+            .stack
+            .iter()
+            .copied()
+            .chain(self.frames.iter().map(|frame| Value::obj(frame.closure)))
+            .chain([Value::obj(self.init_string)])
+            .chain(
+                self.coroutines
+                    .iter()
+                    .map(|(coroutine, _)| Value::obj(*coroutine)),
+            )
+            .chain(
+                self.open_upvalues
+                    .iter()
+                    .map(|(_, upvalue)| Value::obj(*upvalue)),
+            )
+            .chain(self.globals.roots())
+            .collect::<Vec<_>>();
+        self.heap.collect(roots);
+    }
+    // The upvalue for the variable in stack `slot`, reusing an open one if
+    // another closure already captured it.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        if let Some((_, upvalue)) = self.open_upvalues.iter().find(|(open, _)| *open == slot) {
+            return *upvalue;
+        }
+        let upvalue = self.heap.alloc(Obj::Upvalue(Upvalue::Open(slot)));
+        self.open_upvalues.push((slot, upvalue));
+        upvalue
+    }
+    // Closes every open upvalue at or above stack slot `last`, copying the
+    // variable's current value into it. Returns those closed, with the slot
+    // of each.
+    fn close_upvalues(&mut self, last: usize) -> Vec<(usize, ObjRef)> {
+        let stack = &self.stack;
+        let heap = &mut self.heap;
+        let mut closed = Vec::new();
+        self.open_upvalues.retain(|&(slot, upvalue)| {
+            if slot < last {
+                return true;
+            }
+            *heap.get_mut(upvalue) = Obj::Upvalue(Upvalue::Closed(stack[slot]));
+            closed.push((slot, upvalue));
+            false
+        });
+        closed
+    }
+    fn upvalue(&self, index: u8) -> ObjRef {
+        self.heap.as_closure(self.frame().closure).upvalues[index as usize]
+    }
+    fn check_arity(
+        &self,
+        start: usize,
+        arity: RangeInclusive<usize>,
+        count: usize,
+    ) -> Result<(), Diagnostic> {
+        if !arity.contains(&count) {
+            return Err(self.error(start, &arity_mismatch(&arity, count)));
+        }
+        Ok(())
+    }
+    // `+` adds numbers and concatenates strings.
+    // If the operand beneath the top `count` values is an instance whose
+    // class defines the method `name`, calls it on them for the operator at
+    // `start`, with `after` deciding what becomes of the result.
+    fn overload(
+        &mut self,
+        start: usize,
+        name: &str,
+        count: usize,
+        after: AfterReturn,
+    ) -> Result<bool, Diagnostic> {
+        let Some(instance) = self.as_instance(self.peek(count)) else {
+            return Ok(false);
+        };
+        let class = instance.class;
+        let Some(method) = self
+            .heap
+            .interned(name)
+            .and_then(|name| self.find_method(class, name))
+        else {
+            return Ok(false);
+        };
+        self.call_value(start, method, count)?;
+        self.frame_mut().after = after;
+        Ok(true)
+    }
+    // Replaces the value on top of the stack with its printed form.
+    fn stringify(&mut self) {
+        self.maybe_collect();
+        let value = self.pop();
+        let s = self.heap.format(value);
+        let value = self.heap.string(&s);
+        self.push(value);
+    }
+    fn add(&mut self, start: usize) -> Result<(), Diagnostic> {
+        let (a, b) = (self.peek(1), self.peek(0));
+        let result = match (a.unbox(), b.unbox()) {
+            (Unboxed::Number(a), Unboxed::Number(b)) => Value::number(a + b),
+            _ => match (self.heap.as_str(a), self.heap.as_str(b)) {
+                (Some(a), Some(b)) => {
+                    let s = format!("{}{}", a, b);
+                    // The operands stay on the stack until the result
+                    // exists, so they survive a collection here.
+                    self.maybe_collect();
+                    self.heap.string(&s)
+                }
+                _ => return Err(self.error(start, "Operands must be two numbers or two strings.")),
+            },
+        };
+        self.pop();
+        self.pop();
+        self.push(result);
+        Ok(())
+    }
+    fn arithmetic(&mut self, start: usize, op: fn(f64, f64) -> f64) -> Result<(), Diagnostic> {
+        let (a, b) = self.numbers(start)?;
+        self.push(Value::number(op(a, b)));
+        Ok(())
+    }
+    fn comparison(&mut self, start: usize, op: fn(f64, f64) -> bool) -> Result<(), Diagnostic> {
+        let (a, b) = self.numbers(start)?;
+        self.push(Value::bool(op(a, b)));
+        Ok(())
+    }
+    // Pops the two operands of a numeric operator.
+    fn numbers(&mut self, start: usize) -> Result<(f64, f64), Diagnostic> {
+        match (self.peek(1).unbox(), self.peek(0).unbox()) {
+            (Unboxed::Number(a), Unboxed::Number(b)) => {
+                self.pop();
+                self.pop();
+                Ok((a, b))
+            }
+            _ => Err(self.error(start, "Operands must be numbers.")),
+        }
+    }
+
+    fn trace_instruction(&self, start: usize, map: &SourceMap) {
+        let stack: String = self
+            .stack
+            .iter()
+            .map(|value| format!("[ {} ]", self.heap.format(*value)))
+            .collect();
+        let chunk = &self.heap.as_function(self.frame().function).chunk;
+        let (instruction, _) =
+            debug::disassemble_instruction(chunk, start, &self.heap, &self.globals, map);
+        println!("          {}\n{}", stack, instruction);
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("call frame")
+    }
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("call frame")
+    }
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frame();
+        let byte = self.heap.as_function(frame.function).chunk.code[frame.ip];
+        self.frame_mut().ip += 1;
+        byte
+    }
+    fn read_short(&mut self) -> usize {
+        let high = self.read_byte() as usize;
+        let low = self.read_byte() as usize;
+        high << 8 | low
+    }
+    fn read_constant(&mut self) -> Value {
+        let index = self.read_byte() as usize;
+        self.heap.as_function(self.frame().function).chunk.constants[index]
+    }
+    // Reads a string constant along with its index, which for a property
+    // instruction also picks out its inline cache.
+    fn read_property(&mut self) -> (ObjRef, usize) {
+        let index = self.read_byte() as usize;
+        match self.heap.as_function(self.frame().function).chunk.constants[index].unbox() {
+            Unboxed::Obj(handle) => (handle, index),
+            constant => panic!("expected a string constant, found {:?}", constant),
+        }
+    }
+    fn cache(&self, constant: usize) -> InlineCache {
+        self.heap.as_function(self.frame().function).chunk.caches[constant]
+    }
+    fn set_cache(&mut self, constant: usize, cache: InlineCache) {
+        let function = self.frame().function;
+        if let Obj::Function(function) = self.heap.get_mut(function) {
+            function.chunk.caches[constant] = cache;
+        }
+    }
+    // Reads a constant that the compiler guarantees is a string.
+    fn read_string(&mut self) -> ObjRef {
+        self.read_property().0
+    }
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("stack underflow")
+    }
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    // Locates an error at the instruction starting at `start` in the
+    // current function, with a trace frame for every active call.
+    fn error(&self, start: usize, message: &str) -> Diagnostic {
+        let mut diagnostic = Diagnostic::new(message, self.span_at(self.frame(), start));
+        for i in (1..self.frames.len()).rev() {
+            let caller = &self.frames[i - 1];
+            diagnostic.trace.push(Frame {
+                function: self.heap.as_function(self.frames[i].function).name.clone(),
+                // The caller's ip is just past the argument count of its
+                // call, which is located at the `)`.
+                call_site: self.span_at(caller, caller.ip - 1),
+                import: false,
+            });
+        }
+        diagnostic
+    }
+    fn span_at(&self, frame: &CallFrame, offset: usize) -> Span {
+        self.heap.as_function(frame.function).chunk.spans[offset]
+    }
+    fn undefined_property(&self, start: usize, name: ObjRef) -> Diagnostic {
+        let name = self.heap.as_str(Value::obj(name)).unwrap_or_default();
+        self.error(start, &format!("Undefined property '{}'.", name))
+    }
+    fn undefined_variable(&self, start: usize, slot: usize) -> Diagnostic {
+        let name = self.globals.name(slot);
+        let name = self.heap.as_str(Value::obj(name)).unwrap_or_default();
+        self.error(start, &format!("Undefined variable '{}'.", name))
+    }
+}
+
+// Seconds since the Unix epoch, for timing benchmarks.
+fn clock() -> Result<Value, String> {
+    Ok(Value::number(since_epoch().as_secs_f64()))
+}
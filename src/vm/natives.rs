@@ -0,0 +1,167 @@
+use crate::natives;
+use crate::vm::object::{Coroutine, Heap, Obj};
+use crate::vm::value::Value;
+use crate::vm::Vm;
+use std::f64::consts::PI;
+use std::io::Write;
+
+// The string natives of the tree-walk interpreter, less `split`, whose
+// result would be a list.
+pub(super) fn define_string_natives(vm: &mut Vm) {
+    vm.define_native("len", 1, |vm, args| match vm.heap.as_str(args[0]) {
+        Some(s) => Ok(Value::number(s.chars().count() as f64)),
+        None => Err("len() expects a list, map or string.".to_string()),
+    });
+    vm.define_native("substring", 3, |vm, args| {
+        match (
+            vm.heap.as_str(args[0]),
+            args[1].as_number(),
+            args[2].as_number(),
+        ) {
+            (Some(s), Some(start), Some(end)) => {
+                let sub = natives::substring(s, start, end)?;
+                Ok(vm.heap.string(&sub))
+            }
+            _ => Err("substring() expects a string and two numbers.".to_string()),
+        }
+    });
+    vm.define_native("indexOf", 2, |vm, args| {
+        match (vm.heap.as_str(args[0]), vm.heap.as_str(args[1])) {
+            (Some(s), Some(needle)) => Ok(Value::number(natives::index_of(s, needle))),
+            _ => Err("indexOf() expects two strings.".to_string()),
+        }
+    });
+    vm.define_native("toUpper", 1, |vm, args| {
+        map_string(&mut vm.heap, args[0], str::to_uppercase)
+            .ok_or_else(|| "toUpper() expects a string.".to_string())
+    });
+    vm.define_native("toLower", 1, |vm, args| {
+        map_string(&mut vm.heap, args[0], str::to_lowercase)
+            .ok_or_else(|| "toLower() expects a string.".to_string())
+    });
+    vm.define_native("parseNumber", 1, |vm, args| match vm.heap.as_str(args[0]) {
+        Some(s) => Ok(natives::parse_number(s).map_or(Value::nil(), Value::number)),
+        None => Err("parseNumber() expects a string.".to_string()),
+    });
+    vm.define_native("num", 1, |vm, args| {
+        if let Some(n) = args[0].as_number() {
+            return Ok(Value::number(n));
+        }
+        match vm.heap.as_str(args[0]) {
+            Some(s) => natives::to_number(s).map(Value::number),
+            None => Err("num() expects a number or a string.".to_string()),
+        }
+    });
+    vm.define_native("str", 1, |vm, args| {
+        let s = vm.heap.format(args[0]);
+        Ok(vm.heap.string(&s))
+    });
+    vm.define_native("chr", 1, |vm, args| match args[0].as_number() {
+        Some(code) => {
+            let s = natives::chr(code)?;
+            Ok(vm.heap.string(&s))
+        }
+        None => Err("chr() expects a number.".to_string()),
+    });
+    vm.define_native("ord", 1, |vm, args| match vm.heap.as_str(args[0]) {
+        Some(s) => natives::ord(s).map(Value::number),
+        None => Err("ord() expects a string.".to_string()),
+    });
+}
+
+pub(super) fn define_math_natives(vm: &mut Vm) {
+    vm.define_global("pi", Value::number(PI));
+    vm.define_native("sqrt", 1, |_, args| unary(args, "sqrt", f64::sqrt));
+    vm.define_native("abs", 1, |_, args| unary(args, "abs", f64::abs));
+    vm.define_native("floor", 1, |_, args| unary(args, "floor", f64::floor));
+    vm.define_native("ceil", 1, |_, args| unary(args, "ceil", f64::ceil));
+    vm.define_native("pow", 2, |_, args| binary(args, "pow", f64::powf));
+    vm.define_native("min", 2, |_, args| binary(args, "min", f64::min));
+    vm.define_native("max", 2, |_, args| binary(args, "max", f64::max));
+    vm.define_native("random", 0, |_, _| Ok(Value::number(natives::random())));
+}
+
+// `resume` leaves the VM to switch to the coroutine once it returns.
+pub(super) fn define_coroutine_natives(vm: &mut Vm) {
+    vm.define_native("coroutine", 1, |vm, args| {
+        let function = match args[0].as_obj().map(|handle| vm.heap.get(handle)) {
+            Some(Obj::Closure(closure)) => Some(closure.function),
+            Some(Obj::BoundMethod(bound)) => Some(vm.heap.as_closure(bound.method).function),
+            _ => None,
+        };
+        match function {
+            Some(function) if vm.heap.as_function(function).arities().contains(&0) => Ok(
+                Value::obj(vm.heap.alloc(Obj::Coroutine(Coroutine::Fresh(args[0])))),
+            ),
+            _ => Err("coroutine() expects a function that takes no arguments.".to_string()),
+        }
+    });
+    vm.define_native("resume", 1, |vm, args| {
+        let coroutine = args[0].as_obj();
+        match coroutine.map(|handle| vm.heap.get(handle)) {
+            Some(Obj::Coroutine(Coroutine::Fresh(_) | Coroutine::Suspended(_))) => {
+                vm.resuming = coroutine;
+                Ok(Value::nil())
+            }
+            Some(Obj::Coroutine(Coroutine::Running)) => {
+                Err("Can't resume a running coroutine.".to_string())
+            }
+            Some(Obj::Coroutine(Coroutine::Dead)) => {
+                Err("Can't resume a dead coroutine.".to_string())
+            }
+            _ => Err("resume() expects a coroutine.".to_string()),
+        }
+    });
+    vm.define_native("status", 1, |vm, args| {
+        let status = match args[0].as_obj().map(|handle| vm.heap.get(handle)) {
+            Some(Obj::Coroutine(Coroutine::Fresh(_) | Coroutine::Suspended(_))) => "suspended",
+            Some(Obj::Coroutine(Coroutine::Running)) => "running",
+            Some(Obj::Coroutine(Coroutine::Dead)) => "dead",
+            _ => return Err("status() expects a coroutine.".to_string()),
+        };
+        Ok(vm.heap.string(status))
+    });
+}
+
+pub(super) fn define_io_natives(vm: &mut Vm) {
+    vm.define_native("readLine", 0, |vm, _| match natives::read_line()? {
+        Some(line) => Ok(vm.heap.string(&line)),
+        None => Ok(Value::nil()),
+    });
+    vm.define_native("readFile", 1, |vm, args| match vm.heap.as_str(args[0]) {
+        Some(path) => {
+            let text = natives::read_file(path)?;
+            Ok(vm.heap.string(&text))
+        }
+        None => Err("readFile() expects a path.".to_string()),
+    });
+    vm.define_native("writeFile", 2, |vm, args| {
+        match (vm.heap.as_str(args[0]), vm.heap.as_str(args[1])) {
+            (Some(path), Some(text)) => natives::write_file(path, text).map(|_| Value::nil()),
+            _ => Err("writeFile() expects a path and a string.".to_string()),
+        }
+    });
+    vm.define_native("eprint", 1, |vm, args| {
+        let _ = writeln!(vm.error_output, "{}", vm.heap.format(args[0]));
+        Ok(Value::nil())
+    });
+}
+
+fn unary(args: &[Value], name: &str, f: fn(f64) -> f64) -> Result<Value, String> {
+    match args[0].as_number() {
+        Some(n) => Ok(Value::number(f(n))),
+        None => Err(format!("{}() expects a number.", name)),
+    }
+}
+
+fn binary(args: &[Value], name: &str, f: fn(f64, f64) -> f64) -> Result<Value, String> {
+    match (args[0].as_number(), args[1].as_number()) {
+        (Some(a), Some(b)) => Ok(Value::number(f(a, b))),
+        _ => Err(format!("{}() expects two numbers.", name)),
+    }
+}
+
+fn map_string(heap: &mut Heap, value: Value, f: fn(&str) -> String) -> Option<Value> {
+    let s = f(heap.as_str(value)?);
+    Some(heap.string(&s))
+}
@@ -0,0 +1,407 @@
+use crate::display;
+use crate::span::Span;
+use crate::vm::chunk::{Chunk, InlineCache};
+use crate::vm::value::{Unboxed, Value};
+use crate::vm::Vm;
+use std::collections::HashMap;
+use std::mem;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
+// A handle to an object in the heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(pub(super) usize);
+
+#[derive(Debug)]
+pub enum Obj {
+    // Strings are interned: the heap holds one object per distinct string,
+    // shared with its intern table.
+    Str(Rc<str>),
+    Function(Function),
+    Native(Native),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Trait(Trait),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    Coroutine(Coroutine),
+}
+
+#[derive(Debug, Default)]
+pub struct Function {
+    // Empty for the top-level script.
+    pub name: String,
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    // A method without a parameter list, called when it is read.
+    pub is_getter: bool,
+    // Where the code for each parameter default starts, then the body. A
+    // call starts at the first default it needs, with nil in the slots of
+    // the parameters it leaves out. Empty if no parameter has a default.
+    pub entries: Vec<usize>,
+}
+impl Function {
+    // The argument counts a call may pass.
+    pub fn arities(&self) -> RangeInclusive<usize> {
+        self.arity - self.entries.len().saturating_sub(1)..=self.arity
+    }
+}
+
+// A function together with the variables it captured from enclosing
+// functions. Every function value at runtime is a closure.
+#[derive(Debug)]
+pub struct Closure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+// A captured variable. It stays open, referring to the variable's stack
+// slot, until the variable goes out of scope; then the value moves into
+// the upvalue itself.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+// Methods are keyed by their interned name. A subclass starts with a copy
+// of its superclass's methods and class methods, so lookup never walks the
+// chain. Fields set on the class itself are not inherited.
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    pub methods: HashMap<ObjRef, Value>,
+    pub class_methods: HashMap<ObjRef, Value>,
+    pub fields: HashMap<ObjRef, Value>,
+}
+
+// Methods for classes to copy in, in declaration order.
+#[derive(Debug)]
+pub struct Trait {
+    pub name: String,
+    pub methods: Vec<(ObjRef, Value)>,
+}
+
+// Fields are few, so they are searched in order of creation rather than
+// hashed, which also lets an inline cache remember where one was found.
+#[derive(Debug)]
+pub struct Instance {
+    pub class: ObjRef,
+    pub fields: Vec<(ObjRef, Value)>,
+}
+impl Instance {
+    // Where the field `name` is, trying `cache` first.
+    pub fn field_slot(&self, name: ObjRef, cache: InlineCache) -> Option<usize> {
+        if let InlineCache::Field(slot) = cache {
+            if self
+                .fields
+                .get(slot)
+                .is_some_and(|(field, _)| *field == name)
+            {
+                return Some(slot);
+            }
+        }
+        self.fields.iter().position(|(field, _)| *field == name)
+    }
+}
+
+// A method closure paired with the instance it was accessed on.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+// A function run a piece at a time, made by `coroutine`. While it runs,
+// its frame is on top of the VM's; when it yields, the frame moves here.
+#[derive(Debug)]
+pub enum Coroutine {
+    // Not run yet: the closure or bound method it calls.
+    Fresh(Value),
+    Suspended(Fiber),
+    Running,
+    Dead,
+}
+
+// A suspended coroutine's frame: the closure it was running, the offset of
+// its next instruction and the values of its slots. The upvalues that were
+// open over those slots, with the slot of each, are closed until it is
+// resumed.
+#[derive(Debug)]
+pub struct Fiber {
+    pub closure: ObjRef,
+    pub function: ObjRef,
+    pub ip: usize,
+    pub slots: Vec<Value>,
+    pub upvalues: Vec<(usize, ObjRef)>,
+}
+
+pub type NativeFn = fn(&mut Vm, &[Value]) -> Result<Value, String>;
+
+pub struct Native {
+    pub name: &'static str,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+impl std::fmt::Debug for Native {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Native {{ name: {:?}, arity: {} }}",
+            self.name, self.arity
+        )
+    }
+}
+
+impl Obj {
+    // Roughly how many bytes the object occupies, to pace collections.
+    fn size(&self) -> usize {
+        let extra = match self {
+            Obj::Str(s) => s.len(),
+            Obj::Function(function) => {
+                function.name.len()
+                    + function.chunk.code.len() * (1 + mem::size_of::<Span>())
+                    + function.chunk.constants.len()
+                        * (mem::size_of::<Value>() + mem::size_of::<InlineCache>())
+            }
+            Obj::Closure(closure) => closure.upvalues.len() * mem::size_of::<ObjRef>(),
+            Obj::Class(class) => {
+                class.name.len()
+                    + (class.methods.len() + class.class_methods.len() + class.fields.len())
+                        * mem::size_of::<(ObjRef, Value)>()
+            }
+            Obj::Trait(used) => {
+                used.name.len() + used.methods.len() * mem::size_of::<(ObjRef, Value)>()
+            }
+            Obj::Instance(instance) => instance.fields.len() * mem::size_of::<(ObjRef, Value)>(),
+            Obj::Coroutine(Coroutine::Suspended(fiber)) => {
+                fiber.slots.len() * mem::size_of::<Value>()
+                    + fiber.upvalues.len() * mem::size_of::<(usize, ObjRef)>()
+            }
+            Obj::Native(_) | Obj::Upvalue(_) | Obj::BoundMethod(_) | Obj::Coroutine(_) => 0,
+        };
+        mem::size_of::<Obj>() + extra
+    }
+}
+
+// The first collection happens once this many bytes are allocated.
+pub const DEFAULT_GC_THRESHOLD: usize = 1024 * 1024;
+// After a collection, the next is due when the heap has grown by this
+// factor over what survived.
+const GC_GROWTH_FACTOR: usize = 2;
+
+// Owns every object the compiler and VM create. Unreachable objects are
+// reclaimed by a mark-sweep collection over the roots the VM supplies, and
+// their slots reused, so a handle is valid only while its object is
+// reachable.
+#[derive(Debug)]
+pub struct Heap {
+    objects: Vec<Option<Obj>>,
+    free: Vec<usize>,
+    // Every live string object by content. Entries do not keep strings
+    // alive; a collection drops those for strings it frees.
+    strings: HashMap<Rc<str>, ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+}
+impl Default for Heap {
+    fn default() -> Self {
+        Heap {
+            objects: Vec::new(),
+            free: Vec::new(),
+            strings: HashMap::new(),
+            bytes_allocated: 0,
+            next_gc: DEFAULT_GC_THRESHOLD,
+        }
+    }
+}
+impl Heap {
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        self.bytes_allocated += obj.size();
+        match self.free.pop() {
+            Some(index) => {
+                self.objects[index] = Some(obj);
+                ObjRef(index)
+            }
+            None => {
+                self.objects.push(Some(obj));
+                ObjRef(self.objects.len() - 1)
+            }
+        }
+    }
+    pub fn get(&self, handle: ObjRef) -> &Obj {
+        self.objects[handle.0]
+            .as_ref()
+            .expect("object was collected")
+    }
+    pub fn get_mut(&mut self, handle: ObjRef) -> &mut Obj {
+        self.objects[handle.0]
+            .as_mut()
+            .expect("object was collected")
+    }
+    pub fn set_gc_threshold(&mut self, bytes: usize) {
+        self.next_gc = bytes;
+    }
+    pub fn should_collect(&self) -> bool {
+        self.bytes_allocated > self.next_gc
+    }
+    // Frees every object not reachable from `roots`.
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = Value>) {
+        let mut marked = vec![false; self.objects.len()];
+        let mut gray: Vec<ObjRef> = Vec::new();
+        let mut mark = |value: Value, gray: &mut Vec<ObjRef>| {
+            if let Some(handle) = value.as_obj() {
+                if !marked[handle.0] {
+                    marked[handle.0] = true;
+                    gray.push(handle);
+                }
+            }
+        };
+        for root in roots {
+            mark(root, &mut gray);
+        }
+        // Trace the references out of each reached object until none are
+        // left unvisited.
+        while let Some(handle) = gray.pop() {
+            match self.get(handle) {
+                Obj::Str(_) | Obj::Native(_) | Obj::Upvalue(Upvalue::Open(_)) => {}
+                Obj::Upvalue(Upvalue::Closed(value)) => mark(*value, &mut gray),
+                // Cached classes are kept alive so their handles can't be
+                // reused by another class while a cache still refers to
+                // them.
+                Obj::Function(function) => {
+                    for constant in &function.chunk.constants {
+                        mark(*constant, &mut gray);
+                    }
+                    for cache in &function.chunk.caches {
+                        if let InlineCache::Method { class, method } = cache {
+                            mark(Value::obj(*class), &mut gray);
+                            mark(*method, &mut gray);
+                        }
+                    }
+                }
+                Obj::Closure(closure) => {
+                    mark(Value::obj(closure.function), &mut gray);
+                    for upvalue in &closure.upvalues {
+                        mark(Value::obj(*upvalue), &mut gray);
+                    }
+                }
+                Obj::Class(class) => {
+                    let members = class
+                        .methods
+                        .iter()
+                        .chain(&class.class_methods)
+                        .chain(&class.fields);
+                    for (name, value) in members {
+                        mark(Value::obj(*name), &mut gray);
+                        mark(*value, &mut gray);
+                    }
+                }
+                Obj::Trait(used) => {
+                    for (name, method) in &used.methods {
+                        mark(Value::obj(*name), &mut gray);
+                        mark(*method, &mut gray);
+                    }
+                }
+                Obj::Instance(instance) => {
+                    mark(Value::obj(instance.class), &mut gray);
+                    for (name, value) in &instance.fields {
+                        mark(Value::obj(*name), &mut gray);
+                        mark(*value, &mut gray);
+                    }
+                }
+                Obj::BoundMethod(bound) => {
+                    mark(bound.receiver, &mut gray);
+                    mark(Value::obj(bound.method), &mut gray);
+                }
+                Obj::Coroutine(Coroutine::Fresh(callee)) => mark(*callee, &mut gray),
+                Obj::Coroutine(Coroutine::Suspended(fiber)) => {
+                    mark(Value::obj(fiber.closure), &mut gray);
+                    for slot in &fiber.slots {
+                        mark(*slot, &mut gray);
+                    }
+                    for (_, upvalue) in &fiber.upvalues {
+                        mark(Value::obj(*upvalue), &mut gray);
+                    }
+                }
+                Obj::Coroutine(_) => {}
+            }
+        }
+        for (index, slot) in self.objects.iter_mut().enumerate() {
+            if marked[index] {
+                continue;
+            }
+            if let Some(obj) = slot.take() {
+                if let Obj::Str(s) = &obj {
+                    self.strings.remove(s);
+                }
+                self.bytes_allocated -= obj.size();
+                self.free.push(index);
+            }
+        }
+        self.next_gc = self.bytes_allocated.max(1) * GC_GROWTH_FACTOR;
+    }
+    // The interned string object for `s`, allocated if it is new.
+    pub fn string(&mut self, s: &str) -> Value {
+        if let Some(handle) = self.strings.get(s) {
+            return Value::obj(*handle);
+        }
+        let s: Rc<str> = Rc::from(s);
+        let handle = self.alloc(Obj::Str(Rc::clone(&s)));
+        self.strings.insert(s, handle);
+        Value::obj(handle)
+    }
+    // The interned string object for `s`, if there is one. Without one, no
+    // method or property can have that name.
+    pub fn interned(&self, s: &str) -> Option<ObjRef> {
+        self.strings.get(s).copied()
+    }
+    pub fn as_str(&self, value: Value) -> Option<&str> {
+        match value.unbox() {
+            Unboxed::Obj(handle) => match self.get(handle) {
+                Obj::Str(s) => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+    pub fn as_function(&self, handle: ObjRef) -> &Function {
+        match self.get(handle) {
+            Obj::Function(function) => function,
+            obj => panic!("expected a function, found {:?}", obj),
+        }
+    }
+    pub fn as_closure(&self, handle: ObjRef) -> &Closure {
+        match self.get(handle) {
+            Obj::Closure(closure) => closure,
+            obj => panic!("expected a closure, found {:?}", obj),
+        }
+    }
+    // The text `print` shows for a value, matching the tree-walk
+    // interpreter.
+    pub fn format(&self, value: Value) -> String {
+        match value.unbox() {
+            Unboxed::Nil => "nil".to_string(),
+            Unboxed::Bool(b) => b.to_string(),
+            Unboxed::Number(n) => display::number(n),
+            Unboxed::Obj(handle) => match self.get(handle) {
+                Obj::Str(s) => s.to_string(),
+                Obj::Function(function) if function.name.is_empty() => "<script>".to_string(),
+                Obj::Function(function) => format!("<fn {}>", function.name),
+                Obj::Native(_) => "<native fn>".to_string(),
+                Obj::Closure(closure) => self.format(Value::obj(closure.function)),
+                Obj::Upvalue(_) => "upvalue".to_string(),
+                Obj::Class(class) => class.name.clone(),
+                Obj::Trait(used) => format!("<trait {}>", used.name),
+                Obj::Instance(instance) => match self.get(instance.class) {
+                    Obj::Class(class) => format!("{} instance", class.name),
+                    _ => "instance".to_string(),
+                },
+                Obj::BoundMethod(bound) => self.format(Value::obj(bound.method)),
+                Obj::Coroutine(_) => "<coroutine>".to_string(),
+            },
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use super::chunk::OpCode;
+use super::debug::op_name;
+use super::object::{Heap, ObjRef};
+use crate::profiler::{millis, percent, table};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Counts and times the instructions the VM runs, by opcode and by the
+// function running them. Each instruction is charged the time until the
+// next one starts, so calls are charged to the caller's `Call` and the
+// callee's instructions to the callee.
+#[derive(Debug, Default)]
+pub(super) struct Profile {
+    // The instruction running, what it is and in which function.
+    current: Option<(OpCode, ObjRef, Instant)>,
+    ops: HashMap<OpCode, Sample>,
+    // Functions stay reachable from the script's constants, so their
+    // handles aren't reused while it runs.
+    functions: HashMap<ObjRef, (String, Sample)>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Sample {
+    count: u64,
+    time: Duration,
+}
+
+impl Profile {
+    pub(super) fn instruction(&mut self, op: OpCode, function: ObjRef, heap: &Heap) {
+        let now = Instant::now();
+        self.stop(now);
+        self.functions.entry(function).or_insert_with(|| {
+            let name = &heap.as_function(function).name;
+            let name = if name.is_empty() { "<script>" } else { name };
+            (name.to_string(), Sample::default())
+        });
+        self.current = Some((op, function, now));
+    }
+    // Charges the instruction running with the time until `now`.
+    pub(super) fn stop(&mut self, now: Instant) {
+        let Some((op, function, started)) = self.current.take() else {
+            return;
+        };
+        let time = now.duration_since(started);
+        for sample in [
+            self.ops.entry(op).or_default(),
+            &mut self.functions.entry(function).or_default().1,
+        ] {
+            sample.count += 1;
+            sample.time += time;
+        }
+    }
+    pub(super) fn report(&self) -> String {
+        let total: Duration = self.ops.values().map(|sample| sample.time).sum();
+        let rows = |samples: Vec<(String, Sample)>| {
+            let mut samples = samples;
+            samples.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(&b.0)));
+            samples
+                .into_iter()
+                .map(|(name, sample)| {
+                    vec![
+                        name,
+                        sample.count.to_string(),
+                        millis(sample.time),
+                        percent(sample.time, total),
+                    ]
+                })
+                .collect()
+        };
+        let functions = self.functions.values().cloned().collect();
+        let ops = self
+            .ops
+            .iter()
+            .map(|(op, sample)| (op_name(*op), *sample))
+            .collect();
+        format!(
+            "{}\n{}",
+            table(
+                &["Function", "Instructions", "Self ms", "Self %"],
+                rows(functions)
+            ),
+            table(&["Opcode", "Count", "ms", "%"], rows(ops))
+        )
+    }
+}
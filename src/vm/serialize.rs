@@ -0,0 +1,295 @@
+use crate::span::Span;
+use crate::vm::chunk::{Chunk, OpCode};
+use crate::vm::globals::Globals;
+use crate::vm::object::{Function, Heap, Obj, ObjRef};
+use crate::vm::value::{Unboxed, Value};
+
+// A compiled script as bytes, for caching in a `.loxc` file: a header
+// naming the format version, whether the script was optimized, a checksum
+// of its source and one of the rest, then the global variables its code
+// refers to by slot, then the script's function. Numbers are
+// little-endian.
+const MAGIC: &[u8; 4] = b"LOXC";
+// Bump whenever the layout or an opcode changes.
+const VERSION: u32 = 7;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+// FNV-1a, which unlike std's hasher is the same from one build to the next.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+pub fn write(
+    function: ObjRef,
+    heap: &Heap,
+    globals: &Globals,
+    source: &str,
+    optimized: bool,
+) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.push(optimized as u8);
+    out.extend_from_slice(&checksum(source.as_bytes()).to_le_bytes());
+    let mut body = Vec::new();
+    let names: Vec<String> = globals
+        .names()
+        .map(|name| heap.format(Value::obj(name)))
+        .collect();
+    write_len(&mut body, names.len());
+    for name in &names {
+        write_str(&mut body, name);
+    }
+    write_function(&mut body, heap.as_function(function), heap);
+    out.extend_from_slice(&checksum(&body).to_le_bytes());
+    out.extend_from_slice(&body);
+    out
+}
+
+fn write_len(out: &mut Vec<u8>, len: usize) {
+    out.extend_from_slice(&(len as u32).to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_len(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_function(out: &mut Vec<u8>, function: &Function, heap: &Heap) {
+    write_str(out, &function.name);
+    write_len(out, function.arity);
+    write_len(out, function.upvalue_count);
+    out.push(function.is_getter as u8);
+    write_len(out, function.entries.len());
+    for entry in &function.entries {
+        write_len(out, *entry);
+    }
+    let chunk = &function.chunk;
+    write_len(out, chunk.constants.len());
+    for constant in &chunk.constants {
+        match constant.unbox() {
+            Unboxed::Nil => out.push(TAG_NIL),
+            Unboxed::Bool(false) => out.push(TAG_FALSE),
+            Unboxed::Bool(true) => out.push(TAG_TRUE),
+            Unboxed::Number(n) => {
+                out.push(TAG_NUMBER);
+                out.extend_from_slice(&n.to_le_bytes());
+            }
+            Unboxed::Obj(handle) => match heap.get(handle) {
+                Obj::Str(s) => {
+                    out.push(TAG_STRING);
+                    write_str(out, s);
+                }
+                Obj::Function(function) => {
+                    out.push(TAG_FUNCTION);
+                    write_function(out, function, heap);
+                }
+                // The compiler makes no other constants.
+                obj => panic!("unexpected constant {:?}", obj),
+            },
+        }
+    }
+    write_len(out, chunk.code.len());
+    out.extend_from_slice(&chunk.code);
+    for span in &chunk.spans {
+        write_len(out, span.start);
+        write_len(out, span.end);
+    }
+}
+
+// Loads a script written by `write`, defining slots for its globals.
+// Returns None if `bytes` aren't one, are from another version or for
+// another source, or are damaged. Damage that slips past the checksum and
+// the checks on each instruction goes unnoticed.
+pub fn read(
+    bytes: &[u8],
+    heap: &mut Heap,
+    globals: &mut Globals,
+    source: &str,
+    optimized: bool,
+) -> Option<ObjRef> {
+    let mut reader = Reader { bytes, at: 0 };
+    if reader.take(4)? != MAGIC
+        || reader.u32()? != VERSION
+        || reader.u8()? != optimized as u8
+        || reader.u64()? != checksum(source.as_bytes())
+        || reader.u64()? != checksum(&bytes[reader.at..])
+    {
+        return None;
+    }
+    // The slots this VM has for the names the code was compiled against.
+    let mut slots = Vec::new();
+    for _ in 0..reader.u32()? {
+        let name = heap.string(reader.str()?).as_obj()?;
+        slots.push(globals.slot(name));
+    }
+    let function = reader.function(heap, &slots, source.len())?;
+    if reader.at != bytes.len() {
+        return None;
+    }
+    Some(heap.alloc(Obj::Function(function)))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.at..self.at.checked_add(n)?)?;
+        self.at += n;
+        Some(bytes)
+    }
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+    fn len(&mut self) -> Option<usize> {
+        self.u32().map(|n| n as usize)
+    }
+    fn str(&mut self) -> Option<&'a str> {
+        let len = self.len()?;
+        std::str::from_utf8(self.take(len)?).ok()
+    }
+    fn function(
+        &mut self,
+        heap: &mut Heap,
+        slots: &[usize],
+        source_len: usize,
+    ) -> Option<Function> {
+        let mut function = Function {
+            name: self.str()?.to_string(),
+            arity: self.len()?,
+            upvalue_count: self.len()?,
+            is_getter: self.u8()? != 0,
+            chunk: Chunk::default(),
+            entries: Vec::new(),
+        };
+        for _ in 0..self.len()? {
+            function.entries.push(self.len()?);
+        }
+        if function.entries.len() > function.arity + 1 {
+            return None;
+        }
+        let mut kinds = Vec::new();
+        for _ in 0..self.len()? {
+            let (value, kind) = match self.u8()? {
+                TAG_NIL => (Value::nil(), Kind::Other),
+                TAG_FALSE => (Value::bool(false), Kind::Other),
+                TAG_TRUE => (Value::bool(true), Kind::Other),
+                TAG_NUMBER => (
+                    Value::number(f64::from_le_bytes(self.take(8)?.try_into().ok()?)),
+                    Kind::Other,
+                ),
+                TAG_STRING => (heap.string(self.str()?), Kind::Str),
+                TAG_FUNCTION => {
+                    let inner = self.function(heap, slots, source_len)?;
+                    let kind = Kind::Function(inner.upvalue_count);
+                    (Value::obj(heap.alloc(Obj::Function(inner))), kind)
+                }
+                _ => return None,
+            };
+            function.chunk.add_constant(value);
+            kinds.push(kind);
+        }
+        let len = self.len()?;
+        let mut code = self.take(len)?.to_vec();
+        relocate(&mut code, &kinds, slots)?;
+        for _ in 0..len {
+            let (start, end) = (self.len()?, self.len()?);
+            if start > end || end > source_len {
+                return None;
+            }
+            function.chunk.spans.push(Span::new(start, end));
+        }
+        if function.entries.iter().any(|entry| *entry > code.len()) {
+            return None;
+        }
+        function.chunk.code = code;
+        Some(function)
+    }
+}
+
+// What a constant is, as far as the instructions using it care.
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Str,
+    // A function with this many upvalues, which its closure instruction
+    // is followed by a pair of bytes for each of.
+    Function(usize),
+    Other,
+}
+
+// Checks that each instruction in `code` is whole and its operands are in
+// range and of the right kind, and renumbers global slots from those the
+// code was compiled with to this VM's.
+fn relocate(code: &mut [u8], kinds: &[Kind], slots: &[usize]) -> Option<()> {
+    let mut offset = 0;
+    while offset < code.len() {
+        let op = OpCode::try_from(code[offset]).ok()?;
+        let operand = |i: usize| code.get(offset + i).map(|byte| *byte as usize);
+        let size = match op {
+            OpCode::GetGlobal | OpCode::DefineGlobal | OpCode::SetGlobal => {
+                let slot = *slots.get(operand(1)? << 8 | operand(2)?)?;
+                let slot = u16::try_from(slot).ok()?.to_be_bytes();
+                code[offset + 1..offset + 3].copy_from_slice(&slot);
+                3
+            }
+            OpCode::Constant => {
+                kinds.get(operand(1)?)?;
+                2
+            }
+            OpCode::Class
+            | OpCode::Method
+            | OpCode::ClassMethod
+            | OpCode::Trait
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::GetSuper => (*kinds.get(operand(1)?)? == Kind::Str).then_some(2)?,
+            OpCode::Invoke | OpCode::SuperInvoke => {
+                (*kinds.get(operand(1)?)? == Kind::Str).then_some(3)?
+            }
+            OpCode::Closure => match kinds.get(operand(1)?)? {
+                Kind::Function(upvalues) => 2 + upvalues * 2,
+                _ => return None,
+            },
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call
+            | OpCode::TailCall
+            | OpCode::Mixin => 2,
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfNil => {
+                let target = offset + 3 + (operand(1)? << 8 | operand(2)?);
+                if target > code.len() {
+                    return None;
+                }
+                3
+            }
+            OpCode::Loop => {
+                (offset + 3).checked_sub(operand(1)? << 8 | operand(2)?)?;
+                3
+            }
+            _ => 1,
+        };
+        if offset + size > code.len() {
+            return None;
+        }
+        offset += size;
+    }
+    Some(())
+}
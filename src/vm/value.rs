@@ -0,0 +1,134 @@
+use crate::vm::object::ObjRef;
+use std::fmt;
+
+// What a value is, for matching on. Anything bigger than a number lives in
+// the heap and is referred to by handle. Strings are interned, so comparing
+// handles compares objects by identity and strings by content.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unboxed {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(ObjRef),
+}
+
+// Values the VM works with, built with the constructors below and
+// inspected through `unbox`. By default a value is the plain enum, 16
+// bytes. With the `nan-boxing` feature it is packed into 8 bytes instead.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Value(Repr);
+
+#[cfg(not(feature = "nan-boxing"))]
+type Repr = Unboxed;
+
+#[cfg(not(feature = "nan-boxing"))]
+impl Value {
+    pub fn nil() -> Value {
+        Value(Unboxed::Nil)
+    }
+    pub fn bool(b: bool) -> Value {
+        Value(Unboxed::Bool(b))
+    }
+    pub fn number(n: f64) -> Value {
+        Value(Unboxed::Number(n))
+    }
+    pub fn obj(handle: ObjRef) -> Value {
+        Value(Unboxed::Obj(handle))
+    }
+    pub fn unbox(self) -> Unboxed {
+        self.0
+    }
+}
+
+// A double with every exponent bit and the top two mantissa bits set is a
+// quiet NaN that arithmetic never produces, leaving the other bits free.
+// Nil and the booleans are such NaNs with a tag in the low bits; objects
+// also set the sign bit and keep their handle in the low 48 bits.
+#[cfg(feature = "nan-boxing")]
+type Repr = Boxed;
+
+#[cfg(feature = "nan-boxing")]
+#[derive(Clone, Copy)]
+struct Boxed(u64);
+
+#[cfg(feature = "nan-boxing")]
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+#[cfg(feature = "nan-boxing")]
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+#[cfg(feature = "nan-boxing")]
+const TAG_NIL: u64 = 1;
+#[cfg(feature = "nan-boxing")]
+const TAG_FALSE: u64 = 2;
+#[cfg(feature = "nan-boxing")]
+const TAG_TRUE: u64 = 3;
+#[cfg(feature = "nan-boxing")]
+const HANDLE_MASK: u64 = 0x0000_ffff_ffff_ffff;
+
+#[cfg(feature = "nan-boxing")]
+impl Value {
+    pub fn nil() -> Value {
+        Value(Boxed(QNAN | TAG_NIL))
+    }
+    pub fn bool(b: bool) -> Value {
+        Value(Boxed(QNAN | if b { TAG_TRUE } else { TAG_FALSE }))
+    }
+    pub fn number(n: f64) -> Value {
+        // NaNs are stored as the canonical one, so none can be mistaken for
+        // a boxed value.
+        let n = if n.is_nan() { f64::NAN } else { n };
+        Value(Boxed(n.to_bits()))
+    }
+    pub fn obj(handle: ObjRef) -> Value {
+        Value(Boxed(SIGN_BIT | QNAN | (handle.0 as u64 & HANDLE_MASK)))
+    }
+    pub fn unbox(self) -> Unboxed {
+        let bits = self.0 .0;
+        if bits & QNAN != QNAN {
+            Unboxed::Number(f64::from_bits(bits))
+        } else if bits & SIGN_BIT != 0 {
+            Unboxed::Obj(ObjRef((bits & HANDLE_MASK) as usize))
+        } else if bits & 0b11 == TAG_TRUE {
+            Unboxed::Bool(true)
+        } else if bits & 0b11 == TAG_FALSE {
+            Unboxed::Bool(false)
+        } else {
+            Unboxed::Nil
+        }
+    }
+}
+
+// Numbers compare as numbers, so NaN is unequal to itself. Anything else is
+// equal exactly when its bits are.
+#[cfg(feature = "nan-boxing")]
+impl PartialEq for Boxed {
+    fn eq(&self, other: &Boxed) -> bool {
+        match (Value(*self).unbox(), Value(*other).unbox()) {
+            (Unboxed::Number(a), Unboxed::Number(b)) => a == b,
+            _ => self.0 == other.0,
+        }
+    }
+}
+
+impl Value {
+    pub fn as_number(self) -> Option<f64> {
+        match self.unbox() {
+            Unboxed::Number(n) => Some(n),
+            _ => None,
+        }
+    }
+    pub fn as_obj(self) -> Option<ObjRef> {
+        match self.unbox() {
+            Unboxed::Obj(handle) => Some(handle),
+            _ => None,
+        }
+    }
+    // Lox follows Ruby: only `nil` and `false` are falsey.
+    pub fn is_falsey(self) -> bool {
+        matches!(self.unbox(), Unboxed::Nil | Unboxed::Bool(false))
+    }
+}
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.unbox())
+    }
+}
@@ -0,0 +1,154 @@
+// Bindings for running Lox in a browser, as a WebAssembly module built with
+//
+//     cargo rustc --lib --release --target wasm32-unknown-unknown \
+//         --crate-type cdylib -- -C link-arg=-zstack-size=8388608
+//
+// and wrapped as `run(source)` by web/lox.js. Strings cross as UTF-8 in the
+// module's memory: the host puts source in a buffer from `lox_alloc`, and
+// reads results where `lox_run` says they are. The host provides two
+// functions in the `env` module: `lox_print(ptr, len)`, called with each
+// line the program prints as it runs, and `lox_now()`, the milliseconds
+// since the Unix epoch, for `clock`.
+use crate::error::LoxError;
+use crate::json::Json;
+use crate::limits::Limits;
+use crate::span::SourceMap;
+use crate::Lox;
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::time::Duration;
+
+extern "C" {
+    fn lox_print(ptr: *const u8, len: usize);
+    fn lox_now() -> f64;
+}
+
+// The tree-walk interpreter nests Rust calls for Lox ones, and the 8 MiB
+// stack the module is linked with holds fewer of them than a native thread.
+const MAX_CALL_DEPTH: usize = 256;
+
+pub(crate) fn since_epoch() -> Duration {
+    // SAFETY: the host provides `lox_now`, which takes nothing.
+    let millis = unsafe { lox_now() };
+    Duration::from_secs_f64(millis.max(0.0) / 1000.0)
+}
+
+thread_local! {
+    // The result of the last `lox_run`, kept until the next.
+    static RESULT: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+// A buffer of `len` bytes for the host to write source into.
+#[no_mangle]
+pub extern "C" fn lox_alloc(len: usize) -> *mut u8 {
+    let mut buffer = Vec::<u8>::with_capacity(len);
+    let ptr = buffer.as_mut_ptr();
+    std::mem::forget(buffer);
+    ptr
+}
+
+/// # Safety
+///
+/// `ptr` and `len` must be from one call of `lox_alloc`, not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn lox_free(ptr: *mut u8, len: usize) {
+    drop(Vec::from_raw_parts(ptr, 0, len));
+}
+
+// Runs the `len` bytes of source at `ptr` in a fresh interpreter, stopping
+// it after `fuel` steps unless that is zero. Returns where the result is, a
+// JSON object of everything printed as `output` and the diagnostics that
+// stopped the program, if any, as `errors`; `lox_result_len` is its length.
+/// # Safety
+///
+/// `ptr` must point to `len` initialized bytes.
+#[no_mangle]
+pub unsafe extern "C" fn lox_run(ptr: *const u8, len: usize, fuel: f64) -> *const u8 {
+    let source = String::from_utf8_lossy(std::slice::from_raw_parts(ptr, len));
+    let result = run(&source, (fuel >= 1.0).then_some(fuel as u64)).to_string();
+    RESULT.with(|slot| {
+        *slot.borrow_mut() = result;
+        slot.borrow().as_ptr()
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn lox_result_len() -> usize {
+    RESULT.with(|slot| slot.borrow().len())
+}
+
+fn run(source: &str, fuel: Option<u64>) -> Json {
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let mut lox = Lox::new();
+    lox.set_max_call_depth(MAX_CALL_DEPTH);
+    lox.set_limits(Limits {
+        fuel,
+        timeout: None,
+    });
+    lox.set_output(Printer {
+        output: Rc::clone(&output),
+        line: Vec::new(),
+    });
+    let errors = match lox.run(source) {
+        Ok(_) => Vec::new(),
+        Err(e) => diagnostics(&e, source),
+    };
+    // Output the interpreter still holds is dropped with it, flushing the
+    // last line even if it has no newline.
+    drop(lox);
+    let output = String::from_utf8_lossy(&output.borrow()).into_owned();
+    Json::object(vec![
+        ("output", Json::String(output)),
+        ("errors", Json::Array(errors)),
+    ])
+}
+
+fn diagnostics(e: &LoxError, source: &str) -> Vec<Json> {
+    let map = SourceMap::new(source);
+    e.diagnostics()
+        .iter()
+        .map(|diagnostic| {
+            let (line, column) = map.location(diagnostic.span.start);
+            Json::object(vec![
+                ("line", line.into()),
+                ("column", (column + 1).into()),
+                ("message", Json::String(diagnostic.to_string())),
+                ("rendered", Json::String(diagnostic.render(&map))),
+            ])
+        })
+        .collect()
+}
+
+// Keeps everything printed for the result, and passes it to the host a
+// line at a time as it comes.
+struct Printer {
+    output: Rc<RefCell<Vec<u8>>>,
+    line: Vec<u8>,
+}
+impl Write for Printer {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.output.borrow_mut().extend_from_slice(bytes);
+        for byte in bytes {
+            if *byte == b'\n' {
+                self.flush()?;
+            } else {
+                self.line.push(*byte);
+            }
+        }
+        Ok(bytes.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        // SAFETY: the host provides `lox_print`, which only reads the line.
+        unsafe { lox_print(self.line.as_ptr(), self.line.len()) };
+        self.line.clear();
+        Ok(())
+    }
+}
+impl Drop for Printer {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            let _ = self.flush();
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use tree::vm::Vm;
+
+const SOURCE: &str = "\
+fun greet(name) { return \"hi \" + name; }
+class Box { init(n) { this.n = n; } }
+print greet(\"lox\");
+print Box(2).n * 3;
+";
+
+// Where a VM's printed output goes, to be read back after it runs.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+impl Write for Output {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A VM printing to a fresh `Output`.
+fn vm() -> (Vm, Output) {
+    let output = Output::default();
+    let mut vm = Vm::new();
+    vm.set_output(output.clone());
+    (vm, output)
+}
+
+fn compiled() -> Vec<u8> {
+    let (mut vm, output) = vm();
+    let bytes = vm.compile(SOURCE).unwrap();
+    assert!(output.0.borrow().is_empty(), "compiling ran the script");
+    bytes
+}
+
+#[test]
+fn runs_what_it_compiled_in_another_vm() {
+    let bytes = compiled();
+    let (mut vm, output) = vm();
+    assert!(vm.interpret_compiled(&bytes, SOURCE).unwrap().is_ok());
+    assert_eq!(String::from_utf8(output.0.take()).unwrap(), "hi lox\n6\n");
+}
+
+#[test]
+fn rejects_bytes_for_another_source() {
+    let bytes = compiled();
+    let changed = SOURCE.replace("hi ", "bye ");
+    let (mut vm, output) = vm();
+    assert!(vm.interpret_compiled(&bytes, &changed).is_none());
+    assert!(output.0.borrow().is_empty());
+}
+
+#[test]
+fn rejects_bytes_from_another_version() {
+    let mut bytes = compiled();
+    // The version follows the four magic bytes.
+    bytes[4] = bytes[4].wrapping_add(1);
+    assert!(vm().0.interpret_compiled(&bytes, SOURCE).is_none());
+}
+
+#[test]
+fn rejects_bytes_compiled_with_the_optimizer_set_otherwise() {
+    let (mut unoptimized, _) = vm();
+    unoptimized.set_optimize(false);
+    let bytes = unoptimized.compile(SOURCE).unwrap();
+    let (mut vm, _) = vm();
+    vm.set_optimize(true);
+    assert!(vm.interpret_compiled(&bytes, SOURCE).is_none());
+}
+
+#[test]
+fn rejects_truncated_or_damaged_bytes() {
+    let bytes = compiled();
+    for len in [0, 4, 8, bytes.len() / 2, bytes.len() - 1] {
+        assert!(
+            vm().0.interpret_compiled(&bytes[..len], SOURCE).is_none(),
+            "accepted the first {} of {} bytes",
+            len,
+            bytes.len()
+        );
+    }
+    let mut damaged = bytes.clone();
+    let last = damaged.len() - 1;
+    damaged[last] ^= 0xff;
+    assert!(vm().0.interpret_compiled(&damaged, SOURCE).is_none());
+}
@@ -0,0 +1,93 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Write};
+use std::rc::Rc;
+use tree::dap;
+
+const PROGRAM: &str = "\
+fun greet(name) {
+  var message = \"hi \" + name;
+  return message;
+}
+greet(\"lox\");
+";
+
+// Collects what the server writes, where the test can still read it once
+// the server has taken its writer.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn frame(message: &str) -> String {
+    format!("Content-Length: {}\r\n\r\n{}", message.len(), message)
+}
+
+// Splits the server's output back into the messages it framed.
+fn messages(output: &[u8]) -> Vec<String> {
+    let mut text = std::str::from_utf8(output).unwrap();
+    let mut messages = Vec::new();
+    while let Some(rest) = text.strip_prefix("Content-Length: ") {
+        let (length, rest) = rest.split_once("\r\n\r\n").unwrap();
+        let length: usize = length.parse().unwrap();
+        messages.push(rest[..length].to_string());
+        text = &rest[length..];
+    }
+    assert!(text.is_empty(), "unframed output: {:?}", text);
+    messages
+}
+
+#[test]
+fn breakpoint_session() {
+    let path = std::env::temp_dir().join(format!("dap_session_{}.lox", std::process::id()));
+    fs::write(&path, PROGRAM).unwrap();
+    let path = path.to_str().unwrap().to_string();
+
+    let requests = [
+        r#"{"seq":1,"type":"request","command":"initialize","arguments":{"adapterID":"lox"}}"#
+            .to_string(),
+        format!(
+            r#"{{"seq":2,"type":"request","command":"launch","arguments":{{"program":"{}"}}}}"#,
+            path
+        ),
+        format!(
+            r#"{{"seq":3,"type":"request","command":"setBreakpoints","arguments":{{"source":{{"path":"{}"}},"breakpoints":[{{"line":2}}]}}}}"#,
+            path
+        ),
+        r#"{"seq":4,"type":"request","command":"configurationDone"}"#.to_string(),
+        r#"{"seq":5,"type":"request","command":"stackTrace","arguments":{"threadId":1}}"#
+            .to_string(),
+        r#"{"seq":6,"type":"request","command":"continue","arguments":{"threadId":1}}"#.to_string(),
+        r#"{"seq":7,"type":"request","command":"disconnect"}"#.to_string(),
+    ];
+    let input: String = requests.iter().map(|request| frame(request)).collect();
+    let output = Output::default();
+    dap::serve(io::Cursor::new(input.into_bytes()), output.clone()).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let name = path.rsplit('/').next().unwrap();
+    let source = format!(r#"{{"name":"{}","path":"{}"}}"#, name, path);
+    let expected = [
+        r#"{"seq":1,"type":"response","request_seq":1,"success":true,"command":"initialize","body":{"supportsConfigurationDoneRequest":true}}"#.to_string(),
+        r#"{"seq":2,"type":"event","event":"initialized"}"#.to_string(),
+        r#"{"seq":3,"type":"response","request_seq":2,"success":true,"command":"launch"}"#.to_string(),
+        r#"{"seq":4,"type":"response","request_seq":3,"success":true,"command":"setBreakpoints","body":{"breakpoints":[{"verified":true,"line":2}]}}"#.to_string(),
+        r#"{"seq":5,"type":"response","request_seq":4,"success":true,"command":"configurationDone"}"#.to_string(),
+        r#"{"seq":6,"type":"event","event":"stopped","body":{"reason":"breakpoint","threadId":1,"allThreadsStopped":true}}"#.to_string(),
+        format!(
+            r#"{{"seq":7,"type":"response","request_seq":5,"success":true,"command":"stackTrace","body":{{"totalFrames":2,"stackFrames":[{{"id":0,"name":"greet","source":{source},"line":2,"column":1}},{{"id":1,"name":"script","source":{source},"line":5,"column":1}}]}}}}"#
+        ),
+        r#"{"seq":8,"type":"response","request_seq":6,"success":true,"command":"continue","body":{"allThreadsContinued":true}}"#.to_string(),
+        r#"{"seq":9,"type":"event","event":"exited","body":{"exitCode":0}}"#.to_string(),
+        r#"{"seq":10,"type":"event","event":"terminated"}"#.to_string(),
+        r#"{"seq":11,"type":"response","request_seq":7,"success":true,"command":"disconnect"}"#.to_string(),
+    ];
+    assert_eq!(messages(&output.0.borrow()), expected);
+}
@@ -0,0 +1,141 @@
+#![cfg(feature = "ffi")]
+
+// Drives the C interface the way a C host would, through the exported
+// symbols rather than Rust paths. Naming the crate links them in.
+extern crate tree;
+
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::ptr;
+
+#[repr(C)]
+struct Lox {
+    _private: [u8; 0],
+}
+#[repr(C)]
+struct LoxValue {
+    _private: [u8; 0],
+}
+
+type LoxNative =
+    extern "C" fn(data: *mut c_void, argc: usize, argv: *const *const LoxValue) -> *mut LoxValue;
+
+extern "C" {
+    fn lox_new() -> *mut Lox;
+    fn lox_free(lox: *mut Lox);
+    fn lox_run(lox: *mut Lox, source: *const c_char, result: *mut *mut LoxValue) -> c_int;
+    fn lox_last_error(lox: *const Lox) -> *const c_char;
+    fn lox_release_sources(lox: *mut Lox);
+    fn lox_define_native(
+        lox: *mut Lox,
+        name: *const c_char,
+        arity: usize,
+        function: LoxNative,
+        data: *mut c_void,
+    );
+    fn lox_value_number(n: f64) -> *mut LoxValue;
+    fn lox_value_error(message: *const c_char) -> *mut LoxValue;
+    fn lox_value_free(value: *mut LoxValue);
+    fn lox_value_as_number(value: *const LoxValue) -> f64;
+    fn lox_value_as_string(value: *const LoxValue) -> *const c_char;
+}
+
+// Runs `source`, returning the exit code and the value it left, if any.
+fn run(lox: *mut Lox, source: &str) -> (c_int, *mut LoxValue) {
+    let source = CString::new(source).unwrap();
+    let mut result = ptr::null_mut();
+    let code = unsafe { lox_run(lox, source.as_ptr(), &mut result) };
+    (code, result)
+}
+
+fn last_error(lox: *mut Lox) -> Option<String> {
+    let error = unsafe { lox_last_error(lox) };
+    if error.is_null() {
+        return None;
+    }
+    Some(
+        unsafe { CStr::from_ptr(error) }
+            .to_str()
+            .unwrap()
+            .to_string(),
+    )
+}
+
+#[test]
+fn runs_source_and_returns_its_value() {
+    let lox = unsafe { lox_new() };
+    let (code, value) = run(lox, "var a = \"lo\";");
+    assert_eq!(code, 0);
+    unsafe { lox_value_free(value) };
+    let (code, value) = run(lox, "a + \"x\";");
+    assert_eq!(code, 0);
+    let text = unsafe { CStr::from_ptr(lox_value_as_string(value)) };
+    assert_eq!(text.to_str().unwrap(), "lox");
+    assert_eq!(last_error(lox), None);
+    unsafe {
+        lox_value_free(value);
+        lox_free(lox);
+    }
+}
+
+// Adds its two arguments to the number `data` points at, failing on
+// anything but numbers.
+extern "C" fn add(data: *mut c_void, argc: usize, argv: *const *const LoxValue) -> *mut LoxValue {
+    let base = unsafe { *(data as *const f64) };
+    let args = unsafe { std::slice::from_raw_parts(argv, argc) };
+    let numbers: Vec<f64> = args
+        .iter()
+        .map(|arg| unsafe { lox_value_as_number(*arg) })
+        .collect();
+    if numbers.contains(&0.0) {
+        return unsafe { lox_value_error(c"Arguments must be nonzero.".as_ptr()) };
+    }
+    unsafe { lox_value_number(base + numbers.iter().sum::<f64>()) }
+}
+
+#[test]
+fn calls_a_native_with_its_data() {
+    let lox = unsafe { lox_new() };
+    let mut base = 100.0;
+    unsafe {
+        lox_define_native(
+            lox,
+            c"add".as_ptr(),
+            2,
+            add,
+            &mut base as *mut f64 as *mut c_void,
+        )
+    };
+    let (code, value) = run(lox, "add(1, 2);");
+    assert_eq!(code, 0);
+    assert_eq!(unsafe { lox_value_as_number(value) }, 103.0);
+    unsafe { lox_value_free(value) };
+
+    let (code, value) = run(lox, "add(0, 2);");
+    assert_eq!(code, 70);
+    assert!(value.is_null());
+    let error = last_error(lox).unwrap();
+    assert!(error.contains("Arguments must be nonzero."), "{}", error);
+    unsafe { lox_free(lox) };
+}
+
+#[test]
+fn reports_why_a_run_failed() {
+    let lox = unsafe { lox_new() };
+    let (code, _) = run(lox, "var = 1;");
+    assert_eq!(code, 65);
+    let error = last_error(lox).unwrap();
+    assert!(error.contains("Expect variable name."), "{}", error);
+
+    let (code, _) = run(lox, "nil + 1;");
+    assert_eq!(code, 70);
+    assert!(last_error(lox).unwrap().contains("Operands"));
+
+    unsafe { lox_release_sources(lox) };
+    let (code, value) = run(lox, "1;");
+    assert_eq!(code, 0);
+    assert_eq!(last_error(lox), None);
+    unsafe {
+        lox_value_free(value);
+        lox_free(lox);
+    }
+}
@@ -0,0 +1,112 @@
+use std::fs;
+use std::path::Path;
+use tree::highlight::{self, Class};
+
+// Sources the scanner rejects parts of, where the spans are least likely
+// to line up.
+const TRICKY: &[&str] = &[
+    "",
+    " \n\t ",
+    "1 @@ \t¬ 2",
+    "print \"open",
+    "var é = \"ü\"; // ünïcode\n",
+    "\"a ${\"b ${c}\"} d\"",
+    "\"a ${b",
+    "#\n# x",
+    "// only a comment",
+    "print 1;   ",
+];
+
+// The spans must cover all of `source`, in order, without overlapping or
+// leaving anything out.
+fn assert_tiles(source: &str) {
+    let mut last = 0;
+    for (span, class) in highlight::highlight(source) {
+        assert_eq!(
+            span.start, last,
+            "gap or overlap before {:?} in {:?}",
+            class, source
+        );
+        assert!(
+            span.end > span.start,
+            "empty {:?} span in {:?}",
+            class,
+            source
+        );
+        last = span.end;
+    }
+    assert_eq!(
+        last,
+        source.len(),
+        "spans stop short of the end of {:?}",
+        source
+    );
+}
+
+fn lox_files(dir: &Path, files: &mut Vec<String>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            lox_files(&path, files);
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            files.push(fs::read_to_string(&path).unwrap());
+        }
+    }
+}
+
+#[test]
+fn spans_tile_the_source() {
+    for source in TRICKY {
+        assert_tiles(source);
+    }
+    let mut files = Vec::new();
+    lox_files(
+        &Path::new(env!("CARGO_MANIFEST_DIR")).join("test"),
+        &mut files,
+    );
+    assert!(!files.is_empty());
+    for source in &files {
+        assert_tiles(source);
+    }
+}
+
+#[test]
+fn rejected_text_splits_into_error_and_whitespace_runs() {
+    let source = "1 @@ \t¬ 2";
+    let spans: Vec<(&str, Class)> = highlight::highlight(source)
+        .into_iter()
+        .map(|(span, class)| (&source[span.start..span.end], class))
+        .collect();
+    assert_eq!(
+        spans,
+        [
+            ("1", Class::Number),
+            (" ", Class::Whitespace),
+            ("@@", Class::Error),
+            (" \t", Class::Whitespace),
+            ("¬", Class::Error),
+            (" ", Class::Whitespace),
+            ("2", Class::Number),
+        ]
+    );
+}
+
+#[test]
+fn html_holds_the_whole_source() {
+    for source in TRICKY.iter().chain(&["a < b && c > \"&\""]) {
+        let html = highlight::to_html(source);
+        let mut text = String::new();
+        let mut rest = html.as_str();
+        while let Some(open) = rest.find('<') {
+            text.push_str(&rest[..open]);
+            rest = &rest[open + rest[open..].find('>').unwrap() + 1..];
+        }
+        text.push_str(rest);
+        let text = text
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&amp;", "&");
+        assert_eq!(&text, source);
+    }
+}
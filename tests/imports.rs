@@ -0,0 +1,41 @@
+use std::path::Path;
+use tree::Lox;
+
+fn import_error(lox: &mut Lox, path: &str) -> Option<String> {
+    let source = format!("import \"{}\";", path);
+    let error = lox.run(&source).err()?;
+    Some(error.diagnostics()[0].message.clone())
+}
+
+fn lox_in_test_dir() -> Lox {
+    let mut lox = Lox::new();
+    lox.set_module_root(Path::new(env!("CARGO_MANIFEST_DIR")).join("test/import"));
+    lox
+}
+
+#[test]
+fn imports_are_off_until_allowed() {
+    let mut lox = lox_in_test_dir();
+    assert_eq!(
+        import_error(&mut lox, "lib/util.lox").as_deref(),
+        Some("Imports are not allowed.")
+    );
+    lox.allow_imports();
+    assert_eq!(import_error(&mut lox, "lib/util.lox"), None);
+}
+
+#[test]
+fn imports_stay_under_the_module_root() {
+    let mut lox = lox_in_test_dir();
+    lox.allow_imports();
+    assert_eq!(
+        import_error(&mut lox, "../limits/fuel.lox").as_deref(),
+        Some("Module '../limits/fuel.lox' is outside the script's directory.")
+    );
+    let absolute = Path::new(env!("CARGO_MANIFEST_DIR")).join("test/import/lib/util.lox");
+    let absolute = absolute.to_str().unwrap();
+    assert_eq!(
+        import_error(&mut lox, absolute),
+        Some(format!("Module path '{}' must be relative.", absolute))
+    );
+}
@@ -0,0 +1,78 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// Runs a prompt session on `input`, returning what it printed with the
+// prompts left out, and what it reported.
+fn session(input: &str) -> (String, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_tree"))
+        .arg("repl")
+        .env("LOXRC", "")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap().replace("> ", "");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    (stdout, stderr)
+}
+
+#[test]
+fn undo_reverts_one_line_at_a_time() {
+    let (stdout, stderr) = session(
+        "var a = 1;\n\
+         a = 2;\n\
+         var b = 3;\n\
+         :undo\n\
+         print b;\n\
+         :undo\n\
+         :undo\n\
+         print a;\n",
+    );
+    assert_eq!(stdout, "1\n");
+    assert!(stderr.contains("Undefined variable 'b'."), "{}", stderr);
+}
+
+#[test]
+fn undo_keeps_scope_for_functions_defined_earlier() {
+    let (stdout, stderr) = session(
+        "var n = 1;\n\
+         fun get() { return n; }\n\
+         n = 2;\n\
+         :undo\n\
+         print get();\n",
+    );
+    assert_eq!(stdout, "1\n");
+    assert_eq!(stderr, "");
+}
+
+#[test]
+fn undo_with_nothing_to_undo() {
+    let (stdout, stderr) = session(":undo\nvar a = 1;\n:reset\n:undo\n");
+    assert_eq!(stdout, "");
+    assert_eq!(stderr, "Nothing to undo.\nNothing to undo.\n");
+}
+
+#[test]
+fn undo_history_is_capped() {
+    let mut input = String::from("var n = 0;\n");
+    for _ in 0..150 {
+        input.push_str("n = n + 1;\n");
+    }
+    for _ in 0..150 {
+        input.push_str(":undo\n");
+    }
+    input.push_str("print n;\n");
+    let (stdout, stderr) = session(&input);
+    // Only the last hundred lines can be undone.
+    assert_eq!(stdout, "50\n");
+    assert_eq!(stderr, "Nothing to undo.\n".repeat(50));
+}
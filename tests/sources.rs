@@ -0,0 +1,37 @@
+use tree::Lox;
+
+// The first line of the report of why running `source` failed.
+fn error_line(lox: &mut Lox, source: &str) -> String {
+    let error = lox.run(source).unwrap_err();
+    let report = error.render_with(&lox.source_map());
+    report.lines().next().unwrap().to_string()
+}
+
+#[test]
+fn released_sources_still_place_their_errors() {
+    let mut lox = Lox::new();
+    lox.run("fun fail() {\n  return nil + 1;\n}").unwrap();
+    assert_eq!(
+        error_line(&mut lox, "fail();"),
+        "[line 2] Error: Operands must be two numbers or two strings."
+    );
+    lox.release_sources();
+    assert_eq!(
+        error_line(&mut lox, "fail();"),
+        "[line 1] Error: Operands must be two numbers or two strings."
+    );
+    assert_eq!(
+        error_line(&mut lox, "\n\nnil + 1;"),
+        "[line 3] Error: Operands must be two numbers or two strings."
+    );
+}
+
+#[test]
+fn prompt_lines_count_on_after_a_release() {
+    let mut lox = Lox::new();
+    lox.run_line("var a = 1;\nvar b = 2;").unwrap();
+    lox.release_sources();
+    let error = lox.run_line("nil + 1;").unwrap_err();
+    let report = error.render_with(&lox.source_map());
+    assert!(report.starts_with("[repl:3] Error"), "{}", report);
+}
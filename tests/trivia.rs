@@ -0,0 +1,74 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use tree::scanner::{Scanner, TriviaKind};
+
+// Sources that scan cleanly but put trivia in awkward places.
+const SOURCES: &[&str] = &[
+    "",
+    "\n",
+    "print 1;",
+    "  \t// only a comment",
+    "// first\n\n  // second\nvar a = 1; // trailing\n",
+    "// windows\r\nprint 1; // line endings\r\n",
+    "var é = \"ü\\n\\u{1F600}\";\r\n",
+    "print \"a ${ \"b ${ c }\" } d\"; ",
+    "fun f(a,   b) {\n\treturn a\n\t\t+ b;\n}\n\n\n",
+];
+
+// Puts the trivia and lexemes back together, checking each piece matches
+// the source its span covers.
+fn reassemble(source: &str) -> Option<String> {
+    let mut text = String::new();
+    for item in Scanner::with_trivia(source) {
+        let item = item.ok()?;
+        for trivia in &item.leading {
+            assert_eq!(trivia.text, source[trivia.span.start..trivia.span.end]);
+            if trivia.kind == TriviaKind::Whitespace {
+                assert!(trivia.text.chars().all(char::is_whitespace));
+            }
+            text.push_str(&trivia.text);
+        }
+        let token = &item.token;
+        assert_eq!(*token.lexeme, source[token.span.start..token.span.end]);
+        text.push_str(&token.lexeme);
+    }
+    Some(text)
+}
+
+fn lox_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            lox_files(&path, files);
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            files.push(path);
+        }
+    }
+}
+
+#[test]
+fn trivia_and_lexemes_rebuild_the_source() {
+    for source in SOURCES {
+        assert_eq!(reassemble(source).as_deref(), Some(*source));
+    }
+}
+
+// Every test script that scans without errors, which covers most of the
+// language's syntax.
+#[test]
+fn test_suite_round_trips() {
+    let mut files = Vec::new();
+    lox_files(
+        &Path::new(env!("CARGO_MANIFEST_DIR")).join("test"),
+        &mut files,
+    );
+    let mut checked = 0;
+    for path in files {
+        let source = fs::read_to_string(&path).unwrap();
+        if let Some(text) = reassemble(&source) {
+            assert!(text == source, "{} did not round-trip", path.display());
+            checked += 1;
+        }
+    }
+    assert!(checked > 50, "only {} files scanned cleanly", checked);
+}
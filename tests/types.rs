@@ -0,0 +1,30 @@
+use std::process::Command;
+
+// Runs `check --types` on a test script, returning whether it passed and
+// what it reported.
+fn check_types(path: &str) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_tree"))
+        .args(["check", path, "--types"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    (output.status.success(), stderr)
+}
+
+#[test]
+fn interpolation_makes_a_string_of_any_value() {
+    for path in [
+        "test/types/interpolation.lox",
+        "test/string/interpolation.lox",
+    ] {
+        let (passed, stderr) = check_types(path);
+        assert!(passed, "{}: {}", path, stderr);
+    }
+}
+
+#[test]
+fn mismatched_annotations_are_reported() {
+    let (passed, stderr) = check_types("test/types/annotations.lox");
+    assert!(!passed);
+    assert!(stderr.contains("[line 25]"), "{}", stderr);
+}
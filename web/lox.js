@@ -0,0 +1,40 @@
+// Loads the WebAssembly build of the interpreter (see src/wasm.rs for how
+// to build it) and wraps it for a page:
+//
+//   const lox = await loadLox("tree.wasm", { onPrint: line => ... });
+//   const { output, errors } = lox.run('print "hi";');
+//
+// `onPrint` sees each line as the program prints it; `output` is all of
+// them once it finishes. Each error has a `line`, `column`, one-line
+// `message` and `rendered` report with the source underlined. A `fuel`
+// option stops programs after that many loop iterations and calls, so an
+// endless loop can't hang the page.
+export async function loadLox(url, { onPrint = () => {}, fuel = 0 } = {}) {
+  const decoder = new TextDecoder();
+  const encoder = new TextEncoder();
+  let memory;
+  const env = {
+    lox_print: (ptr, len) =>
+      onPrint(decoder.decode(new Uint8Array(memory.buffer, ptr, len))),
+    lox_now: () => Date.now(),
+  };
+  const { instance } = await WebAssembly.instantiateStreaming(fetch(url), {
+    env,
+  });
+  const exports = instance.exports;
+  memory = exports.memory;
+
+  return {
+    run(source) {
+      const bytes = encoder.encode(source);
+      const ptr = exports.lox_alloc(bytes.length);
+      new Uint8Array(memory.buffer, ptr, bytes.length).set(bytes);
+      const result = exports.lox_run(ptr, bytes.length, fuel);
+      exports.lox_free(ptr, bytes.length);
+      const json = decoder.decode(
+        new Uint8Array(memory.buffer, result, exports.lox_result_len()),
+      );
+      return JSON.parse(json);
+    },
+  };
+}